@@ -26,6 +26,12 @@ fn main() {
                     Err(e) => println!("Error in parsing PPS: {:?}", e)
                 };
             },
+            h264nalparse::H264NalUnitType::SEI => {
+                match parser.parse_sei(&next_unit) {
+                    Ok(messages) => for m in messages { println!("Parsed SEI: {}", m) },
+                    Err(e) => println!("Error in parsing SEI: {:?}", e)
+                };
+            },
             h264nalparse::H264NalUnitType::IDR => {
                 match parser.parse_slice(next_unit.data_offset, &next_unit) {
                     Ok(slice) => println!("Parsed slice: {}", slice),
@@ -0,0 +1,34 @@
+extern crate h264nalparse;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use h264nalparse::sdp::H264FmtpParameters;
+
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        println!("Usage: {} <capture.pcap|pcapng> <output.264> [payload type] [fmtp]", args[0]);
+        return;
+    }
+    let payload_type = args.get(3).map(|pt| pt.parse::<u8>().expect("Payload type should be a number"));
+    let fmtp = args.get(4).map(|f| H264FmtpParameters::parse(f).expect("Couldn't parse fmtp parameters"));
+
+    let mut capture = Vec::new();
+    File::open(&args[1]).and_then(|mut f| f.read_to_end(&mut capture)).expect("Couldn't read capture");
+    let extraction = match h264nalparse::pcap::extract_h264(&capture, payload_type, fmtp.as_ref()) {
+        Ok(e) => e,
+        Err(e) => panic!("Couldn't extract H.264 from capture: {:?}", e)
+    };
+    File::create(&args[2]).and_then(|mut f| f.write_all(&extraction.bytestream)).expect("Couldn't write output");
+
+    let flow = extraction.flow;
+    println!("Flow {} -> {} ssrc {:08x} payload type {}", flow.source, flow.destination, flow.ssrc, flow.payload_type);
+    println!("capture_time,rtp_timestamp,packets,packets_lost,bytes,nal_units,idr,complete");
+    for frame in &extraction.frames {
+        println!("{:.6},{},{},{},{},{},{},{}", frame.capture_time, frame.timestamp, frame.packets,
+                 frame.packets_lost, frame.bytes, frame.nal_units, frame.idr, frame.complete);
+    }
+    println!("{} frames, {} packets lost, {} NAL units dropped, {} packets rejected", extraction.frames.len(),
+             extraction.packets_lost, extraction.nal_units_dropped, extraction.packets_rejected);
+}
@@ -0,0 +1,323 @@
+use parser::{H264NalParser, H264NalParseError};
+use profile::collect_parameter_sets;
+use writer::write_nalunit;
+pub use types::*;
+
+/// AVCDecoderConfigurationRecord from ISO/IEC 14496-15, the payload of the
+/// avcC box and of the CodecPrivate / AVC sequence header in other
+/// containers. Parameter sets are kept as NAL units without start codes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264AVCDecoderConfigurationRecord {
+    pub configuration_version: u8,
+    pub avc_profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub avc_level_indication: u8,
+    pub length_size_minus_one: u8,
+    pub sequence_parameter_sets: Vec<Vec<u8>>,
+    pub picture_parameter_sets: Vec<Vec<u8>>,
+
+    // Only present for the High profiles, and even then often left out.
+    pub high_profile_fields_present: bool,
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub sequence_parameter_set_ext: Vec<Vec<u8>>
+}
+
+fn profile_has_avcc_extension(profile_idc: u8) -> bool {
+    profile_idc == 100 || profile_idc == 110 || profile_idc == 122 || profile_idc == 144
+}
+
+fn read_parameter_sets(data: &[u8], pos: &mut usize, count: usize) -> Result<Vec<Vec<u8>>, H264NalParseError> {
+    let mut sets = Vec::with_capacity(count);
+    for _ in 0..count {
+        if data.len() < *pos + 2 {
+            return Err(H264NalParseError::NotEnoughBytes);
+        }
+        let length = (data[*pos] as usize) << 8 | data[*pos + 1] as usize;
+        *pos += 2;
+        if data.len() < *pos + length {
+            return Err(H264NalParseError::NotEnoughBytes);
+        }
+        sets.push(data[*pos..*pos + length].to_vec());
+        *pos += length;
+    }
+    Ok(sets)
+}
+
+fn write_parameter_sets(out: &mut Vec<u8>, sets: &[Vec<u8>]) {
+    for set in sets {
+        out.push((set.len() >> 8) as u8);
+        out.push(set.len() as u8);
+        out.extend_from_slice(set);
+    }
+}
+
+impl Default for H264AVCDecoderConfigurationRecord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264AVCDecoderConfigurationRecord {
+    pub fn new() -> H264AVCDecoderConfigurationRecord {
+        H264AVCDecoderConfigurationRecord {
+            configuration_version: 1,
+            avc_profile_indication: 0,
+            profile_compatibility: 0,
+            avc_level_indication: 0,
+            length_size_minus_one: 3,
+            sequence_parameter_sets: Vec::new(),
+            picture_parameter_sets: Vec::new(),
+            high_profile_fields_present: false,
+            chroma_format: 1,
+            bit_depth_luma_minus8: 0,
+            bit_depth_chroma_minus8: 0,
+            sequence_parameter_set_ext: Vec::new()
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<H264AVCDecoderConfigurationRecord, H264NalParseError> {
+        if data.len() < 6 {
+            return Err(H264NalParseError::NotEnoughBytes);
+        }
+        let mut record = H264AVCDecoderConfigurationRecord::new();
+        record.configuration_version = data[0];
+        if record.configuration_version != 1 {
+            return Err(H264NalParseError::UnknownFormat);
+        }
+        record.avc_profile_indication = data[1];
+        record.profile_compatibility = data[2];
+        record.avc_level_indication = data[3];
+        record.length_size_minus_one = data[4] & 0x03;
+        let mut pos = 6;
+        record.sequence_parameter_sets = read_parameter_sets(data, &mut pos, (data[5] & 0x1F) as usize)?;
+        if data.len() < pos + 1 {
+            return Err(H264NalParseError::NotEnoughBytes);
+        }
+        let num_pps = data[pos] as usize;
+        pos += 1;
+        record.picture_parameter_sets = read_parameter_sets(data, &mut pos, num_pps)?;
+        if profile_has_avcc_extension(record.avc_profile_indication) && data.len() >= pos + 4 {
+            record.high_profile_fields_present = true;
+            record.chroma_format = data[pos] & 0x03;
+            record.bit_depth_luma_minus8 = data[pos + 1] & 0x07;
+            record.bit_depth_chroma_minus8 = data[pos + 2] & 0x07;
+            let num_ext = data[pos + 3] as usize;
+            pos += 4;
+            record.sequence_parameter_set_ext = read_parameter_sets(data, &mut pos, num_ext)?;
+        }
+        Ok(record)
+    }
+
+    /// Builds a record from parsed parameter sets, taking the profile and
+    /// level from the first SPS. nal_length_size has to be 1, 2 or 4.
+    pub fn from_parameter_sets(sps: &[H264NalUnitSPS], pps: &[H264NalUnitPPS],
+                               nal_length_size: u8) -> Result<H264AVCDecoderConfigurationRecord, H264NalParseError> {
+        if ![1, 2, 4].contains(&nal_length_size) {
+            return Err(H264NalParseError::UnknownFormat);
+        }
+        let mut record = H264AVCDecoderConfigurationRecord::new();
+        record.length_size_minus_one = nal_length_size - 1;
+        record.sequence_parameter_sets = sps.iter().map(|s| s.to_bytes()).collect::<Result<_, _>>()?;
+        record.picture_parameter_sets = pps.iter().map(|p| {
+            let chroma_format_idc = sps.iter()
+                .find(|s| s.seq_parameter_set_id == p.seq_parameter_set_id)
+                .map_or(1, |s| s.chroma_format_idc);
+            p.to_bytes(chroma_format_idc)
+        }).collect::<Result<_, _>>()?;
+        if let Some(first) = sps.first() {
+            record.update_from_sps(first);
+        }
+        Ok(record)
+    }
+
+    /// Builds a record from the parameter sets a parser has seen. Those it
+    /// parsed go in as they were coded, the others are written out.
+    pub fn from_parser(parser: &H264NalParser, nal_length_size: u8) -> Result<H264AVCDecoderConfigurationRecord, H264NalParseError> {
+        let mut record = H264AVCDecoderConfigurationRecord::from_parameter_sets(&parser.sps, &parser.pps, nal_length_size)?;
+        for (nal, sps) in record.sequence_parameter_sets.iter_mut().zip(&parser.sps) {
+            if let Some(original) = parser.sps_nal_unit(sps.seq_parameter_set_id) {
+                *nal = original.to_vec();
+            }
+        }
+        for (nal, pps) in record.picture_parameter_sets.iter_mut().zip(&parser.pps) {
+            if let Some(original) = parser.pps_nal_unit(pps.pic_parameter_set_id) {
+                *nal = original.to_vec();
+            }
+        }
+        Ok(record)
+    }
+
+    /// Copies the profile, level and format fields from an SPS.
+    pub fn update_from_sps(&mut self, sps: &H264NalUnitSPS) {
+        self.avc_profile_indication = sps.profile_idc;
+        self.profile_compatibility = sps.constraint_0_flag << 7 | sps.constraint_1_flag << 6 |
+            sps.constraint_2_flag << 5 | sps.constraint_3_flag << 4 |
+            sps.constraint_4_flag << 3 | sps.constraint_5_flag << 2;
+        self.avc_level_indication = sps.level_idc;
+        self.high_profile_fields_present = profile_has_avcc_extension(sps.profile_idc);
+        self.chroma_format = sps.chroma_format_idc as u8;
+        self.bit_depth_luma_minus8 = sps.bit_depth_luma_minus8 as u8;
+        self.bit_depth_chroma_minus8 = sps.bit_depth_chroma_minus8 as u8;
+    }
+
+    pub fn nal_length_size(&self) -> usize {
+        self.length_size_minus_one as usize + 1
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![
+            self.configuration_version,
+            self.avc_profile_indication,
+            self.profile_compatibility,
+            self.avc_level_indication,
+            0xFC | self.length_size_minus_one,
+            0xE0 | self.sequence_parameter_sets.len() as u8
+        ];
+        write_parameter_sets(&mut out, &self.sequence_parameter_sets);
+        out.push(self.picture_parameter_sets.len() as u8);
+        write_parameter_sets(&mut out, &self.picture_parameter_sets);
+        if self.high_profile_fields_present && profile_has_avcc_extension(self.avc_profile_indication) {
+            out.push(0xFC | self.chroma_format);
+            out.push(0xF8 | self.bit_depth_luma_minus8);
+            out.push(0xF8 | self.bit_depth_chroma_minus8);
+            out.push(self.sequence_parameter_set_ext.len() as u8);
+            write_parameter_sets(&mut out, &self.sequence_parameter_set_ext);
+        }
+        out
+    }
+
+    /// The SPS and PPS NAL units as an Annex B byte stream.
+    pub fn parameter_sets_bytestream(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in self.sequence_parameter_sets.iter().chain(self.picture_parameter_sets.iter()) {
+            write_nalunit(&mut out, H264NalFormat::BYTESTREAM, 4, nal);
+        }
+        out
+    }
+
+    /// Parses the SPS and PPS NAL units of the record.
+    pub fn parse_parameter_sets(&self) -> Result<(Vec<H264NalUnitSPS>, Vec<H264NalUnitPPS>), H264NalParseError> {
+        collect_parameter_sets(&self.parameter_sets_bytestream(), H264NalFormat::BYTESTREAM, 4)
+    }
+
+    /// A parser for AVC format samples described by the record, already
+    /// holding its parameter sets.
+    pub fn parser(&self) -> Result<H264NalParser, H264NalParseError> {
+        let (sps, pps) = self.parse_parameter_sets()?;
+        let mut parser = H264NalParser::from_bytes(Vec::new());
+        parser.format = H264NalFormat::AVC;
+        parser.nal_length_size = self.nal_length_size();
+        for s in sps {
+            parser.store_sps(s);
+        }
+        for p in pps {
+            parser.store_pps(p);
+        }
+        Ok(parser)
+    }
+
+    /// Splits a sample into its NAL units, without their length prefixes.
+    /// A NAL unit or length prefix cut off by the end of the sample is an
+    /// error.
+    pub fn sample_nal_units<'a>(&self, sample: &'a [u8]) -> Result<Vec<&'a [u8]>, H264NalParseError> {
+        let length_size = self.nal_length_size();
+        let mut units = Vec::new();
+        let mut pos = 0;
+        while pos < sample.len() {
+            if pos + length_size > sample.len() {
+                return Err(H264NalParseError::NotEnoughBytes);
+            }
+            let mut length = 0;
+            for i in 0..length_size {
+                length = length << 8 | sample[pos + i] as usize;
+            }
+            pos += length_size;
+            if length > sample.len() - pos {
+                return Err(H264NalParseError::NotEnoughBytes);
+            }
+            units.push(&sample[pos..pos + length]);
+            pos += length;
+        }
+        Ok(units)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A stream with an SPS and a PPS coded with nal_ref_idc 1, where
+    /// to_bytes would write 3.
+    pub fn parameter_sets_stream() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 77;
+        sps.level_idc = 30;
+        sps.pic_width_in_mbs_minus1 = 44;
+        sps.pic_height_in_map_units_minus1 = 35;
+        sps.frame_mbs_only_flag = true;
+        let mut sps_nal = sps.to_bytes().unwrap();
+        sps_nal[0] = 0x27;
+        let mut pps_nal = H264NalUnitPPS::new().to_bytes(1).unwrap();
+        pps_nal[0] = 0x28;
+        let mut data = Vec::new();
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sps_nal);
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &pps_nal);
+        (data, sps_nal, pps_nal)
+    }
+
+    #[test]
+    fn from_parser_keeps_the_coded_parameter_sets() {
+        let (data, sps_nal, pps_nal) = parameter_sets_stream();
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        parser.parse_sps(4).unwrap();
+        parser.parse_pps(4 + sps_nal.len() + 4).unwrap();
+        let record = H264AVCDecoderConfigurationRecord::from_parser(&parser, 4).unwrap();
+        assert_eq!(record.sequence_parameter_sets, vec![sps_nal.clone()]);
+        assert_eq!(record.picture_parameter_sets, vec![pps_nal]);
+
+        // A replaced SPS has no coded form any more.
+        let sps = parser.sps[0].clone();
+        parser.store_sps(sps);
+        assert!(parser.sps_nal_unit(0).is_none());
+        let record = H264AVCDecoderConfigurationRecord::from_parser(&parser, 4).unwrap();
+        assert_eq!(record.sequence_parameter_sets[0][0], 0x67);
+        assert_eq!(record.sequence_parameter_sets[0][1..], sps_nal[1..]);
+    }
+
+    #[test]
+    fn nal_length_size_has_to_be_1_2_or_4() {
+        let (data, _, _) = parameter_sets_stream();
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        parser.parse_sps(4).unwrap();
+        for &size in &[1, 2, 4] {
+            let record = H264AVCDecoderConfigurationRecord::from_parser(&parser, size).unwrap();
+            assert_eq!(record.nal_length_size(), size as usize);
+        }
+        for &size in &[0, 3, 5] {
+            assert_eq!(H264AVCDecoderConfigurationRecord::from_parser(&parser, size),
+                       Err(H264NalParseError::UnknownFormat));
+        }
+    }
+
+    #[test]
+    fn sample_nal_units_checks_the_lengths() {
+        let mut record = H264AVCDecoderConfigurationRecord::new();
+        record.length_size_minus_one = 1;
+        let sample = [0, 2, 0x65, 0x88, 0, 0, 0, 1, 0x06];
+        assert_eq!(record.sample_nal_units(&sample),
+                   Ok(vec![&[0x65, 0x88][..], &[][..], &[0x06][..]]));
+        assert_eq!(record.sample_nal_units(&[]), Ok(vec![]));
+        // The last NAL unit is cut off
+        assert_eq!(record.sample_nal_units(&sample[..8]), Err(H264NalParseError::NotEnoughBytes));
+        // and here its length prefix
+        assert_eq!(record.sample_nal_units(&sample[..7]), Err(H264NalParseError::NotEnoughBytes));
+
+        record.length_size_minus_one = 3;
+        assert_eq!(record.sample_nal_units(&[0, 0, 0, 2, 0x65, 0x88]), Ok(vec![&[0x65, 0x88][..]]));
+        assert_eq!(record.sample_nal_units(&[0xFF, 0xFF, 0xFF, 0xFF, 0x65]), Err(H264NalParseError::NotEnoughBytes));
+    }
+}
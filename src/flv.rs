@@ -0,0 +1,570 @@
+use std::io;
+use std::io::Write;
+use avcc::H264AVCDecoderConfigurationRecord;
+use parser::{H264NalParser, H264NalParseError};
+use poc::H264TimestampGenerator;
+use writer::write_nalunit;
+pub use types::*;
+
+pub const FLV_TAG_AUDIO : u8 = 8;
+pub const FLV_TAG_VIDEO : u8 = 9;
+/// CodecID of AVC in a video tag.
+pub const FLV_CODEC_AVC : u8 = 7;
+
+#[derive(Debug)]
+pub enum H264FlvError {
+    /// The data doesn't start with an FLV header.
+    InvalidHeader,
+    Truncated,
+    /// An AVC NALU packet before any sequence header.
+    NoSequenceHeader,
+    Parse(H264NalParseError),
+    Io(io::Error)
+}
+
+/// A tag of an FLV file, with the body as it would come in an RTMP
+/// message.
+#[derive(Debug, Clone)]
+pub struct H264FlvTag {
+    pub tag_type: u8,
+    /// In milliseconds.
+    pub timestamp: u32,
+    pub data: Vec<u8>
+}
+
+/// Splits an FLV file into its tags. A file that ends in the middle of a
+/// tag, as a recording cut short does, also gives where that tag starts.
+pub fn read_flv_tags(data: &[u8]) -> Result<(Vec<H264FlvTag>, Option<usize>), H264FlvError> {
+    if data.len() < 9 || &data[0..3] != b"FLV" {
+        return Err(H264FlvError::InvalidHeader);
+    }
+    let header_size = (data[5] as usize) << 24 | (data[6] as usize) << 16 | (data[7] as usize) << 8 | data[8] as usize;
+    // Skip PreviousTagSize0 as well.
+    let mut pos = header_size + 4;
+    let mut tags = Vec::new();
+    let mut truncated_at = None;
+    while pos < data.len() {
+        if pos + 11 > data.len() {
+            truncated_at = Some(pos);
+            break;
+        }
+        let size = (data[pos + 1] as usize) << 16 | (data[pos + 2] as usize) << 8 | data[pos + 3] as usize;
+        let timestamp = (data[pos + 7] as u32) << 24 | (data[pos + 4] as u32) << 16 |
+            (data[pos + 5] as u32) << 8 | data[pos + 6] as u32;
+        if pos + 11 + size > data.len() {
+            truncated_at = Some(pos);
+            break;
+        }
+        tags.push(H264FlvTag {
+            // The filter bit is for encrypted tags.
+            tag_type: data[pos] & 0x1F,
+            timestamp,
+            data: data[pos + 11..pos + 11 + size].to_vec()
+        });
+        pos += 11 + size + 4;
+    }
+    Ok((tags, truncated_at))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264FlvPacketType {
+    SequenceHeader,
+    Nalu,
+    EndOfSequence,
+    Reserved(u8)
+}
+
+/// An AVCVIDEOPACKET, the body of an AVC video tag or RTMP video message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264FlvVideoTag {
+    /// DTS in milliseconds.
+    pub timestamp: u32,
+    pub keyframe: bool,
+    pub packet_type: H264FlvPacketType,
+    /// PTS - DTS in milliseconds.
+    pub composition_time: i32,
+    /// The avcC record for a sequence header, length prefixed NAL units
+    /// otherwise.
+    pub data: Vec<u8>
+}
+
+impl H264FlvVideoTag {
+    /// None if the body isn't AVC video.
+    pub fn parse(body: &[u8], timestamp: u32) -> Result<Option<H264FlvVideoTag>, H264FlvError> {
+        if body.is_empty() || body[0] & 0x0F != FLV_CODEC_AVC {
+            return Ok(None);
+        }
+        if body.len() < 5 {
+            return Err(H264FlvError::Truncated);
+        }
+        let packet_type = match body[1] {
+            0 => H264FlvPacketType::SequenceHeader,
+            1 => H264FlvPacketType::Nalu,
+            2 => H264FlvPacketType::EndOfSequence,
+            t => H264FlvPacketType::Reserved(t)
+        };
+        // SI24
+        let composition_time = (((body[2] as u32) << 24 | (body[3] as u32) << 16 | (body[4] as u32) << 8) as i32) >> 8;
+        Ok(Some(H264FlvVideoTag {
+            timestamp,
+            keyframe: body[0] >> 4 == 1,
+            packet_type,
+            composition_time,
+            data: body[5..].to_vec()
+        }))
+    }
+
+    /// PTS in milliseconds.
+    pub fn pts(&self) -> i64 {
+        self.timestamp as i64 + self.composition_time as i64
+    }
+
+    /// The tag body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let frame_type = if self.keyframe { 1 } else { 2 };
+        let packet_type = match self.packet_type {
+            H264FlvPacketType::SequenceHeader => 0,
+            H264FlvPacketType::Nalu => 1,
+            H264FlvPacketType::EndOfSequence => 2,
+            H264FlvPacketType::Reserved(t) => t
+        };
+        let mut body = vec![
+            frame_type << 4 | FLV_CODEC_AVC,
+            packet_type,
+            (self.composition_time >> 16) as u8, (self.composition_time >> 8) as u8, self.composition_time as u8
+        ];
+        body.extend_from_slice(&self.data);
+        body
+    }
+}
+
+/// The NAL units of one NALU packet, without length prefixes.
+#[derive(Debug, Clone)]
+pub struct H264FlvFrame {
+    /// In milliseconds.
+    pub dts: u32,
+    pub pts: i64,
+    pub keyframe: bool,
+    pub nal_units: Vec<Vec<u8>>
+}
+
+impl H264FlvFrame {
+    /// Hands the frame to a parser as Annex B data. The parser keeps the
+    /// parameter sets it has seen, so one parser can be used for the whole
+    /// stream.
+    pub fn load_into(&self, parser: &mut H264NalParser) {
+        let mut data = Vec::new();
+        for nal in &self.nal_units {
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, nal);
+        }
+        parser.set_data(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+    }
+}
+
+/// Follows the video tags of an FLV file or RTMP stream, keeping the last
+/// sequence header to split the NALU packets with.
+pub struct H264FlvDemuxer {
+    pub avcc: Option<H264AVCDecoderConfigurationRecord>,
+    /// Set when a new sequence header arrived since it was last cleared.
+    pub sequence_header_changed: bool
+}
+
+impl Default for H264FlvDemuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264FlvDemuxer {
+    pub fn new() -> H264FlvDemuxer {
+        H264FlvDemuxer {
+            avcc: None,
+            sequence_header_changed: false
+        }
+    }
+
+    /// Takes the body of a video tag. Returns the frame of a NALU packet,
+    /// and None for other packets and non-AVC video.
+    pub fn push_video_tag(&mut self, body: &[u8], timestamp: u32) -> Result<Option<H264FlvFrame>, H264FlvError> {
+        let tag = match H264FlvVideoTag::parse(body, timestamp)? {
+            Some(t) => t,
+            None => return Ok(None)
+        };
+        match tag.packet_type {
+            H264FlvPacketType::SequenceHeader => {
+                let avcc = H264AVCDecoderConfigurationRecord::parse(&tag.data).map_err(H264FlvError::Parse)?;
+                if self.avcc.as_ref() != Some(&avcc) {
+                    self.sequence_header_changed = true;
+                }
+                self.avcc = Some(avcc);
+                Ok(None)
+            },
+            H264FlvPacketType::Nalu => {
+                let avcc = self.avcc.as_ref().ok_or(H264FlvError::NoSequenceHeader)?;
+                let nal_units = avcc.sample_nal_units(&tag.data).map_err(H264FlvError::Parse)?;
+                Ok(Some(H264FlvFrame {
+                    dts: tag.timestamp,
+                    pts: tag.pts(),
+                    keyframe: tag.keyframe,
+                    nal_units: nal_units.iter().map(|n| n.to_vec()).collect()
+                }))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// A parser holding the parameter sets of the current sequence header.
+    pub fn parser(&self) -> Result<H264NalParser, H264FlvError> {
+        let avcc = self.avcc.as_ref().ok_or(H264FlvError::NoSequenceHeader)?;
+        let mut parser = avcc.parser().map_err(H264FlvError::Parse)?;
+        parser.format = H264NalFormat::BYTESTREAM;
+        Ok(parser)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct H264FlvStream {
+    pub frames: Vec<H264FlvFrame>,
+    /// The sequence header in force at the end.
+    pub avcc: Option<H264AVCDecoderConfigurationRecord>,
+    /// Where the file is cut short, as read_flv_tags gives it.
+    pub truncated_at: Option<usize>
+}
+
+/// Every AVC frame of an FLV file.
+pub fn demux_flv(data: &[u8]) -> Result<H264FlvStream, H264FlvError> {
+    let mut demuxer = H264FlvDemuxer::new();
+    let mut frames = Vec::new();
+    let (tags, truncated_at) = read_flv_tags(data)?;
+    for tag in tags {
+        if tag.tag_type == FLV_TAG_VIDEO {
+            if let Some(frame) = demuxer.push_video_tag(&tag.data, tag.timestamp)? {
+                frames.push(frame);
+            }
+        }
+    }
+    Ok(H264FlvStream {
+        frames,
+        avcc: demuxer.avcc,
+        truncated_at
+    })
+}
+
+/// Writes access units as a video only FLV file. A sequence header is
+/// written before the first access unit and whenever the parameter sets
+/// change; parameter sets and access unit delimiters are left out of the
+/// NALU packets.
+pub struct H264FlvWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+    avcc: Option<H264AVCDecoderConfigurationRecord>,
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>
+}
+
+impl<W: Write> H264FlvWriter<W> {
+    pub fn new(writer: W) -> H264FlvWriter<W> {
+        H264FlvWriter {
+            writer,
+            header_written: false,
+            avcc: None,
+            sps: Vec::new(),
+            pps: Vec::new()
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn write_tag(&mut self, tag_type: u8, timestamp: u32, body: &[u8]) -> Result<(), H264FlvError> {
+        if !self.header_written {
+            // Video only, then PreviousTagSize0.
+            self.writer.write_all(&[b'F', b'L', b'V', 1, 0x01, 0, 0, 0, 9, 0, 0, 0, 0]).map_err(H264FlvError::Io)?;
+            self.header_written = true;
+        }
+        let size = body.len();
+        let header = [
+            tag_type, (size >> 16) as u8, (size >> 8) as u8, size as u8,
+            (timestamp >> 16) as u8, (timestamp >> 8) as u8, timestamp as u8, (timestamp >> 24) as u8,
+            0, 0, 0
+        ];
+        self.writer.write_all(&header).map_err(H264FlvError::Io)?;
+        self.writer.write_all(body).map_err(H264FlvError::Io)?;
+        self.writer.write_all(&((11 + size) as u32).to_be_bytes()).map_err(H264FlvError::Io)
+    }
+
+    pub fn write_video_tag(&mut self, tag: &H264FlvVideoTag) -> Result<(), H264FlvError> {
+        self.write_tag(FLV_TAG_VIDEO, tag.timestamp, &tag.to_bytes())
+    }
+
+    /// Writes an access unit given as NAL units without start codes, with
+    /// timestamps in milliseconds.
+    pub fn write_access_unit(&mut self, nal_units: &[Vec<u8>], dts: u32, pts: i64) -> Result<(), H264FlvError> {
+        let mut keyframe = false;
+        let mut sps = Vec::new();
+        let mut pps = Vec::new();
+        let mut data = Vec::new();
+        for nal in nal_units.iter().filter(|n| !n.is_empty()) {
+            match nal[0] & 0x1F {
+                7 => sps.push(nal.clone()),
+                8 => pps.push(nal.clone()),
+                9 => {},
+                t => {
+                    keyframe |= t == 5;
+                    write_nalunit(&mut data, H264NalFormat::AVC, 4, nal);
+                }
+            }
+        }
+        if !sps.is_empty() {
+            self.sps = sps;
+        }
+        if !pps.is_empty() {
+            self.pps = pps;
+        }
+        self.update_sequence_header(dts)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.write_video_tag(&H264FlvVideoTag {
+            timestamp: dts,
+            keyframe,
+            packet_type: H264FlvPacketType::Nalu,
+            composition_time: (pts - dts as i64) as i32,
+            data
+        })
+    }
+
+    /// Writes a sequence header if the parameter sets differ from the last
+    /// one written.
+    fn update_sequence_header(&mut self, timestamp: u32) -> Result<(), H264FlvError> {
+        if self.sps.is_empty() || self.pps.is_empty() {
+            return Ok(());
+        }
+        let mut avcc = H264AVCDecoderConfigurationRecord::new();
+        avcc.sequence_parameter_sets = self.sps.clone();
+        avcc.picture_parameter_sets = self.pps.clone();
+        let (sps, _) = avcc.parse_parameter_sets().map_err(H264FlvError::Parse)?;
+        if let Some(first) = sps.first() {
+            avcc.update_from_sps(first);
+        }
+        if self.avcc.as_ref() == Some(&avcc) {
+            return Ok(());
+        }
+        self.write_video_tag(&H264FlvVideoTag {
+            timestamp,
+            keyframe: true,
+            packet_type: H264FlvPacketType::SequenceHeader,
+            composition_time: 0,
+            data: avcc.to_bytes()
+        })?;
+        self.avcc = Some(avcc);
+        Ok(())
+    }
+
+    /// Writes the end of sequence packet and flushes.
+    pub fn finish(&mut self, timestamp: u32) -> Result<(), H264FlvError> {
+        self.write_video_tag(&H264FlvVideoTag {
+            timestamp,
+            keyframe: true,
+            packet_type: H264FlvPacketType::EndOfSequence,
+            composition_time: 0,
+            data: Vec::new()
+        })?;
+        self.writer.flush().map_err(H264FlvError::Io)
+    }
+
+    /// Writes a whole stream, splitting it with the access unit detection
+    /// of H264NalParser and deriving the timestamps, and finishes.
+    pub fn write_stream(&mut self, data: &[u8], format: H264NalFormat,
+                        nal_length_size: usize) -> Result<(), H264FlvError> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        // Derived at 90kHz so frame rates like 29.97 don't drift.
+        let mut timestamps = H264TimestampGenerator::new(90000);
+        let mut offset = 0;
+        let mut last_dts = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).map_err(H264FlvError::Parse)?;
+            let mut first_slice = None;
+            for unit in &au.nal_units {
+                match unit.nal_unit_type_num {
+                    7 => { parser.parse_sps(unit.data_offset).map_err(H264FlvError::Parse)?; },
+                    8 => { parser.parse_pps(unit.data_offset).map_err(H264FlvError::Parse)?; },
+                    1..=5 if first_slice.is_none() => {
+                        first_slice = parser.parse_slice(unit.data_offset, unit).ok().map(|s| (unit, s));
+                    },
+                    _ => {}
+                }
+            }
+            let sps = first_slice.as_ref().and_then(|(_, slice)| parser.find_pps(slice.pic_parameter_set_id))
+                .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id));
+            let (pts, dts) = match (sps, first_slice.as_ref()) {
+                (Some(sps), Some(&(nalu, ref slice))) => timestamps.next(Some((sps, nalu, slice))),
+                _ => timestamps.next(None)
+            };
+            let nal_units : Vec<Vec<u8>> = au.nal_units.iter().map(|n| parser.nal_data(n).to_vec()).collect();
+            last_dts = (dts / 90) as u32;
+            self.write_access_unit(&nal_units, last_dts, (pts / 90) as i64)?;
+            offset += au.size;
+        }
+        self.finish(last_dts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::H264NalWriter;
+
+    /// An SPS, a PPS and a slice NAL unit of a 16x16 Baseline stream.
+    fn nal_units(level_idc: u8, frame_num: u32, idr: bool) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = level_idc;
+        sps.frame_mbs_only_flag = true;
+        sps.max_num_ref_frames = 1;
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = if idr { 7 } else { 5 };
+        slice.frame_num = frame_num;
+        slice.pic_order_cnt_lsb = (2 * frame_num) as u16;
+        let nal_type = if idr { 5 } else { 1 };
+        let unit = H264NalUnit::new(0, 4, 0, 2, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        (sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), writer.to_nal(2, nal_type))
+    }
+
+    #[test]
+    fn sequence_headers_and_nalu_packets_round_trip() {
+        let (sps, pps, idr) = nal_units(10, 0, true);
+        let (_, _, p1) = nal_units(10, 1, false);
+        let (_, _, p2) = nal_units(10, 2, false);
+        let (sps2, pps2, idr2) = nal_units(11, 0, true);
+        let aud = vec![0x09, 0xF0];
+        let mut writer = H264FlvWriter::new(Vec::new());
+        writer.write_access_unit(&[aud.clone(), sps.clone(), pps.clone(), idr.clone()], 0, 80).unwrap();
+        writer.write_access_unit(&[aud.clone(), p1.clone()], 40, 40).unwrap();
+        writer.write_access_unit(&[p2], 80, 120).unwrap();
+        // The same parameter sets again don't give another sequence header,
+        // new ones do.
+        writer.write_access_unit(&[sps.clone(), pps.clone(), idr.clone()], 120, 160).unwrap();
+        writer.write_access_unit(&[sps2.clone(), pps2.clone(), idr2.clone()], 160, 200).unwrap();
+        writer.finish(200).unwrap();
+        let data = writer.into_inner();
+
+        let (tags, truncated_at) = read_flv_tags(&data).unwrap();
+        assert_eq!(truncated_at, None);
+        assert!(tags.iter().all(|t| t.tag_type == FLV_TAG_VIDEO));
+        let video : Vec<H264FlvVideoTag> = tags.iter()
+            .map(|t| H264FlvVideoTag::parse(&t.data, t.timestamp).unwrap().unwrap())
+            .collect();
+        let types : Vec<H264FlvPacketType> = video.iter().map(|t| t.packet_type).collect();
+        assert_eq!(types, vec![
+            H264FlvPacketType::SequenceHeader, H264FlvPacketType::Nalu, H264FlvPacketType::Nalu,
+            H264FlvPacketType::Nalu, H264FlvPacketType::Nalu, H264FlvPacketType::SequenceHeader,
+            H264FlvPacketType::Nalu, H264FlvPacketType::EndOfSequence
+        ]);
+        // Composition times are PTS - DTS.
+        let composition : Vec<i32> = video.iter().map(|t| t.composition_time).collect();
+        assert_eq!(composition, vec![0, 80, 0, 40, 40, 0, 40, 0]);
+        for tag in &video {
+            assert_eq!(H264FlvVideoTag::parse(&tag.to_bytes(), tag.timestamp).unwrap().as_ref(), Some(tag));
+        }
+
+        let stream = demux_flv(&data).unwrap();
+        assert_eq!(stream.truncated_at, None);
+        let frames = stream.frames;
+        let avcc = stream.avcc.unwrap();
+        assert_eq!(avcc.sequence_parameter_sets, vec![sps2]);
+        assert_eq!(avcc.picture_parameter_sets, vec![pps2]);
+        assert_eq!(avcc.avc_level_indication, 11);
+        let timing : Vec<(u32, i64, bool)> = frames.iter().map(|f| (f.dts, f.pts, f.keyframe)).collect();
+        assert_eq!(timing, vec![(0, 80, true), (40, 40, false), (80, 120, false), (120, 160, true), (160, 200, true)]);
+        // Parameter sets and delimiters only go in the sequence headers.
+        assert_eq!(frames[0].nal_units, vec![idr.clone()]);
+        assert_eq!(frames[1].nal_units, vec![p1]);
+        assert_eq!(frames[4].nal_units, vec![idr2]);
+
+        let mut parser = H264FlvDemuxer::new();
+        for tag in &tags[..2] {
+            parser.push_video_tag(&tag.data, tag.timestamp).unwrap();
+        }
+        let mut nal_parser = parser.parser().unwrap();
+        frames[0].load_into(&mut nal_parser);
+        assert!(nal_parser.find_sps(0).is_some());
+        let au = nal_parser.parse_access_unit(0).unwrap();
+        assert_eq!(au.nal_units.len(), 1);
+        assert_eq!(nal_parser.nal_data(&au.nal_units[0]), &idr[..]);
+    }
+
+    #[test]
+    fn video_tags_are_parsed() {
+        // A negative SI24 composition time.
+        let body = [0x27, 1, 0xFF, 0xFF, 0xD8, 0, 0, 0, 1, 0x41];
+        let tag = H264FlvVideoTag::parse(&body, 1000).unwrap().unwrap();
+        assert_eq!(tag.composition_time, -40);
+        assert_eq!(tag.pts(), 960);
+        assert!(!tag.keyframe);
+        assert_eq!(tag.packet_type, H264FlvPacketType::Nalu);
+        assert_eq!(tag.to_bytes(), body);
+        // Other codecs are skipped, short AVC bodies are errors.
+        assert!(H264FlvVideoTag::parse(&[0x12, 0, 0], 0).unwrap().is_none());
+        assert!(H264FlvVideoTag::parse(&[], 0).unwrap().is_none());
+        assert!(matches!(H264FlvVideoTag::parse(&[0x17, 1, 0], 0), Err(H264FlvError::Truncated)));
+
+        // A NALU packet needs a sequence header to split it.
+        let mut demuxer = H264FlvDemuxer::new();
+        assert!(matches!(demuxer.push_video_tag(&body, 1000), Err(H264FlvError::NoSequenceHeader)));
+        let (sps, pps, _) = nal_units(10, 0, true);
+        let mut avcc = H264AVCDecoderConfigurationRecord::new();
+        avcc.sequence_parameter_sets = vec![sps];
+        avcc.picture_parameter_sets = vec![pps];
+        let header = H264FlvVideoTag {
+            timestamp: 0,
+            keyframe: true,
+            packet_type: H264FlvPacketType::SequenceHeader,
+            composition_time: 0,
+            data: avcc.to_bytes()
+        };
+        assert!(demuxer.push_video_tag(&header.to_bytes(), 0).unwrap().is_none());
+        assert!(demuxer.sequence_header_changed);
+        demuxer.sequence_header_changed = false;
+        assert!(demuxer.push_video_tag(&header.to_bytes(), 0).unwrap().is_none());
+        assert!(!demuxer.sequence_header_changed);
+        let frame = demuxer.push_video_tag(&body, 1000).unwrap().unwrap();
+        assert_eq!((frame.dts, frame.pts, frame.keyframe), (1000, 960, false));
+        assert_eq!(frame.nal_units, vec![vec![0x41]]);
+    }
+
+    #[test]
+    fn cut_files_keep_the_whole_tags() {
+        let (sps, pps, idr) = nal_units(10, 0, true);
+        let mut writer = H264FlvWriter::new(Vec::new());
+        writer.write_access_unit(&[sps, pps, idr.clone()], 0, 0).unwrap();
+        writer.write_access_unit(&[idr], 40, 40).unwrap();
+        let data = writer.into_inner();
+        let (tags, _) = read_flv_tags(&data).unwrap();
+        // Header, PreviousTagSize0, the sequence header and the first frame
+        let last_tag = 13 + 2 * 15 + tags[0].data.len() + tags[1].data.len();
+        assert_eq!(tags.len(), 3);
+
+        for &end in &[data.len() - 5, last_tag + 5] {
+            let (tags, truncated_at) = read_flv_tags(&data[..end]).unwrap();
+            assert_eq!(tags.len(), 2);
+            assert_eq!(truncated_at, Some(last_tag));
+            let stream = demux_flv(&data[..end]).unwrap();
+            assert_eq!(stream.frames.len(), 1);
+            assert!(stream.avcc.is_some());
+            assert_eq!(stream.truncated_at, Some(last_tag));
+        }
+        // Only the last PreviousTagSize missing isn't a cut tag.
+        let (whole, truncated_at) = read_flv_tags(&data[..data.len() - 4]).unwrap();
+        assert_eq!((whole.len(), truncated_at), (3, None));
+        assert!(matches!(read_flv_tags(b"FLX\x01"), Err(H264FlvError::InvalidHeader)));
+    }
+}
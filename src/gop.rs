@@ -0,0 +1,506 @@
+use std::cmp;
+use parser::{H264NalParser, H264NalParseError};
+use poc::H264PocCalculator;
+pub use types::*;
+
+/// SEI payload type of the recovery point SEI.
+const SEI_RECOVERY_POINT : u32 = 6;
+
+/// Pictures decoded before a frame and shown after it can't be more than a
+/// full DPB, so the reorder depth only needs to look that far back.
+const MAX_DPB_FRAMES : usize = 16;
+
+/// The type of a picture, from the slice types of all of its slices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum H264PictureType {
+    I,
+    P,
+    B
+}
+
+impl H264PictureType {
+    /// The type of a picture given one of its slices. SI slices count as
+    /// I and SP slices as P.
+    pub fn from_slice_type(slice_type: u32) -> H264PictureType {
+        if slice_type_is_b_slice(slice_type) {
+            H264PictureType::B
+        } else if slice_type_is_p_slice(slice_type) || slice_type_is_sp_slice(slice_type) {
+            H264PictureType::P
+        } else {
+            H264PictureType::I
+        }
+    }
+
+    pub fn letter(&self) -> char {
+        match *self {
+            H264PictureType::I => 'I',
+            H264PictureType::P => 'P',
+            H264PictureType::B => 'B'
+        }
+    }
+}
+
+/// A frame or complementary field pair of the stream, in decoding order.
+#[derive(Debug, Clone)]
+pub struct H264GopPicture {
+    /// Index of the (first) access unit of the picture in the stream.
+    pub access_unit: u64,
+    /// B if any slice is a B slice, else P if any is a P slice.
+    pub picture_type: H264PictureType,
+    pub idr: bool,
+    /// An IDR picture or one with a recovery point SEI.
+    pub random_access: bool,
+    pub reference: bool,
+    pub field: bool,
+    pub pic_order_cnt: i32,
+    /// Counts the coded video sequences, or rather the resets of the
+    /// picture order count, so pictures can be compared by
+    /// (poc_epoch, pic_order_cnt).
+    pub poc_epoch: u32
+}
+
+/// A group of pictures, from one random access point to the next in
+/// decoding order.
+#[derive(Debug, Clone)]
+pub struct H264Gop {
+    /// Index into H264GopReport::pictures of the first picture.
+    pub first_picture: usize,
+    pub frames: u32,
+    pub idr: bool,
+    /// False for the pictures before the first random access point of a
+    /// stream.
+    pub random_access: bool,
+    /// No picture of the group is shown before the first one, so it
+    /// doesn't need the group before it.
+    pub closed: bool,
+    /// Picture types in output order, e.g. IBBPBBP. The leading pictures
+    /// of an open GOP come before the I.
+    pub pattern: String,
+    /// Longest run of B pictures in output order.
+    pub max_consecutive_b: u32,
+    /// B pictures with nal_ref_idc != 0, used as references by other B
+    /// pictures.
+    pub reference_b_frames: u32
+}
+
+/// GOP structure of a stream.
+#[derive(Debug, Clone)]
+pub struct H264GopReport {
+    pub pictures: Vec<H264GopPicture>,
+    pub gops: Vec<H264Gop>,
+    /// From VUI timing unless given by the caller.
+    pub frame_rate: Option<f64>,
+    pub max_consecutive_b: u32,
+    /// Any B picture is used as a reference: hierarchical B frames.
+    pub b_pyramid: bool,
+    /// The most frames that precede a frame in decoding order and follow it
+    /// in output order, which max_num_reorder_frames has to allow for.
+    pub reorder_depth: u32,
+    /// max_num_reorder_frames from the VUI bitstream restriction of the
+    /// first SPS, if it has one.
+    pub max_num_reorder_frames: Option<u32>,
+    /// Access units whose slices refer to a missing parameter set.
+    pub skipped_access_units: u64
+}
+
+impl H264GopReport {
+    /// Frames between consecutive random access points. The last GOP isn't
+    /// included as the stream could have been cut short.
+    pub fn keyframe_intervals(&self) -> Vec<u32> {
+        let gops = self.gops.iter().filter(|g| g.random_access).collect::<Vec<_>>();
+        if gops.len() < 2 {
+            return Vec::new();
+        }
+        gops[..gops.len() - 1].iter().map(|g| g.frames).collect()
+    }
+
+    pub fn average_keyframe_interval(&self) -> Option<f64> {
+        let intervals = self.keyframe_intervals();
+        if intervals.is_empty() {
+            return None;
+        }
+        Some(intervals.iter().map(|&i| i as f64).sum::<f64>() / intervals.len() as f64)
+    }
+
+    /// The average keyframe interval in seconds, which needs a frame rate.
+    pub fn average_keyframe_interval_seconds(&self) -> Option<f64> {
+        match (self.average_keyframe_interval(), self.frame_rate) {
+            (Some(frames), Some(rate)) if rate > 0.0 => Some(frames / rate),
+            _ => None
+        }
+    }
+
+    pub fn min_keyframe_interval(&self) -> Option<u32> {
+        self.keyframe_intervals().into_iter().min()
+    }
+
+    pub fn max_keyframe_interval(&self) -> Option<u32> {
+        self.keyframe_intervals().into_iter().max()
+    }
+
+    pub fn closed_gops(&self) -> usize {
+        self.gops.iter().filter(|g| g.random_access && g.closed).count()
+    }
+
+    pub fn open_gops(&self) -> usize {
+        self.gops.iter().filter(|g| g.random_access && !g.closed).count()
+    }
+
+    /// The stream reorders more than its SPS declares, which breaks
+    /// decoders that output pictures as early as the SPS allows.
+    pub fn exceeds_declared_reorder_frames(&self) -> bool {
+        self.max_num_reorder_frames.is_some_and(|declared| self.reorder_depth > declared)
+    }
+}
+
+/// Goes through the slice headers of a stream for its GOP structure.
+/// frame_rate is used when the SPS has no VUI timing info.
+pub fn analyze_gops(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                    frame_rate: Option<f64>) -> Result<H264GopReport, H264NalParseError> {
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut poc = H264PocCalculator::new();
+    let mut first_sps : Option<H264NalUnitSPS> = None;
+    let mut pictures : Vec<H264GopPicture> = Vec::new();
+    // frame_num and bottom_field_flag of the last picture if it's a first
+    // field still waiting for the second one.
+    let mut open_field : Option<(u32, bool)> = None;
+    let mut epoch = 0;
+    let mut skipped = 0;
+    let mut access_unit = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let au = parser.parse_access_unit(offset)?;
+        if au.size == 0 {
+            break;
+        }
+        offset += au.size;
+        access_unit += 1;
+
+        let mut picture_type = None;
+        let mut first_slice = None;
+        for unit in &au.nal_units {
+            match unit.nal_unit_type_num {
+                7 => {
+                    let sps = parser.parse_sps(unit.data_offset)?;
+                    if first_sps.is_none() {
+                        first_sps = Some(sps);
+                    }
+                },
+                8 => { parser.parse_pps(unit.data_offset)?; },
+                1..=5 => {
+                    let slice = parser.parse_slice(unit.data_offset, unit)?;
+                    let slice_picture_type = H264PictureType::from_slice_type(slice.slice_type);
+                    picture_type = Some(cmp::max(picture_type.unwrap_or(slice_picture_type), slice_picture_type));
+                    if first_slice.is_none() {
+                        first_slice = Some((unit.clone(), slice));
+                    }
+                },
+                _ => {}
+            }
+        }
+        let (unit, slice) = match first_slice {
+            Some(s) => s,
+            None => continue
+        };
+        let picture_type = picture_type.unwrap_or(H264PictureType::I);
+        let sps = match parser.find_pps(slice.pic_parameter_set_id)
+            .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id)) {
+            Some(sps) => sps.clone(),
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let after_mmco5 = poc.last_had_mmco5();
+        let pic_order_cnt = poc.compute(&sps, &unit, &slice).pic_order_cnt();
+        if (unit.idr_pic_flag || after_mmco5) && !pictures.is_empty() {
+            epoch += 1;
+        }
+        let random_access = unit.idr_pic_flag || au.sei.iter().any(|m| m.payload_type == SEI_RECOVERY_POINT);
+
+        // The second field of a pair joins the picture of the first one.
+        if slice.field_pic_flag {
+            if let Some((frame_num, bottom)) = open_field.take() {
+                if frame_num == slice.frame_num && bottom != slice.bottom_field_flag && !unit.idr_pic_flag {
+                    let last = pictures.last_mut().unwrap();
+                    last.picture_type = cmp::max(last.picture_type, picture_type);
+                    last.reference |= unit.nal_ref_idc != 0;
+                    last.pic_order_cnt = cmp::min(last.pic_order_cnt, pic_order_cnt);
+                    continue;
+                }
+            }
+            open_field = Some((slice.frame_num, slice.bottom_field_flag));
+        } else {
+            open_field = None;
+        }
+
+        pictures.push(H264GopPicture {
+            access_unit: access_unit - 1,
+            picture_type,
+            idr: unit.idr_pic_flag,
+            random_access,
+            reference: unit.nal_ref_idc != 0,
+            field: slice.field_pic_flag,
+            pic_order_cnt,
+            poc_epoch: epoch
+        });
+    }
+    let sps = match first_sps {
+        Some(s) => s,
+        None => return Err(H264NalParseError::GenericParseError)
+    };
+
+    let mut starts = pictures.iter().enumerate()
+        .filter(|&(_, p)| p.random_access)
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if starts.first() != Some(&0) && !pictures.is_empty() {
+        starts.insert(0, 0);
+    }
+    let gops = starts.iter().enumerate().map(|(n, &start)| {
+        let end = starts.get(n + 1).cloned().unwrap_or(pictures.len());
+        analyze_gop(&pictures, start, end)
+    }).collect::<Vec<_>>();
+
+    let max_num_reorder_frames = match sps.vui_parameters {
+        Some(ref vui) if vui.bitstream_restriction_flag != 0 => Some(vui.max_num_reorder_frames),
+        _ => None
+    };
+    Ok(H264GopReport {
+        max_consecutive_b: gops.iter().map(|g| g.max_consecutive_b).max().unwrap_or(0),
+        b_pyramid: gops.iter().any(|g| g.reference_b_frames > 0),
+        reorder_depth: reorder_depth(&pictures),
+        max_num_reorder_frames,
+        frame_rate: sps.frame_rate().or(frame_rate),
+        pictures,
+        gops,
+        skipped_access_units: skipped
+    })
+}
+
+fn analyze_gop(pictures: &[H264GopPicture], start: usize, end: usize) -> H264Gop {
+    let first = &pictures[start];
+    let mut output_order = pictures[start..end].iter().collect::<Vec<_>>();
+    output_order.sort_by_key(|p| (p.poc_epoch, p.pic_order_cnt));
+    let pattern = output_order.iter().map(|p| p.picture_type.letter()).collect::<String>();
+    let max_consecutive_b = pattern.split(|c| c != 'B').map(|run| run.len()).max().unwrap_or(0);
+    // Leading pictures are decoded after the first one but shown before
+    // it, and reference the group before.
+    let leading = pictures[start + 1..end].iter()
+        .any(|p| (p.poc_epoch, p.pic_order_cnt) < (first.poc_epoch, first.pic_order_cnt));
+    H264Gop {
+        first_picture: start,
+        frames: (end - start) as u32,
+        idr: first.idr,
+        random_access: first.random_access,
+        closed: first.random_access && (first.idr || !leading),
+        pattern,
+        max_consecutive_b: max_consecutive_b as u32,
+        reference_b_frames: pictures[start..end].iter()
+            .filter(|p| p.picture_type == H264PictureType::B && p.reference)
+            .count() as u32
+    }
+}
+
+fn reorder_depth(pictures: &[H264GopPicture]) -> u32 {
+    let mut depth = 0;
+    for (i, picture) in pictures.iter().enumerate() {
+        let key = (picture.poc_epoch, picture.pic_order_cnt);
+        let earlier = &pictures[i.saturating_sub(MAX_DPB_FRAMES)..i];
+        let later_output = earlier.iter().filter(|p| (p.poc_epoch, p.pic_order_cnt) > key).count();
+        depth = cmp::max(depth, later_output as u32);
+    }
+    depth
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use writer::{H264NalWriter, H264SEIBuilder, write_nalunit};
+
+    /// A 16x16 SPS with picture order count type 0 and 8 bit lsbs.
+    pub fn sps(frame_mbs_only: bool) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 77;
+        sps.level_idc = 30;
+        sps.frame_mbs_only_flag = frame_mbs_only;
+        sps.direct_8x8_inference_flag = 1;
+        sps.max_num_ref_frames = 2;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = 4;
+        sps
+    }
+
+    /// A picture for stream(): slice type letter, IDR, reference,
+    /// frame_num, pic_order_cnt_lsb, and for a field whether it's the
+    /// bottom one.
+    pub type TestPicture = (char, bool, bool, u32, u16, Option<bool>);
+
+    /// The first slice of a picture, with slice_qp_delta qp_delta.
+    pub fn slice_nal(sps: &H264NalUnitSPS, picture: &TestPicture, qp_delta: i32) -> Vec<u8> {
+        let &(slice_type, idr, reference, frame_num, lsb, field) = picture;
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = match slice_type {
+            'I' => 7,
+            'P' => 5,
+            _ => 6
+        };
+        slice.frame_num = frame_num;
+        slice.pic_order_cnt_lsb = lsb;
+        slice.field_pic_flag = field.is_some();
+        slice.bottom_field_flag = field == Some(true);
+        slice.slice_qp_delta = qp_delta;
+        let nal_type = if idr { 5 } else { 1 };
+        let nal_ref_idc = if reference { 2 } else { 0 };
+        let unit = H264NalUnit::new(0, 4, 0, nal_ref_idc, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, sps, &H264NalUnitPPS::new()).unwrap();
+        writer.write_rbsp_trailing_bits();
+        writer.to_nal(nal_ref_idc, nal_type)
+    }
+
+    /// A byte stream with the parameter sets before the first and every
+    /// IDR picture and a recovery point SEI before the pictures in
+    /// recovery_points.
+    pub fn stream(sps: &H264NalUnitSPS, pictures: &[TestPicture], recovery_points: &[usize]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (i, picture) in pictures.iter().enumerate() {
+            if picture.1 || i == 0 {
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sps.to_bytes().unwrap());
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &H264NalUnitPPS::new().to_bytes(1).unwrap());
+            }
+            if recovery_points.contains(&i) {
+                // recovery_frame_cnt 0, exact_match_flag 1
+                let sei = H264SEIBuilder::new().add_message(SEI_RECOVERY_POINT, &[0xC4]).build();
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sei);
+            }
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &slice_nal(sps, picture, 0));
+        }
+        data
+    }
+
+    fn analyze(data: &[u8]) -> H264GopReport {
+        analyze_gops(data, H264NalFormat::BYTESTREAM, 4, Some(25.0)).unwrap()
+    }
+
+    #[test]
+    fn open_and_closed_gops() {
+        let pictures = [
+            // A closed GOP starting with an IDR picture
+            ('I', true, true, 0, 0, None),
+            ('P', false, true, 1, 6, None),
+            ('B', false, false, 2, 2, None),
+            ('B', false, false, 2, 4, None),
+            ('P', false, true, 2, 12, None),
+            ('B', false, false, 3, 8, None),
+            ('B', false, false, 3, 10, None),
+            // An open GOP, whose first two B pictures are shown before
+            // the I picture
+            ('I', false, true, 3, 18, None),
+            ('B', false, false, 4, 14, None),
+            ('B', false, false, 4, 16, None),
+            ('P', false, true, 4, 24, None),
+            ('B', false, false, 5, 20, None),
+            ('B', false, false, 5, 22, None),
+            // A closed one without an IDR picture
+            ('I', false, true, 5, 30, None),
+            ('P', false, true, 6, 36, None),
+            ('B', false, false, 7, 32, None),
+            ('B', false, false, 7, 34, None),
+            ('I', true, true, 0, 0, None),
+            ('P', false, true, 1, 2, None)
+        ];
+        let report = analyze(&stream(&sps(true), &pictures, &[7, 13]));
+        assert_eq!(report.pictures.len(), pictures.len());
+        let gops : Vec<(&str, u32, bool, bool)> = report.gops.iter()
+            .map(|g| (g.pattern.as_str(), g.frames, g.idr, g.closed))
+            .collect();
+        assert_eq!(gops, vec![
+            ("IBBPBBP", 7, true, true),
+            ("BBIBBP", 6, false, false),
+            ("IBBP", 4, false, true),
+            ("IP", 2, true, true)
+        ]);
+        assert_eq!((report.closed_gops(), report.open_gops()), (3, 1));
+        assert_eq!(report.keyframe_intervals(), vec![7, 6, 4]);
+        assert_eq!((report.min_keyframe_interval(), report.max_keyframe_interval()), (Some(4), Some(7)));
+        assert_eq!(report.average_keyframe_interval_seconds(), Some(17.0 / 3.0 / 25.0));
+        assert_eq!(report.pictures[17].poc_epoch, 1);
+        assert_eq!((report.max_consecutive_b, report.b_pyramid, report.reorder_depth), (2, false, 1));
+
+        // Pictures before the first random access point form a GOP of
+        // their own, which isn't a keyframe interval.
+        let report = analyze(&stream(&sps(true), &pictures[14..], &[]));
+        let gops : Vec<(&str, bool, bool)> = report.gops.iter()
+            .map(|g| (g.pattern.as_str(), g.random_access, g.closed))
+            .collect();
+        assert_eq!(gops, vec![("BBP", false, false), ("IP", true, true)]);
+        assert_eq!((report.closed_gops(), report.open_gops()), (1, 0));
+        assert!(report.keyframe_intervals().is_empty());
+    }
+
+    #[test]
+    fn reorder_depth_against_max_num_reorder_frames() {
+        // Hierarchical B frames: the reference B picture is shown between
+        // the two non-reference ones.
+        let pictures = [
+            ('I', true, true, 0, 0, None),
+            ('P', false, true, 1, 8, None),
+            ('B', false, true, 2, 4, None),
+            ('B', false, false, 3, 2, None),
+            ('B', false, false, 3, 6, None),
+            ('P', false, true, 3, 16, None)
+        ];
+        let mut sps = sps(true);
+        let report = analyze(&stream(&sps, &pictures, &[]));
+        assert_eq!(report.gops[0].pattern, "IBBBPP");
+        assert_eq!(report.gops[0].reference_b_frames, 1);
+        assert_eq!((report.max_consecutive_b, report.b_pyramid, report.reorder_depth), (3, true, 2));
+        assert_eq!(report.max_num_reorder_frames, None);
+        assert!(!report.exceeds_declared_reorder_frames());
+
+        sps.vui_mut().bitstream_restriction_flag = 1;
+        sps.vui_mut().max_num_reorder_frames = 1;
+        sps.vui_mut().max_dec_frame_buffering = 2;
+        let report = analyze(&stream(&sps, &pictures, &[]));
+        assert_eq!(report.max_num_reorder_frames, Some(1));
+        assert!(report.exceeds_declared_reorder_frames());
+        sps.vui_mut().max_num_reorder_frames = 2;
+        assert!(!analyze(&stream(&sps, &pictures, &[])).exceeds_declared_reorder_frames());
+    }
+
+    #[test]
+    fn second_fields_join_their_first_field() {
+        let pictures = [
+            ('I', true, true, 0, 0, Some(false)),
+            ('I', false, true, 0, 1, Some(true)),
+            ('P', false, true, 1, 8, Some(false)),
+            ('P', false, true, 1, 9, Some(true)),
+            ('B', false, false, 2, 4, Some(true)),
+            ('B', false, false, 2, 5, Some(false)),
+            // Two top fields don't make a pair.
+            ('P', false, true, 2, 12, Some(false)),
+            ('P', false, true, 3, 16, Some(false)),
+            // A field pair of different picture types is the higher one.
+            ('P', false, true, 4, 20, Some(false)),
+            ('B', false, true, 4, 21, Some(true))
+        ];
+        let report = analyze(&stream(&sps(false), &pictures, &[]));
+        let merged : Vec<(u64, char, i32, bool)> = report.pictures.iter()
+            .map(|p| (p.access_unit, p.picture_type.letter(), p.pic_order_cnt, p.field))
+            .collect();
+        assert_eq!(merged, vec![
+            (0, 'I', 0, true),
+            (2, 'P', 8, true),
+            (4, 'B', 4, true),
+            (6, 'P', 12, true),
+            (7, 'P', 16, true),
+            (8, 'B', 20, true)
+        ]);
+        assert_eq!(report.gops.len(), 1);
+        assert_eq!(report.gops[0].pattern, "IBPPPB");
+        assert_eq!(report.gops[0].frames, 6);
+        assert_eq!(report.reorder_depth, 1);
+    }
+}
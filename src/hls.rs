@@ -0,0 +1,556 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use parser::{H264NalParser, H264NalParseError};
+use poc::H264TimestampGenerator;
+use mp4::{H264Mp4Muxer, H264Mp4Error, make_box};
+use ts::{H264TsMuxer, H264TsError, TS_CLOCK_RATE};
+pub use types::*;
+
+/// SEI payload type of the recovery point SEI.
+const SEI_RECOVERY_POINT : u32 = 6;
+
+#[derive(Debug)]
+pub enum H264HlsError {
+    Io(io::Error),
+    Ts(H264TsError),
+    Mp4(H264Mp4Error),
+    Parse(H264NalParseError)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264HlsSegmentFormat {
+    TS, FMP4
+}
+
+/// A random access point inside a segment, for the I-frame playlist. The
+/// byte range covers the access unit along with the PAT and PMT before it,
+/// or the moof of its fragment for fMP4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264HlsKeyframe {
+    pub offset: usize,
+    pub length: usize,
+    /// In 90kHz ticks.
+    pub dts: u64
+}
+
+/// A finished segment file.
+#[derive(Debug, Clone)]
+pub struct H264HlsSegment {
+    pub sequence_number: u64,
+    pub filename: String,
+    /// In 90kHz ticks.
+    pub start_dts: u64,
+    pub end_dts: u64,
+    pub keyframes: Vec<H264HlsKeyframe>
+}
+
+impl H264HlsSegment {
+    /// In seconds.
+    pub fn duration(&self) -> f64 {
+        (self.end_dts - self.start_dts) as f64 / TS_CLOCK_RATE as f64
+    }
+
+    /// Duration of each keyframe until the next one, in seconds. The
+    /// segment always starts with a keyframe, so the last one lasts until
+    /// the end of the segment.
+    pub fn keyframe_durations(&self) -> Vec<f64> {
+        (0..self.keyframes.len()).map(|i| {
+            let end = self.keyframes.get(i + 1).map_or(self.end_dts, |k| k.dts);
+            (end - self.keyframes[i].dts) as f64 / TS_CLOCK_RATE as f64
+        }).collect()
+    }
+}
+
+/// Splits a stream into HLS segments in a directory, with a media playlist
+/// and an I-frame only playlist next to them. Segments start at a random
+/// access point (IDR or recovery point SEI) once target_duration has
+/// passed, and access units before the first one are dropped.
+///
+/// Timestamps are derived with an H264TimestampGenerator. In live mode the
+/// playlists are rewritten after every segment and only list the last
+/// window_size segments.
+pub struct H264HlsSegmenter {
+    pub directory: PathBuf,
+    pub format: H264HlsSegmentFormat,
+    /// In seconds.
+    pub target_duration: f64,
+    pub live: bool,
+    pub window_size: usize,
+    /// Remove segment files that dropped out of the live window.
+    pub delete_old_segments: bool,
+    /// Segments are named segment_prefix followed by the sequence number.
+    pub segment_prefix: String,
+    pub playlist_name: String,
+    pub iframe_playlist_name: String,
+    pub init_segment_name: String,
+    /// Frame duration in 90kHz ticks, overriding the VUI.
+    pub frame_duration: Option<u64>,
+    /// Every segment written so far, including those that left the live
+    /// window.
+    pub segments: Vec<H264HlsSegment>,
+    /// Access units dropped before the first random access point, or
+    /// before the first one with parameter sets for the fMP4 init segment.
+    pub dropped_access_units: u64,
+
+    parser: H264NalParser,
+    timestamps: H264TimestampGenerator,
+    ts_muxer: H264TsMuxer<Vec<u8>>,
+    mp4_muxer: H264Mp4Muxer,
+    started: bool,
+    init_written: bool,
+    current: Vec<u8>,
+    current_start: u64,
+    current_keyframes: Vec<H264HlsKeyframe>,
+    last_dts: u64,
+    last_duration: u64,
+    sequence_number: u64,
+    /// How many segments have left the live window.
+    removed: usize
+}
+
+impl H264HlsSegmenter {
+    pub fn new(directory: &str, format: H264HlsSegmentFormat) -> H264HlsSegmenter {
+        let mut mp4_muxer = H264Mp4Muxer::new();
+        // One fragment per keyframe, so each has a byte range.
+        mp4_muxer.fragment_duration = 0;
+        mp4_muxer.styp = false;
+        H264HlsSegmenter {
+            directory: PathBuf::from(directory),
+            format,
+            target_duration: 6.0,
+            live: false,
+            window_size: 6,
+            delete_old_segments: true,
+            segment_prefix: "segment".to_string(),
+            playlist_name: "index.m3u8".to_string(),
+            iframe_playlist_name: "iframes.m3u8".to_string(),
+            init_segment_name: "init.mp4".to_string(),
+            frame_duration: None,
+            segments: Vec::new(),
+            dropped_access_units: 0,
+            parser: H264NalParser::from_bytes(Vec::new()),
+            timestamps: H264TimestampGenerator::new(TS_CLOCK_RATE),
+            ts_muxer: H264TsMuxer::new(Vec::new()),
+            mp4_muxer,
+            started: false,
+            init_written: false,
+            current: Vec::new(),
+            current_start: 0,
+            current_keyframes: Vec::new(),
+            last_dts: 0,
+            last_duration: 0,
+            sequence_number: 0,
+            removed: 0
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            H264HlsSegmentFormat::TS => "ts",
+            H264HlsSegmentFormat::FMP4 => "m4s"
+        }
+    }
+
+    /// Takes an access unit given as NAL units without start codes.
+    pub fn push_access_unit(&mut self, nal_units: &[Vec<u8>]) -> Result<(), H264HlsError> {
+        let nal_units : Vec<Vec<u8>> = nal_units.iter().filter(|n| !n.is_empty()).cloned().collect();
+        let mut bytestream = Vec::new();
+        for nal in &nal_units {
+            bytestream.extend_from_slice(&[0, 0, 0, 1]);
+            bytestream.extend_from_slice(nal);
+        }
+        self.parser.set_data(bytestream);
+        self.parser.format = H264NalFormat::BYTESTREAM;
+
+        let mut random_access = false;
+        let mut first_slice = None;
+        let mut offset = 0;
+        for _ in 0..nal_units.len() {
+            let unit = self.parser.parse_nalunit(offset).map_err(H264HlsError::Parse)?;
+            match unit.nal_unit_type_num {
+                7 => { self.parser.parse_sps(unit.data_offset).map_err(H264HlsError::Parse)?; },
+                8 => { self.parser.parse_pps(unit.data_offset).map_err(H264HlsError::Parse)?; },
+                6 => {
+                    if let Ok(messages) = self.parser.parse_sei(&unit) {
+                        random_access |= messages.iter().any(|m| m.payload_type == SEI_RECOVERY_POINT);
+                    }
+                },
+                1..=5 => {
+                    random_access |= unit.idr_pic_flag;
+                    if first_slice.is_none() {
+                        if let Ok(slice) = self.parser.parse_slice(unit.data_offset, &unit) {
+                            first_slice = Some((unit.clone(), slice));
+                        }
+                    }
+                },
+                _ => {}
+            }
+            offset += unit.size;
+        }
+        if !self.started && !random_access {
+            self.dropped_access_units += 1;
+            return Ok(());
+        }
+
+        let sps = first_slice.as_ref().and_then(|(_, slice)| {
+            self.parser.find_pps(slice.pic_parameter_set_id)
+                .and_then(|pps| self.parser.find_sps(pps.seq_parameter_set_id))
+                .cloned()
+        });
+        self.timestamps.frame_duration = self.frame_duration;
+        let (pts, dts) = match (sps.as_ref(), first_slice.as_ref()) {
+            (Some(sps), Some((nalu, slice))) => self.timestamps.next(Some((sps, nalu, slice))),
+            _ => self.timestamps.next(None)
+        };
+        self.last_duration = self.timestamps.frame_duration(sps.as_ref());
+
+        if !self.started {
+            self.started = true;
+            self.current_start = dts;
+        }
+        let elapsed = dts.saturating_sub(self.current_start) as f64 / TS_CLOCK_RATE as f64;
+        match self.format {
+            H264HlsSegmentFormat::TS => {
+                if random_access && elapsed >= self.target_duration {
+                    self.finish_segment(dts)?;
+                }
+                let start = self.ts_muxer.get_mut().len();
+                self.ts_muxer.write_access_unit(&nal_units, Some(pts), Some(dts)).map_err(H264HlsError::Ts)?;
+                let end = self.ts_muxer.get_mut().len();
+                self.current.append(self.ts_muxer.get_mut());
+                if random_access {
+                    self.current_keyframes.push(H264HlsKeyframe {
+                        offset: self.current.len() - (end - start),
+                        length: end - start,
+                        dts
+                    });
+                }
+            },
+            H264HlsSegmentFormat::FMP4 => {
+                let dropped = self.mp4_muxer.dropped_access_units;
+                self.mp4_muxer.push_access_unit(&nal_units, Some(pts), Some(dts)).map_err(H264HlsError::Mp4)?;
+                if self.mp4_muxer.dropped_access_units > dropped {
+                    // The mp4 muxer hasn't got the parameter sets yet.
+                    self.dropped_access_units += 1;
+                    self.started = false;
+                    return Ok(());
+                }
+                // Adding a keyframe closes the previous fragment.
+                self.take_fragments();
+                if !self.init_written {
+                    if let Some(init) = self.mp4_muxer.init_segment() {
+                        fs::write(self.directory.join(&self.init_segment_name), init).map_err(H264HlsError::Io)?;
+                        self.init_written = true;
+                    }
+                }
+                if random_access && elapsed >= self.target_duration {
+                    self.finish_segment(dts)?;
+                }
+            }
+        }
+        self.last_dts = dts;
+        Ok(())
+    }
+
+    /// Moves the fragments the mp4 muxer has finished into the current
+    /// segment, each one starting at a keyframe.
+    fn take_fragments(&mut self) {
+        while let Some(fragment) = self.mp4_muxer.pop_fragment() {
+            // The byte range ends after the first sample.
+            let rest : u32 = fragment.sample_sizes.iter().skip(1).sum();
+            self.current_keyframes.push(H264HlsKeyframe {
+                offset: self.current.len(),
+                length: fragment.data.len() - rest as usize,
+                dts: fragment.decode_time
+            });
+            self.current.extend(fragment.data);
+        }
+    }
+
+    fn finish_segment(&mut self, end_dts: u64) -> Result<(), H264HlsError> {
+        if self.current.is_empty() {
+            return Ok(());
+        }
+        let filename = format!("{}{}.{}", self.segment_prefix, self.sequence_number, self.extension());
+        let mut data = Vec::new();
+        if self.format == H264HlsSegmentFormat::FMP4 {
+            // The fragments go without one so the keyframe byte ranges
+            // start at a moof.
+            data = make_box(b"styp", b"msdh\0\0\0\0msdhcmfs");
+            for keyframe in self.current_keyframes.iter_mut() {
+                keyframe.offset += data.len();
+            }
+        }
+        data.append(&mut self.current);
+        fs::write(self.directory.join(&filename), &data).map_err(H264HlsError::Io)?;
+        self.segments.push(H264HlsSegment {
+            sequence_number: self.sequence_number,
+            filename,
+            start_dts: self.current_start,
+            end_dts,
+            keyframes: self.current_keyframes.drain(..).collect()
+        });
+        self.sequence_number += 1;
+        self.current_start = end_dts;
+
+        if self.live {
+            let first_removed = self.removed;
+            while self.segments.len() - self.removed > self.window_size {
+                self.removed += 1;
+            }
+            self.write_playlists(false)?;
+            // Only once the playlists no longer list them. A segment that's
+            // already gone is fine.
+            if self.delete_old_segments {
+                for segment in &self.segments[first_removed..self.removed] {
+                    if let Err(e) = fs::remove_file(self.directory.join(&segment.filename)) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            return Err(H264HlsError::Io(e));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the last segment and the final playlists.
+    pub fn finish(&mut self) -> Result<(), H264HlsError> {
+        if self.format == H264HlsSegmentFormat::FMP4 {
+            self.mp4_muxer.flush();
+            self.take_fragments();
+        }
+        let end = self.last_dts + self.last_duration;
+        self.finish_segment(end)?;
+        self.write_playlists(true)
+    }
+
+    fn write_playlists(&self, ended: bool) -> Result<(), H264HlsError> {
+        let segments = &self.segments[self.removed..];
+        let mut target = self.target_duration.ceil() as u64;
+        for s in segments {
+            target = target.max(s.duration().round() as u64);
+        }
+        let first_sequence = segments.first().map_or(0, |s| s.sequence_number);
+        let version = match self.format {
+            H264HlsSegmentFormat::TS => 4,
+            H264HlsSegmentFormat::FMP4 => 7
+        };
+        let mut header = format!("#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+                                 version, target, first_sequence);
+        if !self.live {
+            header.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+        }
+        header.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+        if self.format == H264HlsSegmentFormat::FMP4 {
+            header.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", self.init_segment_name));
+        }
+
+        let mut playlist = header.clone();
+        let mut iframes = header;
+        iframes.push_str("#EXT-X-I-FRAMES-ONLY\n");
+        for s in segments {
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", s.duration(), s.filename));
+            for (keyframe, duration) in s.keyframes.iter().zip(s.keyframe_durations()) {
+                iframes.push_str(&format!("#EXTINF:{:.3},\n#EXT-X-BYTERANGE:{}@{}\n{}\n",
+                                          duration, keyframe.length, keyframe.offset, s.filename));
+            }
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+            iframes.push_str("#EXT-X-ENDLIST\n");
+        }
+        fs::write(self.directory.join(&self.playlist_name), playlist).map_err(H264HlsError::Io)?;
+        fs::write(self.directory.join(&self.iframe_playlist_name), iframes).map_err(H264HlsError::Io)
+    }
+
+    /// Segments a whole stream, splitting it with the access unit detection
+    /// of H264NalParser, and finishes.
+    pub fn push_stream(&mut self, data: &[u8], format: H264NalFormat,
+                       nal_length_size: usize) -> Result<(), H264HlsError> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        let mut offset = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).map_err(H264HlsError::Parse)?;
+            let nal_units : Vec<Vec<u8>> = au.nal_units.iter().map(|n| parser.nal_data(n).to_vec()).collect();
+            self.push_access_unit(&nal_units)?;
+            offset += au.size;
+        }
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+    use std::process;
+    use mp4::{H264Mp4Demuxer, read_boxes};
+    use writer::H264NalWriter;
+
+    /// An empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("h264nalparse-hls-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Frame i of a 16x16 Baseline stream with an IDR picture every 15
+    /// frames. IDR access units carry the parameter sets when asked to.
+    fn frame(i: u32, parameter_sets: bool) -> Vec<Vec<u8>> {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        sps.max_num_ref_frames = 1;
+        let pps = H264NalUnitPPS::new();
+        let frame_num = i % 15;
+        let idr = frame_num == 0;
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = if idr { 7 } else { 5 };
+        slice.frame_num = frame_num;
+        slice.idr_pic_id = i / 15 % 2;
+        slice.pic_order_cnt_lsb = (2 * frame_num % 16) as u16;
+        let nal_type = if idr { 5 } else { 1 };
+        let unit = H264NalUnit::new(0, 4, 0, 2, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let nal = writer.to_nal(2, nal_type);
+        if idr && parameter_sets {
+            vec![sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), nal]
+        } else {
+            vec![nal]
+        }
+    }
+
+    fn new_segmenter(dir: &Path, format: H264HlsSegmentFormat) -> H264HlsSegmenter {
+        let mut segmenter = H264HlsSegmenter::new(dir.to_str().unwrap(), format);
+        segmenter.target_duration = 1.0;
+        segmenter.frame_duration = Some(3000);
+        segmenter
+    }
+
+    fn read(dir: &Path, name: &str) -> String {
+        String::from_utf8(fs::read(dir.join(name)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn segments_roll_over_at_keyframes() {
+        let dir = temp_dir("rollover");
+        let mut segmenter = new_segmenter(&dir, H264HlsSegmentFormat::TS);
+        segmenter.push_access_unit(&frame(1, true)).unwrap();
+        segmenter.push_access_unit(&frame(2, true)).unwrap();
+        for i in 0..90 {
+            segmenter.push_access_unit(&frame(i, true)).unwrap();
+        }
+        segmenter.finish().unwrap();
+        assert_eq!(segmenter.dropped_access_units, 2);
+        assert_eq!(segmenter.segments.len(), 3);
+        for segment in &segmenter.segments {
+            assert_eq!(segment.duration(), 1.0);
+            assert_eq!(segment.keyframe_durations(), vec![0.5, 0.5]);
+            let data = fs::read(dir.join(&segment.filename)).unwrap();
+            // Each keyframe starts with the PAT.
+            for keyframe in &segment.keyframes {
+                assert_eq!(data[keyframe.offset..keyframe.offset + 3], [0x47, 0x40, 0x00]);
+                assert_eq!(keyframe.length % 188, 0);
+            }
+        }
+
+        let playlist = read(&dir, "index.m3u8");
+        assert!(playlist.starts_with("#EXTM3U\n#EXT-X-VERSION:4\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+        assert!(playlist.contains("#EXTINF:1.000,\nsegment0.ts\n#EXTINF:1.000,\nsegment1.ts\n#EXTINF:1.000,\nsegment2.ts\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+        let iframes = read(&dir, "iframes.m3u8");
+        assert!(iframes.contains("#EXT-X-I-FRAMES-ONLY\n"));
+        assert_eq!(iframes.matches("#EXT-X-BYTERANGE:").count(), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn live_playlists_keep_a_window() {
+        let dir = temp_dir("live");
+        let mut segmenter = new_segmenter(&dir, H264HlsSegmentFormat::TS);
+        segmenter.live = true;
+        segmenter.window_size = 2;
+        for i in 0..90 {
+            segmenter.push_access_unit(&frame(i, true)).unwrap();
+        }
+        // Two segments are done, the third is still being written.
+        assert_eq!(segmenter.segments.len(), 2);
+        let playlist = read(&dir, "index.m3u8");
+        assert!(!playlist.contains("#EXT-X-ENDLIST"));
+        assert!(!playlist.contains("#EXT-X-PLAYLIST-TYPE"));
+        for i in 90..150 {
+            segmenter.push_access_unit(&frame(i, true)).unwrap();
+        }
+        segmenter.finish().unwrap();
+        assert_eq!(segmenter.segments.len(), 5);
+        let playlist = read(&dir, "index.m3u8");
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:3\n"));
+        assert_eq!(playlist.matches("#EXTINF").count(), 2);
+        assert!(playlist.contains("segment3.ts\n") && playlist.contains("segment4.ts\n"));
+        for i in 0..5 {
+            assert_eq!(dir.join(format!("segment{}.ts", i)).exists(), i >= 3);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dir = temp_dir("keep");
+        let mut segmenter = new_segmenter(&dir, H264HlsSegmentFormat::TS);
+        segmenter.live = true;
+        segmenter.window_size = 2;
+        segmenter.delete_old_segments = false;
+        for i in 0..150 {
+            segmenter.push_access_unit(&frame(i, true)).unwrap();
+        }
+        segmenter.finish().unwrap();
+        assert!(read(&dir, "index.m3u8").contains("#EXT-X-MEDIA-SEQUENCE:3\n"));
+        assert!((0..5).all(|i| dir.join(format!("segment{}.ts", i)).exists()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fmp4_keyframes_cover_moof_and_first_sample() {
+        let dir = temp_dir("fmp4");
+        let mut segmenter = new_segmenter(&dir, H264HlsSegmentFormat::FMP4);
+        // A keyframe without parameter sets can't start the init segment.
+        segmenter.push_access_unit(&frame(0, false)).unwrap();
+        for i in 0..60 {
+            segmenter.push_access_unit(&frame(i, true)).unwrap();
+        }
+        segmenter.finish().unwrap();
+        assert_eq!(segmenter.dropped_access_units, 1);
+        assert_eq!(segmenter.segments.len(), 2);
+        assert!(read(&dir, "index.m3u8").contains("#EXT-X-MAP:URI=\"init.mp4\"\n"));
+
+        let init = fs::read(dir.join("init.mp4")).unwrap();
+        let idr_sample = 4 + frame(0, false)[0].len();
+        for segment in &segmenter.segments {
+            assert_eq!(segment.duration(), 1.0);
+            let data = fs::read(dir.join(&segment.filename)).unwrap();
+            assert_eq!(&data[4..8], b"styp");
+            assert_eq!(segment.keyframes.len(), 2);
+            for keyframe in &segment.keyframes {
+                let boxes = read_boxes(&data[keyframe.offset..], 0).unwrap();
+                assert!(boxes[0].box_type.is(b"moof") && boxes[1].box_type.is(b"mdat"));
+                assert_eq!(keyframe.length, boxes[1].offset + idr_sample);
+            }
+
+            let mut file = init.clone();
+            file.extend_from_slice(&data);
+            let demuxer = H264Mp4Demuxer::from_bytes(file).unwrap();
+            let samples = &demuxer.tracks[0].samples;
+            assert_eq!(samples.len(), 30);
+            assert_eq!(samples.iter().filter(|s| s.sync).count(), 2);
+            assert_eq!(samples[0].decode_time, segment.start_dts);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+use parser::{H264NalParser, H264NalParseError, parse_buffering_period, parse_pic_timing};
+pub use types::*;
+
+/// Something the hypothetical reference decoder of Annex C doesn't allow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264HrdViolationKind {
+    /// The access unit hasn't fully arrived by its removal time and
+    /// low_delay_hrd_flag isn't set.
+    Underflow,
+    /// The CPB holds more than CpbSize bits.
+    Overflow,
+    /// The nominal removal time isn't after the one of the previous access
+    /// unit.
+    RemovalTimeNotIncreasing,
+    /// No pic_timing SEI with cpb_removal_delay, so the removal time is
+    /// guessed from the previous one.
+    MissingPicTiming,
+    /// initial_cpb_removal_delay is more than 90000 * CpbSize / BitRate.
+    InitialDelayTooLarge,
+    /// initial_cpb_removal_delay of a later buffering period doesn't match
+    /// the time since the previous access unit arrived (C.3 item 2).
+    InitialDelayMismatch
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264HrdViolation {
+    /// Index of the access unit in the stream.
+    pub access_unit: u64,
+    /// Nominal removal time in seconds.
+    pub time: f64,
+    pub kind: H264HrdViolationKind
+}
+
+/// CPB fullness in bits at a point in time, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264HrdFullness {
+    pub time: f64,
+    pub bits: f64
+}
+
+/// The result of running the stream through the HRD with the delivery
+/// schedule of one SchedSelIdx.
+#[derive(Debug, Clone)]
+pub struct H264HrdSchedule {
+    /// The NAL HRD, counting every NAL unit (a Type II bitstream), or the
+    /// VCL HRD, counting only slices and filler data (Type I).
+    pub nal: bool,
+    pub sched_sel_idx: usize,
+    pub bit_rate: u64,
+    pub cpb_size: u64,
+    pub cbr: bool,
+    pub violations: Vec<H264HrdViolation>,
+    /// Two points per access unit: just before and just after its removal.
+    pub fullness: Vec<H264HrdFullness>
+}
+
+impl H264HrdSchedule {
+    pub fn max_fullness(&self) -> f64 {
+        self.fullness.iter().map(|f| f.bits).fold(0.0, f64::max)
+    }
+
+    pub fn is_conformant(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct H264HrdReport {
+    /// Access units before the first buffering period, which the HRD
+    /// can't start from.
+    pub skipped_access_units: u64,
+    /// The first SPS has no VUI timing info or no HRD parameters, so there
+    /// was nothing to check.
+    pub hrd_unavailable: bool,
+    /// Every SchedSelIdx of the NAL HRD and then the VCL HRD, as the first
+    /// SPS declares them. Empty if the HRD is unavailable.
+    pub schedules: Vec<H264HrdSchedule>
+}
+
+impl H264HrdReport {
+    /// False if the HRD is unavailable, as then nothing was checked.
+    pub fn is_conformant(&self) -> bool {
+        !self.hrd_unavailable && self.schedules.iter().all(|s| s.is_conformant())
+    }
+}
+
+/// What the HRD needs of an access unit.
+struct HrdAccessUnit {
+    index: u64,
+    nal_bits: u64,
+    vcl_bits: u64,
+    buffering_period: Option<H264BufferingPeriod>,
+    cpb_removal_delay: Option<u32>
+}
+
+/// Runs a stream through the hypothetical reference decoder of Annex C for
+/// every delivery schedule its first SPS declares, with the timing from
+/// buffering period and pic_timing SEI.
+pub fn verify_hrd(data: &[u8], format: H264NalFormat, nal_length_size: usize)
+                  -> Result<H264HrdReport, H264NalParseError> {
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut first_sps : Option<H264NalUnitSPS> = None;
+    let mut access_units = Vec::new();
+    let mut skipped = 0;
+    let mut index = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let au = parser.parse_access_unit(offset)?;
+        if au.size == 0 {
+            break;
+        }
+        offset += au.size;
+        index += 1;
+
+        let mut first_slice = None;
+        for unit in &au.nal_units {
+            match unit.nal_unit_type_num {
+                7 => {
+                    let sps = parser.parse_sps(unit.data_offset)?;
+                    if first_sps.is_none() {
+                        first_sps = Some(sps);
+                    }
+                },
+                8 => { parser.parse_pps(unit.data_offset)?; },
+                1..=5 if first_slice.is_none() => {
+                    first_slice = Some(parser.parse_slice(unit.data_offset, unit)?);
+                },
+                _ => {}
+            }
+        }
+        let sps = match first_slice.as_ref()
+            .and_then(|slice| parser.find_pps(slice.pic_parameter_set_id))
+            .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id)) {
+            Some(sps) => sps,
+            None => continue
+        };
+        let buffering_period = au.sei.iter().filter_map(|m| parse_buffering_period(m, sps)).next();
+        if access_units.is_empty() && buffering_period.is_none() {
+            skipped += 1;
+            continue;
+        }
+        // Type II bitstreams include the start codes, there is nothing
+        // like them in the other formats.
+        let nal_bytes : usize = match format {
+            H264NalFormat::BYTESTREAM => au.size,
+            _ => au.nal_units.iter().map(|n| n.sc_offset + n.size - n.data_offset).sum()
+        };
+        let vcl_bytes : usize = au.nal_units.iter()
+            .filter(|n| (n.nal_unit_type_num >= 1 && n.nal_unit_type_num <= 5) || n.nal_unit_type_num == 12)
+            .map(|n| n.sc_offset + n.size - n.data_offset)
+            .sum();
+        access_units.push(HrdAccessUnit {
+            index: index - 1,
+            nal_bits: nal_bytes as u64 * 8,
+            vcl_bits: vcl_bytes as u64 * 8,
+            buffering_period,
+            cpb_removal_delay: au.sei.iter().filter_map(|m| parse_pic_timing(m, sps))
+                .filter_map(|t| t.cpb_removal_delay).next()
+        });
+    }
+    let sps = match first_sps {
+        Some(s) => s,
+        None => return Err(H264NalParseError::GenericParseError)
+    };
+
+    let mut schedules = Vec::new();
+    let vui = match sps.vui_parameters {
+        Some(ref vui) if vui.timing_info_present_flag != 0 && vui.time_scale != 0 => vui,
+        _ => return Ok(H264HrdReport {
+            skipped_access_units: skipped,
+            hrd_unavailable: true,
+            schedules
+        })
+    };
+    let tc = vui.num_units_in_tick as f64 / vui.time_scale as f64;
+    for &(nal, hrd) in &[(true, vui.nal_hrd_parameters.as_ref()), (false, vui.vcl_hrd_parameters.as_ref())] {
+        if let Some(hrd) = hrd {
+            for sched_sel_idx in 0..hrd.cpb_cnt_minus1 as usize + 1 {
+                schedules.push(run_schedule(&access_units, hrd, nal, sched_sel_idx, tc, vui.low_delay_hrd_flag != 0));
+            }
+        }
+    }
+    Ok(H264HrdReport {
+        skipped_access_units: skipped,
+        hrd_unavailable: schedules.is_empty(),
+        schedules
+    })
+}
+
+/// initial_cpb_removal_delay and its offset in seconds.
+fn initial_delays(period: &H264BufferingPeriod, nal: bool, sched_sel_idx: usize) -> (f64, f64) {
+    let (delays, offsets) = if nal {
+        (&period.nal_initial_cpb_removal_delay, &period.nal_initial_cpb_removal_delay_offset)
+    } else {
+        (&period.vcl_initial_cpb_removal_delay, &period.vcl_initial_cpb_removal_delay_offset)
+    };
+    (delays.get(sched_sel_idx).cloned().unwrap_or(0) as f64 / 90000.0,
+     offsets.get(sched_sel_idx).cloned().unwrap_or(0) as f64 / 90000.0)
+}
+
+/// The timing of C.1 for one schedule, then the fullness at each removal.
+fn run_schedule(access_units: &[HrdAccessUnit], hrd: &H264HDRParameters, nal: bool,
+                sched_sel_idx: usize, tc: f64, low_delay: bool) -> H264HrdSchedule {
+    let bit_rate = hrd.bit_rate(sched_sel_idx).unwrap_or(0);
+    let cpb_size = hrd.cpb_size(sched_sel_idx).unwrap_or(0);
+    let cbr = hrd.cbr(sched_sel_idx);
+    let mut violations = Vec::new();
+    // Initial arrival, final arrival and removal time of each access unit.
+    let mut times : Vec<(f64, f64, f64)> = Vec::with_capacity(access_units.len());
+    // Nominal removal time of the last access unit with a buffering
+    // period, and its initial delays.
+    let mut anchor = 0.0;
+    let mut delays = (0.0, 0.0);
+    let mut prev_nominal = 0.0;
+    let mut prev_final_arrival = 0.0;
+    for (n, au) in access_units.iter().enumerate() {
+        let bits = if nal { au.nal_bits } else { au.vcl_bits };
+        let violation = |kind, time| H264HrdViolation { access_unit: au.index, time, kind };
+
+        // C.1.2
+        let nominal = if n == 0 {
+            initial_delays(au.buffering_period.as_ref().unwrap(), nal, sched_sel_idx).0
+        } else {
+            match au.cpb_removal_delay {
+                Some(delay) => anchor + tc * delay as f64,
+                None => {
+                    violations.push(violation(H264HrdViolationKind::MissingPicTiming, prev_nominal));
+                    prev_nominal + 2.0 * tc
+                }
+            }
+        };
+        if n > 0 && nominal <= prev_nominal {
+            violations.push(violation(H264HrdViolationKind::RemovalTimeNotIncreasing, nominal));
+        }
+
+        // C.1.1
+        let new_period = au.buffering_period.is_some();
+        if let Some(ref period) = au.buffering_period {
+            anchor = nominal;
+            delays = initial_delays(period, nal, sched_sel_idx);
+            if bit_rate > 0 && delays.0 > cpb_size as f64 / bit_rate as f64 {
+                violations.push(violation(H264HrdViolationKind::InitialDelayTooLarge, nominal));
+            }
+            if n > 0 {
+                let gap = 90000.0 * (nominal - prev_final_arrival);
+                let delay = delays.0 * 90000.0;
+                let mismatch = if cbr {
+                    delay < gap.floor() - 0.5 || delay > gap.ceil() + 0.5
+                } else {
+                    delay > gap.ceil() + 0.5
+                };
+                if mismatch {
+                    violations.push(violation(H264HrdViolationKind::InitialDelayMismatch, nominal));
+                }
+            }
+        }
+        let initial_arrival = if n == 0 || cbr {
+            prev_final_arrival
+        } else {
+            let earliest = if new_period {
+                nominal - delays.0
+            } else {
+                nominal - delays.0 - delays.1
+            };
+            earliest.max(prev_final_arrival)
+        };
+        let final_arrival = if bit_rate > 0 {
+            initial_arrival + bits as f64 / bit_rate as f64
+        } else {
+            initial_arrival
+        };
+
+        // C.1.2, a big picture in low delay mode is removed at a later
+        // tick instead.
+        let mut removal = nominal;
+        if final_arrival > nominal {
+            if low_delay {
+                removal = nominal + tc * ((final_arrival - nominal) / tc).ceil();
+            } else {
+                violations.push(violation(H264HrdViolationKind::Underflow, nominal));
+            }
+        }
+        times.push((initial_arrival, final_arrival, removal));
+        prev_nominal = nominal;
+        prev_final_arrival = final_arrival;
+    }
+
+    // Bits arrive at bit_rate from the initial to the final arrival time of
+    // each access unit, the fullness peaks just before each removal.
+    let mut fullness = Vec::with_capacity(access_units.len() * 2);
+    let mut arrived_bits = 0.0;
+    let mut arrived = 0;
+    let mut removed_bits = 0.0;
+    for (n, au) in access_units.iter().enumerate() {
+        let bits = if nal { au.nal_bits } else { au.vcl_bits } as f64;
+        let removal = times[n].2;
+        while arrived < times.len() && times[arrived].1 <= removal {
+            arrived_bits += if nal { access_units[arrived].nal_bits } else { access_units[arrived].vcl_bits } as f64;
+            arrived += 1;
+        }
+        let partial = if arrived < times.len() && times[arrived].0 < removal {
+            (removal - times[arrived].0) * bit_rate as f64
+        } else {
+            0.0
+        };
+        let before = arrived_bits + partial - removed_bits;
+        // Allow for rounding of the arrival times.
+        if before > cpb_size as f64 + 1.0 {
+            violations.push(H264HrdViolation {
+                access_unit: au.index,
+                time: removal,
+                kind: H264HrdViolationKind::Overflow
+            });
+        }
+        removed_bits += bits;
+        fullness.push(H264HrdFullness { time: removal, bits: before });
+        fullness.push(H264HrdFullness { time: removal, bits: before - bits });
+    }
+
+    H264HrdSchedule {
+        nal,
+        sched_sel_idx,
+        bit_rate,
+        cpb_size,
+        cbr,
+        violations,
+        fullness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::{H264NalWriter, H264SEIBuilder, write_nalunit};
+
+    /// 25 frames a second, so a frame is two ticks of 0.02 s, with a NAL
+    /// HRD of 64000 bits/s and a 16000 bit CPB. Initial delays have 24
+    /// bits, cpb_removal_delay and dpb_output_delay 16.
+    fn hrd_sps(cbr: bool, low_delay: bool) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        sps.pic_order_cnt_type = 2;
+        let mut hrd = H264HDRParameters::new();
+        hrd.bit_rate_value_minus1 = vec![999];
+        hrd.cpb_size_value_minus1 = vec![999];
+        hrd.cbr_flag = vec![cbr as u8];
+        hrd.initial_cpb_removal_delay_length_minus1 = 23;
+        hrd.cpb_removal_delay_length_minus1 = 15;
+        hrd.dpb_output_delay_length_minus1 = 15;
+        let vui = sps.vui_mut();
+        vui.timing_info_present_flag = 1;
+        vui.num_units_in_tick = 1;
+        vui.time_scale = 50;
+        vui.nal_hrd_parameters_present_flag = 1;
+        vui.nal_hrd_parameters = Some(hrd);
+        vui.low_delay_hrd_flag = low_delay as u8;
+        sps
+    }
+
+    /// A byte stream of access units given as the initial_cpb_removal_delay
+    /// of a buffering period, if they start one, cpb_removal_delay and the
+    /// size in bytes, which the slice is padded to.
+    fn hrd_stream(sps: &H264NalUnitSPS, access_units: &[(Option<u32>, u32, usize)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (n, &(initial_delay, removal_delay, size)) in access_units.iter().enumerate() {
+            let start = data.len();
+            if n == 0 {
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sps.to_bytes().unwrap());
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &H264NalUnitPPS::new().to_bytes(1).unwrap());
+            }
+            let mut sei = H264SEIBuilder::new();
+            if let Some(delay) = initial_delay {
+                let mut period = H264NalWriter::new();
+                period.write_ue(0).unwrap();
+                period.write_u32(24, delay).unwrap();
+                period.write_u32(24, 0).unwrap();
+                sei.add_message(SEI_BUFFERING_PERIOD, &period.rbsp());
+            }
+            sei.add_message(SEI_PIC_TIMING, &[(removal_delay >> 8) as u8, removal_delay as u8, 0, 0]);
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sei.build());
+
+            let mut slice = H264NalUnitSlice::new();
+            slice.slice_type = if n == 0 { 7 } else { 5 };
+            slice.frame_num = n as u32 % 16;
+            let nal_type = if n == 0 { 5 } else { 1 };
+            let unit = H264NalUnit::new(0, 4, 0, 3, nal_type);
+            let mut writer = H264NalWriter::new();
+            slice.write(&mut writer, &unit, sps, &H264NalUnitPPS::new()).unwrap();
+            writer.write_rbsp_trailing_bits();
+            let mut nal = writer.to_nal(3, nal_type);
+            let padding = size - (data.len() - start) - 4 - nal.len();
+            nal.extend(vec![0x55; padding]);
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &nal);
+        }
+        data
+    }
+
+    /// 200 byte access units, one every frame, with a buffering period
+    /// at the start.
+    fn steady(count: usize, initial_delay: u32) -> Vec<(Option<u32>, u32, usize)> {
+        (0..count).map(|n| (if n == 0 { Some(initial_delay) } else { None }, 2 * n as u32, 200)).collect()
+    }
+
+    fn check(sps: &H264NalUnitSPS, access_units: &[(Option<u32>, u32, usize)]) -> H264HrdSchedule {
+        let report = verify_hrd(&hrd_stream(sps, access_units), H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert!(!report.hrd_unavailable);
+        assert_eq!(report.schedules.len(), 1);
+        report.schedules[0].clone()
+    }
+
+    fn violations(schedule: &H264HrdSchedule) -> Vec<(u64, H264HrdViolationKind)> {
+        schedule.violations.iter().map(|v| (v.access_unit, v.kind)).collect()
+    }
+
+    fn removal_times(schedule: &H264HrdSchedule) -> Vec<f64> {
+        schedule.fullness.iter().step_by(2).map(|f| (f.time * 1000.0).round() / 1000.0).collect()
+    }
+
+    #[test]
+    fn removal_times_come_from_the_sei() {
+        // A second buffering period counts its cpb_removal_delay from the
+        // first, the access units after it from the second.
+        let mut access_units = steady(8, 9000);
+        access_units[5] = (Some(9000), 10, 200);
+        access_units[6].1 = 2;
+        access_units[7].1 = 4;
+        let schedule = check(&hrd_sps(false, false), &access_units);
+        assert!(schedule.is_conformant(), "{:?}", schedule.violations);
+        assert_eq!(removal_times(&schedule), vec![0.1, 0.14, 0.18, 0.22, 0.26, 0.3, 0.34, 0.38]);
+        // Each access unit leaves the CPB whole at its removal time.
+        for pair in schedule.fullness.chunks(2) {
+            assert!((pair[0].bits - pair[1].bits - 1600.0).abs() < 1e-6);
+        }
+        assert!(schedule.max_fullness() <= 16000.0);
+
+        // A removal time that doesn't move on, and a missing pic_timing
+        // SEI, after which the removal time is a frame later.
+        let mut access_units = steady(4, 9000);
+        access_units[2].1 = 2;
+        let data = hrd_stream(&hrd_sps(false, false), &access_units);
+        let report = verify_hrd(&data, H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert_eq!(violations(&report.schedules[0]), vec![(2, H264HrdViolationKind::RemovalTimeNotIncreasing)]);
+
+        let mut without_pic_timing = hrd_stream(&hrd_sps(false, false), &steady(2, 9000));
+        let sps = hrd_sps(false, false);
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 5;
+        slice.frame_num = 2;
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &H264NalUnit::new(0, 4, 0, 3, 1), &sps, &H264NalUnitPPS::new()).unwrap();
+        writer.write_rbsp_trailing_bits();
+        write_nalunit(&mut without_pic_timing, H264NalFormat::BYTESTREAM, 4, &writer.to_nal(3, 1));
+        let report = verify_hrd(&without_pic_timing, H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert_eq!(violations(&report.schedules[0]), vec![(2, H264HrdViolationKind::MissingPicTiming)]);
+        assert_eq!(removal_times(&report.schedules[0]), vec![0.1, 0.14, 0.18]);
+
+        // Access units before the first buffering period are skipped.
+        let mut access_units = steady(4, 9000);
+        access_units[0].0 = None;
+        access_units[1] = (Some(9000), 0, 200);
+        access_units[2].1 = 2;
+        access_units[3].1 = 4;
+        let report = verify_hrd(&hrd_stream(&hrd_sps(false, false), &access_units), H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert_eq!(report.skipped_access_units, 1);
+        assert_eq!(removal_times(&report.schedules[0]), vec![0.1, 0.14, 0.18]);
+    }
+
+    #[test]
+    fn late_access_units_underflow() {
+        // 14400 bits take 0.225 s to arrive.
+        let mut access_units = steady(6, 9000);
+        access_units[3].2 = 1800;
+        let schedule = check(&hrd_sps(false, false), &access_units);
+        let found = violations(&schedule);
+        assert_eq!(found[0], (3, H264HrdViolationKind::Underflow));
+        assert!(found.iter().all(|v| v.1 == H264HrdViolationKind::Underflow));
+
+        // In low delay mode the picture is removed at the first tick it is
+        // all there.
+        let schedule = check(&hrd_sps(false, true), &access_units);
+        assert!(schedule.is_conformant(), "{:?}", schedule.violations);
+        assert_eq!(removal_times(&schedule)[..4], [0.1, 0.14, 0.18, 0.36]);
+    }
+
+    #[test]
+    fn cbr_without_enough_bits_overflows() {
+        // At a constant 64000 bits/s, 1600 bit pictures 25 times a second
+        // leave 960 bits a frame behind in the CPB.
+        let access_units = steady(40, 9000);
+        let cbr = check(&hrd_sps(true, false), &access_units);
+        assert!(cbr.cbr);
+        let found = violations(&cbr);
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|v| v.1 == H264HrdViolationKind::Overflow));
+        assert!(cbr.max_fullness() > 16000.0);
+
+        // With VBR the bits only arrive initial_cpb_removal_delay ahead.
+        let vbr = check(&hrd_sps(false, false), &access_units);
+        assert!(vbr.is_conformant(), "{:?}", vbr.violations);
+        assert!(vbr.max_fullness() < 5000.0);
+    }
+
+    #[test]
+    fn initial_cpb_removal_delay_is_checked() {
+        // 0.3 s of bits is more than the CPB holds.
+        let schedule = check(&hrd_sps(false, false), &steady(4, 27000));
+        assert_eq!(violations(&schedule)[0], (0, H264HrdViolationKind::InitialDelayTooLarge));
+
+        // A later buffering period: with CBR its delay has to match the
+        // time since the previous access unit arrived, with VBR it may be
+        // shorter.
+        let mut access_units = steady(8, 9000);
+        access_units[5] = (Some(9000), 10, 200);
+        access_units[6].1 = 2;
+        access_units[7].1 = 4;
+        assert!(check(&hrd_sps(false, false), &access_units).is_conformant());
+        let cbr = check(&hrd_sps(true, false), &access_units);
+        assert_eq!(violations(&cbr), vec![(5, H264HrdViolationKind::InitialDelayMismatch)]);
+        access_units[5].0 = Some(18000);
+        let vbr = check(&hrd_sps(false, false), &access_units);
+        assert_eq!(violations(&vbr), vec![(5, H264HrdViolationKind::InitialDelayMismatch)]);
+    }
+
+    #[test]
+    fn no_timing_info_makes_the_hrd_unavailable() {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 7;
+        let unit = H264NalUnit::new(0, 4, 0, 3, 5);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let mut data = Vec::new();
+        for nal in &[sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), writer.to_nal(3, 5)] {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        let report = verify_hrd(&data, H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert!(report.hrd_unavailable);
+        assert!(report.schedules.is_empty());
+        assert!(!report.is_conformant());
+    }
+}
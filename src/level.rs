@@ -0,0 +1,467 @@
+use parser::{H264NalParser, H264NalParseError};
+use profile::*;
+pub use types::*;
+
+/// The limits of one row of Table A-1. max_br and max_cpb are in units of
+/// cpbBrVclFactor and cpbBrNalFactor bits, max_vmv_r in luma frame samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264LevelLimits {
+    /// The level times ten, with level 1b as 9.
+    pub level_number: u8,
+    pub max_mbps: u32,
+    pub max_fs: u32,
+    pub max_dpb_mbs: u32,
+    pub max_br: u32,
+    pub max_cpb: u32,
+    pub max_vmv_r: u32,
+    pub min_cr: u32
+}
+
+// One argument per column of the table.
+#[allow(clippy::too_many_arguments)]
+const fn limits(level_number: u8, max_mbps: u32, max_fs: u32, max_dpb_mbs: u32, max_br: u32,
+                max_cpb: u32, max_vmv_r: u32, min_cr: u32) -> H264LevelLimits {
+    H264LevelLimits {
+        level_number,
+        max_mbps,
+        max_fs,
+        max_dpb_mbs,
+        max_br,
+        max_cpb,
+        max_vmv_r,
+        min_cr
+    }
+}
+
+pub const LEVEL_LIMITS : [H264LevelLimits; 20] = [
+    limits(10, 1485, 99, 396, 64, 175, 64, 2),
+    limits(9, 1485, 99, 396, 128, 350, 64, 2),
+    limits(11, 3000, 396, 900, 192, 500, 128, 2),
+    limits(12, 6000, 396, 2376, 384, 1000, 128, 2),
+    limits(13, 11880, 396, 2376, 768, 2000, 128, 2),
+    limits(20, 11880, 396, 2376, 2000, 2000, 128, 2),
+    limits(21, 19800, 792, 4752, 4000, 4000, 256, 2),
+    limits(22, 20250, 1620, 8100, 4000, 4000, 256, 2),
+    limits(30, 40500, 1620, 8100, 10000, 10000, 256, 2),
+    limits(31, 108000, 3600, 18000, 14000, 14000, 512, 4),
+    limits(32, 216000, 5120, 20480, 20000, 20000, 512, 4),
+    limits(40, 245760, 8192, 32768, 20000, 25000, 512, 4),
+    limits(41, 245760, 8192, 32768, 50000, 62500, 512, 2),
+    limits(42, 522240, 8704, 34816, 50000, 62500, 512, 2),
+    limits(50, 589824, 22080, 110400, 135000, 135000, 512, 2),
+    limits(51, 983040, 36864, 184320, 240000, 240000, 512, 2),
+    limits(52, 2073600, 36864, 184320, 240000, 240000, 512, 2),
+    limits(60, 4177920, 139264, 696320, 240000, 240000, 8192, 2),
+    limits(61, 8355840, 139264, 696320, 480000, 480000, 8192, 2),
+    limits(62, 16711680, 139264, 696320, 800000, 800000, 8192, 2)
+];
+
+/// The Table A-1 row of a level, with level_number as returned by
+/// H264ProfileLevel::level_number.
+pub fn level_limits(level_number: u8) -> Option<&'static H264LevelLimits> {
+    LEVEL_LIMITS.iter().find(|l| l.level_number == level_number)
+}
+
+/// cpbBrNalFactor from Table A-2. The measured sizes include every NAL
+/// unit, so the NAL factor is the one that applies.
+pub fn cpb_br_nal_factor(profile_idc: u8) -> u32 {
+    match profile_idc {
+        PROFILE_HIGH => 1500,
+        PROFILE_HIGH_10 => 3600,
+        PROFILE_HIGH_422 | PROFILE_HIGH_444 | PROFILE_CAVLC_444_INTRA => 4800,
+        _ => 1200
+    }
+}
+
+/// cpbBrVclFactor from Table A-2, for VCL HRD parameters.
+pub fn cpb_br_vcl_factor(profile_idc: u8) -> u32 {
+    match profile_idc {
+        PROFILE_HIGH => 1250,
+        PROFILE_HIGH_10 => 3000,
+        PROFILE_HIGH_422 | PROFILE_HIGH_444 | PROFILE_CAVLC_444_INTRA => 4000,
+        _ => 1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264LevelLimit {
+    MaxMBPS,
+    MaxFS,
+    MaxDpbMbs,
+    MaxBR,
+    MaxCPB,
+    MaxVmvR,
+    MinCR
+}
+
+/// What a stream needs from a level, measured from its first SPS and its
+/// access units.
+#[derive(Debug, Clone)]
+pub struct H264StreamMeasurements {
+    pub profile_level: H264ProfileLevel,
+    pub frame_size_in_mbs: u64,
+    pub frame_width_in_mbs: u32,
+    pub frame_height_in_mbs: u32,
+    /// max_dec_frame_buffering if the VUI has it, max_num_ref_frames otherwise.
+    pub dpb_frames: u32,
+    /// From VUI timing unless given by the caller. Without it none of the
+    /// rate limits can be checked.
+    pub frame_rate: Option<f64>,
+    pub access_units: u64,
+    pub total_bytes: u64,
+    pub max_access_unit_bytes: u64,
+    /// Uncompressed size of a frame in bytes, for MinCR.
+    pub raw_frame_bytes: u64,
+    /// Largest vertical motion vector in luma samples allowed by the VUI
+    /// bitstream restriction, if it restricts them at all.
+    pub max_vertical_mv: Option<u32>,
+    access_unit_sizes: Vec<u64>
+}
+
+impl H264StreamMeasurements {
+    pub fn macroblock_rate(&self) -> Option<f64> {
+        self.frame_rate.map(|r| r * self.frame_size_in_mbs as f64)
+    }
+
+    pub fn bitrate(&self) -> Option<f64> {
+        match self.frame_rate {
+            Some(r) if self.access_units > 0 => Some(self.total_bytes as f64 * 8.0 * r / self.access_units as f64),
+            _ => None
+        }
+    }
+
+    /// Size in bits of the leaky bucket that is needed to deliver the access
+    /// units at the given bit rate without the encoder buffer overflowing.
+    pub fn cpb_size(&self, bitrate: f64) -> Option<f64> {
+        let frame_rate = self.frame_rate?;
+        let drain = bitrate / frame_rate;
+        let mut fullness : f64 = 0.0;
+        let mut max_fullness : f64 = 0.0;
+        for size in &self.access_unit_sizes {
+            fullness += *size as f64 * 8.0;
+            if fullness > max_fullness {
+                max_fullness = fullness;
+            }
+            fullness = (fullness - drain).max(0.0);
+        }
+        Some(max_fullness)
+    }
+
+    /// The raw frame size divided by the largest access unit.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.max_access_unit_bytes == 0 {
+            return 0.0;
+        }
+        self.raw_frame_bytes as f64 / self.max_access_unit_bytes as f64
+    }
+
+    /// The limits of a level that the stream goes over.
+    pub fn exceeded_limits(&self, limits: &H264LevelLimits) -> Vec<H264LevelLimit> {
+        let mut exceeded = Vec::new();
+        if let Some(mbps) = self.macroblock_rate() {
+            if mbps > limits.max_mbps as f64 {
+                exceeded.push(H264LevelLimit::MaxMBPS);
+            }
+        }
+        // Besides the frame size, A.3.1 limits each dimension to
+        // Sqrt(MaxFS * 8) macroblocks.
+        let max_dim = limits.max_fs as u64 * 8;
+        if self.frame_size_in_mbs > limits.max_fs as u64 ||
+            self.frame_width_in_mbs as u64 * self.frame_width_in_mbs as u64 > max_dim ||
+            self.frame_height_in_mbs as u64 * self.frame_height_in_mbs as u64 > max_dim {
+            exceeded.push(H264LevelLimit::MaxFS);
+        }
+        if (self.dpb_frames as u64).saturating_mul(self.frame_size_in_mbs) > limits.max_dpb_mbs as u64 {
+            exceeded.push(H264LevelLimit::MaxDpbMbs);
+        }
+        let factor = cpb_br_nal_factor(self.profile_level.profile_idc) as f64;
+        let max_br = limits.max_br as f64 * factor;
+        if let Some(bitrate) = self.bitrate() {
+            if bitrate > max_br {
+                exceeded.push(H264LevelLimit::MaxBR);
+            }
+        }
+        if let Some(cpb) = self.cpb_size(max_br) {
+            if cpb > limits.max_cpb as f64 * factor {
+                exceeded.push(H264LevelLimit::MaxCPB);
+            }
+        }
+        if let Some(mv) = self.max_vertical_mv {
+            if mv > limits.max_vmv_r {
+                exceeded.push(H264LevelLimit::MaxVmvR);
+            }
+        }
+        if self.compression_ratio() < limits.min_cr as f64 {
+            exceeded.push(H264LevelLimit::MinCR);
+        }
+        exceeded
+    }
+
+    /// The lowest level whose limits the stream stays within, with the
+    /// profile and constraint flags of the stream.
+    pub fn minimum_level(&self) -> Option<H264ProfileLevel> {
+        let profile_idc = self.profile_level.profile_idc;
+        let level_1b_flag = profile_idc == PROFILE_BASELINE || profile_idc == PROFILE_MAIN ||
+            profile_idc == PROFILE_EXTENDED;
+        let flags = if level_1b_flag {
+            self.profile_level.constraint_flags & !0x10
+        } else {
+            self.profile_level.constraint_flags
+        };
+        for limits in LEVEL_LIMITS.iter() {
+            if !self.exceeded_limits(limits).is_empty() {
+                continue;
+            }
+            if limits.level_number == LEVEL_IDC_1B {
+                return Some(H264ProfileLevel::level_1b(profile_idc, flags));
+            }
+            return Some(H264ProfileLevel::new(profile_idc, flags, limits.level_number));
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct H264LevelReport {
+    pub measurements: H264StreamMeasurements,
+    pub declared_level: H264ProfileLevel,
+    /// None if even the highest level is exceeded.
+    pub minimum_level: Option<H264ProfileLevel>,
+    /// Limits of the declared level that the stream goes over. Empty if the
+    /// declared level_idc isn't a known level.
+    pub exceeded_limits: Vec<H264LevelLimit>
+}
+
+/// Measures a stream from its first SPS and its access units. frame_rate
+/// is used when the SPS has no VUI timing info.
+pub fn measure_stream(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                      frame_rate: Option<f64>) -> Result<H264StreamMeasurements, H264NalParseError> {
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut sps : Option<H264NalUnitSPS> = None;
+    let mut sizes = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let au = parser.parse_access_unit(offset)?;
+        if sps.is_none() {
+            for unit in &au.nal_units {
+                if unit.nal_unit_type == H264NalUnitType::SPS {
+                    sps = Some(parser.parse_sps(unit.data_offset)?);
+                    break;
+                }
+            }
+        }
+        // Parameter sets and SEI count towards the access unit, start codes
+        // and length prefixes don't.
+        let payload : usize = au.nal_units.iter().map(|n| n.sc_offset + n.size - n.data_offset).sum();
+        if au.nal_units.iter().any(|n| n.nal_unit_type_num >= 1 && n.nal_unit_type_num <= 5) {
+            sizes.push(payload as u64);
+        }
+        offset += au.size;
+    }
+    let sps = match sps {
+        Some(s) => s,
+        None => return Err(H264NalParseError::GenericParseError)
+    };
+
+    let width = sps.frame_width_in_mbs();
+    let height = sps.frame_height_in_mbs();
+    let frame_size = width as u64 * height as u64;
+    let (dpb_frames, max_vertical_mv) = match sps.vui_parameters {
+        Some(ref vui) if vui.bitstream_restriction_flag != 0 => {
+            // 16 is the value inferred when there is no restriction.
+            let mv = if vui.log2_max_mv_length_vertical < 16 {
+                Some((1u32 << vui.log2_max_mv_length_vertical) / 4)
+            } else {
+                None
+            };
+            (vui.max_dec_frame_buffering, mv)
+        },
+        _ => (sps.max_num_ref_frames, None)
+    };
+    let chroma_samples = match sps.chroma_subsampling() {
+        (0, 0) => 0,
+        (sub_width_c, sub_height_c) => 2 * 256 / (sub_width_c * sub_height_c) as u64
+    };
+    let raw_mb_bits = 256 * sps.bit_depth_luma() as u64 + chroma_samples * sps.bit_depth_chroma() as u64;
+
+    Ok(H264StreamMeasurements {
+        profile_level: sps.profile_level(),
+        frame_size_in_mbs: frame_size,
+        frame_width_in_mbs: width,
+        frame_height_in_mbs: height,
+        dpb_frames,
+        frame_rate: sps.frame_rate().or(frame_rate),
+        access_units: sizes.len() as u64,
+        total_bytes: sizes.iter().sum(),
+        max_access_unit_bytes: sizes.iter().cloned().max().unwrap_or(0),
+        raw_frame_bytes: frame_size.saturating_mul(raw_mb_bits) / 8,
+        max_vertical_mv,
+        access_unit_sizes: sizes
+    })
+}
+
+/// Measures a stream and compares it against its declared level.
+pub fn level_report(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                    frame_rate: Option<f64>) -> Result<H264LevelReport, H264NalParseError> {
+    let measurements = measure_stream(data, format, nal_length_size, frame_rate)?;
+    let declared = measurements.profile_level;
+    let exceeded = match level_limits(declared.level_number()) {
+        Some(limits) => measurements.exceeded_limits(limits),
+        None => Vec::new()
+    };
+    Ok(H264LevelReport {
+        minimum_level: measurements.minimum_level(),
+        declared_level: declared,
+        exceeded_limits: exceeded,
+        measurements
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::{write_nalunit, H264NalWriter};
+
+    fn measurements(width: u32, height: u32, frame_rate: f64, sizes: &[u64]) -> H264StreamMeasurements {
+        H264StreamMeasurements {
+            profile_level: H264ProfileLevel::new(PROFILE_HIGH, 0, 40),
+            frame_size_in_mbs: width as u64 * height as u64,
+            frame_width_in_mbs: width,
+            frame_height_in_mbs: height,
+            dpb_frames: 4,
+            frame_rate: Some(frame_rate),
+            access_units: sizes.len() as u64,
+            total_bytes: sizes.iter().sum(),
+            max_access_unit_bytes: sizes.iter().cloned().max().unwrap_or(0),
+            raw_frame_bytes: width as u64 * height as u64 * 384,
+            max_vertical_mv: None,
+            access_unit_sizes: sizes.to_vec()
+        }
+    }
+
+    #[test]
+    fn minimum_level_follows_size_and_rate() {
+        // 1080p30 at 10 Mbit/s
+        let m = measurements(120, 68, 30.0, &[41667; 30]);
+        assert_eq!(m.minimum_level(), Some(H264ProfileLevel::new(PROFILE_HIGH, 0, 40)));
+        // 1080p60 needs the rate of level 4.2
+        let m = measurements(120, 68, 60.0, &[20833; 60]);
+        assert_eq!(m.minimum_level(), Some(H264ProfileLevel::new(PROFILE_HIGH, 0, 42)));
+        // 720p30
+        let m = measurements(80, 45, 30.0, &[10000; 30]);
+        assert_eq!(m.minimum_level(), Some(H264ProfileLevel::new(PROFILE_HIGH, 0, 31)));
+        // QCIF at 15 fps and 102 kbit/s is over level 1 but fits 1b.
+        let mut m = measurements(11, 9, 15.0, &[850; 15]);
+        m.profile_level = H264ProfileLevel::new(PROFILE_BASELINE, 0xC0, 10);
+        assert_eq!(m.minimum_level(), Some(H264ProfileLevel::new(PROFILE_BASELINE, 0xD0, 11)));
+        m.profile_level = H264ProfileLevel::new(PROFILE_HIGH, 0, 10);
+        assert_eq!(m.minimum_level(), Some(H264ProfileLevel::new(PROFILE_HIGH, 0, LEVEL_IDC_1B)));
+        // Over every level.
+        let m = measurements(1024, 1024, 30.0, &[1000; 30]);
+        assert_eq!(m.minimum_level(), None);
+    }
+
+    #[test]
+    fn exceeded_limits_of_level_4() {
+        let limits = level_limits(40).unwrap();
+        let m = measurements(120, 68, 30.0, &[41667; 30]);
+        assert!(m.exceeded_limits(limits).is_empty());
+
+        // A strip within MaxFS is still too wide.
+        let m = measurements(260, 30, 30.0, &[41667; 30]);
+        assert_eq!(m.exceeded_limits(limits), vec![H264LevelLimit::MaxFS]);
+
+        let mut m = measurements(120, 68, 30.0, &[41667; 30]);
+        m.dpb_frames = 5;
+        m.max_vertical_mv = Some(1024);
+        assert_eq!(m.exceeded_limits(limits), vec![H264LevelLimit::MaxDpbMbs, H264LevelLimit::MaxVmvR]);
+        // Doesn't overflow
+        m.dpb_frames = u32::MAX;
+        m.frame_size_in_mbs = u32::MAX as u64 * 2;
+        m.max_vertical_mv = None;
+        assert!(m.exceeded_limits(limits).contains(&H264LevelLimit::MaxDpbMbs));
+
+        // 31 Mbit/s, over the 30 Mbit/s of High profile level 4
+        let m = measurements(120, 68, 30.0, &[130000; 30]);
+        assert_eq!(m.exceeded_limits(limits), vec![H264LevelLimit::MaxBR]);
+        // 27 Mbit/s on average, but with a burst at the start that leaves
+        // 47 Mbit in the CPB.
+        let mut sizes = vec![700000; 10];
+        sizes.extend_from_slice(&[40000; 80]);
+        let m = measurements(120, 68, 30.0, &sizes);
+        assert_eq!(m.exceeded_limits(limits), vec![H264LevelLimit::MaxCPB]);
+
+        // A 3 MB frame of 1080p 4:2:0 8 bit is compressed less than 4:1.
+        let m = measurements(120, 68, 1.0, &[800000]);
+        assert_eq!(m.exceeded_limits(limits), vec![H264LevelLimit::MinCR]);
+    }
+
+    fn stream(sps: &H264NalUnitSPS, frames: usize, payload: usize) -> Vec<u8> {
+        let pps = H264NalUnitPPS::new();
+        let mut data = Vec::new();
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sps.to_bytes().unwrap());
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &pps.to_bytes(1).unwrap());
+        for i in 0..frames {
+            let idr = i == 0;
+            let mut slice = H264NalUnitSlice::new();
+            slice.slice_type = if idr { 7 } else { 5 };
+            slice.frame_num = i as u32;
+            slice.pic_order_cnt_lsb = 2 * i as u16;
+            let unit = H264NalUnit::new(0, 4, 0, 3, if idr { 5 } else { 1 });
+            let mut writer = H264NalWriter::new();
+            slice.write(&mut writer, &unit, sps, &pps).unwrap();
+            for _ in 0..payload {
+                writer.write_u8(8, 0xA5).unwrap();
+            }
+            writer.write_rbsp_trailing_bits();
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &writer.to_nal(3, if idr { 5 } else { 1 }));
+        }
+        data
+    }
+
+    #[test]
+    fn measure_stream_from_the_sps_and_access_units() {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = PROFILE_MAIN;
+        sps.level_idc = 30;
+        sps.log2_max_frame_num_minus4 = 4;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = 4;
+        sps.max_num_ref_frames = 2;
+        sps.pic_width_in_mbs_minus1 = 44;
+        sps.pic_height_in_map_units_minus1 = 35;
+        sps.frame_mbs_only_flag = true;
+        let data = stream(&sps, 10, 1000);
+
+        let m = measure_stream(&data, H264NalFormat::BYTESTREAM, 4, None).unwrap();
+        assert_eq!((m.frame_width_in_mbs, m.frame_height_in_mbs, m.frame_size_in_mbs), (45, 36, 1620));
+        assert_eq!(m.dpb_frames, 2);
+        assert_eq!(m.frame_rate, None);
+        assert_eq!(m.bitrate(), None);
+        assert_eq!(m.access_units, 10);
+        assert!(m.max_access_unit_bytes > 1000);
+        assert_eq!(m.raw_frame_bytes, 1620 * 384);
+        assert_eq!(m.max_vertical_mv, None);
+
+        // VUI timing wins over the caller's rate, and the bitstream
+        // restriction gives the DPB size and motion vector range.
+        sps.set_timing_info(1, 24, true);
+        sps.set_bitstream_restriction(0, 3);
+        let data = stream(&sps, 10, 1000);
+        let report = level_report(&data, H264NalFormat::BYTESTREAM, 4, Some(60.0)).unwrap();
+        let m = &report.measurements;
+        assert_eq!(m.frame_rate, Some(12.0));
+        assert_eq!(m.dpb_frames, 3);
+        // log2_max_mv_length 16 is no restriction
+        assert_eq!(m.max_vertical_mv, None);
+        assert_eq!(report.declared_level, H264ProfileLevel::new(PROFILE_MAIN, 0, 30));
+        assert!(report.exceeded_limits.is_empty());
+        assert_eq!(report.minimum_level, Some(H264ProfileLevel::new(PROFILE_MAIN, 0, 22)));
+
+        sps.vui_mut().log2_max_mv_length_vertical = 11;
+        let data = stream(&sps, 2, 10);
+        let m = measure_stream(&data, H264NalFormat::BYTESTREAM, 4, None).unwrap();
+        assert_eq!(m.max_vertical_mv, Some(512));
+    }
+}
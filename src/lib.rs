@@ -1,3 +1,21 @@
+
 pub mod types;
 pub mod parser;
+pub mod writer;
+pub mod profile;
+pub mod level;
+pub mod avcc;
+pub mod sdp;
+pub mod rtp;
+pub mod pcap;
+pub mod ts;
+pub mod poc;
+pub mod mp4;
+pub mod hls;
+pub mod mkv;
+pub mod flv;
+pub mod gop;
+pub mod stats;
+pub mod hrd;
+pub mod validate;
 pub use types::*;
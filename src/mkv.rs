@@ -0,0 +1,582 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use avcc::H264AVCDecoderConfigurationRecord;
+use parser::{H264NalParser, H264NalParseError};
+pub use types::*;
+
+/// The codec id of H.264 in AVC format.
+pub const MKV_CODEC_ID_AVC : &str = "V_MPEG4/ISO/AVC";
+
+const MKV_ID_SEGMENT : u32 = 0x18538067;
+const MKV_ID_INFO : u32 = 0x1549A966;
+const MKV_ID_TIMESTAMP_SCALE : u32 = 0x2AD7B1;
+const MKV_ID_TRACKS : u32 = 0x1654AE6B;
+const MKV_ID_TRACK_ENTRY : u32 = 0xAE;
+const MKV_ID_TRACK_NUMBER : u32 = 0xD7;
+const MKV_ID_TRACK_TYPE : u32 = 0x83;
+const MKV_ID_CODEC_ID : u32 = 0x86;
+const MKV_ID_CODEC_PRIVATE : u32 = 0x63A2;
+const MKV_ID_DEFAULT_DURATION : u32 = 0x23E383;
+const MKV_ID_VIDEO : u32 = 0xE0;
+const MKV_ID_PIXEL_WIDTH : u32 = 0xB0;
+const MKV_ID_PIXEL_HEIGHT : u32 = 0xBA;
+const MKV_ID_CLUSTER : u32 = 0x1F43B675;
+const MKV_ID_CLUSTER_TIMESTAMP : u32 = 0xE7;
+const MKV_ID_SIMPLE_BLOCK : u32 = 0xA3;
+const MKV_ID_BLOCK_GROUP : u32 = 0xA0;
+const MKV_ID_BLOCK : u32 = 0xA1;
+const MKV_ID_BLOCK_DURATION : u32 = 0x9B;
+const MKV_ID_REFERENCE_BLOCK : u32 = 0xFB;
+
+/// IDs of the top level elements of a segment, which end a cluster of
+/// unknown size.
+const MKV_LEVEL1_IDS : [u32; 8] = [
+    0x114D9B74, MKV_ID_INFO, MKV_ID_TRACKS, MKV_ID_CLUSTER,
+    0x1C53BB6B, 0x1941A469, 0x1043A770, 0x1254C367
+];
+
+#[derive(Debug)]
+pub enum H264MkvError {
+    /// An element runs past the end of its parent or of the file.
+    Truncated,
+    /// An element whose contents don't make sense.
+    InvalidElement(u32),
+    NoVideoTrack,
+    Parse(H264NalParseError),
+    Io(io::Error)
+}
+
+/// An EBML element. offset is where its payload starts; size is None for
+/// an element of unknown size.
+#[derive(Debug, Clone, Copy)]
+struct H264MkvElement {
+    id: u32,
+    offset: usize,
+    size: Option<usize>
+}
+
+/// A variable size integer. The marker bit is kept for IDs and removed for
+/// sizes, where all ones means unknown.
+fn read_vint(data: &[u8], pos: usize, keep_marker: bool) -> Result<(u64, usize, bool), H264MkvError> {
+    let first = *data.get(pos).ok_or(H264MkvError::Truncated)?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 || pos + length > data.len() {
+        return Err(H264MkvError::Truncated);
+    }
+    let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xFF >> length) };
+    let mut all_ones = value == (0xFF >> length) as u64;
+    for i in 1..length {
+        value = value << 8 | data[pos + i] as u64;
+        all_ones &= data[pos + i] == 0xFF;
+    }
+    Ok((value, length, all_ones && !keep_marker))
+}
+
+fn read_element(data: &[u8], pos: usize) -> Result<H264MkvElement, H264MkvError> {
+    let (id, id_length, _) = read_vint(data, pos, true)?;
+    let (size, size_length, unknown) = read_vint(data, pos + id_length, false)?;
+    let offset = pos + id_length + size_length;
+    if !unknown && offset as u64 + size > data.len() as u64 {
+        return Err(H264MkvError::Truncated);
+    }
+    Ok(H264MkvElement {
+        id: id as u32,
+        offset,
+        size: if unknown { None } else { Some(size as usize) }
+    })
+}
+
+/// The children of the range start..end, which must not be of unknown
+/// size.
+fn read_children(data: &[u8], start: usize, end: usize) -> Result<Vec<H264MkvElement>, H264MkvError> {
+    let mut children = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let element = read_element(&data[..end], pos)?;
+        let size = element.size.ok_or(H264MkvError::InvalidElement(element.id))?;
+        children.push(element);
+        pos = element.offset + size;
+    }
+    Ok(children)
+}
+
+fn read_uint(data: &[u8], element: &H264MkvElement) -> u64 {
+    let size = element.size.unwrap_or(0);
+    data[element.offset..element.offset + size].iter().fold(0, |v, &b| v << 8 | b as u64)
+}
+
+/// One frame of a video track. A laced block gives several. offset is the
+/// file offset of its data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264MkvFrame {
+    pub offset: usize,
+    pub size: usize,
+    /// Presentation time in nanoseconds. Blocks are stored in decoding
+    /// order, so these aren't always increasing.
+    pub timestamp: i64,
+    /// From BlockDuration or DefaultDuration, in nanoseconds.
+    pub duration: Option<u64>,
+    pub keyframe: bool
+}
+
+/// A video track with codec id V_MPEG4/ISO/AVC, whose CodecPrivate is an
+/// avcC record.
+#[derive(Debug, Clone)]
+pub struct H264MkvTrack {
+    pub track_number: u64,
+    pub width: u32,
+    pub height: u32,
+    /// In nanoseconds.
+    pub default_duration: Option<u64>,
+    pub avcc: H264AVCDecoderConfigurationRecord,
+    pub frames: Vec<H264MkvFrame>
+}
+
+impl H264MkvTrack {
+    /// A parser for the frames of the track, already holding the
+    /// parameter sets of the CodecPrivate.
+    pub fn parser(&self) -> Result<H264NalParser, H264NalParseError> {
+        self.avcc.parser()
+    }
+}
+
+/// Reads the H.264 video tracks of a Matroska or WebM file.
+pub struct H264MkvDemuxer {
+    data: Vec<u8>,
+    /// Nanoseconds per timestamp tick.
+    pub timestamp_scale: u64,
+    pub tracks: Vec<H264MkvTrack>,
+    /// Where the file ended in the middle of a top level element. The
+    /// elements before it have been read.
+    pub truncated_at: Option<usize>
+}
+
+impl H264MkvDemuxer {
+    pub fn new(path: &str) -> Result<H264MkvDemuxer, H264MkvError> {
+        let mut file = File::open(path).map_err(H264MkvError::Io)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(H264MkvError::Io)?;
+        H264MkvDemuxer::from_bytes(data)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<H264MkvDemuxer, H264MkvError> {
+        let mut demuxer = H264MkvDemuxer {
+            data: Vec::new(),
+            timestamp_scale: 1_000_000,
+            tracks: Vec::new(),
+            truncated_at: None
+        };
+        // Skip the EBML header and anything else before the segment.
+        let mut pos = 0;
+        let segment = loop {
+            let element = read_element(&data, pos)?;
+            if element.id == MKV_ID_SEGMENT {
+                break element;
+            }
+            pos = element.offset + element.size.ok_or(H264MkvError::InvalidElement(element.id))?;
+        };
+        let segment_end = segment.size.map_or(data.len(), |s| segment.offset + s);
+
+        let mut pos = segment.offset;
+        while pos < segment_end {
+            let element = match read_element(&data[..segment_end], pos) {
+                Ok(e) => e,
+                Err(H264MkvError::Truncated) => {
+                    demuxer.truncated_at = Some(pos);
+                    break;
+                },
+                Err(e) => return Err(e)
+            };
+            pos = match element.id {
+                MKV_ID_INFO => {
+                    let end = element.offset + element.size.ok_or(H264MkvError::InvalidElement(element.id))?;
+                    for child in read_children(&data, element.offset, end)? {
+                        if child.id == MKV_ID_TIMESTAMP_SCALE {
+                            demuxer.timestamp_scale = read_uint(&data, &child);
+                        }
+                    }
+                    end
+                },
+                MKV_ID_TRACKS => {
+                    let end = element.offset + element.size.ok_or(H264MkvError::InvalidElement(element.id))?;
+                    for child in read_children(&data, element.offset, end)? {
+                        if child.id == MKV_ID_TRACK_ENTRY {
+                            if let Some(track) = parse_track_entry(&data, &child)? {
+                                demuxer.tracks.push(track);
+                            }
+                        }
+                    }
+                    end
+                },
+                MKV_ID_CLUSTER => demuxer.parse_cluster(&data, &element, segment_end)?,
+                _ => match element.size {
+                    Some(size) => element.offset + size,
+                    None => return Err(H264MkvError::InvalidElement(element.id))
+                }
+            };
+        }
+        if demuxer.tracks.is_empty() {
+            return Err(H264MkvError::NoVideoTrack);
+        }
+        demuxer.data = data;
+        Ok(demuxer)
+    }
+
+    /// Collects the frames of a cluster and returns where it ends.
+    fn parse_cluster(&mut self, data: &[u8], cluster: &H264MkvElement,
+                     segment_end: usize) -> Result<usize, H264MkvError> {
+        let end = cluster.size.map_or(segment_end, |s| cluster.offset + s);
+        let mut cluster_timestamp = 0;
+        let mut pos = cluster.offset;
+        while pos < end {
+            let element = match read_element(&data[..end], pos) {
+                Ok(e) => e,
+                Err(H264MkvError::Truncated) => {
+                    // A recording cut short, keep the frames so far.
+                    self.truncated_at = Some(pos);
+                    return Ok(end);
+                },
+                Err(e) => return Err(e)
+            };
+            if cluster.size.is_none() && MKV_LEVEL1_IDS.contains(&element.id) {
+                // The next cluster or another top level element.
+                return Ok(pos);
+            }
+            let size = element.size.ok_or(H264MkvError::InvalidElement(element.id))?;
+            match element.id {
+                MKV_ID_CLUSTER_TIMESTAMP => cluster_timestamp = read_uint(data, &element) as i64,
+                MKV_ID_SIMPLE_BLOCK => self.parse_block(data, &element, cluster_timestamp, None, None)?,
+                MKV_ID_BLOCK_GROUP => {
+                    let mut block = None;
+                    let mut keyframe = Some(true);
+                    let mut duration = None;
+                    for child in read_children(data, element.offset, element.offset + size)? {
+                        match child.id {
+                            MKV_ID_BLOCK => block = Some(child),
+                            MKV_ID_REFERENCE_BLOCK => keyframe = Some(false),
+                            MKV_ID_BLOCK_DURATION => duration = Some(read_uint(data, &child)),
+                            _ => {}
+                        }
+                    }
+                    if let Some(block) = block {
+                        self.parse_block(data, &block, cluster_timestamp, keyframe, duration)?;
+                    }
+                },
+                _ => {}
+            }
+            pos = element.offset + size;
+        }
+        Ok(end)
+    }
+
+    /// keyframe is None for a SimpleBlock, whose flags tell.
+    fn parse_block(&mut self, data: &[u8], block: &H264MkvElement, cluster_timestamp: i64,
+                   keyframe: Option<bool>, duration: Option<u64>) -> Result<(), H264MkvError> {
+        let end = block.offset + block.size.unwrap_or(0);
+        let block_data = &data[..end];
+        let (track_number, length, _) = read_vint(block_data, block.offset, false)?;
+        let timestamp_scale = self.timestamp_scale;
+        let track = match self.tracks.iter_mut().find(|t| t.track_number == track_number) {
+            Some(t) => t,
+            None => return Ok(())
+        };
+        let mut pos = block.offset + length;
+        if pos + 3 > end {
+            return Err(H264MkvError::Truncated);
+        }
+        let relative = (data[pos] as i16) << 8 | data[pos + 1] as i16;
+        let flags = data[pos + 2];
+        let keyframe = keyframe.unwrap_or(flags & 0x80 != 0);
+        pos += 3;
+
+        // Frame sizes of laced blocks, all but the last one.
+        let lacing = (flags >> 1) & 0x03;
+        let mut sizes = Vec::new();
+        if lacing != 0 {
+            let count = *data.get(pos).ok_or(H264MkvError::Truncated)? as usize + 1;
+            pos += 1;
+            match lacing {
+                // Xiph
+                1 => for _ in 0..count - 1 {
+                    let mut size = 0;
+                    loop {
+                        let byte = *block_data.get(pos).ok_or(H264MkvError::Truncated)?;
+                        pos += 1;
+                        size += byte as usize;
+                        if byte != 255 {
+                            break;
+                        }
+                    }
+                    sizes.push(size);
+                },
+                // EBML, the sizes after the first are signed differences.
+                3 => for i in 0..count - 1 {
+                    let (value, length, _) = read_vint(block_data, pos, false)?;
+                    pos += length;
+                    let size = if i == 0 {
+                        value as i64
+                    } else {
+                        let bias = (1i64 << (7 * length - 1)) - 1;
+                        sizes[i - 1] as i64 + value as i64 - bias
+                    };
+                    if size < 0 {
+                        return Err(H264MkvError::InvalidElement(block.id));
+                    }
+                    sizes.push(size as usize);
+                },
+                // Fixed size
+                _ => {
+                    let size = (end.saturating_sub(pos)) / count;
+                    sizes = vec![size; count - 1];
+                }
+            }
+        }
+        let laced : usize = sizes.iter().sum();
+        if pos + laced > end {
+            return Err(H264MkvError::Truncated);
+        }
+        sizes.push(end - pos - laced);
+
+        let timestamp = cluster_timestamp.saturating_add(relative as i64).saturating_mul(timestamp_scale as i64);
+        let frame_duration = duration.map(|d| d.saturating_mul(timestamp_scale) / sizes.len() as u64)
+            .or(track.default_duration);
+        for (i, &size) in sizes.iter().enumerate() {
+            track.frames.push(H264MkvFrame {
+                offset: pos,
+                size,
+                timestamp: timestamp.saturating_add((i as i64).saturating_mul(frame_duration.unwrap_or(0) as i64)),
+                duration: frame_duration,
+                // Only the first frame of a laced keyframe block is one.
+                keyframe: keyframe && i == 0
+            });
+            pos += size;
+        }
+        Ok(())
+    }
+
+    pub fn frame_data(&self, frame: &H264MkvFrame) -> &[u8] {
+        &self.data[frame.offset..frame.offset + frame.size]
+    }
+
+    /// The NAL units of a frame, without their length prefixes.
+    pub fn frame_nal_units(&self, track: &H264MkvTrack, frame: &H264MkvFrame) -> Result<Vec<&[u8]>, H264NalParseError> {
+        track.avcc.sample_nal_units(self.frame_data(frame))
+    }
+
+    /// Hands a frame to a parser made by H264MkvTrack::parser.
+    pub fn load_frame(&self, track: &H264MkvTrack, frame: &H264MkvFrame, parser: &mut H264NalParser) {
+        parser.set_data(self.frame_data(frame).to_vec());
+        parser.format = H264NalFormat::AVC;
+        parser.nal_length_size = track.avcc.nal_length_size();
+    }
+}
+
+/// None for tracks that aren't H.264 video.
+fn parse_track_entry(data: &[u8], entry: &H264MkvElement) -> Result<Option<H264MkvTrack>, H264MkvError> {
+    let end = entry.offset + entry.size.unwrap_or(0);
+    let mut track_number = 0;
+    let mut track_type = 0;
+    let mut codec_id = String::new();
+    let mut codec_private = None;
+    let mut default_duration = None;
+    let mut width = 0;
+    let mut height = 0;
+    for child in read_children(data, entry.offset, end)? {
+        let size = child.size.unwrap_or(0);
+        match child.id {
+            MKV_ID_TRACK_NUMBER => track_number = read_uint(data, &child),
+            MKV_ID_TRACK_TYPE => track_type = read_uint(data, &child),
+            MKV_ID_CODEC_ID => {
+                let id = &data[child.offset..child.offset + size];
+                codec_id = String::from_utf8_lossy(id).trim_end_matches('\0').to_string();
+            },
+            MKV_ID_CODEC_PRIVATE => codec_private = Some(&data[child.offset..child.offset + size]),
+            MKV_ID_DEFAULT_DURATION => default_duration = Some(read_uint(data, &child)),
+            MKV_ID_VIDEO => {
+                for video in read_children(data, child.offset, child.offset + size)? {
+                    match video.id {
+                        MKV_ID_PIXEL_WIDTH => width = read_uint(data, &video) as u32,
+                        MKV_ID_PIXEL_HEIGHT => height = read_uint(data, &video) as u32,
+                        _ => {}
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    if track_type != 1 || codec_id != MKV_CODEC_ID_AVC {
+        return Ok(None);
+    }
+    let avcc = match codec_private {
+        Some(p) => H264AVCDecoderConfigurationRecord::parse(p).map_err(H264MkvError::Parse)?,
+        None => return Err(H264MkvError::InvalidElement(entry.id))
+    };
+    Ok(Some(H264MkvTrack {
+        track_number,
+        width,
+        height,
+        default_duration,
+        avcc,
+        frames: Vec::new()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An element with a known size, written as an 8 byte vint.
+    fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data : Vec<u8> = id.to_be_bytes().iter().cloned().skip_while(|&b| b == 0).collect();
+        data.push(0x01);
+        data.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn unknown_size(id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data : Vec<u8> = id.to_be_bytes().iter().cloned().skip_while(|&b| b == 0).collect();
+        data.push(0xFF);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn uint(id: u32, value: u64) -> Vec<u8> {
+        element(id, &value.to_be_bytes())
+    }
+
+    /// EBML header, Info and Tracks with an H.264 track of the given
+    /// number and a 40ms DefaultDuration, with the segment left open.
+    fn header(track_number: u64) -> (Vec<u8>, Vec<u8>) {
+        let ebml = element(0x1A45DFA3, &element(0x4282, b"matroska"));
+        let mut segment = element(MKV_ID_INFO, &uint(MKV_ID_TIMESTAMP_SCALE, 1_000_000));
+        let mut entry = uint(MKV_ID_TRACK_NUMBER, track_number);
+        entry.extend(uint(MKV_ID_TRACK_TYPE, 1));
+        entry.extend(element(MKV_ID_CODEC_ID, MKV_CODEC_ID_AVC.as_bytes()));
+        entry.extend(element(MKV_ID_CODEC_PRIVATE, &H264AVCDecoderConfigurationRecord::new().to_bytes()));
+        entry.extend(uint(MKV_ID_DEFAULT_DURATION, 40_000_000));
+        let mut video = uint(MKV_ID_PIXEL_WIDTH, 64);
+        video.extend(uint(MKV_ID_PIXEL_HEIGHT, 48));
+        entry.extend(element(MKV_ID_VIDEO, &video));
+        // An audio track, which is skipped.
+        let mut audio = uint(MKV_ID_TRACK_NUMBER, 2);
+        audio.extend(uint(MKV_ID_TRACK_TYPE, 2));
+        audio.extend(element(MKV_ID_CODEC_ID, b"A_OPUS"));
+        let mut tracks = element(MKV_ID_TRACK_ENTRY, &entry);
+        tracks.extend(element(MKV_ID_TRACK_ENTRY, &audio));
+        segment.extend(element(MKV_ID_TRACKS, &tracks));
+        (ebml, segment)
+    }
+
+    /// Block payload: track number vint, relative timestamp, flags, then
+    /// the rest.
+    fn block(track: &[u8], relative: i16, flags: u8, rest: &[u8]) -> Vec<u8> {
+        let mut data = track.to_vec();
+        data.extend_from_slice(&relative.to_be_bytes());
+        data.push(flags);
+        data.extend_from_slice(rest);
+        data
+    }
+
+    fn frames(demuxer: &H264MkvDemuxer) -> Vec<(Vec<u8>, i64, Option<u64>, bool)> {
+        demuxer.tracks[0].frames.iter()
+            .map(|f| (demuxer.frame_data(f).to_vec(), f.timestamp / 1_000_000, f.duration.map(|d| d / 1_000_000), f.keyframe))
+            .collect()
+    }
+
+    #[test]
+    fn laced_blocks_are_split() {
+        let (mut file, mut segment) = header(1);
+        let mut cluster = uint(MKV_ID_CLUSTER_TIMESTAMP, 1000);
+        // Xiph lacing: 300 and 2 bytes, then the rest
+        let mut xiph = vec![2, 255, 45, 2];
+        xiph.extend(vec![0xAA; 300]);
+        xiph.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], 0, 0x80 | 0x02, &xiph)));
+        // EBML lacing: 4 bytes, then 4 + 2 as a signed difference
+        let ebml = [2, 0x84, 0xC1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 3, 3, 3];
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], 40, 0x06, &ebml)));
+        // Fixed size lacing
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], 80, 0x04, &[1, 4, 4, 5, 5])));
+        // A block group with a reference and a duration
+        let mut group = element(MKV_ID_BLOCK, &block(&[0x81], 120, 0x00, &[9, 9]));
+        group.extend(uint(MKV_ID_REFERENCE_BLOCK, 40));
+        group.extend(uint(MKV_ID_BLOCK_DURATION, 80));
+        cluster.extend(element(MKV_ID_BLOCK_GROUP, &group));
+        // Blocks of other tracks are skipped.
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x82], 0, 0x80, &[0xFF])));
+        segment.extend(element(MKV_ID_CLUSTER, &cluster));
+        file.extend(element(MKV_ID_SEGMENT, &segment));
+
+        let demuxer = H264MkvDemuxer::from_bytes(file).unwrap();
+        assert_eq!(demuxer.tracks.len(), 1);
+        assert_eq!((demuxer.tracks[0].width, demuxer.tracks[0].height), (64, 48));
+        assert_eq!(demuxer.truncated_at, None);
+        let frames = frames(&demuxer);
+        assert_eq!(frames.len(), 9);
+        assert_eq!(frames[0], (vec![0xAA; 300], 1000, Some(40), true));
+        assert_eq!(frames[1], (vec![1, 2], 1040, Some(40), false));
+        assert_eq!(frames[2], (vec![3, 4, 5, 6, 7], 1080, Some(40), false));
+        assert_eq!(frames[3], (vec![1, 1, 1, 1], 1040, Some(40), false));
+        assert_eq!(frames[4], (vec![2, 2, 2, 2, 2, 2], 1080, Some(40), false));
+        assert_eq!(frames[5], (vec![3, 3, 3], 1120, Some(40), false));
+        assert_eq!(frames[6], (vec![4, 4], 1080, Some(40), false));
+        assert_eq!(frames[7], (vec![5, 5], 1120, Some(40), false));
+        assert_eq!(frames[8], (vec![9, 9], 1120, Some(80), false));
+    }
+
+    #[test]
+    fn unknown_size_clusters_end_at_the_next_top_level_element() {
+        let (mut file, mut segment) = header(1);
+        let mut cluster = uint(MKV_ID_CLUSTER_TIMESTAMP, 0);
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], 0, 0x80, &[1])));
+        segment.extend(unknown_size(MKV_ID_CLUSTER, &cluster));
+        let mut cluster = uint(MKV_ID_CLUSTER_TIMESTAMP, 2000);
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], -40, 0x00, &[2])));
+        segment.extend(unknown_size(MKV_ID_CLUSTER, &cluster));
+        // Cues
+        segment.extend(element(0x1C53BB6B, &[0; 4]));
+        let mut cluster = uint(MKV_ID_CLUSTER_TIMESTAMP, 3000);
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x81], 0, 0x80, &[3])));
+        segment.extend(unknown_size(MKV_ID_CLUSTER, &cluster));
+        file.extend(unknown_size(MKV_ID_SEGMENT, &segment));
+
+        let demuxer = H264MkvDemuxer::from_bytes(file.clone()).unwrap();
+        assert_eq!(frames(&demuxer), vec![
+            (vec![1], 0, Some(40), true),
+            (vec![2], 1960, Some(40), false),
+            (vec![3], 3000, Some(40), true)
+        ]);
+        assert_eq!(demuxer.truncated_at, None);
+
+        // A top level element cut short is reported, what came before it
+        // is kept.
+        let end = file.len();
+        file.extend(element(0x1254C367, &[0; 10]));
+        file.truncate(end + 8);
+        let demuxer = H264MkvDemuxer::from_bytes(file).unwrap();
+        assert_eq!(demuxer.tracks[0].frames.len(), 3);
+        assert_eq!(demuxer.truncated_at, Some(end));
+    }
+
+    #[test]
+    fn multi_byte_track_numbers() {
+        let (mut file, mut segment) = header(200);
+        let mut cluster = uint(MKV_ID_CLUSTER_TIMESTAMP, 0);
+        // Track 200 takes two bytes, so the flags are one byte later.
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x40, 0xC8], 0, 0x80, &[1])));
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x40, 0xC8], 0x80, 0x00, &[2])));
+        // Track 72 in two bytes isn't ours.
+        cluster.extend(element(MKV_ID_SIMPLE_BLOCK, &block(&[0x40, 0x48], 0, 0x80, &[3])));
+        segment.extend(element(MKV_ID_CLUSTER, &cluster));
+        file.extend(element(MKV_ID_SEGMENT, &segment));
+
+        let demuxer = H264MkvDemuxer::from_bytes(file).unwrap();
+        assert_eq!(demuxer.tracks[0].track_number, 200);
+        assert_eq!(frames(&demuxer), vec![
+            (vec![1], 0, Some(40), true),
+            (vec![2], 128, Some(40), false)
+        ]);
+    }
+}
@@ -0,0 +1,629 @@
+use std::fs::File;
+use std::io::Read;
+use avcc::H264AVCDecoderConfigurationRecord;
+use parser::{H264NalParser, H264NalParseError};
+use mp4::*;
+
+/// One sample of a video track. offset is the file offset of its data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264Mp4Sample {
+    pub offset: u64,
+    pub size: u32,
+    /// In the timescale of the track.
+    pub decode_time: u64,
+    pub composition_time: i64,
+    pub duration: u32,
+    pub sync: bool
+}
+
+/// An avc1 or avc3 video track. The avcC is the one of the first sample
+/// description; avc3 tracks may carry the parameter sets in the samples
+/// instead.
+#[derive(Debug, Clone)]
+pub struct H264Mp4Track {
+    pub track_id: u32,
+    pub timescale: u32,
+    pub duration: u64,
+    pub width: u16,
+    pub height: u16,
+    pub sample_entry: H264BoxType,
+    pub avcc: H264AVCDecoderConfigurationRecord,
+    pub samples: Vec<H264Mp4Sample>,
+    /// Samples of the stsz that the stsc doesn't put in any chunk. They
+    /// are left out of samples.
+    pub samples_without_chunk: usize
+}
+
+impl H264Mp4Track {
+    /// A parser for the samples of the track, already holding the
+    /// parameter sets of the avcC.
+    pub fn parser(&self) -> Result<H264NalParser, H264NalParseError> {
+        self.avcc.parser()
+    }
+}
+
+/// Default sample values from the trex box of a track.
+#[derive(Clone, Copy)]
+struct H264TrackExtends {
+    track_id: u32,
+    duration: u32,
+    size: u32,
+    flags: u32
+}
+
+/// Reads the avc1/avc3 video tracks of an MP4 file, both the sample tables
+/// of the moov and the track fragments of fragmented files.
+pub struct H264Mp4Demuxer {
+    data: Vec<u8>,
+    pub tracks: Vec<H264Mp4Track>
+}
+
+impl H264Mp4Demuxer {
+    pub fn new(path: &str) -> Result<H264Mp4Demuxer, H264Mp4Error> {
+        let mut file = File::open(path).map_err(H264Mp4Error::Io)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(H264Mp4Error::Io)?;
+        H264Mp4Demuxer::from_bytes(data)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<H264Mp4Demuxer, H264Mp4Error> {
+        let mut tracks = Vec::new();
+        let mut extends = Vec::new();
+        {
+            let boxes = read_boxes(&data, 0)?;
+            let moov = find_box(&boxes, b"moov").ok_or(H264Mp4Error::NoVideoTrack)?;
+            let moov_boxes = moov.children()?;
+            for trak in moov_boxes.iter().filter(|b| b.box_type.is(b"trak")) {
+                if let Some(track) = parse_trak(trak, data.len() as u64)? {
+                    tracks.push(track);
+                }
+            }
+            if let Some(mvex) = find_box(&moov_boxes, b"mvex") {
+                for trex in mvex.children()?.iter().filter(|b| b.box_type.is(b"trex")) {
+                    extends.push(parse_trex(trex)?);
+                }
+            }
+            for moof in boxes.iter().filter(|b| b.box_type.is(b"moof")) {
+                parse_moof(moof, &mut tracks, &extends, data.len() as u64)?;
+            }
+        }
+        if tracks.is_empty() {
+            return Err(H264Mp4Error::NoVideoTrack);
+        }
+        for track in &tracks {
+            if track.samples.iter().any(|s| s.offset.checked_add(s.size as u64).is_none_or(|end| end > data.len() as u64)) {
+                return Err(H264Mp4Error::Truncated);
+            }
+        }
+        Ok(H264Mp4Demuxer { data, tracks })
+    }
+
+    pub fn sample_data(&self, sample: &H264Mp4Sample) -> &[u8] {
+        &self.data[sample.offset as usize..(sample.offset + sample.size as u64) as usize]
+    }
+
+    /// The NAL units of a sample, without their length prefixes.
+    pub fn sample_nal_units(&self, track: &H264Mp4Track, sample: &H264Mp4Sample) -> Result<Vec<&[u8]>, H264NalParseError> {
+        track.avcc.sample_nal_units(self.sample_data(sample))
+    }
+
+    /// Hands a sample to a parser made by H264Mp4Track::parser. In-band
+    /// parameter sets of avc3 tracks are picked up as they are parsed.
+    pub fn load_sample(&self, track: &H264Mp4Track, sample: &H264Mp4Sample, parser: &mut H264NalParser) {
+        parser.set_data(self.sample_data(sample).to_vec());
+        parser.format = H264NalFormat::AVC;
+        parser.nal_length_size = track.avcc.nal_length_size();
+    }
+}
+
+fn check_len(data: &[u8], len: usize) -> Result<(), H264Mp4Error> {
+    if data.len() < len {
+        Err(H264Mp4Error::Truncated)
+    } else {
+        Ok(())
+    }
+}
+
+/// None for tracks that aren't avc1 or avc3 video. file_size bounds the
+/// sample counts.
+fn parse_trak(trak: &H264Box, file_size: u64) -> Result<Option<H264Mp4Track>, H264Mp4Error> {
+    let children = trak.children()?;
+    let tkhd = find_box(&children, b"tkhd").ok_or(H264Mp4Error::InvalidBox(trak.box_type))?;
+    let (version, _, body) = tkhd.full_box()?;
+    let track_id = if version == 1 {
+        check_len(body, 20)?;
+        read_u32(&body[16..])
+    } else {
+        check_len(body, 12)?;
+        read_u32(&body[8..])
+    };
+    let mdia = match find_box(&children, b"mdia") {
+        Some(b) => b.children()?,
+        None => return Ok(None)
+    };
+    let mdhd = find_box(&mdia, b"mdhd").ok_or(H264Mp4Error::InvalidBox(trak.box_type))?;
+    let (version, _, body) = mdhd.full_box()?;
+    let (timescale, duration) = if version == 1 {
+        check_len(body, 28)?;
+        (read_u32(&body[16..]), read_u64(&body[20..]))
+    } else {
+        check_len(body, 16)?;
+        (read_u32(&body[8..]), read_u32(&body[12..]) as u64)
+    };
+    let stbl = match find_box(&mdia, b"minf") {
+        Some(minf) => match find_box(&minf.children()?, b"stbl") {
+            Some(b) => b.children()?,
+            None => return Ok(None)
+        },
+        None => return Ok(None)
+    };
+
+    let stsd = find_box(&stbl, b"stsd").ok_or(H264Mp4Error::InvalidBox(trak.box_type))?;
+    let (_, _, body) = stsd.full_box()?;
+    check_len(body, 4)?;
+    let entries = read_boxes(&body[4..], stsd.offset + 8)?;
+    let entry = match entries.first() {
+        Some(e) if e.box_type.is(b"avc1") || e.box_type.is(b"avc3") => *e,
+        _ => return Ok(None)
+    };
+    // The VisualSampleEntry fields come before the child boxes.
+    check_len(entry.data, 78)?;
+    let width = read_u16(&entry.data[24..]);
+    let height = read_u16(&entry.data[26..]);
+    let entry_children = read_boxes(&entry.data[78..], entry.offset + 78)?;
+    let avcc = match find_box(&entry_children, b"avcC") {
+        Some(b) => H264AVCDecoderConfigurationRecord::parse(b.data).map_err(H264Mp4Error::Parse)?,
+        None => return Err(H264Mp4Error::InvalidBox(entry.box_type))
+    };
+
+    let (samples, samples_without_chunk) = parse_sample_table(&stbl, file_size)?;
+    Ok(Some(H264Mp4Track {
+        track_id,
+        timescale,
+        duration,
+        width,
+        height,
+        sample_entry: entry.box_type,
+        avcc,
+        samples,
+        samples_without_chunk
+    }))
+}
+
+/// The entries of a full box that starts with an entry_count, each
+/// entry_size bytes long.
+fn table<'a>(b: &H264Box<'a>, entry_size: usize) -> Result<Vec<&'a [u8]>, H264Mp4Error> {
+    let (_, _, body) = b.full_box()?;
+    check_len(body, 4)?;
+    let count = read_u32(body) as usize;
+    check_len(&body[4..], count.saturating_mul(entry_size))?;
+    Ok(body[4..4 + count * entry_size].chunks(entry_size).collect())
+}
+
+/// The samples of a sample table and how many of them the stsc left out.
+fn parse_sample_table(stbl: &[H264Box], file_size: u64) -> Result<(Vec<H264Mp4Sample>, usize), H264Mp4Error> {
+    // Sample sizes.
+    let mut sizes = Vec::new();
+    if let Some(stsz) = find_box(stbl, b"stsz") {
+        let (_, _, body) = stsz.full_box()?;
+        check_len(body, 8)?;
+        let sample_size = read_u32(body);
+        let count = read_u32(&body[4..]) as usize;
+        if sample_size != 0 {
+            // The samples have to fit in the file.
+            if (count as u64).saturating_mul(sample_size as u64) > file_size {
+                return Err(H264Mp4Error::Truncated);
+            }
+            sizes = vec![sample_size; count];
+        } else {
+            check_len(&body[8..], count.saturating_mul(4))?;
+            sizes = body[8..8 + count * 4].chunks(4).map(read_u32).collect();
+        }
+    }
+    if sizes.is_empty() {
+        // Fragmented files have their samples in the moofs.
+        return Ok((Vec::new(), 0));
+    }
+
+    let (chunk_offsets, offsets_box) : (Vec<u64>, _) = if let Some(stco) = find_box(stbl, b"stco") {
+        (table(&stco, 4)?.iter().map(|e| read_u32(e) as u64).collect(), stco.box_type)
+    } else if let Some(co64) = find_box(stbl, b"co64") {
+        (table(&co64, 8)?.iter().map(|e| read_u64(e)).collect(), co64.box_type)
+    } else {
+        return Err(H264Mp4Error::InvalidBox(H264BoxType(*b"stbl")));
+    };
+    let stsc = match find_box(stbl, b"stsc") {
+        Some(b) => table(&b, 12)?.iter().map(|e| (read_u32(e), read_u32(&e[4..]))).collect(),
+        None => Vec::new()
+    };
+
+    // Sample offsets from the chunks.
+    let mut offsets = Vec::with_capacity(sizes.len());
+    'chunks: for (chunk, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk_number = chunk as u32 + 1;
+        let samples_per_chunk = stsc.iter().rev()
+            .find(|e| e.0 <= chunk_number)
+            .map_or(0, |e| e.1);
+        let mut offset = chunk_offset;
+        for _ in 0..samples_per_chunk {
+            if offsets.len() == sizes.len() {
+                break 'chunks;
+            }
+            offsets.push(offset);
+            offset = offset.checked_add(sizes[offsets.len() - 1] as u64)
+                .ok_or(H264Mp4Error::InvalidBox(offsets_box))?;
+        }
+    }
+    let samples_without_chunk = sizes.len() - offsets.len();
+
+    let mut samples : Vec<H264Mp4Sample> = offsets.iter().zip(sizes.iter()).map(|(&offset, &size)| {
+        H264Mp4Sample {
+            offset,
+            size,
+            decode_time: 0,
+            composition_time: 0,
+            duration: 0,
+            sync: true
+        }
+    }).collect();
+
+    if let Some(stts) = find_box(stbl, b"stts") {
+        let mut time = 0u64;
+        let mut index = 0;
+        for e in table(&stts, 8)? {
+            for _ in 0..read_u32(e) {
+                if index == samples.len() {
+                    break;
+                }
+                samples[index].decode_time = time;
+                samples[index].duration = read_u32(&e[4..]);
+                time += samples[index].duration as u64;
+                index += 1;
+            }
+        }
+    }
+    for s in samples.iter_mut() {
+        s.composition_time = s.decode_time as i64;
+    }
+    if let Some(ctts) = find_box(stbl, b"ctts") {
+        // Version 0 offsets are unsigned, but writers put negative ones in
+        // them too.
+        let mut index = 0;
+        for e in table(&ctts, 8)? {
+            for _ in 0..read_u32(e) {
+                if index == samples.len() {
+                    break;
+                }
+                samples[index].composition_time += read_u32(&e[4..]) as i32 as i64;
+                index += 1;
+            }
+        }
+    }
+    if let Some(stss) = find_box(stbl, b"stss") {
+        for s in samples.iter_mut() {
+            s.sync = false;
+        }
+        for e in table(&stss, 4)? {
+            let number = read_u32(e) as usize;
+            if number >= 1 && number <= samples.len() {
+                samples[number - 1].sync = true;
+            }
+        }
+    }
+    Ok((samples, samples_without_chunk))
+}
+
+fn parse_trex(trex: &H264Box) -> Result<H264TrackExtends, H264Mp4Error> {
+    let (_, _, body) = trex.full_box()?;
+    check_len(body, 20)?;
+    Ok(H264TrackExtends {
+        track_id: read_u32(body),
+        duration: read_u32(&body[8..]),
+        size: read_u32(&body[12..]),
+        flags: read_u32(&body[16..])
+    })
+}
+
+/// sample_is_non_sync_sample of the sample flags.
+fn is_sync(flags: u32) -> bool {
+    flags & 0x0001_0000 == 0
+}
+
+fn parse_moof(moof: &H264Box, tracks: &mut [H264Mp4Track],
+              extends: &[H264TrackExtends], file_size: u64) -> Result<(), H264Mp4Error> {
+    // Without a base_data_offset or default-base-is-moof, the first track
+    // fragment starts at the moof and the others where the data of the
+    // previous one ended.
+    let mut previous_end = moof.start as u64;
+    for traf in moof.children()?.iter().filter(|b| b.box_type.is(b"traf")) {
+        let children = traf.children()?;
+        let tfhd = find_box(&children, b"tfhd").ok_or(H264Mp4Error::InvalidBox(traf.box_type))?;
+        let (_, tf_flags, body) = tfhd.full_box()?;
+        check_len(body, 4)?;
+        let track_id = read_u32(body);
+        let track = match tracks.iter_mut().find(|t| t.track_id == track_id) {
+            Some(t) => t,
+            None => continue
+        };
+        let mut defaults = extends.iter().find(|e| e.track_id == track_id).cloned()
+            .unwrap_or(H264TrackExtends { track_id, duration: 0, size: 0, flags: 0 });
+
+        let mut pos = 4;
+        let mut base = if tf_flags & 0x02_0000 != 0 { moof.start as u64 } else { previous_end };
+        if tf_flags & 0x01 != 0 {
+            check_len(body, pos + 8)?;
+            base = read_u64(&body[pos..]);
+            pos += 8;
+        }
+        if tf_flags & 0x02 != 0 {
+            // sample_description_index
+            pos += 4;
+        }
+        if tf_flags & 0x08 != 0 {
+            check_len(body, pos + 4)?;
+            defaults.duration = read_u32(&body[pos..]);
+            pos += 4;
+        }
+        if tf_flags & 0x10 != 0 {
+            check_len(body, pos + 4)?;
+            defaults.size = read_u32(&body[pos..]);
+            pos += 4;
+        }
+        if tf_flags & 0x20 != 0 {
+            check_len(body, pos + 4)?;
+            defaults.flags = read_u32(&body[pos..]);
+        }
+
+        let mut decode_time = match find_box(&children, b"tfdt") {
+            Some(tfdt) => {
+                let (version, _, body) = tfdt.full_box()?;
+                if version == 1 {
+                    check_len(body, 8)?;
+                    read_u64(body)
+                } else {
+                    check_len(body, 4)?;
+                    read_u32(body) as u64
+                }
+            },
+            None => track.samples.last().map_or(0, |s| s.decode_time.wrapping_add(s.duration as u64))
+        };
+
+        let mut data_offset = base;
+        for trun in children.iter().filter(|b| b.box_type.is(b"trun")) {
+            let (version, flags, body) = trun.full_box()?;
+            check_len(body, 4)?;
+            let count = read_u32(body) as usize;
+            let mut pos = 4;
+            if flags & 0x01 != 0 {
+                check_len(body, pos + 4)?;
+                data_offset = base.checked_add_signed(read_u32(&body[pos..]) as i32 as i64)
+                    .ok_or(H264Mp4Error::InvalidBox(trun.box_type))?;
+                pos += 4;
+            }
+            let mut first_flags = None;
+            if flags & 0x04 != 0 {
+                check_len(body, pos + 4)?;
+                first_flags = Some(read_u32(&body[pos..]));
+                pos += 4;
+            }
+            let fields = [0x100, 0x200, 0x400, 0x800].iter().filter(|&&f| flags & f != 0).count();
+            check_len(body, pos + count.saturating_mul(4 * fields))?;
+            // Without per sample fields only the file size bounds the count.
+            if count as u64 > file_size {
+                return Err(H264Mp4Error::Truncated);
+            }
+            for i in 0..count {
+                let mut sample_duration = defaults.duration;
+                let mut size = defaults.size;
+                let mut sample_flags = if i == 0 { first_flags.unwrap_or(defaults.flags) } else { defaults.flags };
+                let mut cto = 0i64;
+                if flags & 0x100 != 0 {
+                    sample_duration = read_u32(&body[pos..]);
+                    pos += 4;
+                }
+                if flags & 0x200 != 0 {
+                    size = read_u32(&body[pos..]);
+                    pos += 4;
+                }
+                if flags & 0x400 != 0 {
+                    sample_flags = read_u32(&body[pos..]);
+                    pos += 4;
+                }
+                if flags & 0x800 != 0 {
+                    let value = read_u32(&body[pos..]);
+                    cto = if version == 0 { value as i64 } else { value as i32 as i64 };
+                    pos += 4;
+                }
+                track.samples.push(H264Mp4Sample {
+                    offset: data_offset,
+                    size,
+                    decode_time,
+                    composition_time: (decode_time as i64).wrapping_add(cto),
+                    duration: sample_duration,
+                    sync: is_sync(sample_flags)
+                });
+                data_offset = data_offset.checked_add(size as u64).ok_or(H264Mp4Error::InvalidBox(trun.box_type))?;
+                // tfdt can hold anything.
+                decode_time = decode_time.wrapping_add(sample_duration as u64);
+            }
+        }
+        previous_end = data_offset;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect()
+    }
+
+    /// A moov with one 64x48 avc1 track with ID 1 and timescale 90000,
+    /// whose stbl has the given boxes after the stsd, and an mvex with the
+    /// given trex payload.
+    fn moov(tables: &[Vec<u8>], trex: Option<&[u32]>) -> Vec<u8> {
+        let mut entry = vec![0; 78];
+        entry[7] = 1;
+        entry[25] = 64;
+        entry[27] = 48;
+        entry.extend(make_box(b"avcC", &H264AVCDecoderConfigurationRecord::new().to_bytes()));
+        let mut stsd = u32s(&[1]);
+        stsd.extend(make_box(b"avc1", &entry));
+        let mut stbl = make_full_box(b"stsd", 0, 0, &stsd);
+        for table in tables {
+            stbl.extend_from_slice(table);
+        }
+        let minf = make_box(b"stbl", &stbl);
+        let mut mdia = make_full_box(b"mdhd", 0, 0, &u32s(&[0, 0, 90000, 0, 0]));
+        mdia.extend(make_box(b"minf", &minf));
+        let mut trak = make_full_box(b"tkhd", 0, 0, &u32s(&[0, 0, 1, 0, 0]));
+        trak.extend(make_box(b"mdia", &mdia));
+        let mut moov = make_box(b"trak", &trak);
+        if let Some(trex) = trex {
+            moov.extend(make_box(b"mvex", &make_full_box(b"trex", 0, 0, &u32s(trex))));
+        }
+        make_box(b"moov", &moov)
+    }
+
+    /// An mdat with 100 bytes counting up, at offset 0.
+    fn mdat() -> Vec<u8> {
+        make_box(b"mdat", &(0..100).collect::<Vec<u8>>())
+    }
+
+    #[test]
+    fn sample_tables_are_read() {
+        let mut data = mdat();
+        data.extend(moov(&[
+            make_full_box(b"stsz", 0, 0, &u32s(&[0, 4, 10, 20, 30, 5])),
+            // Two samples in the first chunk, one in the others
+            make_full_box(b"stsc", 0, 0, &u32s(&[2, 1, 2, 1, 2, 1, 1])),
+            make_full_box(b"stco", 0, 0, &u32s(&[3, 8, 40, 70])),
+            make_full_box(b"stts", 0, 0, &u32s(&[2, 3, 1000, 1, 500])),
+            make_full_box(b"ctts", 0, 0, &u32s(&[3, 1, 2000, 1, -1000i32 as u32, 2, 0])),
+            make_full_box(b"stss", 0, 0, &u32s(&[2, 1, 3]))
+        ], None));
+        let demuxer = H264Mp4Demuxer::from_bytes(data).unwrap();
+        assert_eq!(demuxer.tracks.len(), 1);
+        let track = &demuxer.tracks[0];
+        assert_eq!((track.track_id, track.timescale, track.width, track.height), (1, 90000, 64, 48));
+        assert!(track.sample_entry.is(b"avc1"));
+        assert_eq!(track.samples_without_chunk, 0);
+        let samples : Vec<(u64, u32, u64, i64, u32, bool)> = track.samples.iter()
+            .map(|s| (s.offset, s.size, s.decode_time, s.composition_time, s.duration, s.sync))
+            .collect();
+        assert_eq!(samples, vec![
+            (8, 10, 0, 2000, 1000, true),
+            (18, 20, 1000, 0, 1000, false),
+            (40, 30, 2000, 2000, 1000, true),
+            (70, 5, 3000, 3000, 500, false)
+        ]);
+        assert_eq!(demuxer.sample_data(&track.samples[3]), &[62, 63, 64, 65, 66]);
+    }
+
+    #[test]
+    fn bad_sample_tables_are_errors() {
+        // More samples of one size than the file could hold.
+        let mut data = mdat();
+        data.extend(moov(&[make_full_box(b"stsz", 0, 0, &u32s(&[1, 0xFFFF_FFFF]))], None));
+        match H264Mp4Demuxer::from_bytes(data) {
+            Err(H264Mp4Error::Truncated) => {},
+            r => panic!("{:?}", r.map(|d| d.tracks))
+        }
+
+        // Sample offsets past the end of the 64 bit range
+        let mut co64 = u32s(&[1]);
+        co64.extend_from_slice(&(u64::MAX - 5).to_be_bytes());
+        let mut data = mdat();
+        data.extend(moov(&[
+            make_full_box(b"stsz", 0, 0, &u32s(&[10, 2])),
+            make_full_box(b"stsc", 0, 0, &u32s(&[1, 1, 2, 1])),
+            make_full_box(b"co64", 0, 0, &co64)
+        ], None));
+        match H264Mp4Demuxer::from_bytes(data) {
+            Err(H264Mp4Error::InvalidBox(b)) if b.is(b"co64") => {},
+            r => panic!("{:?}", r.map(|d| d.tracks))
+        }
+
+        // Samples the stsc doesn't put in a chunk are reported.
+        let mut data = mdat();
+        data.extend(moov(&[
+            make_full_box(b"stsz", 0, 0, &u32s(&[10, 4])),
+            make_full_box(b"stsc", 0, 0, &u32s(&[1, 1, 1, 1])),
+            make_full_box(b"stco", 0, 0, &u32s(&[2, 8, 40]))
+        ], None));
+        let demuxer = H264Mp4Demuxer::from_bytes(data).unwrap();
+        assert_eq!(demuxer.tracks[0].samples.len(), 2);
+        assert_eq!(demuxer.tracks[0].samples_without_chunk, 2);
+    }
+
+    /// A moof with one traf for track 1. trun_offset is added to the
+    /// data_offset field, which points right after the moof and the mdat
+    /// header.
+    fn moof(tfhd: &[u8], trun_flags: u32, trun: &[u32], trun_offset: u64) -> Vec<u8> {
+        let build = |data_offset: u32| {
+            let mut traf = make_full_box(b"tfhd", 0, if tfhd.len() > 4 { 0x01 } else { 0x02_0000 }, tfhd);
+            traf.extend(make_full_box(b"tfdt", 1, 0, &90000u64.to_be_bytes()));
+            let mut body = u32s(&trun[..1]);
+            body.extend(u32s(&[data_offset]));
+            body.extend(u32s(&trun[1..]));
+            traf.extend(make_full_box(b"trun", 1, trun_flags | 0x01, &body));
+            let mut moof = make_full_box(b"mfhd", 0, 0, &u32s(&[1]));
+            moof.extend(make_box(b"traf", &traf));
+            make_box(b"moof", &moof)
+        };
+        let size = build(0).len() as u64 + 8;
+        build((size + trun_offset) as u32)
+    }
+
+    #[test]
+    fn track_fragments_are_read() {
+        // Default duration 3000, size 10 and non-sync samples
+        let mut data = moov(&[], Some(&[1, 1, 3000, 10, 0x0001_0000]));
+        let moof_start = data.len() as u64;
+        // A sync first sample and composition offsets
+        data.extend(moof(&u32s(&[1]), 0x804, &[3, 0, 3000, -3000i32 as u32, 0], 0));
+        data.extend(mdat());
+        let demuxer = H264Mp4Demuxer::from_bytes(data.clone()).unwrap();
+        let track = &demuxer.tracks[0];
+        let samples : Vec<(u64, u32, u64, i64, u32, bool)> = track.samples.iter()
+            .map(|s| (s.offset - moof_start, s.size, s.decode_time, s.composition_time, s.duration, s.sync))
+            .collect();
+        let mdat_data = data.len() as u64 - 100 - moof_start;
+        assert_eq!(samples, vec![
+            (mdat_data, 10, 90000, 93000, 3000, true),
+            (mdat_data + 10, 10, 93000, 90000, 3000, false),
+            (mdat_data + 20, 10, 96000, 96000, 3000, false)
+        ]);
+        assert_eq!(demuxer.sample_data(&track.samples[1])[0], 10);
+
+        // A base_data_offset that overflows with the data_offset
+        let mut tfhd = u32s(&[1]);
+        tfhd.extend_from_slice(&(u64::MAX - 8).to_be_bytes());
+        let mut data = moov(&[], Some(&[1, 1, 3000, 10, 0]));
+        data.extend(moof(&tfhd, 0, &[1], 0));
+        data.extend(mdat());
+        match H264Mp4Demuxer::from_bytes(data) {
+            Err(H264Mp4Error::InvalidBox(b)) if b.is(b"trun") => {},
+            r => panic!("{:?}", r.map(|d| d.tracks))
+        }
+
+        // A sample count without per sample fields that no file could hold
+        let mut data = moov(&[], Some(&[1, 1, 3000, 10, 0]));
+        data.extend(moof(&u32s(&[1]), 0, &[0xFFFF_FFFF], 0));
+        match H264Mp4Demuxer::from_bytes(data) {
+            Err(H264Mp4Error::Truncated) => {},
+            r => panic!("{:?}", r.map(|d| d.tracks))
+        }
+
+        // Samples past the end of the file
+        let mut data = moov(&[], Some(&[1, 1, 3000, 10, 0]));
+        data.extend(moof(&u32s(&[1]), 0, &[3], 95));
+        data.extend(mdat());
+        match H264Mp4Demuxer::from_bytes(data) {
+            Err(H264Mp4Error::Truncated) => {},
+            r => panic!("{:?}", r.map(|d| d.tracks))
+        }
+    }
+}
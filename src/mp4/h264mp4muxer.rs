@@ -0,0 +1,513 @@
+use avcc::H264AVCDecoderConfigurationRecord;
+use parser::H264NalParser;
+use poc::H264TimestampGenerator;
+use mp4::*;
+
+/// SEI payload type of the recovery point SEI.
+const SEI_RECOVERY_POINT : u32 = 6;
+
+/// Sample flags of sync samples (sample_depends_on 2) and of the others
+/// (sample_depends_on 1, sample_is_non_sync_sample).
+const SYNC_SAMPLE_FLAGS : u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS : u32 = 0x0101_0000;
+
+const UNITY_MATRIX : [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// A media segment: styp, moof and mdat, or just moof and mdat when the
+/// fragment is a chunk of a bigger segment.
+#[derive(Debug, Clone)]
+pub struct H264Mp4Fragment {
+    pub sequence_number: u32,
+    /// Decode time of the first sample, in the timescale of the track.
+    pub decode_time: u64,
+    pub duration: u64,
+    pub samples: usize,
+    /// Size of each sample in the mdat.
+    pub sample_sizes: Vec<u32>,
+    pub data: Vec<u8>
+}
+
+struct H264Mp4PendingSample {
+    data: Vec<u8>,
+    pts: u64,
+    dts: u64,
+    duration: u64,
+    sync: bool
+}
+
+/// Packages access units as fragmented MP4 (CMAF) with a single video
+/// track. The init segment is built from the parameter sets and colour
+/// SEI messages seen up to the first random access point, and access units
+/// before that are dropped. Fragments are cut at the first random access
+/// point (IDR or recovery point SEI) after fragment_duration.
+///
+/// The track is avc1 with the parameter sets taken out of the samples,
+/// which needs them to stay the same for the whole stream, or avc3 with
+/// the samples left as they are.
+///
+/// Without caller supplied timestamps they are derived by an
+/// H264TimestampGenerator running at the timescale.
+pub struct H264Mp4Muxer {
+    pub track_id: u32,
+    pub timescale: u32,
+    /// In timescale ticks.
+    pub fragment_duration: u64,
+    /// Frame duration in timescale ticks, overriding the VUI.
+    pub frame_duration: Option<u64>,
+    /// Start each fragment with an styp box.
+    pub styp: bool,
+    /// Write an avc3 sample entry and keep SPS and PPS NAL units in the
+    /// samples.
+    pub in_band_parameter_sets: bool,
+    /// Access units dropped before the first random access point.
+    pub dropped_access_units: u64,
+
+    parser: H264NalParser,
+    timestamps: H264TimestampGenerator,
+    sei_messages: Vec<H264SEIMessage>,
+    init_segment: Option<Vec<u8>>,
+    pending: Vec<H264Mp4PendingSample>,
+    fragments: Vec<H264Mp4Fragment>,
+    sequence_number: u32
+}
+
+impl Default for H264Mp4Muxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264Mp4Muxer {
+    pub fn new() -> H264Mp4Muxer {
+        H264Mp4Muxer {
+            track_id: 1,
+            timescale: 90000,
+            fragment_duration: 2 * 90000,
+            frame_duration: None,
+            styp: true,
+            in_band_parameter_sets: false,
+            dropped_access_units: 0,
+            parser: H264NalParser::from_bytes(Vec::new()),
+            timestamps: H264TimestampGenerator::new(90000),
+            sei_messages: Vec::new(),
+            init_segment: None,
+            pending: Vec::new(),
+            fragments: Vec::new(),
+            sequence_number: 1
+        }
+    }
+
+    /// ftyp and moov, once the first random access point has been seen.
+    pub fn init_segment(&self) -> Option<&[u8]> {
+        self.init_segment.as_ref().map(|i| &i[..])
+    }
+
+    /// Takes an access unit given as NAL units without start codes. With
+    /// pts None, both timestamps are derived; a dts of None means it's the
+    /// same as the PTS.
+    pub fn push_access_unit(&mut self, nal_units: &[Vec<u8>], pts: Option<u64>,
+                            dts: Option<u64>) -> Result<(), H264Mp4Error> {
+        let nal_units : Vec<&Vec<u8>> = nal_units.iter().filter(|n| !n.is_empty()).collect();
+        let mut bytestream = Vec::new();
+        for nal in &nal_units {
+            bytestream.extend_from_slice(&[0, 0, 0, 1]);
+            bytestream.extend_from_slice(nal);
+        }
+        self.parser.set_data(bytestream);
+        self.parser.format = H264NalFormat::BYTESTREAM;
+
+        let mut random_access = false;
+        let mut first_slice = None;
+        let mut offset = 0;
+        for _ in 0..nal_units.len() {
+            let unit = self.parser.parse_nalunit(offset).map_err(H264Mp4Error::Parse)?;
+            match unit.nal_unit_type_num {
+                7 => { self.parser.parse_sps(unit.data_offset).map_err(H264Mp4Error::Parse)?; },
+                8 => { self.parser.parse_pps(unit.data_offset).map_err(H264Mp4Error::Parse)?; },
+                6 => {
+                    if let Ok(messages) = self.parser.parse_sei(&unit) {
+                        random_access |= messages.iter().any(|m| m.payload_type == SEI_RECOVERY_POINT);
+                        if self.init_segment.is_none() {
+                            self.sei_messages.extend(messages);
+                        }
+                    }
+                },
+                1..=5 => {
+                    random_access |= unit.idr_pic_flag;
+                    if first_slice.is_none() {
+                        if let Ok(slice) = self.parser.parse_slice(unit.data_offset, &unit) {
+                            first_slice = Some((unit.clone(), slice));
+                        }
+                    }
+                },
+                _ => {}
+            }
+            offset += unit.size;
+        }
+        let sps = first_slice.as_ref().and_then(|(_, slice)| {
+            self.parser.find_pps(slice.pic_parameter_set_id)
+                .and_then(|pps| self.parser.find_sps(pps.seq_parameter_set_id))
+                .cloned()
+        });
+
+        if self.init_segment.is_none() {
+            match sps {
+                Some(ref sps) if random_access => self.init_segment = Some(self.build_init_segment(sps)?),
+                _ => {
+                    self.dropped_access_units += 1;
+                    self.sei_messages.clear();
+                    return Ok(());
+                }
+            }
+        }
+
+        self.timestamps.frame_duration = self.frame_duration;
+        self.timestamps.clock_rate = self.timescale as u64;
+        let (pts, dts) = match pts {
+            Some(pts) => (pts, dts.unwrap_or(pts)),
+            None => match (sps.as_ref(), first_slice.as_ref()) {
+                (Some(sps), Some((nalu, slice))) => self.timestamps.next(Some((sps, nalu, slice))),
+                _ => self.timestamps.next(None)
+            }
+        };
+
+        if let Some(last) = self.pending.last_mut() {
+            last.duration = dts.saturating_sub(last.dts);
+        }
+        let elapsed = self.pending.first().map_or(0, |s| dts.saturating_sub(s.dts));
+        if random_access && elapsed >= self.fragment_duration {
+            self.finish_fragment();
+        }
+
+        let mut data = Vec::new();
+        for nal in &nal_units {
+            let nal_type = nal[0] & 0x1F;
+            if !self.in_band_parameter_sets && (nal_type == 7 || nal_type == 8 || nal_type == 13) {
+                continue;
+            }
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        let duration = self.timestamps.frame_duration(sps.as_ref());
+        self.pending.push(H264Mp4PendingSample {
+            data,
+            pts,
+            dts,
+            duration,
+            sync: random_access
+        });
+        Ok(())
+    }
+
+    fn build_init_segment(&self, sps: &H264NalUnitSPS) -> Result<Vec<u8>, H264Mp4Error> {
+        let mut ftyp = b"iso6".to_vec();
+        ftyp.extend_from_slice(&[0, 0, 0, 0]);
+        ftyp.extend_from_slice(b"iso6cmfcmp41");
+
+        let avcc = H264AVCDecoderConfigurationRecord::from_parser(&self.parser, 4).map_err(H264Mp4Error::Parse)?;
+        let width = sps.cropped_width();
+        let height = sps.cropped_height();
+        let (sar_width, sar_height) = sps.sample_aspect_ratio().unwrap_or((1, 1));
+        let colour = H264ColourDescription::new(sps, &self.sei_messages);
+
+        let mut entry = vec![0u8; 6];
+        entry.extend_from_slice(&1u16.to_be_bytes());
+        entry.extend_from_slice(&[0u8; 16]);
+        entry.extend_from_slice(&(width as u16).to_be_bytes());
+        entry.extend_from_slice(&(height as u16).to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        entry.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        entry.extend_from_slice(&[0u8; 4]);
+        entry.extend_from_slice(&1u16.to_be_bytes());
+        entry.extend_from_slice(&[0u8; 32]);
+        entry.extend_from_slice(&[0x00, 0x18, 0xFF, 0xFF]);
+        entry.extend(make_box(b"avcC", &avcc.to_bytes()));
+        let mut pasp = sar_width.to_be_bytes().to_vec();
+        pasp.extend_from_slice(&sar_height.to_be_bytes());
+        entry.extend(make_box(b"pasp", &pasp));
+        if sps.vui_parameters.as_ref().is_some_and(|v| v.video_signal_type_present_flag != 0) {
+            entry.extend(make_box(b"colr", &colour.colr_payload()));
+        }
+        if let Some(mdcv) = colour.mdcv_payload() {
+            entry.extend(make_box(b"mdcv", &mdcv));
+        }
+        if let Some(clli) = colour.clli_payload() {
+            entry.extend(make_box(b"clli", &clli));
+        }
+
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(make_box(if self.in_band_parameter_sets { b"avc3" } else { b"avc1" }, &entry));
+        let mut stbl = make_full_box(b"stsd", 0, 0, &stsd);
+        stbl.extend(make_full_box(b"stts", 0, 0, &[0; 4]));
+        stbl.extend(make_full_box(b"stsc", 0, 0, &[0; 4]));
+        stbl.extend(make_full_box(b"stsz", 0, 0, &[0; 8]));
+        stbl.extend(make_full_box(b"stco", 0, 0, &[0; 4]));
+
+        let mut dref = 1u32.to_be_bytes().to_vec();
+        dref.extend(make_full_box(b"url ", 0, 1, &[]));
+        let mut minf = make_full_box(b"vmhd", 0, 1, &[0; 8]);
+        minf.extend(make_box(b"dinf", &make_full_box(b"dref", 0, 0, &dref)));
+        minf.extend(make_box(b"stbl", &stbl));
+
+        let mut mdhd = vec![0u8; 8];
+        mdhd.extend_from_slice(&self.timescale.to_be_bytes());
+        // Duration 0, language "und".
+        mdhd.extend_from_slice(&[0, 0, 0, 0, 0x55, 0xC4, 0, 0]);
+        let mut hdlr = vec![0u8; 4];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0u8; 12]);
+        hdlr.extend_from_slice(b"VideoHandler\0");
+        let mut mdia = make_full_box(b"mdhd", 0, 0, &mdhd);
+        mdia.extend(make_full_box(b"hdlr", 0, 0, &hdlr));
+        mdia.extend(make_box(b"minf", &minf));
+
+        // The track size is the display size.
+        let mut tkhd = vec![0u8; 8];
+        tkhd.extend_from_slice(&self.track_id.to_be_bytes());
+        tkhd.extend_from_slice(&[0u8; 20]);
+        tkhd.extend_from_slice(&[0u8; 4]);
+        for value in UNITY_MATRIX.iter() {
+            tkhd.extend_from_slice(&value.to_be_bytes());
+        }
+        let display_width = width as u64 * sar_width as u64 / sar_height as u64;
+        tkhd.extend_from_slice(&((display_width as u32) << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(height << 16).to_be_bytes());
+        let mut trak = make_full_box(b"tkhd", 0, 3, &tkhd);
+        trak.extend(make_box(b"mdia", &mdia));
+
+        let mut mvhd = vec![0u8; 8];
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0, 0, 0, 0, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00]);
+        mvhd.extend_from_slice(&[0u8; 10]);
+        for value in UNITY_MATRIX.iter() {
+            mvhd.extend_from_slice(&value.to_be_bytes());
+        }
+        mvhd.extend_from_slice(&[0u8; 24]);
+        mvhd.extend_from_slice(&(self.track_id + 1).to_be_bytes());
+
+        let mut trex = self.track_id.to_be_bytes().to_vec();
+        trex.extend_from_slice(&[0, 0, 0, 1]);
+        trex.extend_from_slice(&[0u8; 12]);
+
+        let mut moov = make_full_box(b"mvhd", 0, 0, &mvhd);
+        moov.extend(make_box(b"trak", &trak));
+        moov.extend(make_box(b"mvex", &make_full_box(b"trex", 0, 0, &trex)));
+
+        let mut init = make_box(b"ftyp", &ftyp);
+        init.extend(make_box(b"moov", &moov));
+        Ok(init)
+    }
+
+    fn finish_fragment(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let samples : Vec<H264Mp4PendingSample> = self.pending.drain(..).collect();
+        let decode_time = samples[0].dts;
+        let duration : u64 = samples.iter().map(|s| s.duration).sum();
+
+        let build_moof = |data_offset: u32, sequence_number: u32, track_id: u32| {
+            let mut tfdt = Vec::new();
+            tfdt.extend_from_slice(&decode_time.to_be_bytes());
+            let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+            trun.extend_from_slice(&data_offset.to_be_bytes());
+            for s in &samples {
+                trun.extend_from_slice(&(s.duration as u32).to_be_bytes());
+                trun.extend_from_slice(&(s.data.len() as u32).to_be_bytes());
+                let flags = if s.sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS };
+                trun.extend_from_slice(&flags.to_be_bytes());
+                trun.extend_from_slice(&((s.pts as i64 - s.dts as i64) as i32).to_be_bytes());
+            }
+            let mut traf = make_full_box(b"tfhd", 0, 0x02_0000, &track_id.to_be_bytes());
+            traf.extend(make_full_box(b"tfdt", 1, 0, &tfdt));
+            traf.extend(make_full_box(b"trun", 1, 0x0F01, &trun));
+            let mut moof = make_full_box(b"mfhd", 0, 0, &sequence_number.to_be_bytes());
+            moof.extend(make_box(b"traf", &traf));
+            make_box(b"moof", &moof)
+        };
+        // The data offset is relative to the moof, whose size doesn't
+        // depend on it.
+        let moof_size = build_moof(0, self.sequence_number, self.track_id).len();
+        let moof = build_moof(moof_size as u32 + 8, self.sequence_number, self.track_id);
+
+        let mut mdat = Vec::new();
+        for s in &samples {
+            mdat.extend_from_slice(&s.data);
+        }
+        let mut data = if self.styp { make_box(b"styp", b"cmfs\0\0\0\0cmfsmsdh") } else { Vec::new() };
+        data.extend(moof);
+        data.extend(make_box(b"mdat", &mdat));
+
+        self.fragments.push(H264Mp4Fragment {
+            sequence_number: self.sequence_number,
+            decode_time,
+            duration,
+            samples: samples.len(),
+            sample_sizes: samples.iter().map(|s| s.data.len() as u32).collect(),
+            data
+        });
+        self.sequence_number += 1;
+    }
+
+    /// The next finished media segment.
+    pub fn pop_fragment(&mut self) -> Option<H264Mp4Fragment> {
+        if self.fragments.is_empty() {
+            None
+        } else {
+            Some(self.fragments.remove(0))
+        }
+    }
+
+    /// Closes the current fragment at the end of the stream.
+    pub fn flush(&mut self) {
+        self.finish_fragment();
+    }
+
+    /// Takes a whole stream, splitting it with the access unit detection of
+    /// H264NalParser and deriving the timestamps, and flushes.
+    pub fn push_stream(&mut self, data: &[u8], format: H264NalFormat,
+                       nal_length_size: usize) -> Result<(), H264Mp4Error> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        let mut offset = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).map_err(H264Mp4Error::Parse)?;
+            let nal_units : Vec<Vec<u8>> = au.nal_units.iter().map(|n| parser.nal_data(n).to_vec()).collect();
+            self.push_access_unit(&nal_units, None, None)?;
+            offset += au.size;
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mp4::H264Mp4Demuxer;
+    use writer::H264NalWriter;
+
+    /// Parameter sets and an IDR slice, or a P slice, of a 16x16 Baseline
+    /// picture.
+    fn access_unit(idr: bool, frame_num: u32) -> Vec<Vec<u8>> {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        sps.max_num_ref_frames = 1;
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = if idr { 7 } else { 5 };
+        slice.frame_num = frame_num;
+        let nal_type = if idr { 5 } else { 1 };
+        let unit = H264NalUnit::new(0, 4, 0, 2, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let nal = writer.to_nal(2, nal_type);
+        if idr {
+            vec![sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), nal]
+        } else {
+            vec![nal]
+        }
+    }
+
+    fn box_types(boxes: &[H264Box]) -> Vec<String> {
+        boxes.iter().map(|b| format!("{:?}", b.box_type)).collect()
+    }
+
+    fn child<'a>(parent: &H264Box<'a>, name: &[u8; 4]) -> H264Box<'a> {
+        find_box(&parent.children().unwrap(), name).unwrap()
+    }
+
+    #[test]
+    fn init_segment_layout() {
+        let mut muxer = H264Mp4Muxer::new();
+        muxer.push_access_unit(&access_unit(false, 1), Some(0), None).unwrap();
+        assert!(muxer.init_segment().is_none());
+        muxer.push_access_unit(&access_unit(true, 0), Some(3000), None).unwrap();
+        assert_eq!(muxer.dropped_access_units, 1);
+
+        let init = muxer.init_segment().unwrap().to_vec();
+        let boxes = read_boxes(&init, 0).unwrap();
+        assert_eq!(box_types(&boxes), vec!["ftyp", "moov"]);
+        let moov = boxes[1];
+        assert_eq!(box_types(&moov.children().unwrap()), vec!["mvhd", "trak", "mvex"]);
+        let trak = child(&moov, b"trak");
+        assert_eq!(box_types(&trak.children().unwrap()), vec!["tkhd", "mdia"]);
+        let mdia = child(&trak, b"mdia");
+        assert_eq!(box_types(&mdia.children().unwrap()), vec!["mdhd", "hdlr", "minf"]);
+        let minf = child(&mdia, b"minf");
+        assert_eq!(box_types(&minf.children().unwrap()), vec!["vmhd", "dinf", "stbl"]);
+        let stbl = child(&minf, b"stbl");
+        assert_eq!(box_types(&stbl.children().unwrap()), vec!["stsd", "stts", "stsc", "stsz", "stco"]);
+        let trex = child(&child(&moov, b"mvex"), b"trex");
+        assert_eq!(read_u32(trex.full_box().unwrap().2), 1);
+
+        let demuxer = H264Mp4Demuxer::from_bytes(init.clone()).unwrap();
+        let track = &demuxer.tracks[0];
+        assert!(track.sample_entry.is(b"avc1"));
+        assert_eq!((track.track_id, track.timescale, track.width, track.height), (1, 90000, 16, 16));
+        assert_eq!(track.avcc.sequence_parameter_sets, vec![access_unit(true, 0)[0].clone()]);
+        assert_eq!(track.avcc.picture_parameter_sets, vec![access_unit(true, 0)[1].clone()]);
+        assert!(track.samples.is_empty());
+
+        let mut muxer = H264Mp4Muxer::new();
+        muxer.in_band_parameter_sets = true;
+        muxer.push_access_unit(&access_unit(true, 0), Some(0), None).unwrap();
+        let demuxer = H264Mp4Demuxer::from_bytes(muxer.init_segment().unwrap().to_vec()).unwrap();
+        assert!(demuxer.tracks[0].sample_entry.is(b"avc3"));
+    }
+
+    #[test]
+    fn fragments_are_cut_at_random_access_points() {
+        let mut muxer = H264Mp4Muxer::new();
+        muxer.fragment_duration = 6000;
+        // (IDR, frame_num, pts, dts)
+        let units = [(true, 0, 3000, 0), (false, 1, 9000, 3000), (false, 2, 6000, 6000),
+                     (true, 0, 12000, 9000), (false, 1, 15000, 12000)];
+        for &(idr, frame_num, pts, dts) in &units {
+            muxer.push_access_unit(&access_unit(idr, frame_num), Some(pts), Some(dts)).unwrap();
+        }
+        let first = muxer.pop_fragment().unwrap();
+        assert!(muxer.pop_fragment().is_none());
+        muxer.flush();
+        let second = muxer.pop_fragment().unwrap();
+        assert_eq!((first.sequence_number, first.decode_time, first.duration, first.samples), (1, 0, 9000, 3));
+        assert_eq!((second.sequence_number, second.decode_time, second.duration, second.samples), (2, 9000, 6000, 2));
+        // The parameter sets are taken out of the avc1 samples.
+        let slice_size = |idr, frame_num| 4 + access_unit(idr, frame_num).last().unwrap().len() as u32;
+        assert_eq!(first.sample_sizes, vec![slice_size(true, 0), slice_size(false, 1), slice_size(false, 2)]);
+        assert_eq!(box_types(&read_boxes(&first.data, 0).unwrap()), vec!["styp", "moof", "mdat"]);
+
+        let mut file = muxer.init_segment().unwrap().to_vec();
+        file.extend_from_slice(&first.data);
+        file.extend_from_slice(&second.data);
+        let demuxer = H264Mp4Demuxer::from_bytes(file).unwrap();
+        let track = &demuxer.tracks[0];
+        let samples : Vec<(u64, i64, u32, bool)> = track.samples.iter()
+            .map(|s| (s.decode_time, s.composition_time, s.duration, s.sync))
+            .collect();
+        assert_eq!(samples, vec![
+            (0, 3000, 3000, true),
+            (3000, 9000, 3000, false),
+            (6000, 6000, 3000, false),
+            (9000, 12000, 3000, true),
+            (12000, 15000, 3000, false)
+        ]);
+        for (sample, &(idr, frame_num, _, _)) in track.samples.iter().zip(&units) {
+            let nal_units = demuxer.sample_nal_units(track, sample).unwrap();
+            assert_eq!(nal_units, vec![&access_unit(idr, frame_num).last().unwrap()[..]]);
+        }
+
+        // avc3 keeps them.
+        let mut muxer = H264Mp4Muxer::new();
+        muxer.in_band_parameter_sets = true;
+        muxer.push_access_unit(&access_unit(true, 0), Some(0), None).unwrap();
+        muxer.flush();
+        let fragment = muxer.pop_fragment().unwrap();
+        let size : usize = access_unit(true, 0).iter().map(|n| 4 + n.len()).sum();
+        assert_eq!(fragment.sample_sizes, vec![size as u32]);
+    }
+}
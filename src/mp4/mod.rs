@@ -0,0 +1,132 @@
+use std::fmt;
+use std::io;
+use parser::H264NalParseError;
+pub use types::*;
+
+mod h264mp4demuxer;
+mod h264mp4muxer;
+pub use self::h264mp4demuxer::{H264Mp4Demuxer, H264Mp4Track, H264Mp4Sample};
+pub use self::h264mp4muxer::{H264Mp4Muxer, H264Mp4Fragment};
+
+#[derive(Debug)]
+pub enum H264Mp4Error {
+    /// A box runs past the end of its parent or of the file.
+    Truncated,
+    /// A box whose contents don't make sense.
+    InvalidBox(H264BoxType),
+    NoVideoTrack,
+    Parse(H264NalParseError),
+    Io(io::Error)
+}
+
+/// A four character box type.
+#[derive(Clone, Copy, PartialEq)]
+pub struct H264BoxType(pub [u8; 4]);
+
+impl H264BoxType {
+    pub fn is(&self, name: &[u8; 4]) -> bool {
+        &self.0 == name
+    }
+}
+
+impl fmt::Debug for H264BoxType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+/// A box inside a buffer. start is where its header starts and offset
+/// where its payload starts, in the numbering given to read_boxes.
+#[derive(Debug, Clone, Copy)]
+pub struct H264Box<'a> {
+    pub box_type: H264BoxType,
+    pub start: usize,
+    pub offset: usize,
+    pub data: &'a [u8]
+}
+
+impl<'a> H264Box<'a> {
+    /// version and flags of a full box, and the data after them.
+    pub fn full_box(&self) -> Result<(u8, u32, &'a [u8]), H264Mp4Error> {
+        if self.data.len() < 4 {
+            return Err(H264Mp4Error::Truncated);
+        }
+        Ok((self.data[0], read_u24(&self.data[1..]), &self.data[4..]))
+    }
+
+    pub fn children(&self) -> Result<Vec<H264Box<'a>>, H264Mp4Error> {
+        read_boxes(self.data, self.offset)
+    }
+}
+
+/// Splits a buffer into boxes. base is the offset of the buffer in the
+/// file, so the box offsets are file offsets.
+pub fn read_boxes<'a>(data: &'a [u8], base: usize) -> Result<Vec<H264Box<'a>>, H264Mp4Error> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut size = read_u32(&data[pos..]) as u64;
+        let box_type = H264BoxType([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
+        let mut header = 8;
+        if size == 1 {
+            if pos + 16 > data.len() {
+                return Err(H264Mp4Error::Truncated);
+            }
+            size = read_u64(&data[pos + 8..]);
+            header = 16;
+        } else if size == 0 {
+            size = (data.len() - pos) as u64;
+        }
+        if size < header as u64 || pos as u64 + size > data.len() as u64 {
+            return Err(H264Mp4Error::Truncated);
+        }
+        let size = size as usize;
+        boxes.push(H264Box {
+            box_type,
+            start: base + pos,
+            offset: base + pos + header,
+            data: &data[pos + header..pos + size]
+        });
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+/// The first child box of a type.
+pub fn find_box<'a>(boxes: &[H264Box<'a>], name: &[u8; 4]) -> Option<H264Box<'a>> {
+    boxes.iter().find(|b| b.box_type.is(name)).cloned()
+}
+
+pub fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+pub fn read_u24(data: &[u8]) -> u32 {
+    (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32
+}
+
+pub fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+pub fn read_u64(data: &[u8]) -> u64 {
+    (read_u32(data) as u64) << 32 | read_u32(&data[4..]) as u64
+}
+
+/// A box with the given payload.
+pub fn make_box(name: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    out.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(name);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// A full box with the given version, flags and payload.
+pub fn make_full_box(name: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + payload.len());
+    body.push(version);
+    body.extend_from_slice(&flags.to_be_bytes()[1..]);
+    body.extend_from_slice(payload);
+    make_box(name, &body)
+}
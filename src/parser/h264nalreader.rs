@@ -6,21 +6,49 @@ pub struct H264NalReader<'a> {
     bits_in_cache: u32,
     pos: usize,
     num_epb: u32,
+    zero_count: u32,
+    emulation_prevention: bool,
+}
+
+/// Strips the emulation prevention bytes out of a NAL unit payload and
+/// returns the RBSP.
+pub fn nal_to_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0x00 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
 }
 
 impl<'a> H264NalReader<'a> {
     pub fn new(data: &'a[u8]) -> H264NalReader<'a> {
         H264NalReader {
-            data: data,
+            data,
             size: data.len(),
             next_byte: 0xFF,
             cache: 0xFF,
             bits_in_cache: 0,
             pos: 0,
             num_epb: 0,
+            zero_count: 0,
+            emulation_prevention: true,
         }
     }
 
+    /// Creates a reader over data which already had its emulation
+    /// prevention bytes removed, such as the output of nal_to_rbsp.
+    pub fn new_rbsp(data: &'a[u8]) -> H264NalReader<'a> {
+        let mut reader = H264NalReader::new(data);
+        reader.emulation_prevention = false;
+        reader
+    }
+
     /// Meant to update the cache before reading any bits so
     /// that the nal parser can ensure there are at least nbits bits
     /// in the cache for reading.
@@ -35,16 +63,20 @@ impl<'a> H264NalReader<'a> {
                      nbits, self.bits_in_cache + (self.size - self.pos) as u32 * 8);
             return false;
         }
-        let mut check_three_byte = true;
         while self.bits_in_cache < nbits {
+            if self.pos >= self.size {
+                println!("Ran out of bytes while skipping emulation prevention bytes");
+                return false;
+            }
             let byte = self.data[self.pos];
             self.pos += 1;
-            if check_three_byte && byte == 0x03 && self.next_byte == 0x00 && (self.cache & 0xFF == 0x00) {
-                // This is an emulation byte, don't check the next byte even if it's 0x03
+            if self.emulation_prevention && byte == 0x03 && self.zero_count >= 2 {
+                // This is an emulation byte, the zeros before it don't count
+                // towards the next one.
                 self.num_epb += 1;
-                check_three_byte = false;
+                self.zero_count = 0;
             } else {
-                check_three_byte = true;
+                self.zero_count = if byte == 0x00 { self.zero_count + 1 } else { 0 };
                 // push next byte into the cache
                 self.cache = (self.cache << 8) | self.next_byte as u32;
                 self.next_byte = byte;
@@ -54,9 +86,24 @@ impl<'a> H264NalReader<'a> {
         true
     }
 
+    /// True if there is more data before the rbsp_trailing_bits. The reader
+    /// has to be limited to a single NAL unit for this to work.
+    pub fn more_rbsp_data(&self) -> bool {
+        let last = match self.data.iter().rposition(|&b| b != 0) {
+            Some(l) => l,
+            None => return false
+        };
+        // bit position of the rbsp_stop_one_bit
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        // Every byte before pos has been fetched, the bits left in the cache
+        // all come from the last one of them.
+        let next_bit = self.pos * 8 - self.bits_in_cache as usize;
+        next_bit < stop_bit
+    }
+
     /// Reads 1 bit from the cache and returns it as a boolean.
     pub fn read_flag(&mut self) -> Option<bool> {
-        self.read_u8(1).map(|v| if v == 1 { true } else { false })
+        self.read_u8(1).map(|v| v == 1)
     }
 
     /// Reads nbits from the cache and then returns that as a u8.
@@ -94,52 +141,83 @@ impl<'a> H264NalReader<'a> {
         }
         let shift = self.bits_in_cache - nbits;
         let mut val : u32 = self.next_byte as u32 >> shift;
-        let nooverflow_cache = self.cache & ((0x01 << (7 - shift)) - 1);
-        val |= nooverflow_cache << (8 - shift);
+        val |= self.cache << (8 - shift);
         let mask = if nbits == 32 {
             0xFFFFFFFF
         } else {
             (0x01 << nbits) - 1
         };
-        val = val & mask;
+        val &= mask;
         self.bits_in_cache = shift;
         Some(val)
     }
 
     pub fn read_ue(&mut self) -> Option<u32> {
         let mut leading_zeros = 0;
-        let mut bit = match self.read_u8(1) {
-            None => return None,
-            Some(b) => b
-        };
+        let mut bit = self.read_u8(1)?;
         while bit == 0 {
             leading_zeros += 1;
-            bit = match self.read_u8(1) {
-                None => return None,
-                Some(b) => b
-            };
-        }
-        if leading_zeros > 32 {
-            println!("Reading UE and leading zeros > 32: {}", leading_zeros);
-            return None;
+            // More than 31 leading zeros doesn't fit in 32 bits.
+            if leading_zeros > 31 {
+                return None;
+            }
+            bit = self.read_u8(1)?;
         }
-        let val = match self.read_u32(leading_zeros) {
-            None => return None,
-            Some(v) => v
-        };
+        let val = self.read_u32(leading_zeros)?;
         Some((1 << leading_zeros) - 1 + val)
     }
 
     pub fn read_se(&mut self) -> Option<i32> {
-        let ue = match self.read_ue() {
-            None => return None,
-            Some(v) => v
-        };
+        let ue = self.read_ue()?;
         Some(
             if ue % 2 == 1 {
-                (ue as i32 / 2) + 1
+                (ue as i64 / 2 + 1) as i32
             } else {
-                -(ue as i32 / 2)
+                -(ue as i64 / 2) as i32
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_u32_keeps_the_cached_bits() {
+        let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xA5, 0x5A];
+        let mut reader = H264NalReader::new(&data);
+        assert_eq!(reader.read_u32(16), Some(0xFFFF));
+        assert_eq!(reader.read_u8(1), Some(1));
+        assert_eq!(reader.read_u32(15), Some(0x7FFF));
+        assert_eq!(reader.read_u32(16), Some(0xA55A));
+    }
+
+    #[test]
+    fn read_u32_across_bytes() {
+        let data = [0x80, 0x00, 0x00, 0x01, 0xC3];
+        let mut reader = H264NalReader::new(&data);
+        assert_eq!(reader.read_u32(32), Some(0x80000001));
+        assert_eq!(reader.read_u8(3), Some(6));
+        assert_eq!(reader.read_u8(5), Some(3));
+        assert_eq!(reader.read_u8(1), None);
+    }
+
+    #[test]
+    fn read_skips_emulation_prevention() {
+        let data = [0x00, 0x00, 0x03, 0x01, 0xFF];
+        let mut reader = H264NalReader::new(&data);
+        assert_eq!(reader.read_u32(24), Some(0x000001));
+        assert_eq!(reader.read_u8(8), Some(0xFF));
+        let mut rbsp = H264NalReader::new_rbsp(&data);
+        assert_eq!(rbsp.read_u32(24), Some(0x000003));
+    }
+
+    #[test]
+    fn read_ue_up_to_31_leading_zeros() {
+        // 31 zeros, the marker bit and 31 ones: 2^32 - 2
+        let data = [0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFE];
+        assert_eq!(H264NalReader::new_rbsp(&data).read_ue(), Some(0xFFFFFFFE));
+        let data = [0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(H264NalReader::new_rbsp(&data).read_ue(), None);
+    }
+}
@@ -1,26 +1,47 @@
+use std::cmp;
 use std::fs::File;
 use std::io;
 use std::io::{Read};
 mod h264nalreader;
-use self::h264nalreader::{H264NalReader};
+use self::h264nalreader::{H264NalReader, nal_to_rbsp};
+use writer::H264NalWriteError;
 pub use types::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum H264NalParseError {
     NotEnoughBytes,
     StartCodeParseError,
     UnknownFormat,
     Unimplemented,
+    /// A syntax element has a value its semantics don't allow.
+    OutOfRange,
+    /// Writing a parameter set back out failed.
+    Write(H264NalWriteError),
     GenericParseError
 }
 
+impl From<H264NalWriteError> for H264NalParseError {
+    fn from(e: H264NalWriteError) -> H264NalParseError {
+        H264NalParseError::Write(e)
+    }
+}
+
 pub struct H264NalParser {
     data: Vec<u8>,
     size: usize,
     pub format: H264NalFormat,
+    /// Size of the length prefix of each NAL unit in AVC format.
+    pub nal_length_size: usize,
 
     pub pps: Vec<H264NalUnitPPS>,
     pub sps: Vec<H264NalUnitSPS>,
+    // The NAL units the stored parameter sets were parsed from, by id
+    sps_nal_units: Vec<(u32, Vec<u8>)>,
+    pps_nal_units: Vec<(u32, Vec<u8>)>,
+
+    // SEI state which persists across access units
+    frame_packing_arrangement: Option<H264FramePackingArrangement>,
+    stereo_video_info: Option<H264StereoVideoInfo>,
 }
 
 macro_rules! check_size {
@@ -38,382 +59,506 @@ impl H264NalParser {
     pub fn new(path: &str) -> io::Result<H264NalParser> {
         let mut file = File::open(path)?;
         let mut data = Vec::new();
-        let size = file.read_to_end(&mut data)?;
-        let mut pps_vec = Vec::new();
-        pps_vec.reserve(MAX_PPS_COUNT);
-        let mut sps_vec = Vec::new();
-        sps_vec.reserve(MAX_SPS_COUNT);
-        Ok(H264NalParser {
-            data: data,
-            size: size,
+        file.read_to_end(&mut data)?;
+        Ok(H264NalParser::from_bytes(data))
+    }
+
+    /// Creates a parser over a stream which is already in memory.
+    pub fn from_bytes(data: Vec<u8>) -> H264NalParser {
+        let size = data.len();
+        let pps_vec = Vec::with_capacity(MAX_PPS_COUNT);
+        let sps_vec = Vec::with_capacity(MAX_SPS_COUNT);
+        H264NalParser {
+            data,
+            size,
             format: H264NalFormat::UNKNOWN,
+            nal_length_size: 4,
             pps: pps_vec,
-            sps: sps_vec
-        })
+            sps: sps_vec,
+            sps_nal_units: Vec::new(),
+            pps_nal_units: Vec::new(),
+            frame_packing_arrangement: None,
+            stereo_video_info: None
+        }
+    }
+
+    /// Replaces the data being parsed, keeping the parameter sets and the
+    /// SEI state. Lets one parser follow a stream that arrives in pieces,
+    /// such as one access unit at a time.
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.size = data.len();
+        self.data = data;
+    }
+
+    /// The raw bytes of a NAL unit, without its start code or length prefix.
+    pub fn nal_data(&self, nalu: &H264NalUnit) -> &[u8] {
+        let end = cmp::min(nalu.sc_offset + nalu.size, self.size);
+        &self.data[cmp::min(nalu.data_offset, end)..end]
     }
 
     // SPS
     pub fn parse_sps(&mut self, offset: usize) -> Result<H264NalUnitSPS, H264NalParseError> {
-        let mut reader = H264NalReader::new(&self.data[offset+1..]);
+        let end = self.nal_end(offset);
+        let mut reader = H264NalReader::new(&self.data[offset+1..end]);
         let mut unit = H264NalUnitSPS::new();
-        unit.profile_idc = reader.read_u8(8).unwrap();
+        unit.profile_idc = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
         {
-            unit.constraint_0_flag = reader.read_u8(1).unwrap();
-            unit.constraint_1_flag = reader.read_u8(1).unwrap();
-            unit.constraint_2_flag = reader.read_u8(1).unwrap();
-            unit.constraint_3_flag = reader.read_u8(1).unwrap();
-            unit.constraint_4_flag = reader.read_u8(1).unwrap();
-            unit.constraint_5_flag = reader.read_u8(1).unwrap();
-            reader.read_u8(2); // reserved 0 bits
+            unit.constraint_0_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.constraint_1_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.constraint_2_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.constraint_3_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.constraint_4_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.constraint_5_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.reserved_zero_2bits = reader.read_u8(2).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        unit.level_idc = reader.read_u8(8).unwrap();
-        unit.seq_parameter_set_id = reader.read_ue().unwrap();
+        unit.level_idc = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.seq_parameter_set_id = read_ue_max(&mut reader, 31)?;
 
         // depending on the profile we parse various other flags.
-        let certain_profiles : Vec<u8> = vec![100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
-        if certain_profiles.contains(&unit.profile_idc) {
-            unit.chroma_format_idc = reader.read_ue().unwrap();
+        unit.chroma_format_idc = 1;
+        if profile_idc_has_chroma_info(unit.profile_idc) {
+            unit.chroma_format_idc = read_ue_max(&mut reader, 3)?;
             if unit.chroma_format_idc == 3 {
-                unit.separate_colour_plane_flag = reader.read_flag().unwrap();
+                unit.separate_colour_plane_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             }
-            unit.bit_depth_luma_minus8 = reader.read_ue().unwrap();
-            unit.bit_depth_chroma_minus8 = reader.read_ue().unwrap();
-            unit.qpprime_y_zero_transform_bypass_flag = reader.read_u8(1).unwrap();
-            unit.seq_scaling_matrix_present_flag = reader.read_u8(1).unwrap();
+            unit.bit_depth_luma_minus8 = read_ue_max(&mut reader, 6)?;
+            unit.bit_depth_chroma_minus8 = read_ue_max(&mut reader, 6)?;
+            unit.qpprime_y_zero_transform_bypass_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.seq_scaling_matrix_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
             if unit.seq_scaling_matrix_present_flag == 1 {
                 let scaling_lists = if unit.chroma_format_idc != 3 { 8 } else { 12 };
-                unit.seq_scaling_list_present_flag.reserve(scaling_lists);
-                println!("Scaling list parsing not implemented");
                 for i in 0..scaling_lists {
-                    unit.seq_scaling_list_present_flag[i] = reader.read_u8(1).unwrap();
+                    unit.seq_scaling_list_present_flag.push(reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?);
                     if unit.seq_scaling_list_present_flag[i] == 1 {
-                        if i < 6 {
-                            // TODO: I should do this
+                        let list = if i < 6 {
+                            &mut unit.scaling_list_4x4[i]
                         } else {
-                            // TODO: I should do this
-                        }
+                            &mut unit.scaling_list_8x8[i - 6]
+                        };
+                        let (use_default, delta_scale) = parse_scaling_list(&mut reader, list)?;
+                        unit.use_default_scaling_matrix_flag[i] = use_default;
+                        unit.seq_scaling_list_delta_scale[i] = delta_scale;
                     }
                 }
             }
         }
 
-        unit.log2_max_frame_num_minus4 = reader.read_ue().unwrap();
-        unit.pic_order_cnt_type = reader.read_ue().unwrap();
+        unit.log2_max_frame_num_minus4 = read_ue_max(&mut reader, 12)?;
+        unit.pic_order_cnt_type = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         if unit.pic_order_cnt_type == 0 {
-            unit.log2_max_pic_order_cnt_lsb_minus4 = reader.read_ue().unwrap();
+            unit.log2_max_pic_order_cnt_lsb_minus4 = read_ue_max(&mut reader, 12)?;
         } else if unit.pic_order_cnt_type == 1 {
-            unit.delta_pic_order_always_zero_flag = reader.read_flag().unwrap();
-            unit.offset_for_non_ref_pic = reader.read_se().unwrap();
-            unit.offset_for_top_to_bottom_field = reader.read_se().unwrap();
-            unit.num_ref_frames_in_pic_order_cnt_cycle = reader.read_ue().unwrap();
+            unit.delta_pic_order_always_zero_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.offset_for_non_ref_pic = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.offset_for_top_to_bottom_field = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.num_ref_frames_in_pic_order_cnt_cycle = read_ue_max(&mut reader, 255)?;
             unit.offset_for_ref_frame.reserve(unit.num_ref_frames_in_pic_order_cnt_cycle as usize);
-            for i in 0..unit.num_ref_frames_in_pic_order_cnt_cycle {
-                unit.offset_for_ref_frame[i as usize] = reader.read_se().unwrap();
+            for _ in 0..unit.num_ref_frames_in_pic_order_cnt_cycle {
+                unit.offset_for_ref_frame.push(reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?);
             }
         }
-        unit.max_num_ref_frames = reader.read_ue().unwrap();
-        unit.gaps_in_frame_num_value_allowed_flag = reader.read_u8(1).unwrap();
-        unit.pic_width_in_mbs_minus1 = reader.read_ue().unwrap();
-        unit.pic_height_in_map_units_minus1 = reader.read_ue().unwrap();
-        unit.frame_mbs_only_flag = reader.read_flag().unwrap();
+        unit.max_num_ref_frames = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.gaps_in_frame_num_value_allowed_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.pic_width_in_mbs_minus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.pic_height_in_map_units_minus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.frame_mbs_only_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
         if !unit.frame_mbs_only_flag {
-            unit.mb_adaptive_frame_field_flag = reader.read_u8(1).unwrap();
+            unit.mb_adaptive_frame_field_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        unit.direct_8x8_inference_flag = reader.read_u8(1).unwrap();
-        unit.frame_cropping_flag = reader.read_u8(1).unwrap();
+        unit.direct_8x8_inference_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+        unit.frame_cropping_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if unit.frame_cropping_flag == 1 {
-            unit.frame_crop_left_offset = reader.read_ue().unwrap();
-            unit.frame_crop_right_offset = reader.read_ue().unwrap();
-            unit.frame_crop_top_offset = reader.read_ue().unwrap();
-            unit.frame_crop_bottom_offset = reader.read_ue().unwrap();
+            unit.frame_crop_left_offset = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.frame_crop_right_offset = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.frame_crop_top_offset = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            unit.frame_crop_bottom_offset = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        unit.vui_parameters_present_flag = reader.read_u8(1).unwrap();
+        unit.vui_parameters_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if unit.vui_parameters_present_flag == 1 {
-            unit.vui_parameters = Some(self.parse_vui_params(&mut reader));
+            unit.vui_parameters = Some(self.parse_vui_params(&mut reader)?);
         }
 
-        println!("SPS cap: {} id: {}", self.sps.capacity(),
-            unit.seq_parameter_set_id);
-        self.sps.push(unit.clone());
+        self.store_sps(unit.clone());
+        self.sps_nal_units.push((unit.seq_parameter_set_id, self.data[offset..end].to_vec()));
         Ok(unit)
     }
 
-    fn parse_vui_params(&self, mut reader: &mut H264NalReader) -> H264VUIParameters {
+    fn parse_vui_params(&self, reader: &mut H264NalReader) -> Result<H264VUIParameters, H264NalParseError> {
         let mut params = H264VUIParameters::new();
-        params.aspect_ratio_info_present_flag = reader.read_u8(1).unwrap();
+        params.aspect_ratio_info_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.aspect_ratio_info_present_flag == 1 {
-            params.aspect_ratio_idc = reader.read_u8(8).unwrap();
+            params.aspect_ratio_idc = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
             if params.aspect_ratio_idc == EXTENDED_SAR {
-                params.sar_width = reader.read_u16(16).unwrap();
-                params.sar_height = reader.read_u16(16).unwrap();
+                params.sar_width = reader.read_u16(16).ok_or(H264NalParseError::NotEnoughBytes)?;
+                params.sar_height = reader.read_u16(16).ok_or(H264NalParseError::NotEnoughBytes)?;
             }
         }
-        params.overscan_info_present_flag = reader.read_u8(1).unwrap();
+        params.overscan_info_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.overscan_info_present_flag == 1 {
-            params.overscan_appropriate_flag = reader.read_u8(1).unwrap();
+            params.overscan_appropriate_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        params.video_signal_type_present_flag = reader.read_u8(1).unwrap();
+        params.video_signal_type_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.video_signal_type_present_flag == 1 {
-            params.video_format = reader.read_u8(3).unwrap();
-            params.video_full_range_flag = reader.read_u8(1).unwrap();
-            params.colour_description_present_flag = reader.read_u8(1).unwrap();
+            params.video_format = reader.read_u8(3).ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.video_full_range_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.colour_description_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
             if params.colour_description_present_flag == 1 {
-                params.colour_primaries = reader.read_u8(8).unwrap();
-                params.transfer_characteristics = reader.read_u8(8).unwrap();
-                params.matrix_coefficients = reader.read_u8(8).unwrap();
+                params.colour_primaries = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
+                params.transfer_characteristics = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
+                params.matrix_coefficients = reader.read_u8(8).ok_or(H264NalParseError::NotEnoughBytes)?;
             }
         }
-        params.chroma_loc_info_present_flag = reader.read_u8(1).unwrap();
+        params.chroma_loc_info_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.chroma_loc_info_present_flag == 1 {
-            params.chroma_sample_loc_type_top_field = reader.read_ue().unwrap();
-            params.chroma_sample_loc_type_bottom_field = reader.read_ue().unwrap();
+            params.chroma_sample_loc_type_top_field = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.chroma_sample_loc_type_bottom_field = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        params.timing_info_present_flag = reader.read_u8(1).unwrap();
+        params.timing_info_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.timing_info_present_flag == 1 {
-            params.num_units_in_tick = reader.read_u32(32).unwrap();
-            params.time_scale = reader.read_u32(32).unwrap();
-            params.fixed_frame_rate_flag = reader.read_u8(1).unwrap();
+            params.num_units_in_tick = reader.read_u32(32).ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.time_scale = reader.read_u32(32).ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.fixed_frame_rate_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        params.nal_hrd_parameters_present_flag = reader.read_u8(1).unwrap();
+        params.nal_hrd_parameters_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.nal_hrd_parameters_present_flag == 1 {
-            params.nal_hrd_parameters = Some(self.parse_hdr_params(&mut reader));
+            params.nal_hrd_parameters = Some(self.parse_hdr_params(reader)?);
         }
-        params.vcl_hrd_parameters_present_flag = reader.read_u8(1).unwrap();
+        params.vcl_hrd_parameters_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.vcl_hrd_parameters_present_flag == 1 {
-            params.vcl_hrd_parameters = Some(self.parse_hdr_params(&mut reader));
+            params.vcl_hrd_parameters = Some(self.parse_hdr_params(reader)?);
         }
         if params.nal_hrd_parameters_present_flag == 1 || params.vcl_hrd_parameters_present_flag == 1 {
-            params.low_delay_hrd_flag = reader.read_u8(1).unwrap();
+            params.low_delay_hrd_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        params.pic_struct_present_flag = reader.read_u8(1).unwrap();
-        params.bitstream_restriction_flag = reader.read_u8(1).unwrap();
+        params.pic_struct_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+        params.bitstream_restriction_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
         if params.bitstream_restriction_flag == 1 {
-            params.motion_vectors_over_pic_boundaries_flag = reader.read_u8(1).unwrap();
-            params.max_bytes_per_pic_denom = reader.read_ue().unwrap();
-            params.max_bits_per_mb_denom = reader.read_ue().unwrap();
-            params.log2_max_mv_length_horizontal = reader.read_ue().unwrap();
-            params.log2_max_mv_length_vertical = reader.read_ue().unwrap();
-            params.max_num_reorder_frames = reader.read_ue().unwrap();
-            params.max_dec_frame_buffering = reader.read_ue().unwrap();
+            params.motion_vectors_over_pic_boundaries_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.max_bytes_per_pic_denom = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.max_bits_per_mb_denom = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.log2_max_mv_length_horizontal = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.log2_max_mv_length_vertical = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.max_num_reorder_frames = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+            params.max_dec_frame_buffering = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        params
+        Ok(params)
     }
 
-    pub fn parse_hdr_params(&self, reader: &mut H264NalReader) -> H264HDRParameters {
+    pub fn parse_hdr_params(&self, reader: &mut H264NalReader) -> Result<H264HDRParameters, H264NalParseError> {
         let mut hdr_params = H264HDRParameters::new();
-        hdr_params.cpb_cnt_minus1 = reader.read_ue().unwrap();
-        hdr_params.bit_rate_scale = reader.read_u8(4).unwrap();
-        hdr_params.cpb_size_scale = reader.read_u8(4).unwrap();
+        hdr_params.cpb_cnt_minus1 = read_ue_max(reader, 31)?;
+        hdr_params.bit_rate_scale = reader.read_u8(4).ok_or(H264NalParseError::NotEnoughBytes)?;
+        hdr_params.cpb_size_scale = reader.read_u8(4).ok_or(H264NalParseError::NotEnoughBytes)?;
         let cpb_cnt = (hdr_params.cpb_cnt_minus1 + 1) as usize;
         hdr_params.bit_rate_value_minus1.reserve(cpb_cnt);
         hdr_params.cpb_size_value_minus1.reserve(cpb_cnt);
         hdr_params.cbr_flag.reserve(cpb_cnt);
-        for i in 0..cpb_cnt {
-            hdr_params.bit_rate_value_minus1[i] = reader.read_ue().unwrap();
-            hdr_params.cpb_size_value_minus1[i] = reader.read_ue().unwrap();
-            hdr_params.cbr_flag[i] = reader.read_u8(1).unwrap();
+        for _ in 0..cpb_cnt {
+            hdr_params.bit_rate_value_minus1.push(reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?);
+            hdr_params.cpb_size_value_minus1.push(reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?);
+            hdr_params.cbr_flag.push(reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?);
         }
-        hdr_params.initial_cpb_removal_delay_length_minus1 = reader.read_u8(5).unwrap();
-        hdr_params.cpb_removal_delay_length_minus1 = reader.read_u8(5).unwrap();
-        hdr_params.dpb_output_delay_length_minus1 = reader.read_u8(5).unwrap();
-        hdr_params.time_offset_length = reader.read_u8(5).unwrap();
-        hdr_params
+        hdr_params.initial_cpb_removal_delay_length_minus1 = reader.read_u8(5).ok_or(H264NalParseError::NotEnoughBytes)?;
+        hdr_params.cpb_removal_delay_length_minus1 = reader.read_u8(5).ok_or(H264NalParseError::NotEnoughBytes)?;
+        hdr_params.dpb_output_delay_length_minus1 = reader.read_u8(5).ok_or(H264NalParseError::NotEnoughBytes)?;
+        hdr_params.time_offset_length = reader.read_u8(5).ok_or(H264NalParseError::NotEnoughBytes)?;
+        Ok(hdr_params)
     }
 
     pub fn parse_pps(&mut self, offset: usize) -> Result<H264NalUnitPPS, H264NalParseError> {
-        let mut reader = H264NalReader::new(&self.data[offset+1..]);
+        let end = self.nal_end(offset);
+        let mut reader = H264NalReader::new(&self.data[offset+1..end]);
         let mut pps = H264NalUnitPPS::new();
 
-        pps.pic_parameter_set_id = reader.read_ue().unwrap();
-        pps.seq_parameter_set_id = reader.read_ue().unwrap();
-        pps.entropy_coding_mode_flag = reader.read_flag().unwrap();
-        pps.bottom_field_pic_order_in_frame_present_flag = reader.read_flag().unwrap();
-        pps.num_slice_groups_minus1 = reader.read_ue().unwrap();
+        pps.pic_parameter_set_id = read_ue_max(&mut reader, 255)?;
+        pps.seq_parameter_set_id = read_ue_max(&mut reader, 31)?;
+        pps.entropy_coding_mode_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.bottom_field_pic_order_in_frame_present_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.num_slice_groups_minus1 = read_ue_max(&mut reader, 7)?;
         if pps.num_slice_groups_minus1 > 0 {
-            pps.slice_group_map_type = reader.read_ue().unwrap();
+            pps.slice_group_map_type = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
             if pps.slice_group_map_type == 0 {
-                let size = pps.num_slice_groups_minus1 as usize + 1;
-                pps.run_length_minus1.reserve(size);
-                for i in 0..size {
-                    pps.run_length_minus1[i] = reader.read_ue().unwrap();
+                for _ in 0..pps.num_slice_groups_minus1 + 1 {
+                    pps.run_length_minus1.push(reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?);
                 }
             } else if pps.slice_group_map_type == 2 {
-                let size = pps.num_slice_groups_minus1 as usize + 1;
-                pps.top_left.reserve(size);
-                pps.bottom_right.reserve(size);
-                for i in 0..size {
-                    pps.top_left[i] = reader.read_ue().unwrap();
-                    pps.bottom_right[i] = reader.read_ue().unwrap();
+                for _ in 0..pps.num_slice_groups_minus1 {
+                    pps.top_left.push(reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?);
+                    pps.bottom_right.push(reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?);
                 }
             } else if pps.slice_group_map_type == 3 ||
                         pps.slice_group_map_type == 4 ||
                         pps.slice_group_map_type == 5 {
-                pps.slice_group_change_direction_flag = reader.read_u8(1).unwrap();
-                pps.slice_group_change_rate_minus1 = reader.read_ue().unwrap();
+                pps.slice_group_change_direction_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+                pps.slice_group_change_rate_minus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
             } else if pps.slice_group_map_type == 6 {
-                pps.pic_size_in_map_units_minus1 = reader.read_ue().unwrap();
-                let size = pps.pic_size_in_map_units_minus1 as usize + 1;
-                pps.slice_group_id.reserve(size);
-                for i in 0..size {
-                    pps.slice_group_id[i] = reader.read_ue().unwrap();
+                pps.pic_size_in_map_units_minus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+                let nbits = slice_group_id_bits(pps.num_slice_groups_minus1);
+                for _ in 0..pps.pic_size_in_map_units_minus1 + 1 {
+                    pps.slice_group_id.push(reader.read_u32(nbits).ok_or(H264NalParseError::NotEnoughBytes)?);
                 }
             }
         }
-        pps.num_ref_idx_l0_default_active_minus1 = reader.read_ue().unwrap();
-        pps.num_ref_idx_l1_default_active_minus1 = reader.read_ue().unwrap();
-        pps.weighted_pred_flag = reader.read_flag().unwrap();
-        pps.weighted_bipred_idc = reader.read_u8(2).unwrap();
-        pps.pic_init_qp_minus26 = reader.read_se().unwrap();
-        pps.pic_init_qs_minus26 = reader.read_se().unwrap();
-        pps.chroma_qp_index_offset = reader.read_se().unwrap();
-        pps.deblocking_filter_control_present_flag = reader.read_flag().unwrap();
-        pps.constrained_intra_pred_flag = reader.read_u8(1).unwrap();
-        pps.redundant_pic_cnt_present_flag = reader.read_flag().unwrap();
-
-        // TODO: if more data
-        //transform_8x8_mode_flag: 0,
-        //pic_scaling_matrix_present_flag: 0,
-        //pic_scaling_list_present_flag: Vec::new(),
-        //scaling_list_4x4: vec![vec![0u8; 16]; 6],
-        //scaling_list_8x8: vec![vec![0u8; 64]; 6],
-        //second_chroma_qp_index_offset: 0
-
-        self.pps.push(pps.clone());
+        pps.num_ref_idx_l0_default_active_minus1 = read_ue_max(&mut reader, 31)?;
+        pps.num_ref_idx_l1_default_active_minus1 = read_ue_max(&mut reader, 31)?;
+        pps.weighted_pred_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.weighted_bipred_idc = reader.read_u8(2).ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.pic_init_qp_minus26 = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.pic_init_qs_minus26 = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.chroma_qp_index_offset = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.deblocking_filter_control_present_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.constrained_intra_pred_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+        pps.redundant_pic_cnt_present_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+
+        pps.second_chroma_qp_index_offset = pps.chroma_qp_index_offset;
+        pps.more_rbsp_data = reader.more_rbsp_data();
+        if pps.more_rbsp_data {
+            pps.transform_8x8_mode_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            pps.pic_scaling_matrix_present_flag = reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?;
+            if pps.pic_scaling_matrix_present_flag == 1 {
+                let chroma_format_idc = match self.find_sps(pps.seq_parameter_set_id) {
+                    Some(sps) => sps.chroma_format_idc,
+                    None => 1
+                };
+                let scaling_lists = 6 + (if chroma_format_idc != 3 { 2 } else { 6 }) *
+                    pps.transform_8x8_mode_flag as usize;
+                for i in 0..scaling_lists {
+                    pps.pic_scaling_list_present_flag.push(reader.read_u8(1).ok_or(H264NalParseError::NotEnoughBytes)?);
+                    if pps.pic_scaling_list_present_flag[i] == 1 {
+                        let list = if i < 6 {
+                            &mut pps.scaling_list_4x4[i]
+                        } else {
+                            &mut pps.scaling_list_8x8[i - 6]
+                        };
+                        let (use_default, delta_scale) = parse_scaling_list(&mut reader, list)?;
+                        pps.use_default_scaling_matrix_flag[i] = use_default;
+                        pps.pic_scaling_list_delta_scale[i] = delta_scale;
+                    }
+                }
+            }
+            pps.second_chroma_qp_index_offset = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+        }
+
+        self.store_pps(pps.clone());
+        self.pps_nal_units.push((pps.pic_parameter_set_id, self.data[offset..end].to_vec()));
         Ok(pps)
     }
 
+    pub fn find_sps(&self, seq_parameter_set_id: u32) -> Option<&H264NalUnitSPS> {
+        self.sps.iter().find(|s| s.seq_parameter_set_id == seq_parameter_set_id)
+    }
+
+    pub fn find_pps(&self, pic_parameter_set_id: u32) -> Option<&H264NalUnitPPS> {
+        self.pps.iter().find(|p| p.pic_parameter_set_id == pic_parameter_set_id)
+    }
+
+    /// Stores a parameter set, replacing an earlier one with the same id.
+    pub fn store_sps(&mut self, sps: H264NalUnitSPS) {
+        self.sps_nal_units.retain(|&(id, _)| id != sps.seq_parameter_set_id);
+        match self.sps.iter().position(|s| s.seq_parameter_set_id == sps.seq_parameter_set_id) {
+            Some(i) => self.sps[i] = sps,
+            None => self.sps.push(sps)
+        }
+    }
+
+    pub fn store_pps(&mut self, pps: H264NalUnitPPS) {
+        self.pps_nal_units.retain(|&(id, _)| id != pps.pic_parameter_set_id);
+        match self.pps.iter().position(|p| p.pic_parameter_set_id == pps.pic_parameter_set_id) {
+            Some(i) => self.pps[i] = pps,
+            None => self.pps.push(pps)
+        }
+    }
+
+    /// The NAL unit, without start code or length prefix, which the stored
+    /// SPS was parsed from. None if it was stored with store_sps.
+    pub fn sps_nal_unit(&self, seq_parameter_set_id: u32) -> Option<&[u8]> {
+        self.sps_nal_units.iter().find(|&&(id, _)| id == seq_parameter_set_id).map(|(_, nal)| &nal[..])
+    }
+
+    pub fn pps_nal_unit(&self, pic_parameter_set_id: u32) -> Option<&[u8]> {
+        self.pps_nal_units.iter().find(|&&(id, _)| id == pic_parameter_set_id).map(|(_, nal)| &nal[..])
+    }
+
+    /// The end of the NAL unit whose header is at data_offset.
+    fn nal_end(&self, data_offset: usize) -> usize {
+        if self.format == H264NalFormat::AVC && data_offset >= self.nal_length_size {
+            let mut length = 0;
+            for i in 0..self.nal_length_size {
+                length = (length << 8) | self.data[data_offset - self.nal_length_size + i] as usize;
+            }
+            return cmp::min(data_offset + length, self.size);
+        }
+        // 00 00 00 and 00 00 01 can't appear inside a NAL unit
+        let mut i = data_offset + 1;
+        while i + 2 < self.size {
+            if self.data[i] == 0 && self.data[i+1] == 0 && self.data[i+2] <= 1 {
+                return i;
+            }
+            i += 1;
+        }
+        self.size
+    }
+
+    /// first_mb_in_slice, slice_type and pic_parameter_set_id of a slice,
+    /// which can be read without the parameter sets, unlike the rest of
+    /// the header.
+    pub fn peek_slice_header(&self, nalu: &H264NalUnit) -> Option<(u32, u32, u32)> {
+        let data = self.nal_data(nalu);
+        if data.len() < 2 {
+            return None;
+        }
+        let mut reader = H264NalReader::new(&data[1..]);
+        Some((reader.read_ue()?, reader.read_ue()?, reader.read_ue()?))
+    }
+
     // Slice
     pub fn parse_slice(&self, offset: usize, nalu: &H264NalUnit) -> Result<H264NalUnitSlice, H264NalParseError> {
-        let mut reader = H264NalReader::new(&self.data[offset+1..]);
+        let end = self.nal_end(offset);
+        let mut reader = H264NalReader::new(&self.data[offset+1..end]);
         let mut slice = H264NalUnitSlice::new();
         // slice_header()
-        slice.first_mb_in_slice = reader.read_ue().unwrap();
-        slice.slice_type = reader.read_ue().unwrap();
-        slice.pic_parameter_set_id = reader.read_ue().unwrap();
-        let pps = &self.pps[slice.pic_parameter_set_id as usize];
-        let sps = &self.sps[pps.seq_parameter_set_id as usize];
+        slice.first_mb_in_slice = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        slice.slice_type = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        slice.pic_parameter_set_id = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        let pps = match self.find_pps(slice.pic_parameter_set_id) {
+            Some(pps) => pps,
+            None => return Err(H264NalParseError::GenericParseError)
+        };
+        let sps = match self.find_sps(pps.seq_parameter_set_id) {
+            Some(sps) => sps,
+            None => return Err(H264NalParseError::GenericParseError)
+        };
         if sps.separate_colour_plane_flag {
-            slice.colour_plane_id = reader.read_u8(2).unwrap();
+            slice.colour_plane_id = reader.read_u8(2).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
         let frame_num_bits = sps.log2_max_frame_num_minus4 + 4;
-        slice.frame_num = reader.read_u32(frame_num_bits).unwrap();
+        slice.frame_num = reader.read_u32(frame_num_bits).ok_or(H264NalParseError::NotEnoughBytes)?;
 
         if !sps.frame_mbs_only_flag {
-            slice.field_pic_flag = reader.read_flag().unwrap();
+            slice.field_pic_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             if slice.field_pic_flag {
-                slice.bottom_field_flag = reader.read_flag().unwrap();
+                slice.bottom_field_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             }
         }
         // if slice pic flag
         if nalu.idr_pic_flag {
-            slice.idr_pic_id = reader.read_ue().unwrap();
+            slice.idr_pic_id = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
         if sps.pic_order_cnt_type == 0 {
-            slice.pic_order_cnt_lsb = reader.read_u16(sps.log2_max_pic_order_cnt_lsb_minus4 + 4).unwrap();
+            slice.pic_order_cnt_lsb = reader.read_u16(sps.log2_max_pic_order_cnt_lsb_minus4 + 4).ok_or(H264NalParseError::NotEnoughBytes)?;
             if pps.bottom_field_pic_order_in_frame_present_flag &&
                 !slice.field_pic_flag {
-                    slice.delta_pic_order_cnt_bottom = reader.read_se().unwrap();
+                    slice.delta_pic_order_cnt_bottom = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
                 }
         }
         if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
             match slice.delta_pic_order_cnt.get_mut(0) {
-                Some(elem) => *elem = reader.read_se().unwrap(),
+                Some(elem) => *elem = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?,
                 None => println!("WARNING: unable to get mut ref to delta_pic_order_cnt[0]")
             }
             if pps.bottom_field_pic_order_in_frame_present_flag && slice.field_pic_flag {
                 match slice.delta_pic_order_cnt.get_mut(1) {
-                    Some(elem) => *elem = reader.read_se().unwrap(),
+                    Some(elem) => *elem = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?,
                     None => println!("WARNING: unable to get mut ref to delta_pic_order_cnt[1]")
                 }
             }
         }
         if pps.redundant_pic_cnt_present_flag {
-            slice.redundant_pic_cnt = reader.read_ue().unwrap();
+            slice.redundant_pic_cnt = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
 
         // B Slice
         if slice_type_is_b_slice(slice.slice_type) {
-            slice.direct_spatial_mv_pred_flag = reader.read_flag().unwrap();
+            slice.direct_spatial_mv_pred_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
 
+        // The active number of references defaults to the PPS values
+        slice.num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        slice.num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
         if slice_type_is_p_slice(slice.slice_type) || slice_type_is_b_slice(slice.slice_type)
             || slice_type_is_sp_slice(slice.slice_type) {
-            slice.num_ref_idx_active_override_flag = reader.read_flag().unwrap();
+            slice.num_ref_idx_active_override_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             if slice.num_ref_idx_active_override_flag {
-                slice.num_ref_idx_l0_active_minus1 = reader.read_ue().unwrap();
-            }
-            if slice_type_is_b_slice(slice.slice_type) {
-                slice.num_ref_idx_l1_active_minus1 = reader.read_ue().unwrap();
+                slice.num_ref_idx_l0_active_minus1 = read_ue_max(&mut reader, 31)?;
+                if slice_type_is_b_slice(slice.slice_type) {
+                    slice.num_ref_idx_l1_active_minus1 = read_ue_max(&mut reader, 31)?;
+                }
             }
         }
 
-        if nalu.nal_unit_type_num == 20 || nalu.nal_unit_type_num == 21 {
-            // ref_pic_list_mvc_modification
-            println!("ref_pic_list_mvc_modification");
-        } else {
-            // ref_pic_list_modification
-            println!("ref_pic_list_modification");
+        // ref_pic_list_modification, or ref_pic_list_mvc_modification for
+        // nal_unit_type 20 and 21 which also allows idc 4 and 5.
+        if !slice_type_is_i_slice(slice.slice_type) && !slice_type_is_si_slice(slice.slice_type) {
+            slice.ref_pic_list_modification_flag_l0 = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+            if slice.ref_pic_list_modification_flag_l0 {
+                slice.ref_pic_list_modification_l0 = parse_ref_pic_list_modification(&mut reader)?;
+            }
+        }
+        if slice_type_is_b_slice(slice.slice_type) {
+            slice.ref_pic_list_modification_flag_l1 = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+            if slice.ref_pic_list_modification_flag_l1 {
+                slice.ref_pic_list_modification_l1 = parse_ref_pic_list_modification(&mut reader)?;
+            }
         }
+
         if pps.weighted_pred_flag && (slice_type_is_p_slice(slice.slice_type) || slice_type_is_sp_slice(slice.slice_type)) ||
             (pps.weighted_bipred_idc == 1 && slice_type_is_b_slice(slice.slice_type)) {
-            println!("pred_weight_table");
+            slice.pred_weight_table = Some(parse_pred_weight_table(&mut reader, &slice, sps)?);
         }
         if nalu.nal_ref_idc != 0 {
             // dec_ref_pic_marking
-            if slice_type_is_i_slice(slice.slice_type) {
-                slice.no_output_of_prior_pics_flag = reader.read_flag().unwrap();
-                slice.long_term_reference_flag = reader.read_flag().unwrap();
+            if nalu.idr_pic_flag {
+                slice.no_output_of_prior_pics_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+                slice.long_term_reference_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             } else {
-                slice.adaptive_ref_pic_marking_mode_flag = reader.read_flag().unwrap();
-                loop {
-                    let mem_op = reader.read_ue().unwrap();
+                slice.adaptive_ref_pic_marking_mode_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+                while slice.adaptive_ref_pic_marking_mode_flag {
+                    let mem_op = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
                     if mem_op > 6 || mem_op == 0 {
                         break;
                     }
+                    let mut op = H264MemoryManagementOperation::new(mem_op);
                     if mem_op == 1 || mem_op == 3 {
-                        slice.difference_of_pic_nums_minus1 = reader.read_ue().unwrap();
+                        op.difference_of_pic_nums_minus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
                     }
                     if mem_op == 2 {
-                        slice.long_term_pic_num = reader.read_ue().unwrap();
+                        op.long_term_pic_num = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
                     }
                     if mem_op == 3 || mem_op == 6 {
-                        slice.long_term_frame_idx = reader.read_ue().unwrap();
+                        op.long_term_frame_idx = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
                     }
                     if mem_op == 4 {
-                        slice.max_long_term_frame_idx_plus1 = reader.read_ue().unwrap();
+                        op.max_long_term_frame_idx_plus1 = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
                     }
+                    slice.memory_management_control_operations.push(op);
                 }
             }
         }
 
         if pps.entropy_coding_mode_flag && !slice_type_is_i_slice(slice.slice_type) && !slice_type_is_si_slice(slice.slice_type) {
-            slice.cabac_init_idc = reader.read_ue().unwrap();
+            slice.cabac_init_idc = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
-        slice.slice_qp_delta = reader.read_se().unwrap();
+        slice.slice_qp_delta = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
 
         if slice_type_is_sp_slice(slice.slice_type) || slice_type_is_si_slice(slice.slice_type) {
             if slice_type_is_sp_slice(slice.slice_type) {
-                slice.sp_for_switch_flag = reader.read_flag().unwrap();
+                slice.sp_for_switch_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
             }
-            slice.slice_qs_delta = reader.read_se().unwrap();
+            slice.slice_qs_delta = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
         }
 
         if pps.deblocking_filter_control_present_flag {
-            slice.disable_deblocking_filter_idc = reader.read_ue().unwrap();
+            slice.disable_deblocking_filter_idc = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
             if slice.disable_deblocking_filter_idc != 1 {
-                slice.slice_alpha_c0_offset_div2 = reader.read_se().unwrap();
-                slice.slice_beta_offset_div2 = reader.read_se().unwrap();
+                slice.slice_alpha_c0_offset_div2 = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+                slice.slice_beta_offset_div2 = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
             }
         }
 
         if pps.num_slice_groups_minus1 > 0 && pps.slice_group_map_type >= 3 &&
             pps.slice_group_map_type <= 5 {
-            let pic_width_in_mbs = sps.pic_width_in_mbs_minus1 + 1;
-            let pic_height_in_map_units = sps.pic_height_in_map_units_minus1 + 1;
-            let pic_size_in_map_units = pic_width_in_mbs * pic_height_in_map_units;
-            let slice_group_change_rate = pps.slice_group_change_rate_minus1 + 1;
-            let nbits = ceil_log2(pic_size_in_map_units / slice_group_change_rate + 1);
-            slice.slice_group_change_cycle = reader.read_u32(nbits).unwrap();
+            let nbits = slice_group_change_cycle_bits(sps, pps);
+            slice.slice_group_change_cycle = reader.read_u32(nbits).ok_or(H264NalParseError::NotEnoughBytes)?;
         }
 
         // slice_data()
@@ -421,6 +566,114 @@ impl H264NalParser {
         Ok(slice)
     }
 
+    // SEI
+    pub fn parse_sei(&self, nalu: &H264NalUnit) -> Result<Vec<H264SEIMessage>, H264NalParseError> {
+        check_size!(self, nalu.data_offset, 1);
+        let rbsp = nal_to_rbsp(&self.nal_data(nalu)[1..]);
+        let mut messages = Vec::new();
+        let mut pos = 0;
+        while more_rbsp_data(&rbsp, pos) {
+            let payload_type = read_sei_value(&rbsp, &mut pos)?;
+            let payload_size = read_sei_value(&rbsp, &mut pos)?;
+            if rbsp.len() < pos + payload_size as usize {
+                return Err(H264NalParseError::NotEnoughBytes);
+            }
+            let data = &rbsp[pos..pos + payload_size as usize];
+            pos += payload_size as usize;
+            let payload = match parse_sei_payload(payload_type, data) {
+                Some(p) => p,
+                None => return Err(H264NalParseError::NotEnoughBytes)
+            };
+            messages.push(H264SEIMessage {
+                payload_type,
+                payload_size,
+                payload
+            });
+        }
+        Ok(messages)
+    }
+
+    /// Parses the access unit starting at offset. The access unit ends
+    /// before the first AUD, SPS, PPS, SEI or prefix NAL unit following a
+    /// VCL NAL unit, or before the first slice of the next picture.
+    pub fn parse_access_unit(&mut self, offset: usize) -> Result<H264AccessUnit, H264NalParseError> {
+        let mut au = H264AccessUnit::new(offset);
+        let mut cursor = offset;
+        let mut seen_vcl = false;
+        loop {
+            let unit = match self.parse_nalunit(cursor) {
+                Ok(u) => u,
+                Err(e) => {
+                    if au.nal_units.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                }
+            };
+            let unit_type = unit.nal_unit_type_num;
+            let is_vcl = (1..=5).contains(&unit_type);
+            if seen_vcl {
+                if (6..=9).contains(&unit_type) || (14..=18).contains(&unit_type) {
+                    break;
+                }
+                if is_vcl && self.first_mb_in_slice(&unit) == Some(0) {
+                    break;
+                }
+            }
+            if unit_type == 6 {
+                match self.parse_sei(&unit) {
+                    Ok(mut messages) => au.sei.append(&mut messages),
+                    Err(e) => au.sei_errors.push((au.nal_units.len(), e))
+                }
+            }
+            seen_vcl = seen_vcl || is_vcl;
+            cursor += unit.size;
+            au.nal_units.push(unit);
+        }
+        au.size = cursor - offset;
+        self.update_persistent_sei(&mut au);
+        Ok(au)
+    }
+
+    fn first_mb_in_slice(&self, nalu: &H264NalUnit) -> Option<u32> {
+        if self.size <= nalu.data_offset + 1 {
+            return None;
+        }
+        H264NalReader::new(&self.data[nalu.data_offset+1..]).read_ue()
+    }
+
+    /// A new coded video sequence ends any arrangement that persisted from
+    /// the previous one. A frame packing arrangement with a repetition
+    /// period of 0 only applies to the access unit it was sent in.
+    fn update_persistent_sei(&mut self, au: &mut H264AccessUnit) {
+        if au.is_idr() {
+            self.frame_packing_arrangement = None;
+            self.stereo_video_info = None;
+        }
+        let mut frame_packing = self.frame_packing_arrangement.clone();
+        for message in &au.sei {
+            match message.payload {
+                H264SEIPayload::FramePackingArrangement(ref fpa) => {
+                    frame_packing = if fpa.frame_packing_arrangement_cancel_flag {
+                        None
+                    } else {
+                        Some(fpa.clone())
+                    };
+                    self.frame_packing_arrangement = match frame_packing {
+                        Some(ref f) if f.frame_packing_arrangement_repetition_period > 0 => Some(f.clone()),
+                        _ => None
+                    };
+                },
+                H264SEIPayload::StereoVideoInfo(ref svi) => {
+                    self.stereo_video_info = Some(svi.clone());
+                },
+                _ => {}
+            }
+        }
+        au.frame_packing_arrangement = frame_packing;
+        au.stereo_video_info = self.stereo_video_info.clone();
+    }
+
     fn parse_startcode(&self, sc_offset: usize) -> Result<usize, H264NalParseError> {
         check_size!(self, sc_offset, 3);
         if self.data[sc_offset] != 0 || self.data[sc_offset+1] != 0 {
@@ -437,10 +690,7 @@ impl H264NalParser {
     }
 
     fn parse_bytestream(&self, sc_offset: usize) -> Result<H264NalUnit, H264NalParseError> {
-        let sc_size = match self.parse_startcode(sc_offset) {
-            Ok(sc_size) => sc_size,
-            Err(e) => return Err(e)
-        };
+        let sc_size = self.parse_startcode(sc_offset)?;
         let data_offset = sc_offset + sc_size;
 
         let mut cursor = 0;
@@ -452,18 +702,14 @@ impl H264NalParser {
         let unit_type = byte & 0x1F;
         cursor += 1;
         cursor += 1; // header byte
-        let mut size = self.size - data_offset - sc_size;
+        let mut size = self.size - sc_offset;
         for i in (cursor + data_offset)..self.size {
             if self.size - i < 3 {
                 break;
             }
-            match self.parse_startcode(i) {
-                Ok(_)  => {
-                    println!("i: {} sc_offset: {}", i, sc_offset);
-                    size = i - sc_offset;
-                    break;
-                }
-                Err(_) => {}
+            if self.parse_startcode(i).is_ok() {
+                size = i - sc_offset;
+                break;
             }
         }
 
@@ -471,8 +717,24 @@ impl H264NalParser {
     }
 
     fn parse_avc(&self, sc_offset: usize) -> Result<H264NalUnit, H264NalParseError> {
-        check_size!(self, sc_offset, 4);
-        Err(H264NalParseError::UnknownFormat)
+        check_size!(self, sc_offset, self.nal_length_size + 1);
+        let mut length = 0;
+        for i in 0..self.nal_length_size {
+            length = (length << 8) | self.data[sc_offset + i] as usize;
+        }
+        if length == 0 {
+            return Err(H264NalParseError::GenericParseError);
+        }
+        let data_offset = sc_offset + self.nal_length_size;
+        check_size!(self, data_offset, length);
+
+        let byte = self.data[data_offset];
+        if (byte & 0x80) == 0x80 {
+            return Err(H264NalParseError::GenericParseError);
+        }
+        let ref_idc = (byte & 0x60) >> 5;
+        let unit_type = byte & 0x1F;
+        Ok(H264NalUnit::new(sc_offset, data_offset, self.nal_length_size + length, ref_idc, unit_type))
     }
 
     pub fn parse_nalunit(&mut self, offset: usize) -> Result<H264NalUnit, H264NalParseError> {
@@ -498,3 +760,388 @@ impl H264NalParser {
     }
 }
 
+
+/// read_ue() for a syntax element whose semantics limit it to max.
+fn read_ue_max(reader: &mut H264NalReader, max: u32) -> Result<u32, H264NalParseError> {
+    match reader.read_ue() {
+        Some(value) if value <= max => Ok(value),
+        Some(_) => Err(H264NalParseError::OutOfRange),
+        None => Err(H264NalParseError::NotEnoughBytes)
+    }
+}
+
+/// scaling_list() from 7.3.2.1.1.1. Fills in list and returns
+/// useDefaultScalingMatrixFlag and the coded delta_scale values.
+fn parse_scaling_list(reader: &mut H264NalReader, list: &mut [u8])
+                      -> Result<(bool, Vec<i32>), H264NalParseError> {
+    let mut delta_scale = Vec::new();
+    let mut use_default = false;
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for (j, entry) in list.iter_mut().enumerate() {
+        if next_scale != 0 {
+            let delta = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+            delta_scale.push(delta);
+            next_scale = (last_scale + delta + 256) % 256;
+            use_default = j == 0 && next_scale == 0;
+        }
+        *entry = (if next_scale == 0 { last_scale } else { next_scale }) as u8;
+        last_scale = *entry as i32;
+    }
+    Ok((use_default, delta_scale))
+}
+
+fn parse_ref_pic_list_modification(reader: &mut H264NalReader)
+                                   -> Result<Vec<H264RefPicListModification>, H264NalParseError> {
+    let mut modifications = Vec::new();
+    loop {
+        let idc = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+        if idc == 3 || idc > 5 {
+            break;
+        }
+        modifications.push(H264RefPicListModification {
+            modification_of_pic_nums_idc: idc,
+            value: reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?
+        });
+    }
+    Ok(modifications)
+}
+
+fn parse_pred_weight_table(reader: &mut H264NalReader, slice: &H264NalUnitSlice,
+                           sps: &H264NalUnitSPS) -> Result<H264PredWeightTable, H264NalParseError> {
+    let mut table = H264PredWeightTable::new();
+    let chroma = sps.chroma_array_type() != 0;
+    table.luma_log2_weight_denom = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+    if chroma {
+        table.chroma_log2_weight_denom = reader.read_ue().ok_or(H264NalParseError::NotEnoughBytes)?;
+    }
+    let lists = if slice_type_is_b_slice(slice.slice_type) { 2 } else { 1 };
+    for list in 0..lists {
+        let count = if list == 0 {
+            slice.num_ref_idx_l0_active_minus1 + 1
+        } else {
+            slice.num_ref_idx_l1_active_minus1 + 1
+        };
+        for _ in 0..count {
+            let luma_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+            let (luma_weight, luma_offset) = if luma_flag {
+                (reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?, reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?)
+            } else {
+                (0, 0)
+            };
+            let mut chroma_flag = false;
+            let mut chroma_weight = [0; 2];
+            let mut chroma_offset = [0; 2];
+            if chroma {
+                chroma_flag = reader.read_flag().ok_or(H264NalParseError::NotEnoughBytes)?;
+                if chroma_flag {
+                    for j in 0..2 {
+                        chroma_weight[j] = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+                        chroma_offset[j] = reader.read_se().ok_or(H264NalParseError::NotEnoughBytes)?;
+                    }
+                }
+            }
+            if list == 0 {
+                table.luma_weight_l0_flag.push(luma_flag);
+                table.luma_weight_l0.push(luma_weight);
+                table.luma_offset_l0.push(luma_offset);
+                table.chroma_weight_l0_flag.push(chroma_flag);
+                table.chroma_weight_l0.push(chroma_weight);
+                table.chroma_offset_l0.push(chroma_offset);
+            } else {
+                table.luma_weight_l1_flag.push(luma_flag);
+                table.luma_weight_l1.push(luma_weight);
+                table.luma_offset_l1.push(luma_offset);
+                table.chroma_weight_l1_flag.push(chroma_flag);
+                table.chroma_weight_l1.push(chroma_weight);
+                table.chroma_offset_l1.push(chroma_offset);
+            }
+        }
+    }
+    Ok(table)
+}
+
+/// True if there is anything besides rbsp_trailing_bits left from pos.
+fn more_rbsp_data(rbsp: &[u8], pos: usize) -> bool {
+    if pos >= rbsp.len() {
+        return false;
+    }
+    match rbsp[pos..].iter().rposition(|&b| b != 0) {
+        Some(last) => last > 0 || rbsp[pos] != 0x80,
+        None => false
+    }
+}
+
+/// Reads the 0xFF-extended payloadType/payloadSize values of an SEI message.
+fn read_sei_value(rbsp: &[u8], pos: &mut usize) -> Result<u32, H264NalParseError> {
+    let mut value = 0;
+    loop {
+        let byte = match rbsp.get(*pos) {
+            Some(b) => *b,
+            None => return Err(H264NalParseError::NotEnoughBytes)
+        };
+        *pos += 1;
+        value += byte as u32;
+        if byte != 0xFF {
+            return Ok(value);
+        }
+    }
+}
+
+fn parse_sei_payload(payload_type: u32, data: &[u8]) -> Option<H264SEIPayload> {
+    let mut reader = H264NalReader::new_rbsp(data);
+    match payload_type {
+        SEI_MASTERING_DISPLAY_COLOUR_VOLUME => {
+            let mut mdcv = H264MasteringDisplayColourVolume::new();
+            for c in 0..3 {
+                mdcv.display_primaries_x[c] = reader.read_u16(16)?;
+                mdcv.display_primaries_y[c] = reader.read_u16(16)?;
+            }
+            mdcv.white_point_x = reader.read_u16(16)?;
+            mdcv.white_point_y = reader.read_u16(16)?;
+            mdcv.max_display_mastering_luminance = reader.read_u32(32)?;
+            mdcv.min_display_mastering_luminance = reader.read_u32(32)?;
+            Some(H264SEIPayload::MasteringDisplayColourVolume(mdcv))
+        },
+        SEI_CONTENT_LIGHT_LEVEL_INFO => {
+            let mut clli = H264ContentLightLevelInfo::new();
+            clli.max_content_light_level = reader.read_u16(16)?;
+            clli.max_pic_average_light_level = reader.read_u16(16)?;
+            Some(H264SEIPayload::ContentLightLevelInfo(clli))
+        },
+        SEI_ALTERNATIVE_TRANSFER_CHARACTERISTICS => {
+            Some(H264SEIPayload::AlternativeTransferCharacteristics(
+                H264AlternativeTransferCharacteristics {
+                    preferred_transfer_characteristics: reader.read_u8(8)?
+                }))
+        },
+        SEI_FRAME_PACKING_ARRANGEMENT => {
+            let mut fpa = H264FramePackingArrangement::new();
+            fpa.frame_packing_arrangement_id = reader.read_ue()?;
+            fpa.frame_packing_arrangement_cancel_flag = reader.read_flag()?;
+            if !fpa.frame_packing_arrangement_cancel_flag {
+                fpa.frame_packing_arrangement_type = reader.read_u8(7)?;
+                fpa.quincunx_sampling_flag = reader.read_flag()?;
+                fpa.content_interpretation_type = reader.read_u8(6)?;
+                fpa.spatial_flipping_flag = reader.read_flag()?;
+                fpa.frame0_flipped_flag = reader.read_flag()?;
+                fpa.field_views_flag = reader.read_flag()?;
+                fpa.current_frame_is_frame0_flag = reader.read_flag()?;
+                fpa.frame0_self_contained_flag = reader.read_flag()?;
+                fpa.frame1_self_contained_flag = reader.read_flag()?;
+                if !fpa.quincunx_sampling_flag && fpa.frame_packing_arrangement_type != 5 {
+                    fpa.frame0_grid_position_x = reader.read_u8(4)?;
+                    fpa.frame0_grid_position_y = reader.read_u8(4)?;
+                    fpa.frame1_grid_position_x = reader.read_u8(4)?;
+                    fpa.frame1_grid_position_y = reader.read_u8(4)?;
+                }
+                fpa.frame_packing_arrangement_reserved_byte = reader.read_u8(8)?;
+                fpa.frame_packing_arrangement_repetition_period = reader.read_ue()?;
+            }
+            fpa.frame_packing_arrangement_extension_flag = reader.read_flag()?;
+            Some(H264SEIPayload::FramePackingArrangement(fpa))
+        },
+        SEI_STEREO_VIDEO_INFO => {
+            let mut svi = H264StereoVideoInfo::new();
+            svi.field_views_flag = reader.read_flag()?;
+            if svi.field_views_flag {
+                svi.top_field_is_left_view_flag = reader.read_flag()?;
+            } else {
+                svi.current_frame_is_left_view_flag = reader.read_flag()?;
+                svi.next_frame_is_second_view_flag = reader.read_flag()?;
+            }
+            svi.left_view_self_contained_flag = reader.read_flag()?;
+            svi.right_view_self_contained_flag = reader.read_flag()?;
+            Some(H264SEIPayload::StereoVideoInfo(svi))
+        },
+        _ => Some(H264SEIPayload::Unknown(data.to_vec()))
+    }
+}
+
+/// Reads a buffering period SEI message, which can only be done knowing the
+/// active SPS. The message names the SPS, which D.2.2 says is the active
+/// one. None if the message isn't one, names another SPS or is cut short.
+pub fn parse_buffering_period(message: &H264SEIMessage, sps: &H264NalUnitSPS) -> Option<H264BufferingPeriod> {
+    if message.payload_type != SEI_BUFFERING_PERIOD {
+        return None;
+    }
+    let data = match message.payload {
+        H264SEIPayload::Unknown(ref data) => data,
+        _ => return None
+    };
+    let mut reader = H264NalReader::new_rbsp(data);
+    let seq_parameter_set_id = reader.read_ue()?;
+    if seq_parameter_set_id != sps.seq_parameter_set_id {
+        return None;
+    }
+    let vui = sps.vui_parameters.as_ref()?;
+    let mut period = H264BufferingPeriod {
+        seq_parameter_set_id,
+        nal_initial_cpb_removal_delay: Vec::new(),
+        nal_initial_cpb_removal_delay_offset: Vec::new(),
+        vcl_initial_cpb_removal_delay: Vec::new(),
+        vcl_initial_cpb_removal_delay_offset: Vec::new()
+    };
+    if let Some(ref hrd) = vui.nal_hrd_parameters {
+        let (delays, offsets) = read_initial_cpb_removal_delays(&mut reader, hrd)?;
+        period.nal_initial_cpb_removal_delay = delays;
+        period.nal_initial_cpb_removal_delay_offset = offsets;
+    }
+    if let Some(ref hrd) = vui.vcl_hrd_parameters {
+        let (delays, offsets) = read_initial_cpb_removal_delays(&mut reader, hrd)?;
+        period.vcl_initial_cpb_removal_delay = delays;
+        period.vcl_initial_cpb_removal_delay_offset = offsets;
+    }
+    Some(period)
+}
+
+/// Reads a pic_timing SEI message, which can only be done knowing the
+/// active SPS. None if the message isn't one or is cut short.
+pub fn parse_pic_timing(message: &H264SEIMessage, sps: &H264NalUnitSPS) -> Option<H264PicTiming> {
+    if message.payload_type != SEI_PIC_TIMING {
+        return None;
+    }
+    let data = match message.payload {
+        H264SEIPayload::Unknown(ref data) => data,
+        _ => return None
+    };
+    let vui = sps.vui_parameters.as_ref()?;
+    let mut reader = H264NalReader::new_rbsp(data);
+    let mut timing = H264PicTiming {
+        cpb_removal_delay: None,
+        dpb_output_delay: None,
+        pic_struct: None
+    };
+    // CpbDpbDelaysPresentFlag, the lengths are the same in both.
+    if let Some(hrd) = vui.nal_hrd_parameters.as_ref().or(vui.vcl_hrd_parameters.as_ref()) {
+        timing.cpb_removal_delay = Some(reader.read_u32(hrd.cpb_removal_delay_length_minus1 as u32 + 1)?);
+        timing.dpb_output_delay = Some(reader.read_u32(hrd.dpb_output_delay_length_minus1 as u32 + 1)?);
+    }
+    if vui.pic_struct_present_flag != 0 {
+        timing.pic_struct = Some(reader.read_u8(4)?);
+    }
+    Some(timing)
+}
+
+/// Reads the initial removal delays of one HRD from a buffering period.
+fn read_initial_cpb_removal_delays(reader: &mut H264NalReader, hrd: &H264HDRParameters)
+                                   -> Option<(Vec<u32>, Vec<u32>)> {
+    let length = hrd.initial_cpb_removal_delay_length_minus1 as u32 + 1;
+    let mut delays = Vec::new();
+    let mut offsets = Vec::new();
+    for _ in 0..hrd.cpb_cnt_minus1 + 1 {
+        delays.push(reader.read_u32(length)?);
+        offsets.push(reader.read_u32(length)?);
+    }
+    Some((delays, offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::H264NalWriter;
+
+    #[test]
+    fn last_bytestream_nal_runs_to_the_end() {
+        let data = vec![0x00, 0x00, 0x00, 0x01, 0x09, 0xF0,
+                        0x00, 0x00, 0x01, 0x0C, 0xFF, 0xFF, 0x80];
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        let first = parser.parse_nalunit(0).unwrap();
+        assert_eq!((first.sc_offset, first.data_offset, first.size), (0, 4, 6));
+        let last = parser.parse_nalunit(6).unwrap();
+        assert_eq!((last.sc_offset, last.data_offset, last.size), (6, 9, 7));
+        assert_eq!(parser.nal_data(&last), &[0x0C, 0xFF, 0xFF, 0x80]);
+    }
+
+    #[test]
+    fn hdr_sei_messages_are_parsed() {
+        let mut data = vec![0x00, 0x00, 0x00, 0x01, 0x06];
+        // mastering_display_colour_volume with the BT.2020 primaries, D65
+        // and 1000 / 0.005 cd/m^2
+        data.extend_from_slice(&[137, 24,
+                                 0x84, 0xD0, 0x3E, 0x80, 0x33, 0xC2, 0x86, 0xC4, 0x1D, 0x4C, 0x0B, 0xB8,
+                                 0x3D, 0x13, 0x40, 0x42, 0x00, 0x98, 0x96, 0x80, 0x00, 0x00, 0x03, 0x00, 0x32]);
+        // content_light_level_info of 1000 and 400 cd/m^2
+        data.extend_from_slice(&[144, 4, 0x03, 0xE8, 0x01, 0x90, 0x80]);
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        let unit = parser.parse_nalunit(0).unwrap();
+        let messages = parser.parse_sei(&unit).unwrap();
+        assert_eq!(messages.len(), 2);
+        let mut mdcv = H264MasteringDisplayColourVolume::new();
+        mdcv.display_primaries_x = [34000, 13250, 7500];
+        mdcv.display_primaries_y = [16000, 34500, 3000];
+        mdcv.white_point_x = 15635;
+        mdcv.white_point_y = 16450;
+        mdcv.max_display_mastering_luminance = 10000000;
+        mdcv.min_display_mastering_luminance = 50;
+        assert_eq!(messages[0].payload, H264SEIPayload::MasteringDisplayColourVolume(mdcv));
+        let mut clli = H264ContentLightLevelInfo::new();
+        clli.max_content_light_level = 1000;
+        clli.max_pic_average_light_level = 400;
+        assert_eq!(messages[1].payload, H264SEIPayload::ContentLightLevelInfo(clli));
+    }
+
+    #[test]
+    fn frame_packing_persists_and_bad_sei_is_kept() {
+        let data = vec![
+            // frame_packing_arrangement, side by side with a repetition
+            // period of 1
+            0x00, 0x00, 0x00, 0x01, 0x06, 45, 7, 0x81, 0x81, 0x00, 0x00, 0x03, 0x00, 0x01, 0x20, 0x80,
+            0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x80,
+            // payloadSize runs past the end of the NAL unit
+            0x00, 0x00, 0x00, 0x01, 0x06, 45, 16, 0x81, 0x80,
+            0x00, 0x00, 0x00, 0x01, 0x41, 0x9A, 0x80];
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        let first = parser.parse_access_unit(0).unwrap();
+        assert_eq!(first.nal_units.len(), 2);
+        assert!(first.sei_errors.is_empty());
+        let fpa = first.frame_packing_arrangement.clone().unwrap();
+        assert_eq!(fpa.frame_packing_arrangement_type, 3);
+        assert_eq!(fpa.content_interpretation_type, 1);
+        assert_eq!(fpa.frame_packing_arrangement_repetition_period, 1);
+        assert!(first.is_stereo_3d());
+
+        let second = parser.parse_access_unit(first.size).unwrap();
+        assert_eq!(second.nal_units.len(), 2);
+        assert!(second.sei.is_empty());
+        assert_eq!(second.sei_errors, vec![(0, H264NalParseError::NotEnoughBytes)]);
+        assert_eq!(second.frame_packing_arrangement, Some(fpa));
+    }
+
+    #[test]
+    fn buffering_period_needs_the_sps_it_names() {
+        let mut hrd = H264HDRParameters::new();
+        hrd.bit_rate_value_minus1 = vec![1000];
+        hrd.cpb_size_value_minus1 = vec![2000];
+        hrd.cbr_flag = vec![0];
+        hrd.initial_cpb_removal_delay_length_minus1 = 23;
+        let mut sps = H264NalUnitSPS::new();
+        sps.seq_parameter_set_id = 1;
+        sps.vui_mut().nal_hrd_parameters_present_flag = 1;
+        sps.vui_mut().nal_hrd_parameters = Some(hrd);
+
+        let mut writer = H264NalWriter::new();
+        writer.write_ue(1).unwrap();
+        writer.write_u32(24, 90000).unwrap();
+        writer.write_u32(24, 1234).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let payload = writer.rbsp();
+        let message = H264SEIMessage {
+            payload_type: SEI_BUFFERING_PERIOD,
+            payload_size: payload.len() as u32,
+            payload: H264SEIPayload::Unknown(payload)
+        };
+        let period = parse_buffering_period(&message, &sps).unwrap();
+        assert_eq!(period.seq_parameter_set_id, 1);
+        assert_eq!(period.nal_initial_cpb_removal_delay, vec![90000]);
+        assert_eq!(period.nal_initial_cpb_removal_delay_offset, vec![1234]);
+        assert!(period.vcl_initial_cpb_removal_delay.is_empty());
+
+        sps.seq_parameter_set_id = 0;
+        assert!(parse_buffering_period(&message, &sps).is_none());
+        assert!(parse_pic_timing(&message, &sps).is_none());
+    }
+}
@@ -0,0 +1,775 @@
+use std::cmp;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use rtp::*;
+use sdp::H264FmtpParameters;
+
+const PCAP_MAGIC_MICROSECONDS : u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NANOSECONDS : u32 = 0xA1B23C4D;
+const PCAPNG_SECTION_HEADER : u32 = 0x0A0D0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC : u32 = 0x1A2B3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION : u32 = 1;
+const PCAPNG_SIMPLE_PACKET : u32 = 3;
+const PCAPNG_ENHANCED_PACKET : u32 = 6;
+
+const LINKTYPE_NULL : u32 = 0;
+const LINKTYPE_ETHERNET : u32 = 1;
+const LINKTYPE_RAW : u32 = 101;
+const LINKTYPE_LINUX_SLL : u32 = 113;
+const LINKTYPE_IPV4 : u32 = 228;
+const LINKTYPE_IPV6 : u32 = 229;
+const LINKTYPE_LINUX_SLL2 : u32 = 276;
+
+#[derive(Debug, PartialEq)]
+pub enum H264PcapError {
+    UnknownFormat,
+    Truncated,
+    /// No UDP flow carries H.264 over RTP, or none with the given payload
+    /// type.
+    NoH264Flow
+}
+
+/// One captured frame with its capture time in seconds.
+#[derive(Debug, Clone)]
+pub struct H264PcapPacket {
+    pub time: f64,
+    pub link_type: u32,
+    pub data: Vec<u8>
+}
+
+struct H264PcapReader<'a> {
+    data: &'a [u8],
+    big_endian: bool
+}
+
+impl<'a> H264PcapReader<'a> {
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        if self.data.len() < pos + 2 {
+            return None;
+        }
+        let b = &self.data[pos..pos + 2];
+        Some(if self.big_endian {
+            (b[0] as u16) << 8 | b[1] as u16
+        } else {
+            (b[1] as u16) << 8 | b[0] as u16
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        if self.data.len() < pos + 4 {
+            return None;
+        }
+        let b = &self.data[pos..pos + 4];
+        Some(if self.big_endian {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        } else {
+            (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+        })
+    }
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+/// Reads every packet of a pcap or pcapng capture.
+pub fn read_capture(data: &[u8]) -> Result<Vec<H264PcapPacket>, H264PcapError> {
+    if data.len() < 4 {
+        return Err(H264PcapError::Truncated);
+    }
+    let magic = be_u32(data);
+    if magic == PCAPNG_SECTION_HEADER {
+        read_pcapng(data)
+    } else if magic == PCAP_MAGIC_MICROSECONDS || magic == PCAP_MAGIC_NANOSECONDS ||
+        magic.swap_bytes() == PCAP_MAGIC_MICROSECONDS || magic.swap_bytes() == PCAP_MAGIC_NANOSECONDS {
+        read_pcap(data)
+    } else {
+        Err(H264PcapError::UnknownFormat)
+    }
+}
+
+fn read_pcap(data: &[u8]) -> Result<Vec<H264PcapPacket>, H264PcapError> {
+    let magic = be_u32(data);
+    let reader = H264PcapReader {
+        data,
+        big_endian: magic == PCAP_MAGIC_MICROSECONDS || magic == PCAP_MAGIC_NANOSECONDS
+    };
+    let nanoseconds = magic == PCAP_MAGIC_NANOSECONDS || magic.swap_bytes() == PCAP_MAGIC_NANOSECONDS;
+    let link_type = match reader.u32_at(20) {
+        Some(l) => l & 0x0FFFFFFF,
+        None => return Err(H264PcapError::Truncated)
+    };
+    let mut packets = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        let (seconds, fraction, captured) = match (reader.u32_at(pos), reader.u32_at(pos + 4), reader.u32_at(pos + 8)) {
+            (Some(s), Some(f), Some(c)) => (s, f, c as usize),
+            _ => return Err(H264PcapError::Truncated)
+        };
+        pos += 16;
+        if data.len() < pos + captured {
+            return Err(H264PcapError::Truncated);
+        }
+        let divisor = if nanoseconds { 1e9 } else { 1e6 };
+        packets.push(H264PcapPacket {
+            time: seconds as f64 + fraction as f64 / divisor,
+            link_type,
+            data: data[pos..pos + captured].to_vec()
+        });
+        pos += captured;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(data: &[u8]) -> Result<Vec<H264PcapPacket>, H264PcapError> {
+    let mut packets = Vec::new();
+    let mut reader = H264PcapReader { data, big_endian: false };
+    // Link type and timestamp units per second of each interface of the
+    // current section.
+    let mut interfaces : Vec<(u32, f64)> = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        if be_u32(&data[pos..]) == PCAPNG_SECTION_HEADER {
+            let byte_order = be_u32(&data[pos + 8..]);
+            reader.big_endian = if byte_order == PCAPNG_BYTE_ORDER_MAGIC {
+                true
+            } else if byte_order.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC {
+                false
+            } else {
+                return Err(H264PcapError::UnknownFormat);
+            };
+            interfaces.clear();
+        }
+        let block_type = reader.u32_at(pos).unwrap();
+        let block_length = reader.u32_at(pos + 4).unwrap() as usize;
+        if block_length < 12 || data.len() < pos + block_length {
+            return Err(H264PcapError::Truncated);
+        }
+        let body = pos + 8;
+        let body_end = pos + block_length - 4;
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = reader.u16_at(body).unwrap_or(0) as u32;
+                interfaces.push((link_type, pcapng_timestamp_resolution(&reader, body + 8, body_end)));
+            },
+            PCAPNG_ENHANCED_PACKET => {
+                if body_end < body + 20 {
+                    return Err(H264PcapError::Truncated);
+                }
+                let interface = reader.u32_at(body).unwrap() as usize;
+                let timestamp = (reader.u32_at(body + 4).unwrap() as u64) << 32 | reader.u32_at(body + 8).unwrap() as u64;
+                let captured = reader.u32_at(body + 12).unwrap() as usize;
+                if body_end < body + 20 + captured {
+                    return Err(H264PcapError::Truncated);
+                }
+                let (link_type, resolution) = interfaces.get(interface).cloned().unwrap_or((LINKTYPE_ETHERNET, 1e6));
+                packets.push(H264PcapPacket {
+                    time: timestamp as f64 / resolution,
+                    link_type,
+                    data: data[body + 20..body + 20 + captured].to_vec()
+                });
+            },
+            PCAPNG_SIMPLE_PACKET => {
+                // Simple packets are captured on the first interface and
+                // carry no timestamp.
+                let original = reader.u32_at(body).unwrap_or(0) as usize;
+                let captured = cmp::min(original, body_end.saturating_sub(body + 4));
+                let link_type = interfaces.first().map_or(LINKTYPE_ETHERNET, |i| i.0);
+                packets.push(H264PcapPacket {
+                    time: 0.0,
+                    link_type,
+                    data: data[body + 4..body + 4 + captured].to_vec()
+                });
+            },
+            _ => {}
+        }
+        pos += block_length;
+    }
+    Ok(packets)
+}
+
+/// The if_tsresol option of an interface description block, microseconds
+/// if it isn't there.
+fn pcapng_timestamp_resolution(reader: &H264PcapReader, mut pos: usize, end: usize) -> f64 {
+    while pos + 4 <= end {
+        let code = reader.u16_at(pos).unwrap();
+        let length = reader.u16_at(pos + 2).unwrap() as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && length >= 1 && pos + 5 <= end {
+            let value = reader.data[pos + 4];
+            return if value & 0x80 != 0 {
+                2f64.powi((value & 0x7F) as i32)
+            } else {
+                10f64.powi(value as i32)
+            };
+        }
+        pos += 4 + length.div_ceil(4) * 4;
+    }
+    1e6
+}
+
+/// A UDP datagram taken out of a captured frame.
+#[derive(Debug, Clone)]
+pub struct H264UdpDatagram<'a> {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: &'a [u8]
+}
+
+/// Finds the UDP datagram in a captured frame. Fragmented IPv4 packets and
+/// IPv6 packets with extension headers are skipped.
+pub fn udp_datagram(packet: &H264PcapPacket) -> Option<H264UdpDatagram<'_>> {
+    let data = &packet.data[..];
+    let ip = match packet.link_type {
+        LINKTYPE_ETHERNET => {
+            let mut pos = 12;
+            let mut ether_type = ((*data.get(pos)? as u16) << 8) | *data.get(pos + 1)? as u16;
+            // Skip VLAN tags
+            while ether_type == 0x8100 || ether_type == 0x88A8 {
+                pos += 4;
+                ether_type = ((*data.get(pos)? as u16) << 8) | *data.get(pos + 1)? as u16;
+            }
+            &data[pos + 2..]
+        },
+        LINKTYPE_NULL => data.get(4..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        _ => return None
+    };
+    let version = *ip.first()? >> 4;
+    let (source, destination, udp) = if version == 4 {
+        let header_length = (ip[0] & 0x0F) as usize * 4;
+        if ip.len() < 20 || ip[9] != 17 {
+            return None;
+        }
+        let fragment = ((ip[6] as u16) << 8 | ip[7] as u16) & 0x3FFF;
+        if fragment != 0 {
+            return None;
+        }
+        let source = IpAddr::V4(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+        let destination = IpAddr::V4(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+        (source, destination, ip.get(header_length..)?)
+    } else if version == 6 {
+        if ip.len() < 40 || ip[6] != 17 {
+            return None;
+        }
+        let mut source = [0u8; 16];
+        let mut destination = [0u8; 16];
+        source.copy_from_slice(&ip[8..24]);
+        destination.copy_from_slice(&ip[24..40]);
+        (IpAddr::V6(Ipv6Addr::from(source)), IpAddr::V6(Ipv6Addr::from(destination)), &ip[40..])
+    } else {
+        return None;
+    };
+    if udp.len() < 8 {
+        return None;
+    }
+    let length = (udp[4] as usize) << 8 | udp[5] as usize;
+    let end = if length >= 8 && length <= udp.len() { length } else { udp.len() };
+    Some(H264UdpDatagram {
+        source: SocketAddr::new(source, (udp[0] as u16) << 8 | udp[1] as u16),
+        destination: SocketAddr::new(destination, (udp[2] as u16) << 8 | udp[3] as u16),
+        payload: &udp[8..end]
+    })
+}
+
+/// An RTP stream: one SSRC and payload type between two endpoints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264RtpFlow {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub ssrc: u32,
+    pub payload_type: u8
+}
+
+#[derive(Debug, Clone)]
+pub struct H264RtpFlowInfo {
+    pub flow: H264RtpFlow,
+    pub packets: u64,
+    /// Packets whose payload starts like an RFC 6184 payload.
+    pub h264_packets: u64,
+    /// Packets with parameter sets, alone, aggregated or as first fragment.
+    pub parameter_set_packets: u64,
+    /// Seen STAP-B, MTAP or FU-B packets, which need interleaved mode.
+    pub interleaved: bool
+}
+
+impl H264RtpFlowInfo {
+    /// Dynamic payload type, nearly all packets look like H.264 and
+    /// parameter sets show up.
+    pub fn looks_like_h264(&self) -> bool {
+        self.flow.payload_type >= 96 && self.parameter_set_packets > 0 &&
+            self.h264_packets * 10 >= self.packets * 9
+    }
+}
+
+/// The NAL unit type a payload starts with, looking into STAP-A and FU
+/// packets, or None if it can't be an RFC 6184 payload.
+fn payload_nal_type(payload: &[u8]) -> Option<u8> {
+    let header = *payload.first()?;
+    if header & 0x80 != 0 {
+        return None;
+    }
+    match header & 0x1F {
+        0 | 30 | 31 => None,
+        RTP_STAP_A => payload.get(3).map(|b| b & 0x1F),
+        RTP_STAP_B => payload.get(5).map(|b| b & 0x1F),
+        RTP_MTAP16 | RTP_MTAP24 => Some(header & 0x1F),
+        RTP_FU_A | RTP_FU_B => {
+            let fu_header = *payload.get(1)?;
+            match fu_header & 0x1F {
+                0 | 24..=31 => None,
+                t => Some(t)
+            }
+        },
+        t => Some(t)
+    }
+}
+
+/// Every RTP flow in a capture, in order of first appearance.
+pub fn find_rtp_flows(packets: &[H264PcapPacket]) -> Vec<H264RtpFlowInfo> {
+    let mut flows : Vec<H264RtpFlowInfo> = Vec::new();
+    for packet in packets {
+        let datagram = match udp_datagram(packet) {
+            Some(d) => d,
+            None => continue
+        };
+        let (header, payload) = match parse_rtp_packet(datagram.payload) {
+            Some(p) => p,
+            None => continue
+        };
+        let flow = H264RtpFlow {
+            source: datagram.source,
+            destination: datagram.destination,
+            ssrc: header.ssrc,
+            payload_type: header.payload_type
+        };
+        let index = match flows.iter().position(|f| f.flow == flow) {
+            Some(i) => i,
+            None => {
+                flows.push(H264RtpFlowInfo {
+                    flow,
+                    packets: 0,
+                    h264_packets: 0,
+                    parameter_set_packets: 0,
+                    interleaved: false
+                });
+                flows.len() - 1
+            }
+        };
+        let info = &mut flows[index];
+        info.packets += 1;
+        if let Some(nal_type) = payload_nal_type(payload) {
+            info.h264_packets += 1;
+            if nal_type == 7 || nal_type == 8 {
+                info.parameter_set_packets += 1;
+            }
+            let packet_type = payload[0] & 0x1F;
+            if packet_type == RTP_STAP_B || packet_type == RTP_MTAP16 ||
+                packet_type == RTP_MTAP24 || packet_type == RTP_FU_B {
+                info.interleaved = true;
+            }
+        }
+    }
+    flows
+}
+
+/// What happened to one access unit of an extracted flow.
+#[derive(Debug, Clone)]
+pub struct H264RtpFrameReport {
+    pub timestamp: u32,
+    /// Capture time of the first packet of the access unit.
+    pub capture_time: f64,
+    pub packets: u64,
+    /// Packets lost in the sequence numbers right before or inside the
+    /// access unit.
+    pub packets_lost: u64,
+    pub bytes: usize,
+    pub nal_units: usize,
+    pub complete: bool,
+    pub idr: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct H264RtpExtraction {
+    pub flow: H264RtpFlow,
+    /// The access units as an Annex B byte stream.
+    pub bytestream: Vec<u8>,
+    pub frames: Vec<H264RtpFrameReport>,
+    pub packets_lost: u64,
+    pub nal_units_dropped: u64,
+    /// Packets the depacketizer rejected, such as truncated aggregation
+    /// packets or packet types the packetization mode doesn't allow.
+    pub packets_rejected: u64
+}
+
+/// Finds the H.264 flow of a pcap or pcapng capture and depacketizes it.
+/// Without a payload type the flow with the most H.264-looking packets is
+/// taken. Packets are put in sequence number order first, since captures
+/// keep the order they arrived in. The packetization mode and
+/// sprop-interleaving-depth come from the fmtp parameters of the SDP;
+/// without them the mode is guessed from the packet types and interleaved
+/// NAL units are passed on as they arrive.
+pub fn extract_h264(capture: &[u8], payload_type: Option<u8>,
+                    fmtp: Option<&H264FmtpParameters>) -> Result<H264RtpExtraction, H264PcapError> {
+    let packets = read_capture(capture)?;
+    let flows = find_rtp_flows(&packets);
+    let flow = match payload_type {
+        Some(pt) => flows.iter()
+            .filter(|f| f.flow.payload_type == pt)
+            .max_by_key(|f| f.h264_packets),
+        None => flows.iter()
+            .filter(|f| f.looks_like_h264())
+            .max_by_key(|f| f.h264_packets)
+    };
+    let info = match flow {
+        Some(f) => f.clone(),
+        None => return Err(H264PcapError::NoH264Flow)
+    };
+
+    // (extended sequence number, capture time, header, payload)
+    let mut rtp = Vec::new();
+    let mut last_extended : Option<i64> = None;
+    for packet in &packets {
+        let datagram = match udp_datagram(packet) {
+            Some(d) => d,
+            None => continue
+        };
+        if datagram.source != info.flow.source || datagram.destination != info.flow.destination {
+            continue;
+        }
+        let (header, payload) = match parse_rtp_packet(datagram.payload) {
+            Some(p) => p,
+            None => continue
+        };
+        if header.ssrc != info.flow.ssrc || header.payload_type != info.flow.payload_type {
+            continue;
+        }
+        let extended = match last_extended {
+            Some(last) => last + (header.sequence_number.wrapping_sub(last as u16) as i16) as i64,
+            None => header.sequence_number as i64
+        };
+        last_extended = Some(cmp::max(last_extended.unwrap_or(extended), extended));
+        rtp.push((extended, packet.time, header, payload.to_vec()));
+    }
+    rtp.sort_by_key(|p| p.0);
+    rtp.dedup_by_key(|p| p.0);
+
+    let mut depacketizer = match fmtp {
+        Some(fmtp) => H264RtpDepacketizer::from_fmtp(fmtp),
+        None => H264RtpDepacketizer::new(if info.interleaved { 2 } else { 1 })
+    };
+    let mut extraction = H264RtpExtraction {
+        flow: info.flow,
+        bytestream: Vec::new(),
+        frames: Vec::new(),
+        packets_lost: 0,
+        nal_units_dropped: 0,
+        packets_rejected: 0
+    };
+    // (timestamp, capture time, packets, packets lost) of each access unit
+    // as the packets arrive.
+    let mut arrivals : Vec<(u32, f64, u64, u64)> = Vec::new();
+    for &(_, time, header, ref payload) in &rtp {
+        let lost = depacketizer.packets_lost;
+        if depacketizer.push(payload, header.sequence_number, header.timestamp, header.marker).is_err() {
+            extraction.packets_rejected += 1;
+        }
+        let lost = depacketizer.packets_lost - lost;
+        match arrivals.iter_mut().rev().find(|a| a.0 == header.timestamp) {
+            Some(arrival) => {
+                arrival.2 += 1;
+                arrival.3 += lost;
+            },
+            None => arrivals.push((header.timestamp, time, 1, lost))
+        }
+        collect_frames(&mut depacketizer, &mut arrivals, &mut extraction);
+    }
+    depacketizer.flush();
+    collect_frames(&mut depacketizer, &mut arrivals, &mut extraction);
+    extraction.packets_lost = depacketizer.packets_lost;
+    extraction.nal_units_dropped = depacketizer.nal_units_dropped;
+    Ok(extraction)
+}
+
+fn collect_frames(depacketizer: &mut H264RtpDepacketizer, arrivals: &mut Vec<(u32, f64, u64, u64)>,
+                  extraction: &mut H264RtpExtraction) {
+    while let Some(au) = depacketizer.pop_access_unit() {
+        let bytes = au.to_bytes(H264NalFormat::BYTESTREAM, 4);
+        let (capture_time, packets, lost) = match arrivals.iter().position(|a| a.0 == au.timestamp) {
+            Some(i) => {
+                let a = arrivals.remove(i);
+                (a.1, a.2, a.3)
+            },
+            None => (0.0, 0, 0)
+        };
+        extraction.frames.push(H264RtpFrameReport {
+            timestamp: au.timestamp,
+            capture_time,
+            packets,
+            packets_lost: lost,
+            bytes: bytes.len(),
+            nal_units: au.nal_units.len(),
+            complete: au.complete,
+            idr: au.is_idr()
+        });
+        extraction.bytestream.extend_from_slice(&bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp(payload: &[u8]) -> Vec<u8> {
+        let mut udp = Vec::new();
+        udp.extend_from_slice(&5004u16.to_be_bytes());
+        udp.extend_from_slice(&5006u16.to_be_bytes());
+        udp.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(payload);
+        udp
+    }
+
+    /// An IPv4 packet from 10.0.0.1:5004 to 10.0.0.2:5006.
+    fn ipv4(payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        ip.extend_from_slice(&udp(payload));
+        ip
+    }
+
+    /// An IPv6 packet from ::1:5004 to ::2:5006.
+    fn ipv6(payload: &[u8]) -> Vec<u8> {
+        let udp = udp(payload);
+        let mut ip = vec![0x60, 0, 0, 0];
+        ip.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+        ip.extend_from_slice(&[17, 64]);
+        ip.extend_from_slice(&Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).octets());
+        ip.extend_from_slice(&Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2).octets());
+        ip.extend_from_slice(&udp);
+        ip
+    }
+
+    /// A pcap file with the frames captured a millisecond apart.
+    fn pcap(link_type: u32, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &field in &[PCAP_MAGIC_MICROSECONDS, 0x00020004, 0, 0, 65535, link_type] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            for &field in &[1, i as u32 * 1000, frame.len() as u32, frame.len() as u32] {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    /// A pcap file of raw IPv4 packets carrying the RTP packets.
+    fn capture(rtp_packets: &[Vec<u8>]) -> Vec<u8> {
+        let frames : Vec<Vec<u8>> = rtp_packets.iter().map(|p| ipv4(p)).collect();
+        pcap(LINKTYPE_RAW, &frames)
+    }
+
+    fn rtp_packet(payload_type: u8, ssrc: u32, sequence_number: u16, timestamp: u32, marker: bool,
+                  payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, payload_type | if marker { 0x80 } else { 0 }];
+        packet.extend_from_slice(&sequence_number.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn rtp(sequence_number: u16, marker: bool, payload: &[u8]) -> Vec<u8> {
+        rtp_packet(96, 0x1234, sequence_number, 3000, marker, payload)
+    }
+
+    /// A pcapng block in the given byte order, padded to 32 bits.
+    fn block(big_endian: bool, block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().div_ceil(4) * 4;
+        let length = (12 + padded) as u32;
+        let word = |v: u32| if big_endian { v.to_be_bytes() } else { v.to_le_bytes() };
+        let mut data = Vec::new();
+        data.extend_from_slice(&word(block_type));
+        data.extend_from_slice(&word(length));
+        data.extend_from_slice(body);
+        data.resize(8 + padded, 0);
+        data.extend_from_slice(&word(length));
+        data
+    }
+
+    #[test]
+    fn pcapng_blocks_are_read() {
+        let frame = ipv4(&rtp(1, true, &[0x65, 0x88]));
+        let mut data = Vec::new();
+        // A little endian section with an Ethernet interface in microseconds
+        // and a raw IP interface in nanoseconds.
+        data.extend(block(false, PCAPNG_SECTION_HEADER, &[0x4D, 0x3C, 0x2B, 0x1A, 1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF,
+                                                          0xFF, 0xFF, 0xFF, 0xFF]));
+        data.extend(block(false, PCAPNG_INTERFACE_DESCRIPTION, &[1, 0, 0, 0, 0, 0, 0, 0]));
+        data.extend(block(false, PCAPNG_INTERFACE_DESCRIPTION, &[101, 0, 0, 0, 0, 0, 0, 0,
+                                                                 9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]));
+        let mut epb = vec![1, 0, 0, 0];
+        // 3.5 s in nanoseconds
+        let timestamp = 3_500_000_000u64;
+        epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&frame);
+        data.extend(block(false, PCAPNG_ENHANCED_PACKET, &epb));
+        // Interface statistics are skipped.
+        data.extend(block(false, 5, &[0; 12]));
+        let mut spb = (frame.len() as u32).to_le_bytes().to_vec();
+        spb.extend_from_slice(&frame);
+        data.extend(block(false, PCAPNG_SIMPLE_PACKET, &spb));
+        // A big endian section with a raw IPv6 interface.
+        let ipv6_frame = ipv6(&rtp(2, true, &[0x41, 0x9A]));
+        data.extend(block(true, PCAPNG_SECTION_HEADER, &[0x1A, 0x2B, 0x3C, 0x4D, 0, 1, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF,
+                                                         0xFF, 0xFF, 0xFF, 0xFF]));
+        data.extend(block(true, PCAPNG_INTERFACE_DESCRIPTION, &[0, 229, 0, 0, 0, 0, 0, 0]));
+        let mut epb = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0F, 0x42, 0x40];
+        epb.extend_from_slice(&(ipv6_frame.len() as u32).to_be_bytes());
+        epb.extend_from_slice(&(ipv6_frame.len() as u32).to_be_bytes());
+        epb.extend_from_slice(&ipv6_frame);
+        data.extend(block(true, PCAPNG_ENHANCED_PACKET, &epb));
+
+        let packets = read_capture(&data).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!((packets[0].link_type, packets[0].time), (LINKTYPE_RAW, 3.5));
+        assert_eq!(packets[0].data, frame);
+        // Simple packets take the first interface of the section.
+        assert_eq!((packets[1].link_type, packets[1].time), (LINKTYPE_ETHERNET, 0.0));
+        assert_eq!(packets[1].data, frame);
+        assert_eq!((packets[2].link_type, packets[2].time), (LINKTYPE_IPV6, 1.0));
+        assert_eq!(packets[2].data, ipv6_frame);
+
+        let mut truncated = data.clone();
+        truncated.truncate(data.len() - 4);
+        assert_eq!(read_capture(&truncated).unwrap_err(), H264PcapError::Truncated);
+        assert_eq!(read_capture(&[1, 2, 3, 4, 5]).unwrap_err(), H264PcapError::UnknownFormat);
+    }
+
+    #[test]
+    fn ethernet_vlan_and_ipv6_framing() {
+        let payload = rtp(1, true, &[0x65, 0x88]);
+        // Ethernet with an 802.1ad and an 802.1Q tag, and padding after
+        // the UDP datagram.
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x88, 0xA8, 0, 10, 0x81, 0x00, 0, 20, 0x08, 0x00]);
+        frame.extend(ipv4(&payload));
+        frame.extend_from_slice(&[0; 6]);
+        let packet = H264PcapPacket { time: 0.0, link_type: LINKTYPE_ETHERNET, data: frame };
+        let datagram = udp_datagram(&packet).unwrap();
+        assert_eq!(datagram.source, "10.0.0.1:5004".parse().unwrap());
+        assert_eq!(datagram.destination, "10.0.0.2:5006".parse().unwrap());
+        assert_eq!(datagram.payload, &payload[..]);
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x86, 0xDD]);
+        frame.extend(ipv6(&payload));
+        let packet = H264PcapPacket { time: 0.0, link_type: LINKTYPE_ETHERNET, data: frame };
+        let datagram = udp_datagram(&packet).unwrap();
+        assert_eq!(datagram.source, "[::1]:5004".parse().unwrap());
+        assert_eq!(datagram.destination, "[::2]:5006".parse().unwrap());
+        assert_eq!(datagram.payload, &payload[..]);
+
+        let mut frame = vec![2, 0, 0, 0];
+        frame.extend(ipv4(&payload));
+        let packet = H264PcapPacket { time: 0.0, link_type: LINKTYPE_NULL, data: frame };
+        assert_eq!(udp_datagram(&packet).unwrap().payload, &payload[..]);
+
+        // Fragments, other protocols and unknown link types are skipped.
+        let mut ip = ipv4(&payload);
+        ip[6] = 0x20;
+        assert!(udp_datagram(&H264PcapPacket { time: 0.0, link_type: LINKTYPE_IPV4, data: ip }).is_none());
+        let mut ip = ipv4(&payload);
+        ip[9] = 6;
+        assert!(udp_datagram(&H264PcapPacket { time: 0.0, link_type: LINKTYPE_IPV4, data: ip }).is_none());
+        assert!(udp_datagram(&H264PcapPacket { time: 0.0, link_type: 147, data: ipv4(&payload) }).is_none());
+        assert!(udp_datagram(&H264PcapPacket { time: 0.0, link_type: LINKTYPE_ETHERNET, data: vec![0; 13] }).is_none());
+    }
+
+    #[test]
+    fn the_h264_flow_is_found() {
+        let mut packets = Vec::new();
+        for i in 0..10u16 {
+            // Audio with a static payload type
+            packets.push(rtp_packet(0, 0x1111, i, i as u32 * 160, false, &[0xFF; 20]));
+            // A dynamic payload type that isn't H.264
+            packets.push(rtp_packet(97, 0x2222, i, i as u32 * 960, false, &[0xFC, 0xFF, 0xFE]));
+        }
+        packets.push(rtp_packet(96, 0x3333, 7, 3000, false, &[0x78, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xCE]));
+        packets.push(rtp_packet(96, 0x3333, 8, 3000, true, &[0x65, 0x88]));
+        packets.push(rtp_packet(96, 0x3333, 9, 6000, true, &[0x41, 0x9A]));
+        let data = capture(&packets);
+
+        let flows = find_rtp_flows(&read_capture(&data).unwrap());
+        assert_eq!(flows.len(), 3);
+        assert_eq!((flows[0].packets, flows[0].looks_like_h264()), (10, false));
+        assert_eq!((flows[1].packets, flows[1].h264_packets, flows[1].looks_like_h264()), (10, 0, false));
+        assert_eq!((flows[2].packets, flows[2].parameter_set_packets, flows[2].looks_like_h264()), (3, 1, true));
+        assert!(!flows[2].interleaved);
+
+        let extraction = extract_h264(&data, None, None).unwrap();
+        assert_eq!(extraction.flow.ssrc, 0x3333);
+        assert_eq!(extraction.frames.len(), 2);
+        assert!(extraction.frames[0].idr && extraction.frames[0].complete);
+        assert_eq!(extraction.frames[0].packets, 2);
+        assert_eq!(extraction.bytestream[..8], [0, 0, 0, 1, 0x67, 0x42, 0, 0]);
+
+        assert_eq!(extract_h264(&data, Some(8), None).unwrap_err(), H264PcapError::NoH264Flow);
+        // Without H.264 flows there's nothing to guess.
+        let data = capture(&packets[..20]);
+        assert_eq!(extract_h264(&data, None, None).unwrap_err(), H264PcapError::NoH264Flow);
+    }
+
+    #[test]
+    fn interleaved_mode_takes_its_depth_from_the_fmtp() {
+        let data = capture(&[
+            // STAP-B with DON 65534 and 65535
+            rtp_packet(96, 0x1234, 1, 100, false, &[0x79, 0xFF, 0xFE, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xCE]),
+            // MTAP16 with the P slice of the next picture (DON 1) before the
+            // IDR slice (DON 0)
+            rtp_packet(96, 0x1234, 2, 100, true, &[0x7A, 0, 0, 0, 5, 1, 0x0B, 0xB8, 0x41, 0x9A,
+                                                   0, 5, 0, 0, 0, 0x65, 0x88])]);
+        let flows = find_rtp_flows(&read_capture(&data).unwrap());
+        assert!(flows[0].interleaved);
+
+        // The guessed interleaved mode can't reorder without the depth.
+        let extraction = extract_h264(&data, Some(96), None).unwrap();
+        assert_eq!(extraction.packets_rejected, 0);
+        assert_eq!(extraction.frames.iter().map(|f| f.timestamp).collect::<Vec<_>>(), vec![100, 3100, 100]);
+
+        let fmtp = H264FmtpParameters::parse("packetization-mode=2;sprop-interleaving-depth=2").unwrap();
+        let extraction = extract_h264(&data, Some(96), Some(&fmtp)).unwrap();
+        assert_eq!(extraction.frames.iter().map(|f| (f.timestamp, f.nal_units)).collect::<Vec<_>>(),
+                   vec![(100, 3), (3100, 1)]);
+        assert!(extraction.frames[0].idr);
+
+        // The SDP's packetization mode wins over the guess.
+        let fmtp = H264FmtpParameters::parse("packetization-mode=1").unwrap();
+        let extraction = extract_h264(&data, Some(96), Some(&fmtp)).unwrap();
+        assert_eq!(extraction.packets_rejected, 2);
+        assert!(extraction.frames.is_empty());
+    }
+
+    #[test]
+    fn rejected_packets_are_counted() {
+        let data = capture(&[
+            rtp(1, false, &[0x67, 0x42, 0x00, 0x1E]),
+            // STAP-A whose NAL unit is longer than the packet
+            rtp(2, false, &[0x18, 0x00, 0x10, 0x68]),
+            // Reserved packet type
+            rtp(3, false, &[0x1F, 0x85, 0x00]),
+            rtp(4, true, &[0x65, 0x88, 0x80])]);
+        let extraction = extract_h264(&data, Some(96), None).unwrap();
+        assert_eq!(extraction.packets_rejected, 2);
+        assert_eq!(extraction.packets_lost, 0);
+        assert_eq!(extraction.frames.len(), 1);
+        assert_eq!(extraction.frames[0].nal_units, 2);
+    }
+}
@@ -0,0 +1,639 @@
+use std::cmp;
+use parser::{H264NalParser, H264NalParseError, parse_pic_timing};
+pub use types::*;
+
+/// TopFieldOrderCnt and BottomFieldOrderCnt of a picture. Only the one of
+/// the coded field is meaningful for a field picture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264PictureOrderCount {
+    pub top: i32,
+    pub bottom: i32,
+    pub field_pic: bool,
+    pub bottom_field: bool
+}
+
+impl H264PictureOrderCount {
+    /// PicOrderCnt() of the picture, equation 8-1.
+    pub fn pic_order_cnt(&self) -> i32 {
+        if !self.field_pic {
+            if self.top < self.bottom { self.top } else { self.bottom }
+        } else if self.bottom_field {
+            self.bottom
+        } else {
+            self.top
+        }
+    }
+}
+
+/// Follows the decoding process for picture order count of 8.2.1 across
+/// the pictures of a stream. Give it the first slice of every picture in
+/// decoding order.
+pub struct H264PocCalculator {
+    prev_pic_order_cnt_msb: i32,
+    prev_pic_order_cnt_lsb: i32,
+    prev_frame_num_offset: i32,
+    prev_frame_num: u32,
+    prev_has_mmco5: bool
+}
+
+impl Default for H264PocCalculator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264PocCalculator {
+    pub fn new() -> H264PocCalculator {
+        H264PocCalculator {
+            prev_pic_order_cnt_msb: 0,
+            prev_pic_order_cnt_lsb: 0,
+            prev_frame_num_offset: 0,
+            prev_frame_num: 0,
+            prev_has_mmco5: false
+        }
+    }
+
+    /// The picture order count of the picture the slice belongs to. For a
+    /// picture with memory_management_control_operation 5 this is the
+    /// value before the reset; the following pictures count from 0 again.
+    pub fn compute(&mut self, sps: &H264NalUnitSPS, nalu: &H264NalUnit,
+                   slice: &H264NalUnitSlice) -> H264PictureOrderCount {
+        let idr = nalu.idr_pic_flag;
+        let reference = nalu.nal_ref_idc != 0;
+        let has_mmco5 = slice.memory_management_control_operations.iter()
+            .any(|op| op.memory_management_control_operation == 5);
+        let delta0 = *slice.delta_pic_order_cnt.first().unwrap_or(&0);
+        let delta1 = *slice.delta_pic_order_cnt.get(1).unwrap_or(&0);
+        let max_frame_num = 1i32 << (sps.log2_max_frame_num_minus4 + 4);
+        let mut poc = H264PictureOrderCount {
+            top: 0,
+            bottom: 0,
+            field_pic: slice.field_pic_flag,
+            bottom_field: slice.bottom_field_flag
+        };
+
+        let mut frame_num_offset = 0;
+        match sps.pic_order_cnt_type {
+            0 => {
+                if idr {
+                    self.prev_pic_order_cnt_msb = 0;
+                    self.prev_pic_order_cnt_lsb = 0;
+                }
+                let max_lsb = 1i32 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+                let lsb = slice.pic_order_cnt_lsb as i32;
+                let prev_lsb = self.prev_pic_order_cnt_lsb;
+                let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+                    self.prev_pic_order_cnt_msb + max_lsb
+                } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+                    self.prev_pic_order_cnt_msb - max_lsb
+                } else {
+                    self.prev_pic_order_cnt_msb
+                };
+                if !slice.field_pic_flag {
+                    poc.top = msb + lsb;
+                    poc.bottom = poc.top + slice.delta_pic_order_cnt_bottom;
+                } else if slice.bottom_field_flag {
+                    poc.bottom = msb + lsb;
+                } else {
+                    poc.top = msb + lsb;
+                }
+                if reference {
+                    if has_mmco5 {
+                        // 8.2.1: the reset leaves the top field at
+                        // TopFieldOrderCnt - tempPicOrderCnt.
+                        self.prev_pic_order_cnt_msb = 0;
+                        self.prev_pic_order_cnt_lsb = if slice.field_pic_flag && slice.bottom_field_flag {
+                            0
+                        } else {
+                            poc.top - poc.pic_order_cnt()
+                        };
+                    } else {
+                        self.prev_pic_order_cnt_msb = msb;
+                        self.prev_pic_order_cnt_lsb = lsb;
+                    }
+                }
+            },
+            1 => {
+                frame_num_offset = self.frame_num_offset(idr, slice.frame_num, max_frame_num);
+                let cycle = sps.num_ref_frames_in_pic_order_cnt_cycle as i32;
+                let mut abs_frame_num = if cycle != 0 { frame_num_offset + slice.frame_num as i32 } else { 0 };
+                if !reference && abs_frame_num > 0 {
+                    abs_frame_num -= 1;
+                }
+                let mut expected = 0;
+                if abs_frame_num > 0 {
+                    let cycle_cnt = (abs_frame_num - 1) / cycle;
+                    let frame_num_in_cycle = (abs_frame_num - 1) % cycle;
+                    let delta_per_cycle : i32 = sps.offset_for_ref_frame.iter().take(cycle as usize).sum();
+                    expected = cycle_cnt * delta_per_cycle;
+                    for i in 0..(frame_num_in_cycle + 1) as usize {
+                        expected += *sps.offset_for_ref_frame.get(i).unwrap_or(&0);
+                    }
+                }
+                if !reference {
+                    expected += sps.offset_for_non_ref_pic;
+                }
+                if !slice.field_pic_flag {
+                    poc.top = expected + delta0;
+                    poc.bottom = poc.top + sps.offset_for_top_to_bottom_field + delta1;
+                } else if slice.bottom_field_flag {
+                    poc.bottom = expected + sps.offset_for_top_to_bottom_field + delta0;
+                } else {
+                    poc.top = expected + delta0;
+                }
+            },
+            _ => {
+                frame_num_offset = self.frame_num_offset(idr, slice.frame_num, max_frame_num);
+                let temp = if idr {
+                    0
+                } else if !reference {
+                    2 * (frame_num_offset + slice.frame_num as i32) - 1
+                } else {
+                    2 * (frame_num_offset + slice.frame_num as i32)
+                };
+                poc.top = temp;
+                poc.bottom = temp;
+            }
+        }
+
+        self.prev_frame_num_offset = if has_mmco5 { 0 } else { frame_num_offset };
+        self.prev_frame_num = if has_mmco5 { 0 } else { slice.frame_num };
+        self.prev_has_mmco5 = has_mmco5;
+        poc
+    }
+
+    fn frame_num_offset(&self, idr: bool, frame_num: u32, max_frame_num: i32) -> i32 {
+        if idr {
+            0
+        } else if self.prev_frame_num > frame_num {
+            self.prev_frame_num_offset + max_frame_num
+        } else {
+            self.prev_frame_num_offset
+        }
+    }
+
+    /// True if the last picture reset the picture order count with
+    /// memory_management_control_operation 5.
+    pub fn last_had_mmco5(&self) -> bool {
+        self.prev_has_mmco5
+    }
+}
+
+/// Timestamps of an access unit from H264TimestampGenerator, in units of
+/// its clock_rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264AccessUnitTiming {
+    pub pts: u64,
+    pub dts: u64,
+    /// How long the picture is shown, including pic_struct repeats.
+    pub duration: u64,
+    /// The picture order count started over: an IDR picture, or the one
+    /// after memory_management_control_operation 5.
+    pub poc_reset: bool,
+    /// The PTS comes from dpb_output_delay instead of the picture order
+    /// count.
+    pub from_pic_timing: bool
+}
+
+/// Derives the timestamps of access units that come without any, such as
+/// those of a raw elementary stream.
+///
+/// Time is counted in clock ticks of the VUI timing info, num_units_in_tick
+/// / time_scale, which is a field period. Without timing info the ticks
+/// come from frame_duration or default_frame_rate. The DTS of each access
+/// unit follows the previous one by its display duration (a frame, a
+/// field, or what pic_struct says), or comes from cpb_removal_delay when
+/// pic_timing SEI has it. The PTS comes from dpb_output_delay, or else from
+/// the picture order count, assuming it goes up by 2 per frame as most
+/// encoders do, delayed by max_num_reorder_frames (2 frames when the VUI
+/// doesn't say).
+pub struct H264TimestampGenerator {
+    pub clock_rate: u64,
+    /// Frame duration in clock_rate ticks, overriding the VUI.
+    pub frame_duration: Option<u64>,
+    /// Frame rate as a fraction, for streams without VUI timing info.
+    pub default_frame_rate: (u32, u32),
+    /// DTS of the first access unit.
+    pub first_dts: u64,
+
+    poc: H264PocCalculator,
+    access_units: u64,
+    /// Clock ticks since the first access unit for the next DTS.
+    next_ticks: u64,
+    last_dts_ticks: u64,
+    /// The clock tick of the last access unit with slices.
+    last_tick: Option<(u64, u64)>,
+    /// Ticks of the last access unit with a buffering period SEI, which
+    /// cpb_removal_delay counts from.
+    buffering_period_ticks: Option<u64>,
+    /// PTS in ticks of picture order count 0 of the current coded video
+    /// sequence.
+    poc_base: i64,
+    reorder_delay: u64
+}
+
+impl H264TimestampGenerator {
+    pub fn new(clock_rate: u64) -> H264TimestampGenerator {
+        H264TimestampGenerator {
+            clock_rate,
+            frame_duration: None,
+            default_frame_rate: (30, 1),
+            first_dts: 0,
+            poc: H264PocCalculator::new(),
+            access_units: 0,
+            next_ticks: 0,
+            last_dts_ticks: 0,
+            last_tick: None,
+            buffering_period_ticks: None,
+            poc_base: 0,
+            reorder_delay: 0
+        }
+    }
+
+    /// The length of a clock tick as a fraction of clock_rate ticks.
+    fn tick(&self, sps: Option<&H264NalUnitSPS>) -> (u64, u64) {
+        if let Some(d) = self.frame_duration {
+            return (d, 2);
+        }
+        match sps.and_then(|s| s.frame_rate_fraction()) {
+            Some((num, den)) => (self.clock_rate * den, 2 * num),
+            None => {
+                let (num, den) = self.default_frame_rate;
+                (self.clock_rate * den as u64, 2 * num as u64)
+            }
+        }
+    }
+
+    fn ticks_to_clock(&self, ticks: u64, tick: (u64, u64)) -> u64 {
+        self.first_dts + ticks * tick.0 / tick.1
+    }
+
+    /// The frame duration in clock_rate ticks.
+    pub fn frame_duration(&self, sps: Option<&H264NalUnitSPS>) -> u64 {
+        let (num, den) = self.tick(sps);
+        2 * num / den
+    }
+
+    /// PTS and DTS of the next access unit in decoding order, given the
+    /// first slice of its primary coded picture and the active SPS.
+    pub fn next(&mut self, picture: Option<(&H264NalUnitSPS, &H264NalUnit, &H264NalUnitSlice)>) -> (u64, u64) {
+        match picture {
+            Some((sps, nalu, slice)) => {
+                let timing = self.timing(sps, nalu, slice, &[]);
+                (timing.pts, timing.dts)
+            },
+            None => {
+                // No slices to go by, keep the pace of the last frame.
+                let tick = self.last_tick.unwrap_or_else(|| self.tick(None));
+                let dts_ticks = self.next_ticks;
+                self.access_units += 1;
+                self.last_dts_ticks = dts_ticks;
+                self.next_ticks = dts_ticks + 2;
+                (self.ticks_to_clock(dts_ticks + self.reorder_delay, tick), self.ticks_to_clock(dts_ticks, tick))
+            }
+        }
+    }
+
+    /// Timestamps of an access unit from H264NalParser::parse_access_unit,
+    /// storing its parameter sets in the parser. None if it has no slices.
+    pub fn access_unit_timing(&mut self, parser: &mut H264NalParser, au: &H264AccessUnit)
+                              -> Result<Option<H264AccessUnitTiming>, H264NalParseError> {
+        let mut first_slice = None;
+        for unit in &au.nal_units {
+            match unit.nal_unit_type_num {
+                7 => { parser.parse_sps(unit.data_offset)?; },
+                8 => { parser.parse_pps(unit.data_offset)?; },
+                1..=5 if first_slice.is_none() => {
+                    first_slice = Some((unit, parser.parse_slice(unit.data_offset, unit)?));
+                },
+                _ => {}
+            }
+        }
+        let (unit, slice) = match first_slice {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+        let sps = match parser.find_pps(slice.pic_parameter_set_id)
+            .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id)) {
+            Some(sps) => sps.clone(),
+            None => return Ok(None)
+        };
+        Ok(Some(self.timing(&sps, unit, &slice, &au.sei)))
+    }
+
+    /// Timestamps of the next access unit in decoding order, given the
+    /// first slice of its primary coded picture, the active SPS and the
+    /// SEI messages of the access unit.
+    pub fn timing(&mut self, sps: &H264NalUnitSPS, nalu: &H264NalUnit, slice: &H264NalUnitSlice,
+                  sei: &[H264SEIMessage]) -> H264AccessUnitTiming {
+        let tick = self.tick(Some(sps));
+        self.last_tick = Some(tick);
+        let pic_timing = sei.iter().filter_map(|m| parse_pic_timing(m, sps)).next();
+        let buffering_period = sei.iter().any(|m| m.payload_type == SEI_BUFFERING_PERIOD);
+        let first = self.access_units == 0;
+        self.access_units += 1;
+
+        // C.1.2: the removal time counts from the last buffering period,
+        // or from the previous one for an access unit that starts one.
+        let mut dts_ticks = self.next_ticks;
+        let removal = pic_timing.and_then(|t| t.cpb_removal_delay)
+            .and_then(|delay| self.buffering_period_ticks.map(|b| b + delay as u64));
+        if let Some(removal) = removal {
+            if removal >= self.last_dts_ticks && !first {
+                dts_ticks = removal;
+            }
+        }
+        if buffering_period || self.buffering_period_ticks.is_none() {
+            self.buffering_period_ticks = Some(dts_ticks);
+        }
+        let display_ticks = pic_timing.and_then(|t| t.display_ticks())
+            .unwrap_or(if slice.field_pic_flag { 1 } else { 2 });
+        self.last_dts_ticks = dts_ticks;
+        self.next_ticks = dts_ticks + display_ticks;
+
+        let after_mmco5 = self.poc.last_had_mmco5();
+        let poc = self.poc.compute(sps, nalu, slice).pic_order_cnt();
+        let poc_reset = !first && (nalu.idr_pic_flag || after_mmco5);
+        if poc_reset || first {
+            // The delay never shrinks, or the new sequence could be shown
+            // before the end of the previous one.
+            let reorder_frames = match sps.vui_parameters {
+                Some(ref vui) if vui.bitstream_restriction_flag != 0 => vui.max_num_reorder_frames as u64,
+                _ => if sps.pic_order_cnt_type == 2 { 0 } else { 2 }
+            };
+            self.reorder_delay = cmp::max(self.reorder_delay, reorder_frames * 2);
+            self.poc_base = (dts_ticks + self.reorder_delay) as i64 - poc as i64;
+        }
+
+        let output_delay = pic_timing.and_then(|t| t.dpb_output_delay);
+        let pts_ticks = match output_delay {
+            Some(delay) => dts_ticks + delay as u64,
+            None => {
+                let mut pts = self.poc_base + poc as i64;
+                if pts < dts_ticks as i64 {
+                    // More reordering than expected, delay the rest of the
+                    // sequence.
+                    let late = dts_ticks as i64 - pts;
+                    self.poc_base += late;
+                    self.reorder_delay += late as u64;
+                    pts = dts_ticks as i64;
+                }
+                pts as u64
+            }
+        };
+
+        H264AccessUnitTiming {
+            pts: self.ticks_to_clock(pts_ticks, tick),
+            dts: self.ticks_to_clock(dts_ticks, tick),
+            duration: display_ticks * tick.0 / tick.1,
+            poc_reset,
+            from_pic_timing: output_delay.is_some()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sps(pic_order_cnt_type: u32) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.pic_order_cnt_type = pic_order_cnt_type;
+        sps.log2_max_frame_num_minus4 = 0;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = 0;
+        sps
+    }
+
+    fn nalu(idr: bool, reference: bool) -> H264NalUnit {
+        H264NalUnit::new(0, 4, 0, if reference { 2 } else { 0 }, if idr { 5 } else { 1 })
+    }
+
+    fn slice(frame_num: u32, pic_order_cnt_lsb: u16) -> H264NalUnitSlice {
+        let mut slice = H264NalUnitSlice::new();
+        slice.frame_num = frame_num;
+        slice.pic_order_cnt_lsb = pic_order_cnt_lsb;
+        slice
+    }
+
+    fn field(frame_num: u32, pic_order_cnt_lsb: u16, bottom: bool) -> H264NalUnitSlice {
+        let mut slice = slice(frame_num, pic_order_cnt_lsb);
+        slice.field_pic_flag = true;
+        slice.bottom_field_flag = bottom;
+        slice
+    }
+
+    fn mmco5(mut slice: H264NalUnitSlice) -> H264NalUnitSlice {
+        slice.memory_management_control_operations.push(H264MemoryManagementOperation::new(5));
+        slice
+    }
+
+    /// PicOrderCnt() of each picture, given as (idr, reference, slice).
+    fn pocs(sps: &H264NalUnitSPS, pictures: &[(bool, bool, H264NalUnitSlice)]) -> Vec<i32> {
+        let mut calculator = H264PocCalculator::new();
+        pictures.iter()
+            .map(|&(idr, reference, ref slice)| calculator.compute(sps, &nalu(idr, reference), slice).pic_order_cnt())
+            .collect()
+    }
+
+    #[test]
+    fn type_0_follows_the_lsb_across_wraps() {
+        let sps = sps(0);
+        assert_eq!(pocs(&sps, &[
+            (true, true, slice(0, 0)),
+            (false, true, slice(1, 6)),
+            (false, false, slice(2, 2)),
+            (false, true, slice(2, 12)),
+            // MaxPicOrderCntLsb is 16.
+            (false, true, slice(3, 2)),
+            // Non-reference pictures don't move prevPicOrderCntMsb.
+            (false, false, slice(4, 14)),
+            (false, true, slice(4, 6)),
+            (false, true, slice(5, 12)),
+            (false, true, slice(6, 0)),
+            (true, true, slice(0, 4))
+        ]), vec![0, 6, 2, 12, 18, 14, 22, 28, 32, 4]);
+
+        let mut calculator = H264PocCalculator::new();
+        let mut frame = slice(0, 4);
+        frame.delta_pic_order_cnt_bottom = -1;
+        let poc = calculator.compute(&sps, &nalu(true, true), &frame);
+        assert_eq!((poc.top, poc.bottom, poc.pic_order_cnt()), (4, 3, 3));
+    }
+
+    #[test]
+    fn type_1_expects_the_offsets_of_the_cycle() {
+        let mut sps = sps(1);
+        sps.num_ref_frames_in_pic_order_cnt_cycle = 2;
+        sps.offset_for_ref_frame = vec![4, 2];
+        sps.offset_for_non_ref_pic = -3;
+        sps.offset_for_top_to_bottom_field = 1;
+        let mut delta = slice(4, 0);
+        delta.delta_pic_order_cnt = vec![2, -2];
+        assert_eq!(pocs(&sps, &[
+            (true, true, slice(0, 0)),
+            (false, true, slice(1, 0)),
+            (false, true, slice(2, 0)),
+            (false, false, slice(3, 0)),
+            (false, true, slice(3, 0)),
+            (false, true, delta)
+        ]), vec![0, 4, 6, 3, 10, 13]);
+
+        // The bottom field is offset_for_top_to_bottom_field later, and
+        // frame_num wraps at 16.
+        let mut calculator = H264PocCalculator::new();
+        let top = calculator.compute(&sps, &nalu(true, true), &field(0, 0, false));
+        let bottom = calculator.compute(&sps, &nalu(false, true), &field(0, 0, true));
+        assert_eq!((top.pic_order_cnt(), bottom.pic_order_cnt()), (0, 1));
+        calculator.compute(&sps, &nalu(false, true), &slice(15, 0));
+        let wrapped = calculator.compute(&sps, &nalu(false, true), &slice(0, 0));
+        // FrameNumOffset 16, absFrameNum 16: 7 cycles of 6, then 4 and 2
+        assert_eq!((wrapped.top, wrapped.bottom), (48, 49));
+    }
+
+    #[test]
+    fn type_2_counts_frames() {
+        let sps = sps(2);
+        assert_eq!(pocs(&sps, &[
+            (true, true, slice(0, 0)),
+            (false, true, slice(1, 0)),
+            (false, false, slice(2, 0)),
+            (false, true, slice(2, 0)),
+            (false, true, slice(15, 0)),
+            (false, true, slice(0, 0)),
+            (false, true, slice(1, 0))
+        ]), vec![0, 2, 3, 4, 30, 32, 34]);
+    }
+
+    #[test]
+    fn mmco5_starts_the_count_over() {
+        let sps0 = sps(0);
+        let mut calculator = H264PocCalculator::new();
+        calculator.compute(&sps0, &nalu(true, true), &slice(0, 0));
+        assert_eq!(calculator.compute(&sps0, &nalu(false, true), &mmco5(slice(1, 8))).pic_order_cnt(), 8);
+        assert!(calculator.last_had_mmco5());
+        assert_eq!(calculator.compute(&sps0, &nalu(false, true), &slice(1, 4)).pic_order_cnt(), 4);
+        assert!(!calculator.last_had_mmco5());
+
+        // When the bottom field comes first, prevPicOrderCntLsb is left at
+        // TopFieldOrderCnt - tempPicOrderCnt.
+        let mut calculator = H264PocCalculator::new();
+        calculator.compute(&sps0, &nalu(true, true), &slice(0, 0));
+        let mut frame = mmco5(slice(1, 8));
+        frame.delta_pic_order_cnt_bottom = -2;
+        assert_eq!(calculator.compute(&sps0, &nalu(false, true), &frame).pic_order_cnt(), 6);
+        assert_eq!(calculator.compute(&sps0, &nalu(false, true), &slice(1, 10)).pic_order_cnt(), 10);
+
+        // frame_num counts from 0 again.
+        let sps2 = sps(2);
+        assert_eq!(pocs(&sps2, &[
+            (true, true, slice(0, 0)),
+            (false, true, slice(1, 0)),
+            (false, true, mmco5(slice(2, 0))),
+            (false, true, slice(1, 0))
+        ]), vec![0, 2, 4, 2]);
+    }
+
+    #[test]
+    fn field_pairs_share_the_count_of_their_frame() {
+        let sps = sps(0);
+        let mut calculator = H264PocCalculator::new();
+        let pairs = [(true, 0, 0), (false, 1, 4), (false, 2, 8)];
+        let mut counts = Vec::new();
+        for &(idr, frame_num, lsb) in &pairs {
+            let top = calculator.compute(&sps, &nalu(idr, true), &field(frame_num, lsb, false));
+            let bottom = calculator.compute(&sps, &nalu(idr, true), &field(frame_num, lsb + 1, true));
+            assert!(top.field_pic && !top.bottom_field && bottom.bottom_field);
+            counts.push((top.pic_order_cnt(), bottom.pic_order_cnt()));
+        }
+        assert_eq!(counts, vec![(0, 1), (4, 5), (8, 9)]);
+
+        // Field pictures last one tick.
+        let mut generator = H264TimestampGenerator::new(90000);
+        let sps = self::sps(2);
+        let dts : Vec<u64> = [(true, field(0, 0, false)), (true, field(0, 0, true)), (false, slice(1, 0))].iter()
+            .map(|&(idr, ref slice)| generator.timing(&sps, &nalu(idr, true), slice, &[]).dts)
+            .collect();
+        assert_eq!(dts, vec![0, 1500, 3000]);
+    }
+
+    /// 25 frames a second with pic_struct and, if given, HRD delays of 8
+    /// bits.
+    fn timed_sps(hrd: bool) -> H264NalUnitSPS {
+        let mut sps = sps(2);
+        let mut vui = H264VUIParameters::new();
+        vui.timing_info_present_flag = 1;
+        vui.num_units_in_tick = 1;
+        vui.time_scale = 50;
+        if hrd {
+            let mut hrd = H264HDRParameters::new();
+            hrd.cpb_removal_delay_length_minus1 = 7;
+            hrd.dpb_output_delay_length_minus1 = 7;
+            vui.nal_hrd_parameters_present_flag = 1;
+            vui.nal_hrd_parameters = Some(hrd);
+        } else {
+            vui.pic_struct_present_flag = 1;
+        }
+        sps.vui_parameters = Some(vui);
+        sps
+    }
+
+    fn sei(payload_type: u32, payload: &[u8]) -> H264SEIMessage {
+        H264SEIMessage {
+            payload_type,
+            payload_size: payload.len() as u32,
+            payload: H264SEIPayload::Unknown(payload.to_vec())
+        }
+    }
+
+    #[test]
+    fn pic_struct_sets_the_display_duration() {
+        let sps = timed_sps(false);
+        let mut generator = H264TimestampGenerator::new(90000);
+        assert_eq!(generator.frame_duration(Some(&sps)), 3600);
+        // A frame, top bottom top, frame doubling, then a field.
+        let pic_structs = [0, 5, 7, 1];
+        let timing : Vec<(u64, u64)> = pic_structs.iter().enumerate()
+            .map(|(i, &pic_struct)| {
+                let t = generator.timing(&sps, &nalu(i == 0, true), &slice(i as u32, 0),
+                                         &[sei(SEI_PIC_TIMING, &[pic_struct << 4])]);
+                assert!(!t.from_pic_timing);
+                (t.dts, t.duration)
+            })
+            .collect();
+        assert_eq!(timing, vec![(0, 3600), (3600, 5400), (9000, 7200), (16200, 1800)]);
+    }
+
+    #[test]
+    fn hrd_delays_give_the_timestamps() {
+        let sps = timed_sps(true);
+        let mut generator = H264TimestampGenerator::new(90000);
+        generator.first_dts = 1000;
+        // I, P and B in decoding order, then a P removed late.
+        let delays = [(0, 2), (2, 6), (4, 2), (8, 6)];
+        let timing : Vec<(u64, u64)> = delays.iter().enumerate()
+            .map(|(i, &(cpb, dpb))| {
+                let mut sei_messages = vec![sei(SEI_PIC_TIMING, &[cpb, dpb])];
+                if i == 0 {
+                    sei_messages.push(sei(SEI_BUFFERING_PERIOD, &[0x80]));
+                }
+                let t = generator.timing(&sps, &nalu(i == 0, i != 2), &slice(i as u32, 0), &sei_messages);
+                assert!(t.from_pic_timing);
+                (t.pts - 1000, t.dts - 1000)
+            })
+            .collect();
+        assert_eq!(timing, vec![(3600, 0), (14400, 3600), (10800, 7200), (25200, 14400)]);
+    }
+
+    #[test]
+    fn access_units_without_slices_keep_the_pace() {
+        let sps = timed_sps(false);
+        let mut generator = H264TimestampGenerator::new(90000);
+        let t = generator.timing(&sps, &nalu(true, true), &slice(0, 0), &[]);
+        assert_eq!((t.pts, t.dts), (0, 0));
+        // A frame each at the rate of the last slice, not the default one
+        assert_eq!(generator.next(None), (3600, 3600));
+        assert_eq!(generator.next(None), (7200, 7200));
+        let t = generator.timing(&sps, &nalu(false, true), &slice(1, 0), &[]);
+        assert_eq!(t.dts, 10800);
+    }
+}
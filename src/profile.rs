@@ -0,0 +1,593 @@
+use parser::H264NalParseError;
+use writer::{rewrite_sps, H264NalWriter};
+use avcc::H264AVCDecoderConfigurationRecord;
+use sdp::{base64_decode, base64_encode, parse_fmtp, format_fmtp};
+use level::{level_limits, cpb_br_nal_factor, cpb_br_vcl_factor, H264LevelLimit};
+pub use types::*;
+
+pub const PROFILE_BASELINE : u8 = 66;
+pub const PROFILE_MAIN : u8 = 77;
+pub const PROFILE_EXTENDED : u8 = 88;
+pub const PROFILE_HIGH : u8 = 100;
+pub const PROFILE_HIGH_10 : u8 = 110;
+pub const PROFILE_HIGH_422 : u8 = 122;
+pub const PROFILE_HIGH_444 : u8 = 244;
+pub const PROFILE_CAVLC_444_INTRA : u8 = 44;
+
+/// level_idc of level 1b in profiles other than Baseline, Main and Extended.
+pub const LEVEL_IDC_1B : u8 = 9;
+
+const LEVEL_IDCS : [u8; 20] = [9, 10, 11, 12, 13, 20, 21, 22, 30, 31, 32, 40, 41, 42, 50, 51, 52, 60, 61, 62];
+
+#[derive(Debug)]
+pub enum H264ProfileLevelError {
+    UnknownLevel(u8),
+    /// The stream uses a tool or format the new profile doesn't allow.
+    Violation(&'static str),
+    /// The SPS needs more than the level allows.
+    LevelLimit(H264LevelLimit),
+    Parse(H264NalParseError)
+}
+
+/// profile_idc, the constraint_set flags and level_idc, laid out like the
+/// three bytes of profile-level-id and of the avcC profile fields. Bit 7 of
+/// constraint_flags is constraint_set0_flag, the low two bits are reserved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264ProfileLevel {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8
+}
+
+impl H264ProfileLevel {
+    pub fn new(profile_idc: u8, constraint_flags: u8, level_idc: u8) -> H264ProfileLevel {
+        H264ProfileLevel {
+            profile_idc,
+            constraint_flags,
+            level_idc
+        }
+    }
+
+    /// Level 1b, which Baseline, Main and Extended signal as level_idc 11
+    /// with constraint_set3_flag and the other profiles as level_idc 9.
+    pub fn level_1b(profile_idc: u8, constraint_flags: u8) -> H264ProfileLevel {
+        if profile_has_level_1b_flag(profile_idc) {
+            H264ProfileLevel::new(profile_idc, constraint_flags | 0x10, 11)
+        } else {
+            H264ProfileLevel::new(profile_idc, constraint_flags & !0x10, LEVEL_IDC_1B)
+        }
+    }
+
+    pub fn constraint_set_flag(&self, i: u8) -> bool {
+        (self.constraint_flags >> (7 - i)) & 0x01 == 1
+    }
+
+    pub fn is_level_1b(&self) -> bool {
+        self.level_idc == LEVEL_IDC_1B ||
+            (self.level_idc == 11 && profile_has_level_1b_flag(self.profile_idc) && self.constraint_set_flag(3))
+    }
+
+    /// The level times ten, with level 1b as 9.
+    pub fn level_number(&self) -> u8 {
+        if self.is_level_1b() { LEVEL_IDC_1B } else { self.level_idc }
+    }
+
+    /// The six hex digits of the RFC 6184 profile-level-id.
+    pub fn profile_level_id(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.profile_idc, self.constraint_flags, self.level_idc)
+    }
+
+    pub fn from_profile_level_id(profile_level_id: &str) -> Option<H264ProfileLevel> {
+        let id = profile_level_id.trim();
+        if id.len() != 6 {
+            return None;
+        }
+        let value = match u32::from_str_radix(id, 16) {
+            Ok(v) => v,
+            Err(_) => return None
+        };
+        Some(H264ProfileLevel::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+    }
+
+    /// The RFC 6381 codec string, such as avc1.64001f.
+    pub fn codec_string(&self) -> String {
+        format!("avc1.{}", self.profile_level_id())
+    }
+}
+
+fn profile_has_level_1b_flag(profile_idc: u8) -> bool {
+    profile_idc == PROFILE_BASELINE || profile_idc == PROFILE_MAIN || profile_idc == PROFILE_EXTENDED
+}
+
+impl H264NalUnitSPS {
+    pub fn profile_level(&self) -> H264ProfileLevel {
+        H264ProfileLevel::new(
+            self.profile_idc,
+            self.constraint_0_flag << 7 | self.constraint_1_flag << 6 | self.constraint_2_flag << 5 |
+                self.constraint_3_flag << 4 | self.constraint_4_flag << 3 | self.constraint_5_flag << 2,
+            self.level_idc)
+    }
+
+    /// Changes the profile, constraint flags and level after checking that
+    /// this SPS and the PPSs that refer to it conform to them. Switching
+    /// between profiles with and without chroma_format_idc in the SPS keeps
+    /// the chroma format, bit depths and scaling matrices.
+    pub fn set_profile_level(&mut self, profile_level: &H264ProfileLevel,
+                             pps: &[H264NalUnitPPS]) -> Result<(), H264ProfileLevelError> {
+        self.check_profile_level(profile_level, pps)?;
+        self.apply_profile_level(profile_level);
+        Ok(())
+    }
+
+    fn apply_profile_level(&mut self, profile_level: &H264ProfileLevel) {
+        self.profile_idc = profile_level.profile_idc;
+        self.constraint_0_flag = profile_level.constraint_set_flag(0) as u8;
+        self.constraint_1_flag = profile_level.constraint_set_flag(1) as u8;
+        self.constraint_2_flag = profile_level.constraint_set_flag(2) as u8;
+        self.constraint_3_flag = profile_level.constraint_set_flag(3) as u8;
+        self.constraint_4_flag = profile_level.constraint_set_flag(4) as u8;
+        self.constraint_5_flag = profile_level.constraint_set_flag(5) as u8;
+        self.level_idc = profile_level.level_idc;
+    }
+
+    /// Checks the parts of Annex A that can be told from the parameter sets.
+    /// Only PPSs referring to this SPS are looked at.
+    pub fn check_profile_level(&self, profile_level: &H264ProfileLevel,
+                               pps: &[H264NalUnitPPS]) -> Result<(), H264ProfileLevelError> {
+        let pps : Vec<&H264NalUnitPPS> = pps.iter()
+            .filter(|p| p.seq_parameter_set_id == self.seq_parameter_set_id)
+            .collect();
+        let profile = profile_level.profile_idc;
+        if !LEVEL_IDCS.contains(&profile_level.level_idc) {
+            return Err(H264ProfileLevelError::UnknownLevel(profile_level.level_idc));
+        }
+        if profile_level.level_idc == LEVEL_IDC_1B && profile_has_level_1b_flag(profile) {
+            return Err(H264ProfileLevelError::Violation("level_idc 9 is only allowed in High profiles"));
+        }
+        if profile_level.constraint_set_flag(3) && profile_has_level_1b_flag(profile) && profile_level.level_idc != 11 {
+            return Err(H264ProfileLevelError::Violation("constraint_set3_flag is reserved in this profile except for level 1b"));
+        }
+        if !profile_idc_has_chroma_info(profile) && profile_idc_has_chroma_info(self.profile_idc) {
+            if self.chroma_format_idc != 1 || self.bit_depth_luma_minus8 != 0 || self.bit_depth_chroma_minus8 != 0 {
+                return Err(H264ProfileLevelError::Violation("profile only allows 8 bit 4:2:0"));
+            }
+            if self.seq_scaling_matrix_present_flag != 0 {
+                return Err(H264ProfileLevelError::Violation("profile doesn't allow scaling matrices"));
+            }
+        }
+
+        let baseline = profile == PROFILE_BASELINE || profile_level.constraint_set_flag(0);
+        let main = profile == PROFILE_MAIN || profile_level.constraint_set_flag(1);
+        let extended = profile == PROFILE_EXTENDED || profile_level.constraint_set_flag(2);
+        if (baseline || extended) && pps.iter().any(|p| p.entropy_coding_mode_flag) {
+            return Err(H264ProfileLevelError::Violation("Baseline and Extended don't allow CABAC"));
+        }
+        if baseline {
+            if !self.frame_mbs_only_flag {
+                return Err(H264ProfileLevelError::Violation("Baseline doesn't allow interlaced coding"));
+            }
+            if pps.iter().any(|p| p.weighted_pred_flag || p.weighted_bipred_idc != 0) {
+                return Err(H264ProfileLevelError::Violation("Baseline doesn't allow weighted prediction"));
+            }
+        }
+        if extended && self.direct_8x8_inference_flag == 0 {
+            return Err(H264ProfileLevelError::Violation("Extended requires direct_8x8_inference_flag"));
+        }
+        if main || profile_idc_has_chroma_info(profile) {
+            if pps.iter().any(|p| p.num_slice_groups_minus1 > 0) {
+                return Err(H264ProfileLevelError::Violation("profile doesn't allow slice groups"));
+            }
+            if pps.iter().any(|p| p.redundant_pic_cnt_present_flag) {
+                return Err(H264ProfileLevelError::Violation("profile doesn't allow redundant pictures"));
+            }
+        }
+        if !profile_idc_has_chroma_info(profile) &&
+            pps.iter().any(|p| p.transform_8x8_mode_flag != 0 || p.pic_scaling_matrix_present_flag != 0) {
+            return Err(H264ProfileLevelError::Violation("8x8 transform and scaling matrices need a High profile"));
+        }
+        let (max_chroma_format_idc, max_bit_depth_minus8) = match profile {
+            PROFILE_HIGH => (1, 0),
+            PROFILE_HIGH_10 => (1, 2),
+            PROFILE_HIGH_422 => (2, 2),
+            PROFILE_HIGH_444 | PROFILE_CAVLC_444_INTRA => (3, 6),
+            _ => (1, 0)
+        };
+        if self.chroma_format_idc > max_chroma_format_idc {
+            return Err(H264ProfileLevelError::Violation("chroma format not allowed in profile"));
+        }
+        if self.bit_depth_luma_minus8 > max_bit_depth_minus8 || self.bit_depth_chroma_minus8 > max_bit_depth_minus8 {
+            return Err(H264ProfileLevelError::Violation("bit depth not allowed in profile"));
+        }
+        if self.qpprime_y_zero_transform_bypass_flag != 0 && profile != PROFILE_HIGH_444 && profile != PROFILE_CAVLC_444_INTRA {
+            return Err(H264ProfileLevelError::Violation("lossless coding needs High 4:4:4"));
+        }
+        if profile == PROFILE_CAVLC_444_INTRA && pps.iter().any(|p| p.entropy_coding_mode_flag) {
+            return Err(H264ProfileLevelError::Violation("CAVLC 4:4:4 Intra doesn't allow CABAC"));
+        }
+        if profile_level.constraint_set_flag(4) && !self.frame_mbs_only_flag {
+            return Err(H264ProfileLevelError::Violation("constraint_set4_flag requires frame_mbs_only_flag"));
+        }
+        self.check_level_limits(profile_level)
+    }
+
+    /// The Table A-1 limits which only need the SPS: frame size, DPB size
+    /// and the bit rates and CPB sizes of the HRD parameters.
+    fn check_level_limits(&self, profile_level: &H264ProfileLevel) -> Result<(), H264ProfileLevelError> {
+        let limits = match level_limits(profile_level.level_number()) {
+            Some(l) => l,
+            None => return Err(H264ProfileLevelError::UnknownLevel(profile_level.level_idc))
+        };
+        let frame_size = self.frame_size_in_mbs();
+        if frame_size > limits.max_fs {
+            return Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxFS));
+        }
+        if self.max_num_ref_frames as u64 * frame_size as u64 > limits.max_dpb_mbs as u64 {
+            return Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxDpbMbs));
+        }
+        let vui = match self.vui_parameters {
+            Some(ref vui) if self.vui_parameters_present_flag != 0 => vui,
+            _ => return Ok(())
+        };
+        let hrds = [
+            (vui.nal_hrd_parameters_present_flag, &vui.nal_hrd_parameters, cpb_br_nal_factor(profile_level.profile_idc)),
+            (vui.vcl_hrd_parameters_present_flag, &vui.vcl_hrd_parameters, cpb_br_vcl_factor(profile_level.profile_idc))
+        ];
+        for &(present, hrd, factor) in &hrds {
+            let hrd = match *hrd {
+                Some(ref hrd) if present != 0 => hrd,
+                _ => continue
+            };
+            for i in 0..hrd.cpb_cnt_minus1 as usize + 1 {
+                if hrd.bit_rate(i).unwrap_or(0) > factor as u64 * limits.max_br as u64 {
+                    return Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxBR));
+                }
+                if hrd.cpb_size(i).unwrap_or(0) > factor as u64 * limits.max_cpb as u64 {
+                    return Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxCPB));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Changes the profile and level of every SPS in a stream, refusing to if
+/// any SPS or the PPSs referring to it don't conform to the new profile.
+pub fn rewrite_profile_level(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                             profile_level: &H264ProfileLevel) -> Result<Vec<u8>, H264ProfileLevelError> {
+    let (sps, pps) = match collect_parameter_sets(data, format, nal_length_size) {
+        Ok(sets) => sets,
+        Err(e) => return Err(H264ProfileLevelError::Parse(e))
+    };
+    for s in &sps {
+        s.check_profile_level(profile_level, &pps)?;
+    }
+    rewrite_sps(data, format, nal_length_size, |s| s.apply_profile_level(profile_level))
+        .map_err(H264ProfileLevelError::Parse)
+}
+
+/// Every SPS and PPS of a stream, in the order they appear.
+pub fn collect_parameter_sets(data: &[u8], format: H264NalFormat, nal_length_size: usize)
+    -> Result<(Vec<H264NalUnitSPS>, Vec<H264NalUnitPPS>), H264NalParseError>
+{
+    let mut parser = ::parser::H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut sps = Vec::new();
+    let mut pps = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let unit = parser.parse_nalunit(offset)?;
+        match unit.nal_unit_type {
+            H264NalUnitType::SPS => sps.push(parser.parse_sps(unit.data_offset)?),
+            H264NalUnitType::PPS => pps.push(parser.parse_pps(unit.data_offset)?),
+            _ => {}
+        }
+        offset += unit.size;
+    }
+    Ok((sps, pps))
+}
+
+fn sps_nal_ref_idc(nal: &[u8]) -> u8 {
+    nal.first().map_or(3, |b| (b >> 5) & 0x03)
+}
+
+impl H264AVCDecoderConfigurationRecord {
+    pub fn profile_level(&self) -> H264ProfileLevel {
+        H264ProfileLevel::new(self.avc_profile_indication, self.profile_compatibility, self.avc_level_indication)
+    }
+
+    /// Changes the profile and level of the SPSs in the record and of the
+    /// profile fields, with the same checks as H264NalUnitSPS::set_profile_level.
+    pub fn set_profile_level(&mut self, profile_level: &H264ProfileLevel) -> Result<(), H264ProfileLevelError> {
+        let (mut sps, pps) = self.parse_parameter_sets().map_err(H264ProfileLevelError::Parse)?;
+        for s in &sps {
+            s.check_profile_level(profile_level, &pps)?;
+        }
+        for (i, s) in sps.iter_mut().enumerate() {
+            s.apply_profile_level(profile_level);
+            let nal_ref_idc = sps_nal_ref_idc(&self.sequence_parameter_sets[i]);
+            let mut writer = H264NalWriter::new();
+            s.write(&mut writer).map_err(|e| H264ProfileLevelError::Parse(e.into()))?;
+            self.sequence_parameter_sets[i] = writer.to_nal(nal_ref_idc, 7);
+        }
+        match sps.first() {
+            Some(s) => self.update_from_sps(s),
+            None => {
+                self.avc_profile_indication = profile_level.profile_idc;
+                self.profile_compatibility = profile_level.constraint_flags;
+                self.avc_level_indication = profile_level.level_idc;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Changes profile-level-id and the SPSs in sprop-parameter-sets of the
+/// parameters of an a=fmtp line. The PPSs in sprop-parameter-sets are used
+/// for the conformance check. Other parameters are kept as they are.
+pub fn rewrite_fmtp_profile_level(fmtp: &str, profile_level: &H264ProfileLevel) -> Result<String, H264ProfileLevelError> {
+    let prefix = if fmtp.starts_with("a=fmtp:") {
+        match fmtp.find(' ') {
+            Some(i) => &fmtp[..i + 1],
+            None => fmtp
+        }
+    } else {
+        ""
+    };
+    let mut params = parse_fmtp(fmtp);
+    for &mut (ref name, ref mut value) in params.iter_mut() {
+        if name.eq_ignore_ascii_case("profile-level-id") {
+            *value = profile_level.profile_level_id();
+        } else if name.eq_ignore_ascii_case("sprop-parameter-sets") {
+            let mut nals = Vec::new();
+            for set in value.split(',') {
+                match base64_decode(set) {
+                    Some(ref nal) if !nal.is_empty() => nals.push(nal.clone()),
+                    _ => return Err(H264ProfileLevelError::Parse(H264NalParseError::GenericParseError))
+                }
+            }
+            let mut record = H264AVCDecoderConfigurationRecord::new();
+            let is_sps : Vec<bool> = nals.iter().map(|n| n[0] & 0x1F == 7).collect();
+            for (nal, &sps) in nals.iter().zip(is_sps.iter()) {
+                if sps {
+                    record.sequence_parameter_sets.push(nal.clone());
+                } else if nal[0] & 0x1F == 8 {
+                    record.picture_parameter_sets.push(nal.clone());
+                }
+            }
+            record.set_profile_level(profile_level)?;
+            let mut sps_iter = record.sequence_parameter_sets.into_iter();
+            let mut sets = Vec::with_capacity(nals.len());
+            for (nal, sps) in nals.into_iter().zip(is_sps) {
+                let nal = if sps {
+                    match sps_iter.next() {
+                        Some(rewritten) => rewritten,
+                        None => return Err(H264ProfileLevelError::Parse(H264NalParseError::GenericParseError))
+                    }
+                } else {
+                    nal
+                };
+                sets.push(base64_encode(&nal));
+            }
+            *value = sets.join(",");
+        }
+    }
+    Ok(format!("{}{}", prefix, format_fmtp(&params)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::write_nalunit;
+
+    fn sps_1080p(max_num_ref_frames: u32) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = PROFILE_HIGH;
+        sps.level_idc = 40;
+        sps.chroma_format_idc = 1;
+        sps.max_num_ref_frames = max_num_ref_frames;
+        sps.pic_width_in_mbs_minus1 = 119;
+        sps.pic_height_in_map_units_minus1 = 67;
+        sps.frame_mbs_only_flag = true;
+        sps.direct_8x8_inference_flag = 1;
+        sps
+    }
+
+    fn check(sps: &H264NalUnitSPS, level_idc: u8) -> Result<(), H264ProfileLevelError> {
+        sps.check_profile_level(&H264ProfileLevel::new(sps.profile_idc, 0, level_idc), &[])
+    }
+
+    #[test]
+    fn frame_size_and_dpb_against_level() {
+        assert!(check(&sps_1080p(4), 40).is_ok());
+        match check(&sps_1080p(4), 31) {
+            Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxFS)) => {},
+            r => panic!("{:?}", r)
+        }
+        // 8160 macroblocks a frame, level 4 has room for 4 of them.
+        match check(&sps_1080p(5), 40) {
+            Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxDpbMbs)) => {},
+            r => panic!("{:?}", r)
+        }
+        assert!(check(&sps_1080p(5), 50).is_ok());
+    }
+
+    #[test]
+    fn hrd_against_level() {
+        let mut hrd = H264HDRParameters::new();
+        // 25 Mbit/s and a 25 Mbit CPB, within 1.25 * 20000 kbit/s and
+        // 1.25 * 25000 kbit of High profile level 4.
+        hrd.bit_rate_scale = 0;
+        hrd.bit_rate_value_minus1 = vec![25000000 / 64 - 1];
+        hrd.cpb_size_scale = 0;
+        hrd.cpb_size_value_minus1 = vec![25000000 / 16 - 1];
+        hrd.cbr_flag = vec![0];
+        let mut vui = H264VUIParameters::new();
+        vui.vcl_hrd_parameters_present_flag = 1;
+        vui.vcl_hrd_parameters = Some(hrd.clone());
+        let mut sps = sps_1080p(4);
+        sps.vui_parameters_present_flag = 1;
+        sps.vui_parameters = Some(vui.clone());
+        assert!(check(&sps, 40).is_ok());
+
+        // The same rate is too much for Main, where the factor is 1000.
+        sps.profile_idc = PROFILE_MAIN;
+        match check(&sps, 40) {
+            Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxBR)) => {},
+            r => panic!("{:?}", r)
+        }
+
+        hrd.bit_rate_value_minus1 = vec![20000000 / 64 - 1];
+        hrd.cpb_size_value_minus1 = vec![40000000 / 16 - 1];
+        vui.vcl_hrd_parameters = None;
+        vui.vcl_hrd_parameters_present_flag = 0;
+        vui.nal_hrd_parameters_present_flag = 1;
+        vui.nal_hrd_parameters = Some(hrd);
+        sps.profile_idc = PROFILE_HIGH;
+        sps.vui_parameters = Some(vui);
+        match check(&sps, 40) {
+            Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxCPB)) => {},
+            r => panic!("{:?}", r)
+        }
+        assert!(check(&sps, 41).is_ok());
+    }
+
+    fn sps_qcif() -> H264NalUnitSPS {
+        let mut sps = sps_1080p(1);
+        sps.pic_width_in_mbs_minus1 = 10;
+        sps.pic_height_in_map_units_minus1 = 8;
+        sps
+    }
+
+    /// SPS, PPS and the first bytes of an IDR slice as a byte stream.
+    fn stream(sps: &H264NalUnitSPS, pps: &H264NalUnitPPS) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &sps.to_bytes().unwrap());
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &pps.to_bytes(sps.chroma_format_idc).unwrap());
+        write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, &[0x65, 0x88, 0x84, 0x21, 0xA0]);
+        data
+    }
+
+    fn is_violation<T: ::std::fmt::Debug>(result: Result<T, H264ProfileLevelError>) -> bool {
+        match result {
+            Err(H264ProfileLevelError::Violation(_)) => true,
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn rewrite_changes_every_sps_and_nothing_else() {
+        let sps = sps_qcif();
+        let pps = H264NalUnitPPS::new();
+        let mut data = stream(&sps, &pps);
+        data.extend(stream(&sps, &pps));
+        let main_31 = H264ProfileLevel::new(PROFILE_MAIN, 0x40, 31);
+        let out = rewrite_profile_level(&data, H264NalFormat::BYTESTREAM, 4, &main_31).unwrap();
+        let (rewritten, _) = collect_parameter_sets(&out, H264NalFormat::BYTESTREAM, 4).unwrap();
+        assert_eq!(rewritten.len(), 2);
+        for s in &rewritten {
+            assert_eq!(s.profile_level(), main_31);
+            assert_eq!((s.pic_width_in_mbs_minus1, s.pic_height_in_map_units_minus1), (10, 8));
+        }
+        // Main has no chroma_format_idc in the SPS, so it got shorter, and
+        // the PPS and slice are copied as they were.
+        let pps_and_slice = &data[4 + sps.to_bytes().unwrap().len()..data.len() / 2];
+        assert!(out.len() < data.len());
+        assert!(out.ends_with(pps_and_slice));
+    }
+
+    #[test]
+    fn cabac_and_8x8_transform_need_the_right_profile() {
+        let sps = sps_qcif();
+        let baseline = H264ProfileLevel::new(PROFILE_BASELINE, 0x40, 30);
+        let main = H264ProfileLevel::new(PROFILE_MAIN, 0, 30);
+        let high = H264ProfileLevel::new(PROFILE_HIGH, 0, 30);
+
+        let mut cabac = H264NalUnitPPS::new();
+        cabac.entropy_coding_mode_flag = true;
+        assert!(is_violation(sps.check_profile_level(&baseline, &[cabac.clone()])));
+        // Constrained Baseline is refused too, whatever profile_idc says.
+        assert!(is_violation(sps.check_profile_level(&H264ProfileLevel::new(PROFILE_MAIN, 0x80, 30), &[cabac.clone()])));
+        assert!(sps.check_profile_level(&main, &[cabac.clone()]).is_ok());
+        let data = stream(&sps, &cabac);
+        assert!(is_violation(rewrite_profile_level(&data, H264NalFormat::BYTESTREAM, 4, &baseline)));
+
+        let mut transform_8x8 = H264NalUnitPPS::new();
+        transform_8x8.more_rbsp_data = true;
+        transform_8x8.transform_8x8_mode_flag = 1;
+        assert!(is_violation(sps.check_profile_level(&main, &[transform_8x8.clone()])));
+        assert!(is_violation(sps.check_profile_level(&baseline, &[transform_8x8.clone()])));
+        assert!(sps.check_profile_level(&high, &[transform_8x8.clone()]).is_ok());
+        let data = stream(&sps, &transform_8x8);
+        assert!(is_violation(rewrite_profile_level(&data, H264NalFormat::BYTESTREAM, 4, &main)));
+
+        // PPSs of another SPS don't count.
+        cabac.seq_parameter_set_id = 1;
+        assert!(sps.check_profile_level(&baseline, &[cabac]).is_ok());
+    }
+
+    #[test]
+    fn level_1b() {
+        let baseline = H264ProfileLevel::level_1b(PROFILE_BASELINE, 0xE0);
+        assert_eq!(baseline, H264ProfileLevel::new(PROFILE_BASELINE, 0xF0, 11));
+        assert_eq!(baseline.profile_level_id(), "42f00b");
+        assert!(baseline.is_level_1b());
+        assert_eq!(baseline.level_number(), LEVEL_IDC_1B);
+        let high = H264ProfileLevel::level_1b(PROFILE_HIGH, 0x10);
+        assert_eq!(high, H264ProfileLevel::new(PROFILE_HIGH, 0, LEVEL_IDC_1B));
+        assert!(high.is_level_1b());
+        // Level 1.1 in High, where constraint_set3_flag means something else.
+        assert!(!H264ProfileLevel::new(PROFILE_HIGH, 0x10, 11).is_level_1b());
+
+        let sps = sps_qcif();
+        assert!(sps.check_profile_level(&baseline, &[]).is_ok());
+        assert!(sps.check_profile_level(&high, &[]).is_ok());
+        assert!(is_violation(sps.check_profile_level(&H264ProfileLevel::new(PROFILE_BASELINE, 0, LEVEL_IDC_1B), &[])));
+        assert!(is_violation(sps.check_profile_level(&H264ProfileLevel::new(PROFILE_BASELINE, 0x10, 30), &[])));
+        let mut cif = sps_qcif();
+        cif.pic_width_in_mbs_minus1 = 21;
+        cif.pic_height_in_map_units_minus1 = 17;
+        match cif.check_profile_level(&baseline, &[]) {
+            Err(H264ProfileLevelError::LevelLimit(H264LevelLimit::MaxFS)) => {},
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn avcc_and_fmtp_follow_the_new_profile() {
+        let sps = sps_qcif();
+        let pps = H264NalUnitPPS::new();
+        let sps_nal = sps.to_bytes().unwrap();
+        let pps_nal = pps.to_bytes(1).unwrap();
+        let main_1b = H264ProfileLevel::level_1b(PROFILE_MAIN, 0x40);
+
+        let mut record = H264AVCDecoderConfigurationRecord::from_parameter_sets(::std::slice::from_ref(&sps), ::std::slice::from_ref(&pps), 4).unwrap();
+        record.set_profile_level(&main_1b).unwrap();
+        assert_eq!(record.profile_level(), main_1b);
+        assert!(!record.high_profile_fields_present);
+        assert_eq!(record.picture_parameter_sets, vec![pps_nal.clone()]);
+        let (parsed, _) = record.parse_parameter_sets().unwrap();
+        assert_eq!(parsed[0].profile_level(), main_1b);
+        let record = H264AVCDecoderConfigurationRecord::parse(&record.to_bytes()).unwrap();
+        assert_eq!(record.profile_level(), main_1b);
+
+        let fmtp = format!("a=fmtp:96 packetization-mode=1;profile-level-id=640009;sprop-parameter-sets={},{}",
+                           base64_encode(&sps_nal), base64_encode(&pps_nal));
+        let rewritten = rewrite_fmtp_profile_level(&fmtp, &main_1b).unwrap();
+        assert!(rewritten.starts_with("a=fmtp:96 packetization-mode=1;profile-level-id=4d500b;sprop-parameter-sets="));
+        let params = parse_fmtp(&rewritten);
+        let sets : Vec<Vec<u8>> = params[2].1.split(',').map(|s| base64_decode(s).unwrap()).collect();
+        assert_eq!(sets[1], pps_nal);
+        assert_eq!(sets[0], record.sequence_parameter_sets[0]);
+
+        // The PPS decides here, as it would for the stream.
+        let mut cabac = pps.clone();
+        cabac.entropy_coding_mode_flag = true;
+        let fmtp = format!("sprop-parameter-sets={},{}", base64_encode(&sps_nal), base64_encode(&cabac.to_bytes(1).unwrap()));
+        assert!(is_violation(rewrite_fmtp_profile_level(&fmtp, &H264ProfileLevel::new(PROFILE_BASELINE, 0, 30))));
+        match rewrite_fmtp_profile_level("sprop-parameter-sets=Z0L,", &main_1b) {
+            Err(H264ProfileLevelError::Parse(_)) => {},
+            r => panic!("{:?}", r)
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use rtp::*;
+use sdp::H264FmtpParameters;
+
+/// A NAL unit waiting in the interleaved mode reordering buffer.
+struct H264RtpBufferedNal {
+    don: u16,
+    timestamp: u32,
+    data: Vec<u8>
+}
+
+/// A NAL unit being reassembled from FU-A or FU-B packets.
+struct H264RtpFragment {
+    don: Option<u16>,
+    timestamp: u32,
+    data: Vec<u8>
+}
+
+/// Turns RTP payloads back into access units. Packets have to be pushed
+/// in sequence number order; in non-interleaved mode an access unit ends
+/// with the marker bit or a change of timestamp, in interleaved mode NAL
+/// units are put back into decoding order by DON and grouped by timestamp.
+/// As a lost packet can't be placed in decoding order, interleaved mode
+/// only marks access units damaged when one of their fragmented NAL units
+/// was dropped.
+pub struct H264RtpDepacketizer {
+    pub packetization_mode: u8,
+    /// How many NAL units interleaved mode buffers before passing the one
+    /// with the lowest DON on, from sprop-interleaving-depth.
+    pub interleaving_depth: usize,
+    pub packets_lost: u64,
+    pub nal_units_dropped: u64,
+
+    last_sequence_number: Option<u16>,
+    fragment: Option<H264RtpFragment>,
+    current: Option<H264RtpAccessUnit>,
+    ready: Vec<H264RtpAccessUnit>,
+
+    // Interleaved mode
+    buffer: Vec<H264RtpBufferedNal>,
+    last_don: Option<u16>,
+    damaged_timestamps: Vec<u32>,
+    next_damaged: bool
+}
+
+impl H264RtpDepacketizer {
+    pub fn new(packetization_mode: u8) -> H264RtpDepacketizer {
+        H264RtpDepacketizer {
+            packetization_mode,
+            interleaving_depth: 0,
+            packets_lost: 0,
+            nal_units_dropped: 0,
+            last_sequence_number: None,
+            fragment: None,
+            current: None,
+            ready: Vec::new(),
+            buffer: Vec::new(),
+            last_don: None,
+            damaged_timestamps: Vec::new(),
+            next_damaged: false
+        }
+    }
+
+    /// Uses packetization-mode and sprop-interleaving-depth from the SDP.
+    pub fn from_fmtp(fmtp: &H264FmtpParameters) -> H264RtpDepacketizer {
+        let mut depacketizer = H264RtpDepacketizer::new(fmtp.packetization_mode);
+        for (name, value) in &fmtp.other_parameters {
+            if name.eq_ignore_ascii_case("sprop-interleaving-depth") {
+                depacketizer.interleaving_depth = value.parse().unwrap_or(0);
+            }
+        }
+        depacketizer
+    }
+
+    fn interleaved(&self) -> bool {
+        self.packetization_mode == 2
+    }
+
+    /// Takes the payload of one RTP packet. Packets older than the last one
+    /// are dropped, gaps in the sequence numbers count as lost packets.
+    pub fn push(&mut self, payload: &[u8], sequence_number: u16, timestamp: u32,
+                marker: bool) -> Result<(), H264RtpError> {
+        if let Some(last) = self.last_sequence_number {
+            let gap = sequence_number.wrapping_sub(last) as i16;
+            if gap <= 0 {
+                return Ok(());
+            }
+            if gap > 1 {
+                self.packets_lost += (gap - 1) as u64;
+                self.handle_loss();
+            }
+        }
+        self.last_sequence_number = Some(sequence_number);
+
+        if payload.is_empty() {
+            return Err(H264RtpError::Truncated);
+        }
+        let nal_type = payload[0] & 0x1F;
+        if nal_type != RTP_FU_A {
+            self.drop_fragment();
+        }
+        let result = match nal_type {
+            1..=23 => {
+                if self.interleaved() {
+                    Err(H264RtpError::NotAllowedInMode(nal_type))
+                } else {
+                    self.output_nal(None, timestamp, payload.to_vec());
+                    Ok(())
+                }
+            },
+            RTP_STAP_A => {
+                if self.interleaved() {
+                    Err(H264RtpError::NotAllowedInMode(nal_type))
+                } else {
+                    self.parse_stap(&payload[1..], None, timestamp)
+                }
+            },
+            RTP_STAP_B | RTP_MTAP16 | RTP_MTAP24 | RTP_FU_B if !self.interleaved() => {
+                Err(H264RtpError::NotAllowedInMode(nal_type))
+            },
+            RTP_STAP_B => {
+                if payload.len() < 3 {
+                    return Err(H264RtpError::Truncated);
+                }
+                let don = (payload[1] as u16) << 8 | payload[2] as u16;
+                self.parse_stap(&payload[3..], Some(don), timestamp)
+            },
+            RTP_MTAP16 | RTP_MTAP24 => {
+                let offset_size = if nal_type == RTP_MTAP16 { 2 } else { 3 };
+                self.parse_mtap(&payload[1..], offset_size, timestamp)
+            },
+            RTP_FU_A | RTP_FU_B => self.parse_fu(payload, timestamp),
+            _ => Err(H264RtpError::InvalidPacketType(nal_type))
+        };
+
+        if marker && !self.interleaved() {
+            self.finish_access_unit();
+        }
+        result
+    }
+
+    /// The next complete or damaged access unit, in decoding order.
+    pub fn pop_access_unit(&mut self) -> Option<H264RtpAccessUnit> {
+        if self.ready.is_empty() {
+            None
+        } else {
+            Some(self.ready.remove(0))
+        }
+    }
+
+    /// Passes on everything that's buffered, for the end of a stream.
+    /// A fragmented NAL unit that never got its last fragment is dropped.
+    pub fn flush(&mut self) {
+        self.drop_fragment();
+        while !self.buffer.is_empty() {
+            self.release_lowest_don();
+        }
+        self.finish_access_unit();
+    }
+
+    fn handle_loss(&mut self) {
+        self.drop_fragment();
+        if !self.interleaved() {
+            // The lost packets belong to the access unit being put together
+            // or, if the last one ended, possibly to the next one.
+            match self.current {
+                Some(ref mut au) => au.complete = false,
+                None => self.next_damaged = true
+            }
+        }
+    }
+
+    fn drop_fragment(&mut self) {
+        if let Some(fragment) = self.fragment.take() {
+            self.nal_units_dropped += 1;
+            self.mark_damaged(fragment.timestamp);
+        }
+    }
+
+    fn mark_damaged(&mut self, timestamp: u32) {
+        // The access unit may have been passed on already, don't let the
+        // list grow without bound.
+        if self.damaged_timestamps.len() >= 16 {
+            self.damaged_timestamps.remove(0);
+        }
+        self.damaged_timestamps.push(timestamp);
+    }
+
+    fn parse_stap(&mut self, data: &[u8], don: Option<u16>, timestamp: u32) -> Result<(), H264RtpError> {
+        let mut pos = 0;
+        let mut don = don;
+        while pos < data.len() {
+            if data.len() < pos + 2 {
+                return Err(H264RtpError::Truncated);
+            }
+            let size = (data[pos] as usize) << 8 | data[pos + 1] as usize;
+            pos += 2;
+            if size == 0 || data.len() < pos + size {
+                return Err(H264RtpError::Truncated);
+            }
+            self.output_nal(don, timestamp, data[pos..pos + size].to_vec());
+            don = don.map(|d| d.wrapping_add(1));
+            pos += size;
+        }
+        Ok(())
+    }
+
+    fn parse_mtap(&mut self, data: &[u8], offset_size: usize, timestamp: u32) -> Result<(), H264RtpError> {
+        if data.len() < 2 {
+            return Err(H264RtpError::Truncated);
+        }
+        let donb = (data[0] as u16) << 8 | data[1] as u16;
+        let mut pos = 2;
+        while pos < data.len() {
+            if data.len() < pos + 3 + offset_size {
+                return Err(H264RtpError::Truncated);
+            }
+            let size = (data[pos] as usize) << 8 | data[pos + 1] as usize;
+            let dond = data[pos + 2];
+            let mut ts_offset : u32 = 0;
+            for i in 0..offset_size {
+                ts_offset = ts_offset << 8 | data[pos + 3 + i] as u32;
+            }
+            // The size counts DOND and the timestamp offset too.
+            if size < 1 + offset_size + 1 || data.len() < pos + 2 + size {
+                return Err(H264RtpError::Truncated);
+            }
+            let nal = data[pos + 3 + offset_size..pos + 2 + size].to_vec();
+            self.output_nal(Some(donb.wrapping_add(dond as u16)), timestamp.wrapping_add(ts_offset), nal);
+            pos += 2 + size;
+        }
+        Ok(())
+    }
+
+    fn parse_fu(&mut self, payload: &[u8], timestamp: u32) -> Result<(), H264RtpError> {
+        let fu_b = payload[0] & 0x1F == RTP_FU_B;
+        let header_size = if fu_b { 4 } else { 2 };
+        if payload.len() < header_size + 1 {
+            self.drop_fragment();
+            return Err(H264RtpError::Truncated);
+        }
+        let start = payload[1] & 0x80 != 0;
+        let end = payload[1] & 0x40 != 0;
+        if fu_b && !start {
+            // FU-B is only used for the first fragment.
+            self.drop_fragment();
+            return Err(H264RtpError::InvalidPacketType(RTP_FU_B));
+        }
+        if start {
+            self.drop_fragment();
+            if self.interleaved() && !fu_b {
+                // In interleaved mode the first fragment has to carry the DON.
+                self.nal_units_dropped += 1;
+                self.mark_damaged(timestamp);
+                return Err(H264RtpError::NotAllowedInMode(RTP_FU_A));
+            }
+            let mut data = Vec::with_capacity(payload.len());
+            data.push(payload[0] & 0xE0 | payload[1] & 0x1F);
+            self.fragment = Some(H264RtpFragment {
+                don: if fu_b { Some((payload[2] as u16) << 8 | payload[3] as u16) } else { None },
+                timestamp,
+                data
+            });
+        }
+        match self.fragment {
+            Some(ref mut fragment) => fragment.data.extend_from_slice(&payload[header_size..]),
+            // A middle or last fragment whose start was lost.
+            None => return Ok(())
+        }
+        if end {
+            let fragment = self.fragment.take().unwrap();
+            self.output_nal(fragment.don, fragment.timestamp, fragment.data);
+        }
+        Ok(())
+    }
+
+    fn output_nal(&mut self, don: Option<u16>, timestamp: u32, data: Vec<u8>) {
+        match don {
+            Some(don) => {
+                self.buffer.push(H264RtpBufferedNal {
+                    don,
+                    timestamp,
+                    data
+                });
+                while self.buffer.len() > self.interleaving_depth {
+                    self.release_lowest_don();
+                }
+            },
+            None => self.add_to_access_unit(timestamp, data)
+        }
+    }
+
+    fn release_lowest_don(&mut self) {
+        let reference = match self.last_don {
+            Some(d) => d,
+            None => self.buffer[0].don
+        };
+        let mut lowest = 0;
+        for i in 1..self.buffer.len() {
+            if don_diff(reference, self.buffer[i].don) < don_diff(reference, self.buffer[lowest].don) {
+                lowest = i;
+            }
+        }
+        let nal = self.buffer.remove(lowest);
+        self.last_don = Some(nal.don);
+        self.add_to_access_unit(nal.timestamp, nal.data);
+    }
+
+    fn add_to_access_unit(&mut self, timestamp: u32, data: Vec<u8>) {
+        if self.current.as_ref().is_some_and(|au| au.timestamp != timestamp) {
+            self.finish_access_unit();
+        }
+        if self.current.is_none() {
+            let mut au = H264RtpAccessUnit::new(timestamp);
+            au.complete = !self.next_damaged;
+            self.next_damaged = false;
+            self.current = Some(au);
+        }
+        self.current.as_mut().unwrap().nal_units.push(data);
+    }
+
+    fn finish_access_unit(&mut self) {
+        if let Some(mut au) = self.current.take() {
+            if let Some(i) = self.damaged_timestamps.iter().position(|&t| t == au.timestamp) {
+                self.damaged_timestamps.remove(i);
+                au.complete = false;
+            }
+            if !au.nal_units.is_empty() {
+                self.ready.push(au);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop_all(depacketizer: &mut H264RtpDepacketizer) -> Vec<H264RtpAccessUnit> {
+        let mut units = Vec::new();
+        while let Some(au) = depacketizer.pop_access_unit() {
+            units.push(au);
+        }
+        units
+    }
+
+    #[test]
+    fn marker_and_timestamp_end_access_units() {
+        let mut depacketizer = H264RtpDepacketizer::new(1);
+        depacketizer.push(&[0x67, 0x42], 1, 100, false).unwrap();
+        depacketizer.push(&[0x68, 0xCE], 2, 100, false).unwrap();
+        depacketizer.push(&[0x65, 0x88], 3, 100, true).unwrap();
+        depacketizer.push(&[0x41, 0x9A], 4, 3100, false).unwrap();
+        depacketizer.push(&[0x41, 0x9B], 5, 6100, false).unwrap();
+        // Repeated and late packets are ignored.
+        depacketizer.push(&[0x41, 0x9C], 5, 6100, false).unwrap();
+        depacketizer.push(&[0x41, 0x9D], 2, 100, false).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].timestamp, 100);
+        assert_eq!(units[0].nal_units, vec![vec![0x67, 0x42], vec![0x68, 0xCE], vec![0x65, 0x88]]);
+        assert!(units[0].complete);
+        assert_eq!(units[1].timestamp, 3100);
+        assert_eq!(units[1].nal_units, vec![vec![0x41, 0x9A]]);
+
+        depacketizer.flush();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].nal_units, vec![vec![0x41, 0x9B]]);
+        assert_eq!(depacketizer.packets_lost, 0);
+    }
+
+    #[test]
+    fn lost_packets_damage_access_units() {
+        let mut depacketizer = H264RtpDepacketizer::new(1);
+        depacketizer.push(&[0x65, 0x88], 65534, 100, false).unwrap();
+        depacketizer.push(&[0x65, 0x89], 0, 100, true).unwrap();
+        // The loss falls between two access units, so it goes to the next.
+        depacketizer.push(&[0x41, 0x9A], 3, 3100, true).unwrap();
+        depacketizer.push(&[0x41, 0x9B], 4, 6100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 3);
+        assert!(!units[0].complete);
+        assert!(!units[1].complete);
+        assert!(units[2].complete);
+        assert_eq!(depacketizer.packets_lost, 3);
+        assert_eq!(depacketizer.nal_units_dropped, 0);
+    }
+
+    #[test]
+    fn fu_a_fragments_are_reassembled() {
+        let mut depacketizer = H264RtpDepacketizer::new(1);
+        depacketizer.push(&[0x7C, 0x85, 1, 2, 3], 1, 100, false).unwrap();
+        depacketizer.push(&[0x7C, 0x05, 4, 5, 6], 2, 100, false).unwrap();
+        depacketizer.push(&[0x7C, 0x45, 7, 8], 3, 100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].nal_units, vec![vec![0x65, 1, 2, 3, 4, 5, 6, 7, 8]]);
+        assert!(units[0].complete);
+
+        // A lost middle fragment drops the whole NAL unit.
+        depacketizer.push(&[0x67, 0x42], 4, 3100, false).unwrap();
+        depacketizer.push(&[0x7C, 0x85, 1, 2, 3], 5, 3100, false).unwrap();
+        depacketizer.push(&[0x7C, 0x45, 7, 8], 7, 3100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].nal_units, vec![vec![0x67, 0x42]]);
+        assert!(!units[0].complete);
+        assert_eq!(depacketizer.packets_lost, 1);
+        assert_eq!(depacketizer.nal_units_dropped, 1);
+
+        // So does a new NAL unit before the end fragment.
+        depacketizer.push(&[0x7C, 0x85, 1, 2, 3], 8, 6100, false).unwrap();
+        depacketizer.push(&[0x61, 0x9A], 9, 6100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].nal_units, vec![vec![0x61, 0x9A]]);
+        assert!(!units[0].complete);
+        assert_eq!(depacketizer.nal_units_dropped, 2);
+    }
+
+    #[test]
+    fn stap_a_is_split() {
+        let mut depacketizer = H264RtpDepacketizer::new(1);
+        depacketizer.push(&[0x78, 0, 2, 0x67, 0x42, 0, 3, 0x68, 0xCE, 0x38], 1, 100, false).unwrap();
+        depacketizer.push(&[0x65, 0x88], 2, 100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].nal_units, vec![vec![0x67, 0x42], vec![0x68, 0xCE, 0x38], vec![0x65, 0x88]]);
+
+        match depacketizer.push(&[0x78, 0, 4, 0x67, 0x42], 3, 200, false) {
+            Err(H264RtpError::Truncated) => {},
+            r => panic!("{:?}", r)
+        }
+        match depacketizer.push(&[0x79, 0, 0, 0, 2, 0x67, 0x42], 4, 200, false) {
+            Err(H264RtpError::NotAllowedInMode(RTP_STAP_B)) => {},
+            r => panic!("{:?}", r)
+        }
+        match depacketizer.push(&[0x7E, 0], 5, 200, false) {
+            Err(H264RtpError::InvalidPacketType(30)) => {},
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn interleaved_nal_units_are_put_back_in_don_order() {
+        let mut fmtp = H264FmtpParameters::new();
+        fmtp.packetization_mode = 2;
+        fmtp.other_parameters.push(("sprop-interleaving-depth".to_string(), "2".to_string()));
+        let mut depacketizer = H264RtpDepacketizer::from_fmtp(&fmtp);
+        assert_eq!(depacketizer.interleaving_depth, 2);
+
+        // STAP-B with DON 65534 and 65535
+        depacketizer.push(&[0x79, 0xFF, 0xFE, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xCE], 1, 100, false).unwrap();
+        // MTAP16 with DONB 0: the P slice with DON 1 of the next picture
+        // comes before the IDR slice with DON 0.
+        depacketizer.push(&[0x7A, 0, 0,
+                            0, 5, 1, 0x0B, 0xB8, 0x41, 0x9A,
+                            0, 5, 0, 0, 0, 0x65, 0x88], 2, 100, true).unwrap();
+        let units = pop_all(&mut depacketizer);
+        assert!(units.is_empty());
+        depacketizer.flush();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].timestamp, 100);
+        assert_eq!(units[0].nal_units, vec![vec![0x67, 0x42], vec![0x68, 0xCE], vec![0x65, 0x88]]);
+        assert_eq!(units[1].timestamp, 3100);
+        assert_eq!(units[1].nal_units, vec![vec![0x41, 0x9A]]);
+        assert!(units.iter().all(|au| au.complete));
+
+        match depacketizer.push(&[0x65, 0x88], 3, 200, false) {
+            Err(H264RtpError::NotAllowedInMode(5)) => {},
+            r => panic!("{:?}", r)
+        }
+        match depacketizer.push(&[0x78, 0, 2, 0x67, 0x42], 4, 200, false) {
+            Err(H264RtpError::NotAllowedInMode(RTP_STAP_A)) => {},
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn mtap24_and_fu_b_carry_don_and_timestamps() {
+        let mut depacketizer = H264RtpDepacketizer::new(2);
+        depacketizer.push(&[0x7B, 0, 0x20,
+                            0, 6, 0, 0, 0, 0, 0x65, 0x88,
+                            0, 6, 1, 0, 0x0B, 0xB8, 0x41, 0x9A], 1, 500, false).unwrap();
+        // FU-B with DON 34, then FU-A for the rest.
+        depacketizer.push(&[0x7D, 0x81, 0, 0x22, 1, 2], 2, 6500, false).unwrap();
+        depacketizer.push(&[0x7C, 0x41, 3, 4], 3, 6500, true).unwrap();
+        // The first fragment in interleaved mode has to be FU-B.
+        match depacketizer.push(&[0x7C, 0x81, 1, 2], 4, 9500, false) {
+            Err(H264RtpError::NotAllowedInMode(RTP_FU_A)) => {},
+            r => panic!("{:?}", r)
+        }
+        depacketizer.flush();
+        let units = pop_all(&mut depacketizer);
+        assert_eq!(units.len(), 3);
+        assert_eq!((units[0].timestamp, &units[0].nal_units), (500, &vec![vec![0x65, 0x88]]));
+        assert_eq!((units[1].timestamp, &units[1].nal_units), (3500, &vec![vec![0x41, 0x9A]]));
+        assert_eq!((units[2].timestamp, &units[2].nal_units), (6500, &vec![vec![0x61, 1, 2, 3, 4]]));
+        assert_eq!(depacketizer.nal_units_dropped, 1);
+    }
+}
@@ -0,0 +1,331 @@
+use parser::H264NalParser;
+use poc::H264TimestampGenerator;
+use rtp::*;
+
+/// The payload of one RTP packet with the timestamp and marker bit it has
+/// to be sent with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264RtpPayload {
+    pub payload: Vec<u8>,
+    pub timestamp: u32,
+    pub marker: bool
+}
+
+/// Splits access units into RTP payloads for packetization-mode 0 or 1.
+/// Mode 0 sends every NAL unit in its own packet. Mode 1 aggregates runs
+/// of small non-VCL NAL units into STAP-A packets and fragments NAL units
+/// that don't fit the MTU into FU-A packets.
+pub struct H264RtpPacketizer {
+    pub packetization_mode: u8,
+    /// Largest RTP payload to produce, without RTP header.
+    pub mtu: usize,
+    /// Send the last seen SPS and PPS before IDR access units that don't
+    /// carry their own.
+    pub insert_parameter_sets: bool,
+
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>
+}
+
+fn is_vcl(nal: &[u8]) -> bool {
+    let nal_type = nal[0] & 0x1F;
+    (1..=5).contains(&nal_type)
+}
+
+impl H264RtpPacketizer {
+    pub fn new(packetization_mode: u8, mtu: usize) -> H264RtpPacketizer {
+        H264RtpPacketizer {
+            packetization_mode,
+            mtu,
+            insert_parameter_sets: false,
+            sps: Vec::new(),
+            pps: Vec::new()
+        }
+    }
+
+    /// The payloads of one access unit. The marker bit is set on the last.
+    pub fn packetize_access_unit(&mut self, nal_units: &[Vec<u8>],
+                                 timestamp: u32) -> Result<Vec<H264RtpPayload>, H264RtpError> {
+        if self.packetization_mode > 1 {
+            return Err(H264RtpError::NotAllowedInMode(self.packetization_mode));
+        }
+        let nal_units : Vec<&Vec<u8>> = nal_units.iter().filter(|n| !n.is_empty()).collect();
+        let has_sps = nal_units.iter().any(|n| n[0] & 0x1F == 7);
+        let has_pps = nal_units.iter().any(|n| n[0] & 0x1F == 8);
+        if has_sps {
+            self.sps = nal_units.iter().filter(|n| n[0] & 0x1F == 7).map(|n| n.to_vec()).collect();
+        }
+        if has_pps {
+            self.pps = nal_units.iter().filter(|n| n[0] & 0x1F == 8).map(|n| n.to_vec()).collect();
+        }
+
+        let mut units : Vec<&[u8]> = Vec::new();
+        let is_idr = nal_units.iter().any(|n| n[0] & 0x1F == 5);
+        if self.insert_parameter_sets && is_idr && !(has_sps && has_pps) {
+            // Parameter sets go after an AUD but before anything else, the
+            // ones the access unit carries itself take their place.
+            let aud = nal_units.first().is_some_and(|n| n[0] & 0x1F == 9);
+            if aud {
+                units.push(nal_units[0]);
+            }
+            for nal in self.sps.iter().chain(self.pps.iter()) {
+                units.push(nal);
+            }
+            for nal in nal_units.iter().skip(aud as usize) {
+                if nal[0] & 0x1F != 7 && nal[0] & 0x1F != 8 {
+                    units.push(nal);
+                }
+            }
+        } else {
+            for nal in &nal_units {
+                units.push(nal);
+            }
+        }
+
+        let mut payloads = Vec::new();
+        let mut i = 0;
+        while i < units.len() {
+            let nal = units[i];
+            if self.packetization_mode == 1 && !is_vcl(nal) {
+                // Gather the following non-VCL NAL units that still fit.
+                let mut size = 1 + 2 + nal.len();
+                let mut end = i + 1;
+                while end < units.len() && !is_vcl(units[end]) && size + 2 + units[end].len() <= self.mtu {
+                    size += 2 + units[end].len();
+                    end += 1;
+                }
+                if end - i > 1 {
+                    payloads.push(stap_a(&units[i..end], timestamp));
+                    i = end;
+                    continue;
+                }
+            }
+            if nal.len() <= self.mtu {
+                payloads.push(H264RtpPayload {
+                    payload: nal.to_vec(),
+                    timestamp,
+                    marker: false
+                });
+            } else if self.packetization_mode == 1 && self.mtu > 2 {
+                fu_a(nal, self.mtu, timestamp, &mut payloads);
+            } else {
+                return Err(H264RtpError::NalTooLarge(nal.len()));
+            }
+            i += 1;
+        }
+        if let Some(last) = payloads.last_mut() {
+            last.marker = true;
+        }
+        Ok(payloads)
+    }
+
+    /// Packetizes a whole stream, one access unit at a time. The RTP
+    /// timestamp of each access unit is first_timestamp plus its PTS from
+    /// H264TimestampGenerator, with the DTS of the first at 0.
+    /// frame_duration in 90 kHz ticks overrides the VUI timing info.
+    pub fn packetize_stream(&mut self, data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                            first_timestamp: u32, frame_duration: Option<u64>) -> Result<Vec<H264RtpPayload>, H264RtpError> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        let mut timestamps = H264TimestampGenerator::new(RTP_CLOCK_RATE as u64);
+        timestamps.frame_duration = frame_duration;
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).map_err(H264RtpError::Parse)?;
+            let pts = match timestamps.access_unit_timing(&mut parser, &au).map_err(H264RtpError::Parse)? {
+                Some(timing) => timing.pts,
+                None => timestamps.next(None).0
+            };
+            let nal_units : Vec<Vec<u8>> = au.nal_units.iter().map(|n| parser.nal_data(n).to_vec()).collect();
+            payloads.append(&mut self.packetize_access_unit(&nal_units, first_timestamp.wrapping_add(pts as u32))?);
+            offset += au.size;
+        }
+        Ok(payloads)
+    }
+}
+
+fn stap_a(nal_units: &[&[u8]], timestamp: u32) -> H264RtpPayload {
+    // F is set if any of the NAL units has it, NRI is the highest of them.
+    let forbidden = nal_units.iter().fold(0, |f, n| f | n[0] & 0x80);
+    let nri = nal_units.iter().map(|n| n[0] & 0x60).max().unwrap_or(0);
+    let mut payload = vec![forbidden | nri | RTP_STAP_A];
+    for nal in nal_units {
+        payload.push((nal.len() >> 8) as u8);
+        payload.push(nal.len() as u8);
+        payload.extend_from_slice(nal);
+    }
+    H264RtpPayload {
+        payload,
+        timestamp,
+        marker: false
+    }
+}
+
+fn fu_a(nal: &[u8], mtu: usize, timestamp: u32, payloads: &mut Vec<H264RtpPayload>) {
+    let indicator = nal[0] & 0xE0 | RTP_FU_A;
+    let nal_type = nal[0] & 0x1F;
+    let chunks : Vec<&[u8]> = nal[1..].chunks(mtu - 2).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut header = nal_type;
+        if i == 0 {
+            header |= 0x80;
+        }
+        if i == chunks.len() - 1 {
+            header |= 0x40;
+        }
+        let mut payload = Vec::with_capacity(chunk.len() + 2);
+        payload.push(indicator);
+        payload.push(header);
+        payload.extend_from_slice(chunk);
+        payloads.push(H264RtpPayload {
+            payload,
+            timestamp,
+            marker: false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtp::H264RtpDepacketizer;
+    use writer::H264NalWriter;
+
+    const SPS: [u8; 4] = [0x67, 0x42, 0x00, 0x0A];
+    const PPS: [u8; 3] = [0x68, 0xCE, 0x38];
+    const AUD: [u8; 2] = [0x09, 0x10];
+
+    fn idr(size: usize) -> Vec<u8> {
+        let mut nal = vec![0x65];
+        nal.extend((1..size).map(|i| i as u8));
+        nal
+    }
+
+    fn payloads(packets: &[H264RtpPayload]) -> Vec<Vec<u8>> {
+        packets.iter().map(|p| p.payload.clone()).collect()
+    }
+
+    #[test]
+    fn small_units_are_aggregated_and_large_ones_fragmented() {
+        let mut packetizer = H264RtpPacketizer::new(1, 20);
+        let nal_units = vec![SPS.to_vec(), PPS.to_vec(), idr(25)];
+        let packets = packetizer.packetize_access_unit(&nal_units, 1234).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].payload, vec![0x78, 0, 4, 0x67, 0x42, 0x00, 0x0A, 0, 3, 0x68, 0xCE, 0x38]);
+        // 24 bytes after the NAL header in fragments of 18
+        assert_eq!(packets[1].payload[..2], [0x7C, 0x85]);
+        assert_eq!(packets[1].payload.len(), 20);
+        assert_eq!(packets[2].payload[..2], [0x7C, 0x45]);
+        assert_eq!(packets[2].payload.len(), 8);
+        assert!(packets.iter().all(|p| p.timestamp == 1234 && p.payload.len() <= 20));
+        assert_eq!(packets.iter().map(|p| p.marker).collect::<Vec<_>>(), vec![false, false, true]);
+
+        let mut depacketizer = H264RtpDepacketizer::new(1);
+        for (i, packet) in packets.iter().enumerate() {
+            depacketizer.push(&packet.payload, i as u16, packet.timestamp, packet.marker).unwrap();
+        }
+        let au = depacketizer.pop_access_unit().unwrap();
+        assert!(au.complete);
+        assert_eq!(au.nal_units, nal_units);
+    }
+
+    #[test]
+    fn stap_a_only_takes_what_fits() {
+        let mut packetizer = H264RtpPacketizer::new(1, 12);
+        let sei = vec![0x06, 0x05, 0x01, 0x80];
+        let packets = packetizer.packetize_access_unit(&[SPS.to_vec(), PPS.to_vec(), sei.clone(), idr(5)], 0).unwrap();
+        assert_eq!(payloads(&packets), vec![
+            vec![0x78, 0, 4, 0x67, 0x42, 0x00, 0x0A, 0, 3, 0x68, 0xCE, 0x38],
+            sei,
+            idr(5)
+        ]);
+        assert!(packets[2].marker && !packets[1].marker);
+    }
+
+    #[test]
+    fn mode_0_sends_single_nal_units() {
+        let mut packetizer = H264RtpPacketizer::new(0, 20);
+        let packets = packetizer.packetize_access_unit(&[SPS.to_vec(), Vec::new(), PPS.to_vec(), idr(20)], 0).unwrap();
+        assert_eq!(payloads(&packets), vec![SPS.to_vec(), PPS.to_vec(), idr(20)]);
+        match packetizer.packetize_access_unit(&[idr(21)], 0) {
+            Err(H264RtpError::NalTooLarge(21)) => {},
+            r => panic!("{:?}", r)
+        }
+        packetizer.packetization_mode = 2;
+        match packetizer.packetize_access_unit(&[idr(5)], 0) {
+            Err(H264RtpError::NotAllowedInMode(2)) => {},
+            r => panic!("{:?}", r)
+        }
+    }
+
+    #[test]
+    fn parameter_sets_are_inserted_before_idr_access_units() {
+        let mut packetizer = H264RtpPacketizer::new(0, 1400);
+        packetizer.insert_parameter_sets = true;
+        let packets = packetizer.packetize_access_unit(&[AUD.to_vec(), SPS.to_vec(), PPS.to_vec(), idr(5)], 0).unwrap();
+        assert_eq!(payloads(&packets), vec![AUD.to_vec(), SPS.to_vec(), PPS.to_vec(), idr(5)]);
+
+        let packets = packetizer.packetize_access_unit(&[AUD.to_vec(), idr(6)], 3000).unwrap();
+        assert_eq!(payloads(&packets), vec![AUD.to_vec(), SPS.to_vec(), PPS.to_vec(), idr(6)]);
+
+        // Non-IDR access units are left alone.
+        let packets = packetizer.packetize_access_unit(&[vec![0x41, 0x9A]], 6000).unwrap();
+        assert_eq!(payloads(&packets), vec![vec![0x41, 0x9A]]);
+
+        // A new SPS without a PPS gets the last PPS, and no second SPS.
+        let new_sps = vec![0x67, 0x4D, 0x00, 0x1E];
+        let packets = packetizer.packetize_access_unit(&[new_sps.clone(), idr(7)], 9000).unwrap();
+        assert_eq!(payloads(&packets), vec![new_sps.clone(), PPS.to_vec(), idr(7)]);
+
+        let packets = packetizer.packetize_access_unit(&[PPS.to_vec(), idr(8)], 12000).unwrap();
+        assert_eq!(payloads(&packets), vec![new_sps, PPS.to_vec(), idr(8)]);
+
+        packetizer.insert_parameter_sets = false;
+        let packets = packetizer.packetize_access_unit(&[idr(9)], 15000).unwrap();
+        assert_eq!(payloads(&packets), vec![idr(9)]);
+    }
+
+    #[test]
+    fn stream_timestamps_follow_the_pts() {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 77;
+        sps.level_idc = 30;
+        sps.frame_mbs_only_flag = true;
+        sps.max_num_ref_frames = 1;
+        let pps = H264NalUnitPPS::new();
+        let mut data = Vec::new();
+        // An IDR picture, a P picture two frames later and a non-reference
+        // picture in between, in decoding order.
+        for &(nal_type, ref_idc, frame_num, poc) in &[(5, 3, 0, 0), (1, 2, 1, 4), (1, 0, 2, 2), (1, 2, 2, 6)] {
+            let mut slice = H264NalUnitSlice::new();
+            slice.slice_type = if nal_type == 5 { 7 } else { 5 };
+            slice.frame_num = frame_num;
+            slice.pic_order_cnt_lsb = poc;
+            let unit = H264NalUnit::new(0, 4, 0, ref_idc, nal_type);
+            let mut writer = H264NalWriter::new();
+            slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+            writer.write_rbsp_trailing_bits();
+            let mut nal_units = vec![writer.to_nal(ref_idc, nal_type)];
+            if nal_type == 5 {
+                nal_units.insert(0, sps.to_bytes().unwrap());
+                nal_units.insert(1, pps.to_bytes(1).unwrap());
+            }
+            for nal in nal_units {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend_from_slice(&nal);
+            }
+        }
+
+        let mut packetizer = H264RtpPacketizer::new(0, 1400);
+        let packets = packetizer.packetize_stream(&data, H264NalFormat::BYTESTREAM, 4, 0xFFFF_F000, Some(3000)).unwrap();
+        let markers : Vec<&H264RtpPayload> = packets.iter().filter(|p| p.marker).collect();
+        assert_eq!(markers.len(), 4);
+        let first = markers[0].timestamp;
+        let offsets : Vec<u32> = markers.iter().map(|p| p.timestamp.wrapping_sub(first)).collect();
+        assert_eq!(offsets, vec![0, 6000, 3000, 9000]);
+        // The first PTS is the reorder delay after a DTS of 0.
+        assert_eq!(first, 0xFFFF_F000u32.wrapping_add(6000));
+    }
+}
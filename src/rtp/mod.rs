@@ -0,0 +1,143 @@
+use parser::{H264NalParser, H264NalParseError};
+use writer::write_nalunit;
+pub use types::*;
+
+mod h264depacketizer;
+mod h264packetizer;
+pub use self::h264depacketizer::H264RtpDepacketizer;
+pub use self::h264packetizer::{H264RtpPacketizer, H264RtpPayload};
+
+/// NAL unit types of the RTP payload structures from RFC 6184.
+pub const RTP_STAP_A : u8 = 24;
+pub const RTP_STAP_B : u8 = 25;
+pub const RTP_MTAP16 : u8 = 26;
+pub const RTP_MTAP24 : u8 = 27;
+pub const RTP_FU_A : u8 = 28;
+pub const RTP_FU_B : u8 = 29;
+
+/// The H.264 RTP clock rate.
+pub const RTP_CLOCK_RATE : u32 = 90000;
+
+#[derive(Debug)]
+pub enum H264RtpError {
+    /// The payload ended in the middle of a header or NAL unit.
+    Truncated,
+    /// A reserved or unknown payload structure.
+    InvalidPacketType(u8),
+    /// A payload structure that the packetization mode doesn't allow.
+    NotAllowedInMode(u8),
+    /// A NAL unit bigger than the MTU in packetization-mode 0.
+    NalTooLarge(usize),
+    Parse(H264NalParseError)
+}
+
+/// An access unit reassembled from RTP packets or to be sent in them.
+/// The NAL units have no start codes or length prefixes.
+#[derive(Debug, Clone)]
+pub struct H264RtpAccessUnit {
+    pub timestamp: u32,
+    pub nal_units: Vec<Vec<u8>>,
+    /// False if packets of the access unit were lost, or fragmented NAL
+    /// units of it had to be dropped.
+    pub complete: bool
+}
+
+impl H264RtpAccessUnit {
+    pub fn new(timestamp: u32) -> H264RtpAccessUnit {
+        H264RtpAccessUnit {
+            timestamp,
+            nal_units: Vec::new(),
+            complete: true
+        }
+    }
+
+    pub fn is_idr(&self) -> bool {
+        self.nal_units.iter().any(|n| !n.is_empty() && n[0] & 0x1F == 5)
+    }
+
+    pub fn to_bytes(&self, format: H264NalFormat, nal_length_size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in &self.nal_units {
+            write_nalunit(&mut out, format, nal_length_size, nal);
+        }
+        out
+    }
+
+    /// Hands the access unit to a parser as Annex B data. The parser keeps
+    /// the parameter sets it has seen, so one parser can be used for the
+    /// whole stream.
+    pub fn load_into(&self, parser: &mut H264NalParser) {
+        parser.set_data(self.to_bytes(H264NalFormat::BYTESTREAM, 4));
+        parser.format = H264NalFormat::BYTESTREAM;
+    }
+}
+
+/// The decoding order number distance from RFC 6184 section 5.5, positive
+/// if n follows m.
+pub fn don_diff(m: u16, n: u16) -> i32 {
+    n.wrapping_sub(m) as i16 as i32
+}
+
+/// The fixed RTP header fields from RFC 3550.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264RtpHeader {
+    pub payload_type: u8,
+    pub marker: bool,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32
+}
+
+/// Splits an RTP packet into its header and payload, skipping CSRCs, the
+/// header extension and padding. None if it isn't a valid version 2
+/// packet.
+pub fn parse_rtp_packet(data: &[u8]) -> Option<(H264RtpHeader, &[u8])> {
+    if data.len() < 12 || data[0] >> 6 != 2 {
+        return None;
+    }
+    let padding = data[0] & 0x20 != 0;
+    let extension = data[0] & 0x10 != 0;
+    let csrc_count = (data[0] & 0x0F) as usize;
+    let header = H264RtpHeader {
+        payload_type: data[1] & 0x7F,
+        marker: data[1] & 0x80 != 0,
+        sequence_number: (data[2] as u16) << 8 | data[3] as u16,
+        timestamp: (data[4] as u32) << 24 | (data[5] as u32) << 16 | (data[6] as u32) << 8 | data[7] as u32,
+        ssrc: (data[8] as u32) << 24 | (data[9] as u32) << 16 | (data[10] as u32) << 8 | data[11] as u32
+    };
+    let mut start = 12 + 4 * csrc_count;
+    if extension {
+        if data.len() < start + 4 {
+            return None;
+        }
+        let length = (data[start + 2] as usize) << 8 | data[start + 3] as usize;
+        start += 4 + 4 * length;
+    }
+    let mut end = data.len();
+    if padding {
+        let padding_size = data[end - 1] as usize;
+        if padding_size == 0 || padding_size > end {
+            return None;
+        }
+        end -= padding_size;
+    }
+    if start > end {
+        return None;
+    }
+    Some((header, &data[start..end]))
+}
+
+impl H264RtpHeader {
+    /// The 12 byte header, without CSRCs or extension.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            0x80,
+            (self.marker as u8) << 7 | self.payload_type,
+            (self.sequence_number >> 8) as u8, self.sequence_number as u8,
+            (self.timestamp >> 24) as u8, (self.timestamp >> 16) as u8,
+            (self.timestamp >> 8) as u8, self.timestamp as u8,
+            (self.ssrc >> 24) as u8, (self.ssrc >> 16) as u8,
+            (self.ssrc >> 8) as u8, self.ssrc as u8
+        ]
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+use parser::{H264NalParser, H264NalParseError};
+use writer::{write_nalunit, H264NalWriteError};
+use profile::{H264ProfileLevel, collect_parameter_sets};
+use level::level_limits;
+pub use types::*;
+
+const BASE64_CHARS : &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc : u32 = 0;
+    let mut bits = 0;
+    for c in text.trim().bytes() {
+        if c == b'=' {
+            break;
+        }
+        let v = BASE64_CHARS.iter().position(|&x| x == c)? as u32;
+        acc = acc << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Splits the parameters of an a=fmtp line (with or without the
+/// "a=fmtp:<pt> " prefix) into name/value pairs.
+pub fn parse_fmtp(fmtp: &str) -> Vec<(String, String)> {
+    let params = if fmtp.starts_with("a=fmtp:") {
+        match fmtp.find(' ') {
+            Some(i) => &fmtp[i + 1..],
+            None => ""
+        }
+    } else {
+        fmtp
+    };
+    params.split(';')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.find('=') {
+            Some(i) => (p[..i].trim().to_string(), p[i + 1..].trim().to_string()),
+            None => (p.to_string(), String::new())
+        })
+        .collect()
+}
+
+pub fn format_fmtp(params: &[(String, String)]) -> String {
+    params.iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+/// The RFC 6184 fmtp parameters of an H.264 RTP payload type. Parameters
+/// this struct doesn't know are kept in other_parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264FmtpParameters {
+    pub profile_level_id: Option<H264ProfileLevel>,
+    pub packetization_mode: u8,
+    /// The parameter set NAL units, without start codes.
+    pub sprop_parameter_sets: Vec<Vec<u8>>,
+    pub max_fs: Option<u32>,
+    pub max_mbps: Option<u32>,
+    pub other_parameters: Vec<(String, String)>
+}
+
+impl Default for H264FmtpParameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264FmtpParameters {
+    pub fn new() -> H264FmtpParameters {
+        H264FmtpParameters {
+            profile_level_id: None,
+            packetization_mode: 0,
+            sprop_parameter_sets: Vec::new(),
+            max_fs: None,
+            max_mbps: None,
+            other_parameters: Vec::new()
+        }
+    }
+
+    /// The parameters describing a stream with this SPS and these PPSs.
+    /// max-fs and max-mbps are only set when the stream goes over the
+    /// limits of its level, which RFC 6184 allows for receivers that can
+    /// handle more than the level.
+    pub fn from_parameter_sets(sps: &H264NalUnitSPS, pps: &[H264NalUnitPPS],
+                               packetization_mode: u8) -> Result<H264FmtpParameters, H264NalParseError> {
+        let mut params = H264FmtpParameters::new();
+        let profile_level = sps.profile_level();
+        params.profile_level_id = Some(profile_level);
+        params.packetization_mode = packetization_mode;
+        params.sprop_parameter_sets.push(sps.to_bytes()?);
+        for p in pps {
+            params.sprop_parameter_sets.push(p.to_bytes(sps.chroma_format_idc)?);
+        }
+        if let Some(limits) = level_limits(profile_level.level_number()) {
+            let frame_size = sps.frame_size_in_mbs();
+            if frame_size > limits.max_fs {
+                params.max_fs = Some(frame_size);
+            }
+            if let Some(frame_rate) = sps.frame_rate() {
+                let mbps = (frame_size as f64 * frame_rate).ceil() as u32;
+                if mbps > limits.max_mbps {
+                    params.max_mbps = Some(mbps);
+                }
+            }
+        }
+        Ok(params)
+    }
+
+    /// The parameters for an SPS a parser has seen and the PPSs referring
+    /// to it, with the parameter sets as they were coded in the stream.
+    /// None if the parser hasn't seen that SPS.
+    pub fn from_parser(parser: &H264NalParser, seq_parameter_set_id: u32,
+                       packetization_mode: u8) -> Result<Option<H264FmtpParameters>, H264NalParseError> {
+        let sps = match parser.find_sps(seq_parameter_set_id) {
+            Some(sps) => sps,
+            None => return Ok(None)
+        };
+        let pps : Vec<H264NalUnitPPS> = parser.pps.iter()
+            .filter(|p| p.seq_parameter_set_id == seq_parameter_set_id)
+            .cloned()
+            .collect();
+        let mut params = H264FmtpParameters::from_parameter_sets(sps, &pps, packetization_mode)?;
+        if let Some(original) = parser.sps_nal_unit(seq_parameter_set_id) {
+            params.sprop_parameter_sets[0] = original.to_vec();
+        }
+        for (nal, p) in params.sprop_parameter_sets[1..].iter_mut().zip(&pps) {
+            if let Some(original) = parser.pps_nal_unit(p.pic_parameter_set_id) {
+                *nal = original.to_vec();
+            }
+        }
+        Ok(Some(params))
+    }
+
+    pub fn parse(fmtp: &str) -> Result<H264FmtpParameters, H264NalParseError> {
+        let mut params = H264FmtpParameters::new();
+        for (name, value) in parse_fmtp(fmtp) {
+            match name.to_ascii_lowercase().as_str() {
+                "profile-level-id" => {
+                    params.profile_level_id = H264ProfileLevel::from_profile_level_id(&value);
+                    if params.profile_level_id.is_none() {
+                        return Err(H264NalParseError::GenericParseError);
+                    }
+                },
+                "packetization-mode" => {
+                    params.packetization_mode = match value.parse() {
+                        Ok(v) => v,
+                        Err(_) => return Err(H264NalParseError::GenericParseError)
+                    };
+                },
+                "sprop-parameter-sets" => {
+                    for set in value.split(',').filter(|s| !s.is_empty()) {
+                        match base64_decode(set) {
+                            Some(nal) => params.sprop_parameter_sets.push(nal),
+                            None => return Err(H264NalParseError::GenericParseError)
+                        }
+                    }
+                },
+                "max-fs" => params.max_fs = value.parse().ok(),
+                "max-mbps" => params.max_mbps = value.parse().ok(),
+                _ => params.other_parameters.push((name, value))
+            }
+        }
+        Ok(params)
+    }
+
+    /// The codec string for the profile-level-id, or for the first SPS in
+    /// sprop-parameter-sets if there is none.
+    pub fn codec_string(&self) -> Option<String> {
+        if let Some(ref profile_level) = self.profile_level_id {
+            return Some(profile_level.codec_string());
+        }
+        self.sprop_parameter_sets.iter()
+            .find(|nal| nal.len() >= 4 && nal[0] & 0x1F == 7)
+            .map(|nal| H264ProfileLevel::new(nal[1], nal[2], nal[3]).codec_string())
+    }
+
+    /// The parameter sets as an Annex B byte stream.
+    pub fn parameter_sets_bytestream(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for nal in &self.sprop_parameter_sets {
+            write_nalunit(&mut out, H264NalFormat::BYTESTREAM, 4, nal);
+        }
+        out
+    }
+
+    pub fn parameter_sets(&self) -> Result<(Vec<H264NalUnitSPS>, Vec<H264NalUnitPPS>), H264NalParseError> {
+        collect_parameter_sets(&self.parameter_sets_bytestream(), H264NalFormat::BYTESTREAM, 4)
+    }
+
+    /// Stores the parameter sets in a parser, so it can parse slices of a
+    /// stream that doesn't repeat them in band.
+    pub fn seed_parser(&self, parser: &mut H264NalParser) -> Result<(), H264NalParseError> {
+        let (sps, pps) = self.parameter_sets()?;
+        for s in sps {
+            parser.store_sps(s);
+        }
+        for p in pps {
+            parser.store_pps(p);
+        }
+        Ok(())
+    }
+
+    /// The a=fmtp attribute for an RTP payload type.
+    pub fn to_sdp_line(&self, payload_type: u8) -> String {
+        format!("a=fmtp:{} {}", payload_type, self)
+    }
+}
+
+impl fmt::Display for H264FmtpParameters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params = Vec::new();
+        params.push(("packetization-mode".to_string(), self.packetization_mode.to_string()));
+        if let Some(ref profile_level) = self.profile_level_id {
+            params.push(("profile-level-id".to_string(), profile_level.profile_level_id()));
+        }
+        if !self.sprop_parameter_sets.is_empty() {
+            let sets : Vec<String> = self.sprop_parameter_sets.iter().map(|nal| base64_encode(nal)).collect();
+            params.push(("sprop-parameter-sets".to_string(), sets.join(",")));
+        }
+        if let Some(max_fs) = self.max_fs {
+            params.push(("max-fs".to_string(), max_fs.to_string()));
+        }
+        if let Some(max_mbps) = self.max_mbps {
+            params.push(("max-mbps".to_string(), max_mbps.to_string()));
+        }
+        params.extend(self.other_parameters.iter().cloned());
+        write!(f, "{}", format_fmtp(&params))
+    }
+}
+
+impl H264NalUnitSPS {
+    /// The RFC 6381 codec string, such as avc1.4d401f.
+    pub fn codec_string(&self) -> String {
+        self.profile_level().codec_string()
+    }
+
+    /// The fmtp parameters for this SPS and the PPSs referring to it.
+    pub fn fmtp_parameters(&self, pps: &[H264NalUnitPPS], packetization_mode: u8) -> Result<H264FmtpParameters, H264NalParseError> {
+        let pps : Vec<H264NalUnitPPS> = pps.iter()
+            .filter(|p| p.seq_parameter_set_id == self.seq_parameter_set_id)
+            .cloned()
+            .collect();
+        H264FmtpParameters::from_parameter_sets(self, &pps, packetization_mode)
+    }
+}
+
+impl H264NalUnitPPS {
+    /// The base64 PPS entry of sprop-parameter-sets.
+    pub fn sprop_parameter_set(&self, sps: &H264NalUnitSPS) -> Result<String, H264NalWriteError> {
+        Ok(base64_encode(&self.to_bytes(sps.chroma_format_idc)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avcc::tests::parameter_sets_stream;
+
+    #[test]
+    fn from_parser_keeps_the_coded_parameter_sets() {
+        let (data, sps_nal, pps_nal) = parameter_sets_stream();
+        let mut parser = H264NalParser::from_bytes(data);
+        parser.format = H264NalFormat::BYTESTREAM;
+        parser.parse_sps(4).unwrap();
+        parser.parse_pps(4 + sps_nal.len() + 4).unwrap();
+        let params = H264FmtpParameters::from_parser(&parser, 0, 1).unwrap().unwrap();
+        assert_eq!(params.sprop_parameter_sets, vec![sps_nal, pps_nal]);
+        assert!(H264FmtpParameters::from_parser(&parser, 1, 1).unwrap().is_none());
+    }
+}
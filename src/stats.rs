@@ -0,0 +1,486 @@
+use std::cmp;
+use parser::{H264NalParser, H264NalParseError};
+use poc::H264TimestampGenerator;
+use gop::H264PictureType;
+pub use types::*;
+
+/// SEI payload type of the recovery point SEI.
+const SEI_RECOVERY_POINT : u32 = 6;
+
+/// Timestamps are derived in 90 kHz ticks, as in MPEG-TS.
+pub const STATS_CLOCK_RATE : u64 = 90000;
+
+/// Statistics of one access unit that has slices.
+#[derive(Debug, Clone)]
+pub struct H264AccessUnitStats {
+    /// Index of the access unit in the stream, counting the ones without
+    /// slices too.
+    pub index: u64,
+    pub offset: usize,
+    /// Bytes of all the NAL units, without start codes or length prefixes.
+    pub size: u64,
+    /// slice_type of every slice, in the order they come in.
+    pub slice_types: Vec<u32>,
+    /// SliceQPY of every slice: 26 + pic_init_qp_minus26 + slice_qp_delta.
+    pub qp: Vec<i32>,
+    /// The highest nal_ref_idc of the slices.
+    pub nal_ref_idc: u8,
+    pub idr: bool,
+    /// An IDR picture or one with a recovery point SEI.
+    pub random_access: bool,
+    /// Derived as by H264TimestampGenerator, in STATS_CLOCK_RATE ticks.
+    pub pts: u64,
+    pub dts: u64,
+    pub duration: u64
+}
+
+impl H264AccessUnitStats {
+    pub fn slice_count(&self) -> usize {
+        self.slice_types.len()
+    }
+
+    pub fn picture_type(&self) -> H264PictureType {
+        self.slice_types.iter().map(|&t| H264PictureType::from_slice_type(t))
+            .max().unwrap_or(H264PictureType::I)
+    }
+
+    pub fn min_qp(&self) -> i32 {
+        self.qp.iter().cloned().min().unwrap_or(0)
+    }
+
+    pub fn max_qp(&self) -> i32 {
+        self.qp.iter().cloned().max().unwrap_or(0)
+    }
+
+    pub fn average_qp(&self) -> f64 {
+        if self.qp.is_empty() {
+            return 0.0;
+        }
+        self.qp.iter().map(|&qp| qp as f64).sum::<f64>() / self.qp.len() as f64
+    }
+
+    /// The DTS in seconds.
+    pub fn time(&self) -> f64 {
+        self.dts as f64 / STATS_CLOCK_RATE as f64
+    }
+
+    /// Slice types as letters, e.g. "I" or "PPB" for a picture with several
+    /// slices.
+    pub fn slice_type_string(&self) -> String {
+        self.slice_types.iter().map(|&t| slice_type_name(t)).collect::<Vec<_>>().concat()
+    }
+}
+
+fn slice_type_name(slice_type: u32) -> &'static str {
+    if slice_type_is_p_slice(slice_type) {
+        "P"
+    } else if slice_type_is_b_slice(slice_type) {
+        "B"
+    } else if slice_type_is_i_slice(slice_type) {
+        "I"
+    } else if slice_type_is_sp_slice(slice_type) {
+        "SP"
+    } else {
+        "SI"
+    }
+}
+
+/// Bitrate over a span of the stream, such as a second or a GOP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264BitrateWindow {
+    /// Start of the span in seconds, from the DTS.
+    pub start: f64,
+    pub duration: f64,
+    pub access_units: u64,
+    pub bytes: u64,
+    /// The highest one second sliding window bitrate ending in the span,
+    /// in bits per second.
+    pub peak_bitrate: f64
+}
+
+impl H264BitrateWindow {
+    /// Average bits per second over the span.
+    pub fn bitrate(&self) -> f64 {
+        if self.duration <= 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 * 8.0 / self.duration
+    }
+}
+
+/// Per access unit statistics of a stream.
+#[derive(Debug, Clone)]
+pub struct H264StreamStats {
+    pub access_units: Vec<H264AccessUnitStats>,
+    /// Access units whose slices refer to a missing parameter set.
+    pub skipped_access_units: u64
+}
+
+impl H264StreamStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.access_units.iter().map(|au| au.size).sum()
+    }
+
+    /// From the first DTS to the end of the last access unit, in seconds.
+    pub fn duration(&self) -> f64 {
+        match (self.access_units.first(), self.access_units.last()) {
+            (Some(first), Some(last)) =>
+                (last.dts + last.duration - first.dts) as f64 / STATS_CLOCK_RATE as f64,
+            _ => 0.0
+        }
+    }
+
+    pub fn average_bitrate(&self) -> f64 {
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        self.total_bytes() as f64 * 8.0 / duration
+    }
+
+    /// The bitrate over the window seconds up to and including each access
+    /// unit, in bits per second. Until the stream is window seconds long it
+    /// is averaged over what there is so far.
+    pub fn sliding_bitrates(&self, window: f64) -> Vec<f64> {
+        let window_ticks = (window * STATS_CLOCK_RATE as f64) as u64;
+        let mut bitrates = Vec::with_capacity(self.access_units.len());
+        let mut first = 0;
+        let mut bytes = 0;
+        for (i, au) in self.access_units.iter().enumerate() {
+            bytes += au.size;
+            let end = au.dts + au.duration;
+            while first < i && self.access_units[first].dts + window_ticks < end {
+                bytes -= self.access_units[first].size;
+                first += 1;
+            }
+            let span = cmp::max(end - self.access_units[first].dts, 1);
+            bitrates.push(bytes as f64 * 8.0 * STATS_CLOCK_RATE as f64 / span as f64);
+        }
+        bitrates
+    }
+
+    /// The highest bitrate over any window seconds of the stream.
+    pub fn peak_bitrate(&self, window: f64) -> f64 {
+        self.sliding_bitrates(window).into_iter().fold(0.0, f64::max)
+    }
+
+    /// The bitrate of every second of the stream.
+    pub fn bitrate_per_second(&self) -> Vec<H264BitrateWindow> {
+        let first_dts = self.access_units.first().map_or(0, |au| au.dts);
+        self.windows(|au| (au.dts - first_dts) / STATS_CLOCK_RATE)
+    }
+
+    /// The bitrate of every GOP, from each random access point to the next.
+    pub fn bitrate_per_gop(&self) -> Vec<H264BitrateWindow> {
+        let mut gop = 0;
+        let mut started = false;
+        self.windows(|au| {
+            if au.random_access && started {
+                gop += 1;
+            }
+            started = true;
+            gop
+        })
+    }
+
+    /// Groups consecutive access units by key into windows.
+    fn windows<F>(&self, mut key: F) -> Vec<H264BitrateWindow>
+        where F: FnMut(&H264AccessUnitStats) -> u64 {
+        let sliding = self.sliding_bitrates(1.0);
+        let mut windows = Vec::new();
+        let mut current : Option<(u64, H264BitrateWindow)> = None;
+        for (au, &bitrate) in self.access_units.iter().zip(sliding.iter()) {
+            let k = key(au);
+            let start = au.time();
+            let duration = au.duration as f64 / STATS_CLOCK_RATE as f64;
+            match current {
+                Some((current_key, ref mut window)) if current_key == k => {
+                    window.duration = start + duration - window.start;
+                    window.access_units += 1;
+                    window.bytes += au.size;
+                    window.peak_bitrate = window.peak_bitrate.max(bitrate);
+                    continue;
+                },
+                _ => {}
+            }
+            if let Some((_, window)) = current.take() {
+                windows.push(window);
+            }
+            current = Some((k, H264BitrateWindow {
+                start,
+                duration,
+                access_units: 1,
+                bytes: au.size,
+                peak_bitrate: bitrate
+            }));
+        }
+        if let Some((_, window)) = current {
+            windows.push(window);
+        }
+        windows
+    }
+
+    /// One line per access unit, with a header line.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("index,offset,size,slices,slice_types,picture_type,qp_min,qp_max,qp_avg,nal_ref_idc,idr,random_access,pts,dts,time\n");
+        for au in &self.access_units {
+            csv.push_str(&format!("{},{},{},{},{},{},{},{},{:.2},{},{},{},{},{},{:.6}\n",
+                                  au.index, au.offset, au.size, au.slice_count(), au.slice_type_string(),
+                                  au.picture_type().letter(), au.min_qp(), au.max_qp(), au.average_qp(),
+                                  au.nal_ref_idc, au.idr as u8, au.random_access as u8, au.pts, au.dts, au.time()));
+        }
+        csv
+    }
+
+    /// The access units and the per second and per GOP bitrates as a JSON
+    /// object.
+    pub fn to_json(&self) -> String {
+        let access_units = self.access_units.iter().map(|au| {
+            format!("{{\"index\":{},\"offset\":{},\"size\":{},\"slice_types\":[{}],\"picture_type\":{},\
+                     \"qp\":[{}],\"nal_ref_idc\":{},\"idr\":{},\"random_access\":{},\"pts\":{},\"dts\":{},\"time\":{}}}",
+                    au.index, au.offset, au.size,
+                    au.slice_types.iter().map(|&t| json_string(slice_type_name(t))).collect::<Vec<_>>().join(","),
+                    json_string(&au.picture_type().letter().to_string()),
+                    au.qp.iter().map(|qp| qp.to_string()).collect::<Vec<_>>().join(","),
+                    au.nal_ref_idc, au.idr, au.random_access, au.pts, au.dts, json_number(au.time(), 6))
+        }).collect::<Vec<_>>();
+        format!("{{\"clock_rate\":{},\"duration\":{},\"total_bytes\":{},\"average_bitrate\":{},\
+                 \"peak_bitrate\":{},\"access_units\":[{}],\"per_second\":{},\"per_gop\":{}}}",
+                STATS_CLOCK_RATE, json_number(self.duration(), 6), self.total_bytes(),
+                json_number(self.average_bitrate(), 0), json_number(self.peak_bitrate(1.0), 0),
+                access_units.join(","),
+                windows_to_json(&self.bitrate_per_second()), windows_to_json(&self.bitrate_per_gop()))
+    }
+}
+
+/// One line per window from bitrate_per_second or bitrate_per_gop, with
+/// a header line.
+pub fn windows_to_csv(windows: &[H264BitrateWindow]) -> String {
+    let mut csv = String::from("start,duration,access_units,bytes,bitrate,peak_bitrate\n");
+    for w in windows {
+        csv.push_str(&format!("{:.6},{:.6},{},{},{:.0},{:.0}\n",
+                              w.start, w.duration, w.access_units, w.bytes, w.bitrate(), w.peak_bitrate));
+    }
+    csv
+}
+
+fn windows_to_json(windows: &[H264BitrateWindow]) -> String {
+    let windows = windows.iter().map(|w| {
+        format!("{{\"start\":{},\"duration\":{},\"access_units\":{},\"bytes\":{},\"bitrate\":{},\"peak_bitrate\":{}}}",
+                json_number(w.start, 6), json_number(w.duration, 6), w.access_units, w.bytes,
+                json_number(w.bitrate(), 0), json_number(w.peak_bitrate, 0))
+    }).collect::<Vec<_>>();
+    format!("[{}]", windows.join(","))
+}
+
+/// A JSON string with quotes, backslashes and control characters escaped.
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A number with the given decimals, or null for NaN and infinity, which
+/// JSON can't represent.
+fn json_number(value: f64, decimals: usize) -> String {
+    if value.is_finite() {
+        format!("{:.*}", decimals, value)
+    } else {
+        String::from("null")
+    }
+}
+
+/// Collects the statistics of every access unit with slices. frame_rate is
+/// used for the timestamps when the SPS has no VUI timing info.
+pub fn stream_stats(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                    frame_rate: Option<f64>) -> Result<H264StreamStats, H264NalParseError> {
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut timestamps = H264TimestampGenerator::new(STATS_CLOCK_RATE);
+    if let Some(rate) = frame_rate {
+        timestamps.default_frame_rate = ((rate * 1000.0).round() as u32, 1000);
+    }
+    let mut access_units = Vec::new();
+    let mut skipped = 0;
+    let mut index = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let au = parser.parse_access_unit(offset)?;
+        if au.size == 0 {
+            break;
+        }
+        let mut stats = H264AccessUnitStats {
+            index,
+            offset: au.offset,
+            size: au.nal_units.iter().map(|n| (n.sc_offset + n.size - n.data_offset) as u64).sum(),
+            slice_types: Vec::new(),
+            qp: Vec::new(),
+            nal_ref_idc: 0,
+            idr: au.is_idr(),
+            random_access: au.is_idr() || au.sei.iter().any(|m| m.payload_type == SEI_RECOVERY_POINT),
+            pts: 0,
+            dts: 0,
+            duration: 0
+        };
+        offset += au.size;
+        index += 1;
+
+        let mut first_slice = None;
+        for unit in &au.nal_units {
+            match unit.nal_unit_type_num {
+                7 => { parser.parse_sps(unit.data_offset)?; },
+                8 => { parser.parse_pps(unit.data_offset)?; },
+                1..=5 => {
+                    let slice = parser.parse_slice(unit.data_offset, unit)?;
+                    let pic_init_qp_minus26 = parser.find_pps(slice.pic_parameter_set_id)
+                        .map_or(0, |pps| pps.pic_init_qp_minus26);
+                    stats.slice_types.push(slice.slice_type);
+                    stats.qp.push(26 + pic_init_qp_minus26 + slice.slice_qp_delta);
+                    stats.nal_ref_idc = cmp::max(stats.nal_ref_idc, unit.nal_ref_idc);
+                    if first_slice.is_none() {
+                        first_slice = Some((unit, slice));
+                    }
+                },
+                _ => {}
+            }
+        }
+        let (unit, slice) = match first_slice {
+            Some(s) => s,
+            None => continue
+        };
+        let sps = match parser.find_pps(slice.pic_parameter_set_id)
+            .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id)) {
+            Some(sps) => sps.clone(),
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+        let timing = timestamps.timing(&sps, unit, &slice, &au.sei);
+        stats.pts = timing.pts;
+        stats.dts = timing.dts;
+        stats.duration = timing.duration;
+        access_units.push(stats);
+    }
+    Ok(H264StreamStats {
+        access_units,
+        skipped_access_units: skipped
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gop::tests::{sps, slice_nal};
+    use writer::{H264NalWriter, H264SEIBuilder, write_nalunit};
+
+    /// A second slice of a picture, starting at macroblock 1.
+    fn second_slice(sps: &H264NalUnitSPS, slice_type: u32, idr: bool, qp_delta: i32) -> Vec<u8> {
+        let mut slice = H264NalUnitSlice::new();
+        slice.first_mb_in_slice = 1;
+        slice.slice_type = slice_type;
+        slice.slice_qp_delta = qp_delta;
+        let nal_type = if idr { 5 } else { 1 };
+        let unit = H264NalUnit::new(0, 4, 0, 3, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, sps, &H264NalUnitPPS::new()).unwrap();
+        writer.write_rbsp_trailing_bits();
+        writer.to_nal(3, nal_type)
+    }
+
+    #[test]
+    fn access_unit_counters_and_qp() {
+        let sps = sps(true);
+        let aud = vec![0x09, 0xF0];
+        let recovery_point = H264SEIBuilder::new().add_message(SEI_RECOVERY_POINT, &[0xC4]).build();
+        let access_units = vec![
+            vec![sps.to_bytes().unwrap(), H264NalUnitPPS::new().to_bytes(1).unwrap(),
+                 slice_nal(&sps, &('I', true, true, 0, 0, None), -2), second_slice(&sps, 7, true, 2)],
+            vec![slice_nal(&sps, &('P', false, true, 1, 4, None), 4)],
+            vec![slice_nal(&sps, &('B', false, false, 2, 2, None), 6), second_slice(&sps, 5, false, 5)],
+            vec![recovery_point, slice_nal(&sps, &('I', false, true, 2, 8, None), 0)],
+            vec![aud.clone(), slice_nal(&sps, &('P', false, true, 3, 12, None), 0)],
+            // No slices, only a delimiter and the end of the stream
+            vec![aud, vec![0x0B]]
+        ];
+        let mut data = Vec::new();
+        for au in &access_units {
+            for nal in au {
+                write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, nal);
+            }
+        }
+        let stats = stream_stats(&data, H264NalFormat::BYTESTREAM, 4, Some(25.0)).unwrap();
+        assert_eq!(stats.skipped_access_units, 0);
+        let indices : Vec<u64> = stats.access_units.iter().map(|au| au.index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4]);
+        for au in &stats.access_units {
+            let nal_units = &access_units[au.index as usize];
+            assert_eq!(au.size, nal_units.iter().map(|n| n.len() as u64).sum::<u64>());
+            assert_eq!(au.duration, 3600);
+        }
+        assert_eq!(stats.access_units[1].offset, access_units[0].iter().map(|n| 4 + n.len()).sum::<usize>());
+
+        let summary : Vec<(String, char, u8, bool, bool)> = stats.access_units.iter()
+            .map(|au| (au.slice_type_string(), au.picture_type().letter(), au.nal_ref_idc, au.idr, au.random_access))
+            .collect();
+        assert_eq!(summary, vec![
+            ("II".to_string(), 'I', 3, true, true),
+            ("P".to_string(), 'P', 2, false, false),
+            ("BP".to_string(), 'B', 3, false, false),
+            ("I".to_string(), 'I', 2, false, true),
+            ("P".to_string(), 'P', 2, false, false)
+        ]);
+        let qp : Vec<(i32, i32, f64)> = stats.access_units.iter()
+            .map(|au| (au.min_qp(), au.max_qp(), au.average_qp()))
+            .collect();
+        assert_eq!(qp, vec![(24, 28, 26.0), (30, 30, 30.0), (31, 32, 31.5), (26, 26, 26.0), (26, 26, 26.0)]);
+        // Two frames of reordering as the VUI doesn't say.
+        let timestamps : Vec<(u64, u64)> = stats.access_units.iter().map(|au| (au.pts, au.dts)).collect();
+        assert_eq!(timestamps, vec![(7200, 0), (14400, 3600), (10800, 7200), (21600, 10800), (28800, 14400)]);
+
+        let total = stats.total_bytes();
+        assert_eq!(stats.duration(), 0.2);
+        assert_eq!(stats.average_bitrate(), total as f64 * 8.0 / 0.2);
+        let per_gop : Vec<(u64, u64)> = stats.bitrate_per_gop().iter().map(|w| (w.access_units, w.bytes)).collect();
+        let first_gop : u64 = stats.access_units[..3].iter().map(|au| au.size).sum();
+        assert_eq!(per_gop, vec![(3, first_gop), (2, total - first_gop)]);
+        let per_second = stats.bitrate_per_second();
+        assert_eq!(per_second.len(), 1);
+        assert_eq!(per_second[0].bitrate(), stats.average_bitrate());
+
+        let csv = stats.to_csv();
+        assert_eq!(csv.lines().count(), 6);
+        assert!(csv.lines().nth(3).unwrap().starts_with(&format!("2,{},", stats.access_units[2].offset)));
+        let json = stats.to_json();
+        assert!(json.contains("\"slice_types\":[\"B\",\"P\"],\"picture_type\":\"B\",\"qp\":[32,31]"));
+        assert!(json.contains(&format!("\"total_bytes\":{},", total)));
+    }
+
+    #[test]
+    fn json_values_are_escaped() {
+        assert_eq!(json_string("I"), "\"I\"");
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+        assert_eq!(json_number(1.23456, 2), "1.23");
+        assert_eq!(json_number(f64::NAN, 0), "null");
+        assert_eq!(json_number(f64::INFINITY, 6), "null");
+
+        let empty = H264StreamStats {
+            access_units: Vec::new(),
+            skipped_access_units: 0
+        };
+        assert_eq!(empty.to_json(), "{\"clock_rate\":90000,\"duration\":0.000000,\"total_bytes\":0,\"average_bitrate\":0,\
+                                     \"peak_bitrate\":0,\"access_units\":[],\"per_second\":[],\"per_gop\":[]}");
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::VecDeque;
+use ts::*;
+
+/// A PSI section being collected from the packets of one PID.
+struct H264TsSection {
+    pid: u16,
+    data: Vec<u8>
+}
+
+/// Pulls the H.264 PES packets out of a transport stream. The video PID is
+/// the first stream_type 0x1B stream of the first program in the PAT
+/// unless one is set before the first packet. New versions of the PAT and
+/// PMT move the PIDs that weren't set.
+pub struct H264TsDemuxer {
+    pub video_pid: Option<u16>,
+    pub pmt_pid: Option<u16>,
+    pub pcr_pid: Option<u16>,
+    pub continuity_errors: u64,
+    /// Bytes skipped while looking for the sync byte.
+    pub skipped_bytes: u64,
+
+    sections: Vec<H264TsSection>,
+    continuity: Vec<(u16, u8)>,
+    current: Option<H264TsPes>,
+    /// PES_packet_length of the current PES, 0 if unbounded.
+    current_length: usize,
+    ready: VecDeque<H264TsPes>,
+    leftover: Vec<u8>,
+    /// Whether pmt_pid and video_pid were set before the first packet,
+    /// once it came.
+    fixed_pids: Option<(bool, bool)>,
+    pat_version: Option<u8>,
+    pmt_version: Option<u8>
+}
+
+impl Default for H264TsDemuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264TsDemuxer {
+    pub fn new() -> H264TsDemuxer {
+        H264TsDemuxer {
+            video_pid: None,
+            pmt_pid: None,
+            pcr_pid: None,
+            continuity_errors: 0,
+            skipped_bytes: 0,
+            sections: Vec::new(),
+            continuity: Vec::new(),
+            current: None,
+            current_length: 0,
+            ready: VecDeque::new(),
+            leftover: Vec::new(),
+            fixed_pids: None,
+            pat_version: None,
+            pmt_version: None
+        }
+    }
+
+    /// Takes any amount of transport stream data. Packets can be split
+    /// across calls, and garbage between packets is skipped. Returns the
+    /// first error of the packets in data.
+    pub fn push_bytes(&mut self, data: &[u8]) -> Result<(), H264TsError> {
+        let mut result = Ok(());
+        self.push_bytes_with(data, &mut |e| {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        });
+        result
+    }
+
+    /// Like push_bytes, passing every error to on_error.
+    fn push_bytes_with(&mut self, data: &[u8], on_error: &mut dyn FnMut(H264TsError)) {
+        let mut buffer = Vec::new();
+        let data = if self.leftover.is_empty() {
+            data
+        } else {
+            buffer.append(&mut self.leftover);
+            buffer.extend_from_slice(data);
+            &buffer[..]
+        };
+        let mut pos = 0;
+        while pos + TS_PACKET_SIZE <= data.len() {
+            // Only trust a sync byte that's followed by another one.
+            let synced = data[pos] == TS_SYNC_BYTE &&
+                (pos + 2 * TS_PACKET_SIZE > data.len() || data[pos + TS_PACKET_SIZE] == TS_SYNC_BYTE);
+            if !synced {
+                pos += 1;
+                self.skipped_bytes += 1;
+                continue;
+            }
+            // Keep going after a bad packet, the next ones may be fine.
+            if let Err(e) = self.push_packet(&data[pos..pos + TS_PACKET_SIZE]) {
+                on_error(e);
+            }
+            pos += TS_PACKET_SIZE;
+        }
+        self.leftover = data[pos..].to_vec();
+    }
+
+    /// Takes one 188 byte packet.
+    pub fn push_packet(&mut self, packet: &[u8]) -> Result<(), H264TsError> {
+        if packet.len() < TS_PACKET_SIZE {
+            return Err(H264TsError::Truncated);
+        }
+        if packet[0] != TS_SYNC_BYTE {
+            return Err(H264TsError::LostSync);
+        }
+        let transport_error = packet[1] & 0x80 != 0;
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let pid = ((packet[1] & 0x1F) as u16) << 8 | packet[2] as u16;
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+        let continuity_counter = packet[3] & 0x0F;
+        if pid == TS_NULL_PID {
+            return Ok(());
+        }
+        if self.fixed_pids.is_none() {
+            self.fixed_pids = Some((self.pmt_pid.is_some(), self.video_pid.is_some()));
+        }
+
+        let mut pos = 4;
+        let mut discontinuity = false;
+        let mut random_access = false;
+        if adaptation_field_control & 0x02 != 0 {
+            let length = packet[4] as usize;
+            if length > 0 {
+                discontinuity = packet[5] & 0x80 != 0;
+                random_access = packet[5] & 0x40 != 0;
+            }
+            pos += 1 + length;
+        }
+        let has_payload = adaptation_field_control & 0x01 != 0;
+        if !has_payload || pos >= TS_PACKET_SIZE {
+            return Ok(());
+        }
+        let payload = &packet[pos..];
+
+        let is_video = Some(pid) == self.video_pid;
+        if !self.check_continuity(pid, continuity_counter, discontinuity) {
+            // A repeated packet.
+            return Ok(());
+        }
+        if transport_error {
+            self.continuity_errors += 1;
+            if is_video {
+                if let Some(ref mut pes) = self.current {
+                    pes.damaged = true;
+                }
+            }
+            return Ok(());
+        }
+
+        if pid == TS_PAT_PID || Some(pid) == self.pmt_pid {
+            return self.push_section(pid, payload_unit_start, payload);
+        }
+        if is_video {
+            return self.push_pes(pid, payload_unit_start, payload, random_access, discontinuity);
+        }
+        Ok(())
+    }
+
+    /// False for a duplicate packet. Counts and flags lost packets.
+    fn check_continuity(&mut self, pid: u16, counter: u8, discontinuity: bool) -> bool {
+        let index = match self.continuity.iter().position(|c| c.0 == pid) {
+            Some(i) => i,
+            None => {
+                self.continuity.push((pid, counter));
+                return true;
+            }
+        };
+        let last = self.continuity[index].1;
+        self.continuity[index].1 = counter;
+        if discontinuity {
+            return true;
+        }
+        if counter == last {
+            return false;
+        }
+        if counter != (last + 1) & 0x0F {
+            self.continuity_errors += 1;
+            if Some(pid) == self.video_pid {
+                if let Some(ref mut pes) = self.current {
+                    pes.damaged = true;
+                }
+            }
+        }
+        true
+    }
+
+    fn push_section(&mut self, pid: u16, payload_unit_start: bool, payload: &[u8]) -> Result<(), H264TsError> {
+        let index = match self.sections.iter().position(|s| s.pid == pid) {
+            Some(i) => i,
+            None => {
+                self.sections.push(H264TsSection { pid, data: Vec::new() });
+                self.sections.len() - 1
+            }
+        };
+        if payload_unit_start {
+            let pointer = payload[0] as usize;
+            if 1 + pointer > payload.len() {
+                return Err(H264TsError::Truncated);
+            }
+            self.sections[index].data = payload[1 + pointer..].to_vec();
+        } else if !self.sections[index].data.is_empty() {
+            self.sections[index].data.extend_from_slice(payload);
+        }
+        let section = self.sections[index].data.clone();
+        if section.len() < 3 || section[0] == 0xFF {
+            return Ok(());
+        }
+        let length = 3 + (((section[1] & 0x0F) as usize) << 8 | section[2] as usize);
+        if section.len() < length {
+            return Ok(());
+        }
+        self.sections[index].data.clear();
+        let section = &section[..length];
+        if length < 12 || crc32_mpeg2(section) != 0 {
+            return Err(H264TsError::BadCrc(pid));
+        }
+        match section[0] {
+            0x00 if pid == TS_PAT_PID => self.parse_pat(section),
+            0x02 => self.parse_pmt(section),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The version_number of a section, None unless it's current.
+    fn section_version(section: &[u8]) -> Option<u8> {
+        if section[5] & 0x01 != 0 {
+            Some((section[5] >> 1) & 0x1F)
+        } else {
+            None
+        }
+    }
+
+    fn parse_pat(&mut self, section: &[u8]) {
+        let version = match H264TsDemuxer::section_version(section) {
+            Some(v) if Some(v) != self.pat_version => v,
+            _ => return
+        };
+        self.pat_version = Some(version);
+        if self.fixed_pids.is_some_and(|f| f.0) {
+            return;
+        }
+        let mut pos = 8;
+        while pos + 4 <= section.len() - 4 {
+            let program_number = (section[pos] as u16) << 8 | section[pos + 1] as u16;
+            let pid = ((section[pos + 2] & 0x1F) as u16) << 8 | section[pos + 3] as u16;
+            if program_number != 0 {
+                if self.pmt_pid != Some(pid) {
+                    self.pmt_pid = Some(pid);
+                    self.pmt_version = None;
+                }
+                break;
+            }
+            pos += 4;
+        }
+    }
+
+    fn parse_pmt(&mut self, section: &[u8]) {
+        let version = match H264TsDemuxer::section_version(section) {
+            Some(v) if Some(v) != self.pmt_version => v,
+            _ => return
+        };
+        self.pmt_version = Some(version);
+        self.pcr_pid = Some(((section[8] & 0x1F) as u16) << 8 | section[9] as u16);
+        if self.fixed_pids.is_some_and(|f| f.1) {
+            return;
+        }
+        let program_info_length = ((section[10] & 0x0F) as usize) << 8 | section[11] as usize;
+        let mut pos = 12 + program_info_length;
+        let mut video_pid = None;
+        while pos + 5 <= section.len() - 4 {
+            let stream_type = section[pos];
+            let pid = ((section[pos + 1] & 0x1F) as u16) << 8 | section[pos + 2] as u16;
+            let es_info_length = ((section[pos + 3] & 0x0F) as usize) << 8 | section[pos + 4] as usize;
+            if stream_type == TS_STREAM_TYPE_H264 && video_pid.is_none() {
+                video_pid = Some(pid);
+            }
+            pos += 5 + es_info_length;
+        }
+        if video_pid != self.video_pid {
+            // The PES of the old PID ends here.
+            self.finish_pes();
+            self.video_pid = video_pid;
+        }
+    }
+
+    fn push_pes(&mut self, pid: u16, payload_unit_start: bool, payload: &[u8],
+                random_access: bool, discontinuity: bool) -> Result<(), H264TsError> {
+        if payload_unit_start {
+            self.finish_pes();
+            let mut pes = H264TsPes::new(pid);
+            pes.random_access = random_access;
+            pes.discontinuity = discontinuity;
+            if payload.len() < 9 || payload[0] != 0 || payload[1] != 0 || payload[2] != 1 ||
+                payload.len() < 9 + payload[8] as usize {
+                // Keep the PES so whoever reads it knows something is
+                // missing between the ones around it.
+                pes.damaged = true;
+                self.current = Some(pes);
+                return Err(H264TsError::InvalidPes(pid));
+            }
+            let packet_length = (payload[4] as usize) << 8 | payload[5] as usize;
+            let pts_dts_flags = payload[7] >> 6;
+            let header_length = payload[8] as usize;
+            if pts_dts_flags & 0x02 != 0 && header_length >= 5 {
+                pes.pts = Some(read_timestamp(&payload[9..]));
+            }
+            if pts_dts_flags == 0x03 && header_length >= 10 {
+                pes.dts = Some(read_timestamp(&payload[14..]));
+            }
+            pes.data.extend_from_slice(&payload[9 + header_length..]);
+            // PES_packet_length counts the bytes after itself.
+            self.current_length = if packet_length == 0 { 0 } else { packet_length.saturating_sub(3 + header_length) };
+            self.current = Some(pes);
+        } else {
+            match self.current {
+                Some(ref mut pes) => pes.data.extend_from_slice(payload),
+                // The start of this PES was lost or came before we found
+                // the PMT.
+                None => return Ok(())
+            }
+        }
+        if self.current_length > 0 && self.current.as_ref().map_or(0, |p| p.data.len()) >= self.current_length {
+            self.finish_pes();
+        }
+        Ok(())
+    }
+
+    fn finish_pes(&mut self) {
+        if let Some(mut pes) = self.current.take() {
+            if self.current_length > 0 {
+                if pes.data.len() < self.current_length {
+                    pes.damaged = true;
+                }
+                pes.data.truncate(self.current_length);
+            }
+            self.ready.push_back(pes);
+        }
+        self.current_length = 0;
+    }
+
+    /// The next PES of the video PID.
+    pub fn pop_pes(&mut self) -> Option<H264TsPes> {
+        self.ready.pop_front()
+    }
+
+    /// Passes on the last PES, which only ends at the end of the stream
+    /// when its length isn't given.
+    pub fn flush(&mut self) {
+        self.finish_pes();
+    }
+}
+
+/// What demux_stream found in a transport stream.
+#[derive(Debug)]
+pub struct H264TsDemuxReport {
+    pub pes: Vec<H264TsPes>,
+    /// Every error, in stream order. A PES hit by one is marked damaged.
+    pub errors: Vec<H264TsError>,
+    pub continuity_errors: u64,
+    pub skipped_bytes: u64
+}
+
+/// Every H.264 PES of a whole transport stream. Bad packets don't stop the
+/// demuxing, they are reported with the PES.
+pub fn demux_stream(data: &[u8]) -> H264TsDemuxReport {
+    let mut demuxer = H264TsDemuxer::new();
+    let mut errors = Vec::new();
+    demuxer.push_bytes_with(data, &mut |e| errors.push(e));
+    demuxer.flush();
+    let mut pes = Vec::new();
+    while let Some(p) = demuxer.pop_pes() {
+        pes.push(p);
+    }
+    H264TsDemuxReport {
+        pes,
+        errors,
+        continuity_errors: demuxer.continuity_errors,
+        skipped_bytes: demuxer.skipped_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::H264NalWriter;
+
+    /// Three IDR pictures of a 16x16 Baseline stream.
+    fn stream() -> Vec<u8> {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        let pps = H264NalUnitPPS::new();
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        for i in 0..3 {
+            let mut slice = H264NalUnitSlice::new();
+            slice.slice_type = 7;
+            slice.idr_pic_id = i as u32;
+            let unit = H264NalUnit::new(0, 4, 0, 3, 5);
+            let mut writer = H264NalWriter::new();
+            slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+            writer.write_rbsp_trailing_bits();
+            let nal_units = [sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), writer.to_nal(3, 5)];
+            muxer.write_access_unit(&nal_units, Some(126000 + i * 3000), None).unwrap();
+        }
+        muxer.into_inner()
+    }
+
+    /// Offset of the payload of a packet.
+    fn payload_start(packet: &[u8]) -> usize {
+        if packet[3] & 0x20 != 0 { 5 + packet[4] as usize } else { 4 }
+    }
+
+    /// A packet with one PSI section. Sections that aren't current get
+    /// version 31 without current_next_indicator.
+    fn section_packet(pid: u16, table_id: u8, version: Option<u8>, body: &[u8]) -> Vec<u8> {
+        let length = 5 + body.len() + 4;
+        let mut section = vec![table_id, 0xB0, length as u8, 0, 1, version.map_or(0xFE, |v| 0xC1 | v << 1), 0, 0];
+        section.extend_from_slice(body);
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        let mut packet = vec![TS_SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10, 0];
+        packet.extend_from_slice(&section);
+        packet.resize(TS_PACKET_SIZE, 0xFF);
+        packet
+    }
+
+    fn pat(version: Option<u8>, pmt_pid: u16) -> Vec<u8> {
+        section_packet(TS_PAT_PID, 0x00, version, &[0, 0, 0xE0, 0x10, 0, 1, 0xE0 | (pmt_pid >> 8) as u8, pmt_pid as u8])
+    }
+
+    fn pmt(pmt_pid: u16, version: Option<u8>, video_pid: u16) -> Vec<u8> {
+        section_packet(pmt_pid, 0x02, version, &[
+            0xE1, 0x00, 0xF0, 0x00,
+            0x0F, 0xE1, 0x01, 0xF0, 0x00,
+            TS_STREAM_TYPE_H264, 0xE0 | (video_pid >> 8) as u8, video_pid as u8, 0xF0, 0x00
+        ])
+    }
+
+    /// A whole PES without timestamps in one packet.
+    fn pes_packet(pid: u16, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, 0xE0, 0, 3 + data.len() as u8, 0x80, 0x00, 0x00];
+        pes.extend_from_slice(data);
+        let stuffing = TS_PACKET_SIZE - 5 - pes.len();
+        let mut packet = vec![TS_SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x30, stuffing as u8, 0x00];
+        packet.resize(5 + stuffing, 0xFF);
+        packet.extend_from_slice(&pes);
+        packet
+    }
+
+    fn demux_packets(demuxer: &mut H264TsDemuxer, packets: &[Vec<u8>]) -> Vec<(u16, Vec<u8>)> {
+        // Every packet has its own continuity counter sequence.
+        let mut counters : Vec<(u16, u8)> = Vec::new();
+        for packet in packets {
+            let mut packet = packet.clone();
+            let pid = ((packet[1] & 0x1F) as u16) << 8 | packet[2] as u16;
+            let cc = match counters.iter_mut().find(|c| c.0 == pid) {
+                Some(c) => {
+                    c.1 = (c.1 + 1) & 0x0F;
+                    c.1
+                },
+                None => {
+                    counters.push((pid, 0));
+                    0
+                }
+            };
+            packet[3] |= cc;
+            demuxer.push_packet(&packet).unwrap();
+        }
+        demuxer.flush();
+        let mut pes = Vec::new();
+        while let Some(p) = demuxer.pop_pes() {
+            pes.push((p.pid, p.data));
+        }
+        pes
+    }
+
+    #[test]
+    fn new_pat_and_pmt_versions_move_the_pids() {
+        let packets = [
+            pat(Some(0), 0x1000),
+            pes_packet(0x100, &[1]),
+            pmt(0x1000, Some(0), 0x100),
+            pes_packet(0x100, &[2]),
+            pes_packet(0x101, &[3]),
+            // A section that isn't current yet is ignored, as is a repeat
+            // of the current version.
+            pmt(0x1000, None, 0x101),
+            pmt(0x1000, Some(0), 0x101),
+            pes_packet(0x100, &[4]),
+            pmt(0x1000, Some(1), 0x101),
+            pes_packet(0x100, &[5]),
+            pes_packet(0x101, &[6]),
+            // The PMT moves, its version counts from scratch.
+            pat(Some(1), 0x1001),
+            pmt(0x1000, Some(2), 0x100),
+            pmt(0x1001, Some(0), 0x102),
+            pes_packet(0x102, &[7])
+        ];
+        let mut demuxer = H264TsDemuxer::new();
+        let pes = demux_packets(&mut demuxer, &packets);
+        assert_eq!(pes, vec![(0x100, vec![2]), (0x100, vec![4]), (0x101, vec![6]), (0x102, vec![7])]);
+        assert_eq!((demuxer.pmt_pid, demuxer.video_pid, demuxer.pcr_pid), (Some(0x1001), Some(0x102), Some(0x100)));
+        assert_eq!(demuxer.continuity_errors, 0);
+
+        // PIDs set before the first packet stay.
+        let mut demuxer = H264TsDemuxer::new();
+        demuxer.video_pid = Some(0x100);
+        let pes = demux_packets(&mut demuxer, &packets);
+        assert_eq!(pes, vec![(0x100, vec![1]), (0x100, vec![2]), (0x100, vec![4]), (0x100, vec![5])]);
+        assert_eq!(demuxer.pmt_pid, Some(0x1001));
+    }
+
+    #[test]
+    fn bad_pes_is_reported_and_marked_damaged() {
+        let mut data = stream();
+        let starts = data.chunks(TS_PACKET_SIZE).enumerate()
+            .filter(|&(_, p)| p[1] & 0x40 != 0 && ((p[1] & 0x1F) as u16) << 8 | p[2] as u16 == 0x100)
+            .map(|(i, p)| i * TS_PACKET_SIZE + payload_start(p))
+            .collect::<Vec<_>>();
+        assert_eq!(starts.len(), 3);
+        // No start code prefix
+        data[starts[1] + 2] = 0x02;
+
+        let report = demux_stream(&data);
+        assert_eq!(report.pes.len(), 3);
+        assert_eq!(report.errors.len(), 1);
+        match report.errors[0] {
+            H264TsError::InvalidPes(0x100) => {},
+            ref e => panic!("{:?}", e)
+        }
+        assert!(!report.pes[0].damaged);
+        assert!(report.pes[1].damaged);
+        assert!(!report.pes[2].damaged);
+        assert_eq!(report.pes[2].pts, Some(132000));
+        assert_eq!(report.continuity_errors, 0);
+    }
+
+    #[test]
+    fn timestamps_go_with_the_first_access_unit() {
+        let report = demux_stream(&stream());
+        assert!(report.errors.is_empty());
+        let mut pes = report.pes[0].clone();
+        let second = pes.data.clone();
+        pes.data.extend_from_slice(&second);
+        let mut parser = H264NalParser::from_bytes(Vec::new());
+        let units = pes.access_units(&mut parser).unwrap();
+        assert_eq!(units.len(), 2);
+        assert_eq!((units[0].1, units[0].2), (Some(126000), None));
+        assert_eq!((units[1].1, units[1].2), (None, None));
+    }
+}
@@ -0,0 +1,464 @@
+use std::io::Write;
+use parser::H264NalParser;
+use poc::H264TimestampGenerator;
+use ts::*;
+
+/// SEI payload type of the recovery point SEI.
+const SEI_RECOVERY_POINT : u32 = 6;
+
+/// Writes access units as a single program transport stream with one
+/// H.264 PID.
+///
+/// Without caller supplied timestamps, they are derived by an
+/// H264TimestampGenerator.
+pub struct H264TsMuxer<W: Write> {
+    writer: W,
+    pub video_pid: u16,
+    pub pmt_pid: u16,
+    pub program_number: u16,
+    /// Insert an access unit delimiter into access units without one.
+    pub insert_aud: bool,
+    /// PAT and PMT are sent before every random access point and at least
+    /// every psi_interval access units.
+    pub psi_interval: u32,
+    /// Frame duration in 90kHz ticks, overriding the VUI.
+    pub frame_duration: Option<u64>,
+    /// How far the PCR runs ahead of the DTS, in 90kHz ticks.
+    pub pcr_offset: u64,
+    /// A PCR goes with random access points and with the first PES at
+    /// least pcr_interval 90kHz ticks after the last PCR.
+    pub pcr_interval: u64,
+    /// DTS of the first access unit when the timestamps are derived.
+    pub first_dts: u64,
+
+    parser: H264NalParser,
+    timestamps: H264TimestampGenerator,
+    pat_cc: u8,
+    pmt_cc: u8,
+    video_cc: u8,
+    access_units: u64,
+    since_psi: u32,
+    last_pcr: Option<u64>
+}
+
+impl<W: Write> H264TsMuxer<W> {
+    pub fn new(writer: W) -> H264TsMuxer<W> {
+        H264TsMuxer {
+            writer,
+            video_pid: 0x100,
+            pmt_pid: 0x1000,
+            program_number: 1,
+            insert_aud: true,
+            psi_interval: 25,
+            frame_duration: None,
+            pcr_offset: 9000,
+            pcr_interval: 3600,
+            first_dts: 126000,
+            parser: H264NalParser::from_bytes(Vec::new()),
+            timestamps: H264TimestampGenerator::new(TS_CLOCK_RATE),
+            pat_cc: 0,
+            pmt_cc: 0,
+            video_cc: 0,
+            access_units: 0,
+            since_psi: 0,
+            last_pcr: None
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Writes an access unit given as NAL units without start codes. With
+    /// pts None, both timestamps are derived; a dts of None means it's the
+    /// same as the PTS.
+    pub fn write_access_unit(&mut self, nal_units: &[Vec<u8>], pts: Option<u64>,
+                             dts: Option<u64>) -> Result<(), H264TsError> {
+        let nal_units : Vec<&Vec<u8>> = nal_units.iter().filter(|n| !n.is_empty()).collect();
+        let mut bytestream = Vec::new();
+        for nal in &nal_units {
+            bytestream.extend_from_slice(&[0, 0, 0, 1]);
+            bytestream.extend_from_slice(nal);
+        }
+        self.parser.set_data(bytestream);
+        self.parser.format = H264NalFormat::BYTESTREAM;
+
+        // Go through the NAL units for parameter sets, random access points,
+        // slice types and the picture order count.
+        let mut random_access = false;
+        let mut slice_types = 0u8;
+        let mut first_slice = None;
+        let mut offset = 0;
+        for _ in 0..nal_units.len() {
+            let unit = self.parser.parse_nalunit(offset).map_err(H264TsError::Parse)?;
+            match unit.nal_unit_type_num {
+                7 => { self.parser.parse_sps(unit.data_offset).map_err(H264TsError::Parse)?; },
+                8 => { self.parser.parse_pps(unit.data_offset).map_err(H264TsError::Parse)?; },
+                6 => {
+                    if let Ok(messages) = self.parser.parse_sei(&unit) {
+                        random_access |= messages.iter().any(|m| m.payload_type == SEI_RECOVERY_POINT);
+                    }
+                },
+                1..=5 => {
+                    random_access |= unit.idr_pic_flag;
+                    if let Ok(slice) = self.parser.parse_slice(unit.data_offset, &unit) {
+                        slice_types |= 1 << (slice.slice_type % 5);
+                        if first_slice.is_none() {
+                            first_slice = Some((unit.clone(), slice));
+                        }
+                    }
+                },
+                _ => {}
+            }
+            offset += unit.size;
+        }
+
+        let (pts, dts) = match pts {
+            Some(pts) => (pts, dts.unwrap_or(pts)),
+            None => self.derive_timestamps(first_slice)
+        };
+
+        let mut payload = Vec::new();
+        let has_aud = nal_units.first().is_some_and(|n| n[0] & 0x1F == 9);
+        if self.insert_aud && !has_aud {
+            payload.extend_from_slice(&[0, 0, 0, 1, 0x09, primary_pic_type(slice_types) << 5 | 0x10]);
+        }
+        for nal in &nal_units {
+            payload.extend_from_slice(&[0, 0, 0, 1]);
+            payload.extend_from_slice(nal);
+        }
+
+        if self.access_units == 0 || random_access || self.since_psi >= self.psi_interval {
+            self.write_psi()?;
+            self.since_psi = 0;
+        }
+        self.since_psi += 1;
+        self.access_units += 1;
+        self.write_pes(&payload, pts, dts, random_access)
+    }
+
+    /// Timestamps from the picture order count and the frame rate.
+    fn derive_timestamps(&mut self, first_slice: Option<(H264NalUnit, H264NalUnitSlice)>) -> (u64, u64) {
+        self.timestamps.frame_duration = self.frame_duration;
+        self.timestamps.first_dts = self.first_dts;
+        let sps = first_slice.as_ref().and_then(|(_, slice)| {
+            self.parser.find_pps(slice.pic_parameter_set_id)
+                .and_then(|pps| self.parser.find_sps(pps.seq_parameter_set_id))
+                .cloned()
+        });
+        match (sps, first_slice) {
+            (Some(sps), Some((nalu, slice))) => self.timestamps.next(Some((&sps, &nalu, &slice))),
+            _ => self.timestamps.next(None)
+        }
+    }
+
+    fn write_psi(&mut self) -> Result<(), H264TsError> {
+        let pat = psi_section(0x00, 1, &[
+            (self.program_number >> 8) as u8, self.program_number as u8,
+            0xE0 | (self.pmt_pid >> 8) as u8, self.pmt_pid as u8
+        ]);
+        let pmt = psi_section(0x02, self.program_number, &[
+            0xE0 | (self.video_pid >> 8) as u8, self.video_pid as u8,
+            0xF0, 0x00,
+            TS_STREAM_TYPE_H264, 0xE0 | (self.video_pid >> 8) as u8, self.video_pid as u8, 0xF0, 0x00
+        ]);
+        let mut cc = self.pat_cc;
+        self.write_section(TS_PAT_PID, &pat, &mut cc)?;
+        self.pat_cc = cc;
+        let mut cc = self.pmt_cc;
+        let pid = self.pmt_pid;
+        self.write_section(pid, &pmt, &mut cc)?;
+        self.pmt_cc = cc;
+        Ok(())
+    }
+
+    fn write_section(&mut self, pid: u16, section: &[u8], cc: &mut u8) -> Result<(), H264TsError> {
+        // Sections this small always fit in one packet.
+        let mut packet = [0xFFu8; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet[1] = 0x40 | (pid >> 8) as u8;
+        packet[2] = pid as u8;
+        packet[3] = 0x10 | *cc;
+        packet[4] = 0;
+        packet[5..5 + section.len()].copy_from_slice(section);
+        *cc = (*cc + 1) & 0x0F;
+        self.writer.write_all(&packet).map_err(H264TsError::Io)
+    }
+
+    fn write_pes(&mut self, data: &[u8], pts: u64, dts: u64, random_access: bool) -> Result<(), H264TsError> {
+        let pts = pts & 0x1FFFFFFFF;
+        let dts = dts & 0x1FFFFFFFF;
+        let mut pes = vec![0, 0, 1, 0xE0, 0, 0, 0x84];
+        if pts != dts {
+            pes.extend_from_slice(&[0xC0, 10]);
+            pes.extend_from_slice(&write_timestamp(pts, 3));
+            pes.extend_from_slice(&write_timestamp(dts, 1));
+        } else {
+            pes.extend_from_slice(&[0x80, 5]);
+            pes.extend_from_slice(&write_timestamp(pts, 2));
+        }
+        // PES_packet_length can be 0 for video when it doesn't fit.
+        let length = pes.len() - 6 + data.len();
+        if length <= 0xFFFF {
+            pes[4] = (length >> 8) as u8;
+            pes[5] = length as u8;
+        }
+        pes.extend_from_slice(data);
+
+        let pcr = dts.wrapping_sub(self.pcr_offset) & 0x1FFFFFFFF;
+        // A PCR that went backwards, across a wrap or not, is a new timeline.
+        let write_pcr = random_access || self.last_pcr.is_none_or(|last| {
+            let elapsed = pcr.wrapping_sub(last) & 0x1FFFFFFFF;
+            elapsed >= self.pcr_interval || elapsed >= 1 << 32
+        });
+        if write_pcr {
+            self.last_pcr = Some(pcr);
+        }
+        let mut pos = 0;
+        let mut first = true;
+        while pos < pes.len() {
+            let mut adaptation = Vec::new();
+            if first && write_pcr {
+                adaptation.push(0x10 | if random_access { 0x40 } else { 0 });
+                adaptation.extend_from_slice(&[
+                    (pcr >> 25) as u8, (pcr >> 17) as u8, (pcr >> 9) as u8, (pcr >> 1) as u8,
+                    ((pcr & 0x01) << 7) as u8 | 0x7E, 0x00
+                ]);
+            }
+            let mut room = TS_PACKET_SIZE - 4 - if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let left = pes.len() - pos;
+            if left < room {
+                // Stuff the last packet through the adaptation field.
+                let mut stuffing = room - left;
+                if adaptation.is_empty() {
+                    stuffing -= 1;
+                    if stuffing > 0 {
+                        adaptation.push(0x00);
+                        stuffing -= 1;
+                    }
+                }
+                adaptation.extend(vec![0xFF; stuffing]);
+                room = left;
+            }
+            let mut packet = Vec::with_capacity(TS_PACKET_SIZE);
+            packet.push(TS_SYNC_BYTE);
+            packet.push(if first { 0x40 } else { 0 } | (self.video_pid >> 8) as u8);
+            packet.push(self.video_pid as u8);
+            if adaptation.is_empty() && room == TS_PACKET_SIZE - 4 && left >= room {
+                packet.push(0x10 | self.video_cc);
+            } else {
+                packet.push(0x30 | self.video_cc);
+                packet.push(adaptation.len() as u8);
+                packet.extend_from_slice(&adaptation);
+            }
+            packet.extend_from_slice(&pes[pos..pos + room]);
+            self.writer.write_all(&packet).map_err(H264TsError::Io)?;
+            self.video_cc = (self.video_cc + 1) & 0x0F;
+            pos += room;
+            first = false;
+        }
+        Ok(())
+    }
+
+    /// Muxes a whole stream, splitting it with the access unit detection of
+    /// H264NalParser and deriving the timestamps.
+    pub fn write_stream(&mut self, data: &[u8], format: H264NalFormat,
+                        nal_length_size: usize) -> Result<(), H264TsError> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        let mut offset = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).map_err(H264TsError::Parse)?;
+            let nal_units : Vec<Vec<u8>> = au.nal_units.iter().map(|n| parser.nal_data(n).to_vec()).collect();
+            self.write_access_unit(&nal_units, None, None)?;
+            offset += au.size;
+        }
+        self.writer.flush().map_err(H264TsError::Io)
+    }
+}
+
+/// primary_pic_type of an AUD for the slice types present, as a bit mask
+/// of slice_type % 5.
+fn primary_pic_type(slice_types: u8) -> u8 {
+    let (p, b, i, sp, si) = (1, 2, 4, 8, 16);
+    match slice_types {
+        0 => 7,
+        t if t & !i == 0 => 0,
+        t if t & !(i | p) == 0 => 1,
+        t if t & !(i | p | b) == 0 => 2,
+        t if t & !si == 0 => 3,
+        t if t & !(si | sp) == 0 => 4,
+        t if t & !(i | si) == 0 => 5,
+        t if t & !(i | si | p | sp) == 0 => 6,
+        _ => 7
+    }
+}
+
+fn psi_section(table_id: u8, table_id_extension: u16, body: &[u8]) -> Vec<u8> {
+    let length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id, 0xB0 | (length >> 8) as u8, length as u8,
+        (table_id_extension >> 8) as u8, table_id_extension as u8,
+        0xC1, 0x00, 0x00
+    ];
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+    section
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ts::demux_stream;
+    use writer::H264NalWriter;
+
+    /// Parameter sets and an IDR or P slice of a 16x16 Baseline picture.
+    fn access_unit(idr: bool, frame_num: u32, padding: usize) -> Vec<Vec<u8>> {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        sps.max_num_ref_frames = 1;
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = if idr { 7 } else { 5 };
+        slice.frame_num = frame_num;
+        slice.pic_order_cnt_lsb = (2 * frame_num % 16) as u16;
+        let nal_type = if idr { 5 } else { 1 };
+        let unit = H264NalUnit::new(0, 4, 0, 2, nal_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let mut nal = writer.to_nal(2, nal_type);
+        // Stands in for the macroblock data.
+        nal.extend((0..padding).map(|i| 0x10 | (i % 8) as u8));
+        if idr {
+            vec![sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), nal]
+        } else {
+            vec![nal]
+        }
+    }
+
+    fn annex_b(nal_units: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nal_units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    fn packets(data: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(data.len() % TS_PACKET_SIZE, 0);
+        data.chunks(TS_PACKET_SIZE).collect()
+    }
+
+    fn pid(packet: &[u8]) -> u16 {
+        ((packet[1] & 0x1F) as u16) << 8 | packet[2] as u16
+    }
+
+    fn pcr(packet: &[u8]) -> Option<u64> {
+        if packet[3] & 0x20 != 0 && packet[4] > 0 && packet[5] & 0x10 != 0 {
+            Some((packet[6] as u64) << 25 | (packet[7] as u64) << 17 | (packet[8] as u64) << 9 |
+                 (packet[9] as u64) << 1 | (packet[10] >> 7) as u64)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn muxed_access_units_demux_unchanged() {
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        muxer.insert_aud = false;
+        let units = vec![
+            (access_unit(true, 0, 100), 129000, 126000),
+            // Too long for PES_packet_length
+            (access_unit(false, 2, 70000), 135000, 129000),
+            (access_unit(false, 1, 10), 132000, 132000)
+        ];
+        for &(ref nal_units, pts, dts) in &units {
+            muxer.write_access_unit(nal_units, Some(pts), Some(dts)).unwrap();
+        }
+        let data = muxer.into_inner();
+        let report = demux_stream(&data);
+        assert!(report.errors.is_empty());
+        assert_eq!((report.continuity_errors, report.skipped_bytes), (0, 0));
+        assert_eq!(report.pes.len(), 3);
+        for (pes, &(ref nal_units, pts, dts)) in report.pes.iter().zip(&units) {
+            assert_eq!(pes.pid, 0x100);
+            assert_eq!(pes.data, annex_b(nal_units));
+            assert_eq!(pes.pts, Some(pts));
+            assert_eq!(pes.dts, if pts == dts { None } else { Some(dts) });
+            assert!(!pes.damaged);
+        }
+        assert!(report.pes[0].random_access);
+        assert!(!report.pes[1].random_access);
+    }
+
+    #[test]
+    fn derived_timestamps_and_aud() {
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        muxer.frame_duration = Some(3000);
+        muxer.write_access_unit(&access_unit(true, 0, 10), None, None).unwrap();
+        muxer.write_access_unit(&access_unit(false, 1, 10), None, None).unwrap();
+        let report = demux_stream(&muxer.into_inner());
+        assert_eq!(report.pes.len(), 2);
+        // I slices only, then P slices
+        assert_eq!(report.pes[0].data[..6], [0, 0, 0, 1, 0x09, 0x10]);
+        assert_eq!(report.pes[1].data[..6], [0, 0, 0, 1, 0x09, 0x30]);
+        assert_eq!(report.pes[0].decode_time(), Some(126000));
+        assert_eq!(report.pes[1].decode_time(), Some(129000));
+        assert!(report.pes[1].pts > report.pes[0].pts);
+    }
+
+    #[test]
+    fn psi_goes_before_random_access_points_and_every_interval() {
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        muxer.psi_interval = 3;
+        for i in 0..8u32 {
+            let idr = i == 0 || i == 4;
+            let dts = 126000 + i as u64 * 3000;
+            muxer.write_access_unit(&access_unit(idr, i % 4, 10), Some(dts), None).unwrap();
+        }
+        let data = muxer.into_inner();
+        // The access unit each PAT goes before
+        let mut pat_before = Vec::new();
+        let mut access_units = 0;
+        for packet in packets(&data) {
+            match pid(packet) {
+                TS_PAT_PID => pat_before.push(access_units),
+                0x100 if packet[1] & 0x40 != 0 => access_units += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(pat_before, vec![0, 3, 4, 7]);
+    }
+
+    #[test]
+    fn pcr_interval_is_bounded() {
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        for i in 0..12u32 {
+            let dts = 126000 + i as u64 * 1500;
+            muxer.write_access_unit(&access_unit(i == 8, i, 10), Some(dts), None).unwrap();
+        }
+        let data = muxer.into_inner();
+        let pcrs : Vec<u64> = packets(&data).iter().filter(|p| pid(p) == 0x100).filter_map(|p| pcr(p)).collect();
+        // Every third access unit, and the IDR access unit.
+        assert_eq!(pcrs, vec![117000, 121500, 126000, 129000, 133500]);
+
+        // A PCR every access unit when asked for.
+        let mut muxer = H264TsMuxer::new(Vec::new());
+        muxer.pcr_interval = 0;
+        for i in 0..3u32 {
+            muxer.write_access_unit(&access_unit(i == 0, i, 10), Some(126000 + i as u64 * 1500), None).unwrap();
+        }
+        let data = muxer.into_inner();
+        assert_eq!(packets(&data).iter().filter(|p| pcr(p).is_some()).count(), 3);
+    }
+}
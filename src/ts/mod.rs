@@ -0,0 +1,137 @@
+use std::io;
+use parser::{H264NalParser, H264NalParseError};
+pub use types::*;
+
+mod h264tsdemuxer;
+mod h264tsmuxer;
+pub use self::h264tsdemuxer::{H264TsDemuxer, H264TsDemuxReport, demux_stream};
+pub use self::h264tsmuxer::H264TsMuxer;
+
+pub const TS_PACKET_SIZE : usize = 188;
+pub const TS_SYNC_BYTE : u8 = 0x47;
+pub const TS_PAT_PID : u16 = 0;
+pub const TS_NULL_PID : u16 = 0x1FFF;
+/// stream_type of H.264 video in a PMT.
+pub const TS_STREAM_TYPE_H264 : u8 = 0x1B;
+/// Frequency of PTS and DTS.
+pub const TS_CLOCK_RATE : u64 = 90000;
+
+#[derive(Debug)]
+pub enum H264TsError {
+    /// A packet doesn't start with the sync byte.
+    LostSync,
+    Truncated,
+    /// A PAT or PMT whose CRC doesn't match.
+    BadCrc(u16),
+    /// A PES packet which doesn't start with a start code prefix.
+    InvalidPes(u16),
+    Parse(H264NalParseError),
+    Io(io::Error)
+}
+
+/// An access unit of a PES with its PTS and DTS.
+pub type H264TsAccessUnit = (H264AccessUnit, Option<u64>, Option<u64>);
+
+/// The payload of one PES packet of the H.264 PID, an Annex B byte
+/// stream with the timestamps of the access unit it starts.
+#[derive(Debug, Clone)]
+pub struct H264TsPes {
+    pub pid: u16,
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    /// random_access_indicator of the packet that started the PES.
+    pub random_access: bool,
+    /// discontinuity_indicator of the packet that started the PES, the
+    /// timestamps don't follow on from the previous PES.
+    pub discontinuity: bool,
+    /// Packets of the PES were lost according to the continuity counter,
+    /// or the PES was cut short.
+    pub damaged: bool,
+    pub data: Vec<u8>
+}
+
+impl H264TsPes {
+    pub fn new(pid: u16) -> H264TsPes {
+        H264TsPes {
+            pid,
+            pts: None,
+            dts: None,
+            random_access: false,
+            discontinuity: false,
+            damaged: false,
+            data: Vec::new()
+        }
+    }
+
+    /// The decoding timestamp, which is the PTS when there is no DTS.
+    pub fn decode_time(&self) -> Option<u64> {
+        self.dts.or(self.pts)
+    }
+
+    /// Hands the payload to a parser, keeping its parameter sets.
+    pub fn load_into(&self, parser: &mut H264NalParser) {
+        parser.set_data(self.data.clone());
+        parser.format = H264NalFormat::BYTESTREAM;
+    }
+
+    /// Loads the payload into a parser and splits it into access units,
+    /// each with its PTS and DTS. There's normally one, and the timestamps
+    /// of the PES belong to the first.
+    pub fn access_units(&self, parser: &mut H264NalParser) -> Result<Vec<H264TsAccessUnit>, H264NalParseError> {
+        self.load_into(parser);
+        let mut units = Vec::new();
+        let mut offset = 0;
+        // Some muxers pad the payload with zero bytes before the first
+        // start code.
+        while offset + 3 < self.data.len() && self.data[offset] == 0 && self.data[offset + 1] == 0 &&
+            self.data[offset + 2] == 0 && self.data[offset + 3] == 0 {
+            offset += 1;
+        }
+        while offset < self.data.len() {
+            let au = parser.parse_access_unit(offset)?;
+            offset += au.size;
+            if units.is_empty() {
+                units.push((au, self.pts, self.dts));
+            } else {
+                units.push((au, None, None));
+            }
+        }
+        Ok(units)
+    }
+}
+
+/// CRC-32/MPEG-2 of PSI sections.
+pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc : u32 = 0xFFFFFFFF;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04C11DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Writes a 33 bit PTS or DTS with its 4 bit prefix and marker bits.
+pub fn write_timestamp(timestamp: u64, prefix: u8) -> [u8; 5] {
+    [
+        prefix << 4 | ((timestamp >> 30) as u8 & 0x07) << 1 | 0x01,
+        (timestamp >> 22) as u8,
+        ((timestamp >> 15) as u8 & 0x7F) << 1 | 0x01,
+        (timestamp >> 7) as u8,
+        ((timestamp as u8) & 0x7F) << 1 | 0x01
+    ]
+}
+
+/// Reads a 33 bit PTS or DTS.
+pub fn read_timestamp(data: &[u8]) -> u64 {
+    ((data[0] as u64 >> 1) & 0x07) << 30 |
+        (data[1] as u64) << 22 |
+        (data[2] as u64 >> 1) << 15 |
+        (data[3] as u64) << 7 |
+        data[4] as u64 >> 1
+}
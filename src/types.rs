@@ -1,5 +1,8 @@
+use std::cmp;
 use std::fmt;
+use parser::H264NalParseError;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum H264NalFormat {
     BYTESTREAM, AVC, UNKNOWN
 }
@@ -8,6 +11,7 @@ pub enum H264NalFormat {
 pub enum H264NalUnitType {
     SPS,
     PPS,
+    SEI,
     IDR,
     P,
     UNKNOWN
@@ -59,6 +63,12 @@ pub struct H264VUIParameters {
     pub max_dec_frame_buffering: u32
 }
 
+impl Default for H264VUIParameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl H264VUIParameters {
     pub fn new() -> H264VUIParameters {
         H264VUIParameters {
@@ -102,42 +112,42 @@ impl H264VUIParameters {
 
 impl fmt::Display for H264VUIParameters {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "VUI {{\n")?;
-        write!(f, "    aspect_ratio_info_present_flag: {:?}\n", self.aspect_ratio_info_present_flag)?;
-        write!(f, "    aspect_ratio_idc: {:?}\n", self.aspect_ratio_idc)?;
-        write!(f, "    sar_width: {:?}\n", self.sar_width)?;
-        write!(f, "    sar_height: {:?}\n", self.sar_height)?;
-        write!(f, "    overscan_info_present_flag: {:?}\n", self.overscan_info_present_flag)?;
-        write!(f, "    overscan_appropriate_flag: {:?}\n", self.overscan_appropriate_flag)?;
-        write!(f, "    video_signal_type_present_flag: {:?}\n", self.video_signal_type_present_flag)?;
-        write!(f, "    video_format: {:?}\n", self.video_format)?;
-        write!(f, "    video_full_range_flag: {:?}\n", self.video_full_range_flag)?;
-        write!(f, "    colour_description_present_flag: {:?}\n", self.colour_description_present_flag)?;
-        write!(f, "    colour_primaries: {:?}\n", self.colour_primaries)?;
-        write!(f, "    transfer_characteristics: {:?}\n", self.transfer_characteristics)?;
-        write!(f, "    matrix_coefficients: {:?}\n", self.matrix_coefficients)?;
-        write!(f, "    chroma_loc_info_present_flag: {:?}\n", self.chroma_loc_info_present_flag)?;
-        write!(f, "    chroma_sample_loc_type_top_field: {:?}\n", self.chroma_sample_loc_type_top_field)?;
-        write!(f, "    chroma_sample_loc_type_bottom_field: {:?}\n", self.chroma_sample_loc_type_bottom_field)?;
-        write!(f, "    timing_info_present_flag: {:?}\n", self.timing_info_present_flag)?;
-        write!(f, "    num_units_in_tick: {:?}\n", self.num_units_in_tick)?;
-        write!(f, "    time_scale: {:?}\n", self.time_scale)?;
-        write!(f, "    fixed_frame_rate_flag: {:?}\n", self.fixed_frame_rate_flag)?;
-        write!(f, "    nal_hrd_parameters_present_flag: {:?}\n", self.nal_hrd_parameters_present_flag)?;
-        write!(f, "    nal_hrd_parameters: {:?}\n", self.nal_hrd_parameters)?;
-        write!(f, "    vcl_hrd_parameters_present_flag: {:?}\n", self.vcl_hrd_parameters_present_flag)?;
-        write!(f, "    vcl_hrd_parameters: {:?}\n", self.vcl_hrd_parameters)?;
-        write!(f, "    low_delay_hrd_flag: {:?}\n", self.low_delay_hrd_flag)?;
-        write!(f, "    pic_struct_present_flag: {:?}\n", self.pic_struct_present_flag)?;
-        write!(f, "    bitstream_restriction_flag: {:?}\n", self.bitstream_restriction_flag)?;
-        write!(f, "    motion_vectors_over_pic_boundaries_flag: {:?}\n", self.motion_vectors_over_pic_boundaries_flag)?;
-        write!(f, "    max_bytes_per_pic_denom: {:?}\n", self.max_bytes_per_pic_denom)?;
-        write!(f, "    max_bits_per_mb_denom: {:?}\n", self.max_bits_per_mb_denom)?;
-        write!(f, "    log2_max_mv_length_horizontal: {:?}\n", self.log2_max_mv_length_horizontal)?;
-        write!(f, "    log2_max_mv_length_vertical: {:?}\n", self.log2_max_mv_length_vertical)?;
-        write!(f, "    max_num_reorder_frames: {:?}\n", self.max_num_reorder_frames)?;
-        write!(f, "    max_dec_frame_buffering: {:?}\n", self.max_dec_frame_buffering)?;
-        write!(f, "}}\n")
+        writeln!(f, "VUI {{")?;
+        writeln!(f, "    aspect_ratio_info_present_flag: {:?}", self.aspect_ratio_info_present_flag)?;
+        writeln!(f, "    aspect_ratio_idc: {:?}", self.aspect_ratio_idc)?;
+        writeln!(f, "    sar_width: {:?}", self.sar_width)?;
+        writeln!(f, "    sar_height: {:?}", self.sar_height)?;
+        writeln!(f, "    overscan_info_present_flag: {:?}", self.overscan_info_present_flag)?;
+        writeln!(f, "    overscan_appropriate_flag: {:?}", self.overscan_appropriate_flag)?;
+        writeln!(f, "    video_signal_type_present_flag: {:?}", self.video_signal_type_present_flag)?;
+        writeln!(f, "    video_format: {:?}", self.video_format)?;
+        writeln!(f, "    video_full_range_flag: {:?}", self.video_full_range_flag)?;
+        writeln!(f, "    colour_description_present_flag: {:?}", self.colour_description_present_flag)?;
+        writeln!(f, "    colour_primaries: {:?}", self.colour_primaries)?;
+        writeln!(f, "    transfer_characteristics: {:?}", self.transfer_characteristics)?;
+        writeln!(f, "    matrix_coefficients: {:?}", self.matrix_coefficients)?;
+        writeln!(f, "    chroma_loc_info_present_flag: {:?}", self.chroma_loc_info_present_flag)?;
+        writeln!(f, "    chroma_sample_loc_type_top_field: {:?}", self.chroma_sample_loc_type_top_field)?;
+        writeln!(f, "    chroma_sample_loc_type_bottom_field: {:?}", self.chroma_sample_loc_type_bottom_field)?;
+        writeln!(f, "    timing_info_present_flag: {:?}", self.timing_info_present_flag)?;
+        writeln!(f, "    num_units_in_tick: {:?}", self.num_units_in_tick)?;
+        writeln!(f, "    time_scale: {:?}", self.time_scale)?;
+        writeln!(f, "    fixed_frame_rate_flag: {:?}", self.fixed_frame_rate_flag)?;
+        writeln!(f, "    nal_hrd_parameters_present_flag: {:?}", self.nal_hrd_parameters_present_flag)?;
+        writeln!(f, "    nal_hrd_parameters: {:?}", self.nal_hrd_parameters)?;
+        writeln!(f, "    vcl_hrd_parameters_present_flag: {:?}", self.vcl_hrd_parameters_present_flag)?;
+        writeln!(f, "    vcl_hrd_parameters: {:?}", self.vcl_hrd_parameters)?;
+        writeln!(f, "    low_delay_hrd_flag: {:?}", self.low_delay_hrd_flag)?;
+        writeln!(f, "    pic_struct_present_flag: {:?}", self.pic_struct_present_flag)?;
+        writeln!(f, "    bitstream_restriction_flag: {:?}", self.bitstream_restriction_flag)?;
+        writeln!(f, "    motion_vectors_over_pic_boundaries_flag: {:?}", self.motion_vectors_over_pic_boundaries_flag)?;
+        writeln!(f, "    max_bytes_per_pic_denom: {:?}", self.max_bytes_per_pic_denom)?;
+        writeln!(f, "    max_bits_per_mb_denom: {:?}", self.max_bits_per_mb_denom)?;
+        writeln!(f, "    log2_max_mv_length_horizontal: {:?}", self.log2_max_mv_length_horizontal)?;
+        writeln!(f, "    log2_max_mv_length_vertical: {:?}", self.log2_max_mv_length_vertical)?;
+        writeln!(f, "    max_num_reorder_frames: {:?}", self.max_num_reorder_frames)?;
+        writeln!(f, "    max_dec_frame_buffering: {:?}", self.max_dec_frame_buffering)?;
+        writeln!(f, "}}")
     }
 }
 
@@ -155,6 +165,12 @@ pub struct H264HDRParameters {
     pub time_offset_length: u8
 }
 
+impl Default for H264HDRParameters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl H264HDRParameters {
     pub fn new() -> H264HDRParameters {
         H264HDRParameters {
@@ -172,20 +188,38 @@ impl H264HDRParameters {
     }
 }
 
+impl H264HDRParameters {
+    /// BitRate[SchedSelIdx] in bits per second, from E.2.2.
+    pub fn bit_rate(&self, sched_sel_idx: usize) -> Option<u64> {
+        self.bit_rate_value_minus1.get(sched_sel_idx)
+            .map(|&v| (v as u64 + 1) << (6 + self.bit_rate_scale as u64))
+    }
+
+    /// CpbSize[SchedSelIdx] in bits, from E.2.2.
+    pub fn cpb_size(&self, sched_sel_idx: usize) -> Option<u64> {
+        self.cpb_size_value_minus1.get(sched_sel_idx)
+            .map(|&v| (v as u64 + 1) << (4 + self.cpb_size_scale as u64))
+    }
+
+    pub fn cbr(&self, sched_sel_idx: usize) -> bool {
+        self.cbr_flag.get(sched_sel_idx).is_some_and(|&f| f != 0)
+    }
+}
+
 impl fmt::Display for H264HDRParameters {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "HDR Params {{\n");
-        write!(f, "cpb_cnt_minus1: {:?}\n", self.cpb_cnt_minus1)?;
-        write!(f, "bit_rate_scale: {:?}\n", self.bit_rate_scale)?;
-        write!(f, "cpb_size_scale: {:?}\n", self.cpb_size_scale)?;
-        write!(f, "bit_rate_value_minus1: {:?}\n", self.bit_rate_value_minus1)?;
-        write!(f, "cpb_size_value_minus1: {:?}\n", self.cpb_size_value_minus1)?;
-        write!(f, "cbr_flag: {:?}\n", self.cbr_flag)?;
-        write!(f, "initial_cpb_removal_delay_length_minus1: {:?}\n", self.initial_cpb_removal_delay_length_minus1)?;
-        write!(f, "cpb_removal_delay_length_minus1: {:?}\n", self.cpb_removal_delay_length_minus1)?;
-        write!(f, "dpb_output_delay_length_minus1: {:?}\n", self.dpb_output_delay_length_minus1)?;
-        write!(f, "time_offset_length: {:?}\n", self.time_offset_length)?;
-        write!(f, "}}\n")
+        writeln!(f, "HDR Params {{")?;
+        writeln!(f, "cpb_cnt_minus1: {:?}", self.cpb_cnt_minus1)?;
+        writeln!(f, "bit_rate_scale: {:?}", self.bit_rate_scale)?;
+        writeln!(f, "cpb_size_scale: {:?}", self.cpb_size_scale)?;
+        writeln!(f, "bit_rate_value_minus1: {:?}", self.bit_rate_value_minus1)?;
+        writeln!(f, "cpb_size_value_minus1: {:?}", self.cpb_size_value_minus1)?;
+        writeln!(f, "cbr_flag: {:?}", self.cbr_flag)?;
+        writeln!(f, "initial_cpb_removal_delay_length_minus1: {:?}", self.initial_cpb_removal_delay_length_minus1)?;
+        writeln!(f, "cpb_removal_delay_length_minus1: {:?}", self.cpb_removal_delay_length_minus1)?;
+        writeln!(f, "dpb_output_delay_length_minus1: {:?}", self.dpb_output_delay_length_minus1)?;
+        writeln!(f, "time_offset_length: {:?}", self.time_offset_length)?;
+        writeln!(f, "}}")
     }
 }
 
@@ -198,6 +232,7 @@ pub struct H264NalUnitSPS {
     pub constraint_3_flag: u8,
     pub constraint_4_flag: u8,
     pub constraint_5_flag: u8,
+    pub reserved_zero_2bits: u8,
     pub level_idc: u8,
     pub seq_parameter_set_id: u32,
 
@@ -211,6 +246,10 @@ pub struct H264NalUnitSPS {
     pub seq_scaling_list_present_flag: Vec<u8>,
     pub scaling_list_4x4: Vec<Vec<u8>>,
     pub scaling_list_8x8: Vec<Vec<u8>>,
+    pub use_default_scaling_matrix_flag: Vec<bool>,
+    /// The delta_scale values as coded for each present scaling list. The
+    /// lists above are in zigzag scan order and are derived from these.
+    pub seq_scaling_list_delta_scale: Vec<Vec<i32>>,
 
     pub log2_max_frame_num_minus4: u32,
     pub pic_order_cnt_type: u32,
@@ -241,6 +280,12 @@ pub struct H264NalUnitSPS {
     pub vui_parameters: Option<H264VUIParameters>
 }
 
+impl Default for H264NalUnitSPS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl H264NalUnitSPS {
     pub fn new() -> H264NalUnitSPS {
         H264NalUnitSPS {
@@ -251,6 +296,7 @@ impl H264NalUnitSPS {
             constraint_3_flag: 0,
             constraint_4_flag: 0,
             constraint_5_flag: 0,
+            reserved_zero_2bits: 0,
             level_idc: 0,
             seq_parameter_set_id: 0,
             chroma_format_idc: 0,
@@ -262,6 +308,8 @@ impl H264NalUnitSPS {
             seq_scaling_list_present_flag: Vec::new(),
             scaling_list_4x4: vec![vec![0u8; 16]; 6],
             scaling_list_8x8: vec![vec![0u8; 64]; 6],
+            use_default_scaling_matrix_flag: vec![false; 12],
+            seq_scaling_list_delta_scale: vec![Vec::new(); 12],
             log2_max_frame_num_minus4: 0,
             pic_order_cnt_type: 0,
             log2_max_pic_order_cnt_lsb_minus4: 0,
@@ -288,55 +336,287 @@ impl H264NalUnitSPS {
     }
 }
 
+impl H264NalUnitSPS {
+    /// ChromaArrayType, which is 0 for monochrome or separately coded
+    /// colour planes and chroma_format_idc otherwise.
+    pub fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane_flag {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    pub fn bit_depth_luma(&self) -> u32 {
+        8 + self.bit_depth_luma_minus8
+    }
+
+    pub fn bit_depth_chroma(&self) -> u32 {
+        8 + self.bit_depth_chroma_minus8
+    }
+
+    /// SubWidthC and SubHeightC from Table 6-1, (0, 0) without chroma
+    /// arrays.
+    pub fn chroma_subsampling(&self) -> (u32, u32) {
+        match self.chroma_array_type() {
+            1 => (2, 2),
+            2 => (2, 1),
+            3 => (1, 1),
+            _ => (0, 0)
+        }
+    }
+
+    pub fn frame_width_in_mbs(&self) -> u32 {
+        self.pic_width_in_mbs_minus1.saturating_add(1)
+    }
+
+    /// FrameHeightInMbs, twice the map units when fields can be coded.
+    pub fn frame_height_in_mbs(&self) -> u32 {
+        self.pic_height_in_map_units_minus1.saturating_add(1).saturating_mul(if self.frame_mbs_only_flag { 1 } else { 2 })
+    }
+
+    /// The sizes below saturate at u32::MAX for values no level allows.
+    pub fn frame_size_in_mbs(&self) -> u32 {
+        self.frame_width_in_mbs().saturating_mul(self.frame_height_in_mbs())
+    }
+
+    /// Decoded frame width in luma samples, before cropping.
+    pub fn width(&self) -> u32 {
+        self.frame_width_in_mbs().saturating_mul(16)
+    }
+
+    /// Decoded frame height in luma samples, before cropping.
+    pub fn height(&self) -> u32 {
+        self.frame_height_in_mbs().saturating_mul(16)
+    }
+
+    /// CropUnitX and CropUnitY, equations 7-19 to 7-22.
+    pub fn crop_units(&self) -> (u32, u32) {
+        let field_factor = if self.frame_mbs_only_flag { 1 } else { 2 };
+        match self.chroma_array_type() {
+            0 => (1, field_factor),
+            _ => {
+                let (sub_width_c, sub_height_c) = self.chroma_subsampling();
+                (sub_width_c, sub_height_c * field_factor)
+            }
+        }
+    }
+
+    /// Width in luma samples after the frame cropping rectangle.
+    pub fn cropped_width(&self) -> u32 {
+        if self.frame_cropping_flag == 0 {
+            return self.width();
+        }
+        let crop = self.crop_units().0.saturating_mul(self.frame_crop_left_offset.saturating_add(self.frame_crop_right_offset));
+        self.width().saturating_sub(crop)
+    }
+
+    /// Height in luma samples after the frame cropping rectangle.
+    pub fn cropped_height(&self) -> u32 {
+        if self.frame_cropping_flag == 0 {
+            return self.height();
+        }
+        let crop = self.crop_units().1.saturating_mul(self.frame_crop_top_offset.saturating_add(self.frame_crop_bottom_offset));
+        self.height().saturating_sub(crop)
+    }
+
+    /// The sample aspect ratio from Table E-1 or EXTENDED_SAR. None if it
+    /// isn't signalled or is unspecified.
+    pub fn sample_aspect_ratio(&self) -> Option<(u32, u32)> {
+        let vui = match self.vui_parameters {
+            Some(ref vui) if vui.aspect_ratio_info_present_flag != 0 => vui,
+            _ => return None
+        };
+        let table : [(u32, u32); 17] = [
+            (0, 0), (1, 1), (12, 11), (10, 11), (16, 11), (40, 33), (24, 11), (20, 11), (32, 11),
+            (80, 33), (18, 11), (15, 11), (64, 33), (160, 99), (4, 3), (3, 2), (2, 1)
+        ];
+        let sar = match vui.aspect_ratio_idc {
+            EXTENDED_SAR => (vui.sar_width as u32, vui.sar_height as u32),
+            idc if (idc as usize) < table.len() => table[idc as usize],
+            _ => (0, 0)
+        };
+        if sar.0 == 0 || sar.1 == 0 {
+            None
+        } else {
+            Some(sar)
+        }
+    }
+
+    /// The display aspect ratio of the cropped frame in lowest terms, taking
+    /// the sample aspect ratio as 1:1 when it isn't known.
+    pub fn display_aspect_ratio(&self) -> (u32, u32) {
+        let (sar_width, sar_height) = self.sample_aspect_ratio().unwrap_or((1, 1));
+        let width = self.cropped_width() as u64 * sar_width as u64;
+        let height = self.cropped_height() as u64 * sar_height as u64;
+        let divisor = gcd(width, height);
+        if divisor == 0 {
+            return (0, 0);
+        }
+        ((width / divisor) as u32, (height / divisor) as u32)
+    }
+
+    /// The frame rate as time_scale / (2 * num_units_in_tick) in lowest
+    /// terms, from the VUI timing info.
+    pub fn frame_rate_fraction(&self) -> Option<(u64, u64)> {
+        match self.vui_parameters {
+            Some(ref vui) if vui.timing_info_present_flag != 0 && vui.num_units_in_tick != 0 &&
+                vui.time_scale != 0 => {
+                let num = vui.time_scale as u64;
+                let den = 2 * vui.num_units_in_tick as u64;
+                let divisor = gcd(num, den);
+                Some((num / divisor, den / divisor))
+            },
+            _ => None
+        }
+    }
+
+    pub fn frame_rate(&self) -> Option<f64> {
+        self.frame_rate_fraction().map(|(num, den)| num as f64 / den as f64)
+    }
+
+    /// The VUI, which is added with all of its flags cleared if the SPS
+    /// doesn't have one yet.
+    pub fn vui_mut(&mut self) -> &mut H264VUIParameters {
+        self.vui_parameters_present_flag = 1;
+        if self.vui_parameters.is_none() {
+            self.vui_parameters = Some(H264VUIParameters::new());
+        }
+        self.vui_parameters.as_mut().unwrap()
+    }
+
+    /// Sets the VUI timing info. The frame rate of progressive content is
+    /// time_scale / (2 * num_units_in_tick).
+    pub fn set_timing_info(&mut self, num_units_in_tick: u32, time_scale: u32, fixed_frame_rate: bool) {
+        let vui = self.vui_mut();
+        vui.timing_info_present_flag = 1;
+        vui.num_units_in_tick = num_units_in_tick;
+        vui.time_scale = time_scale;
+        vui.fixed_frame_rate_flag = fixed_frame_rate as u8;
+    }
+
+    pub fn clear_timing_info(&mut self) {
+        if let Some(ref mut vui) = self.vui_parameters {
+            vui.timing_info_present_flag = 0;
+            vui.num_units_in_tick = 0;
+            vui.time_scale = 0;
+            vui.fixed_frame_rate_flag = 0;
+        }
+    }
+
+    /// Turns on video_signal_type_present_flag, keeping the other signal
+    /// type fields or using "unspecified" video_format if there were none.
+    fn video_signal_type_mut(&mut self) -> &mut H264VUIParameters {
+        let vui = self.vui_mut();
+        if vui.video_signal_type_present_flag == 0 {
+            vui.video_signal_type_present_flag = 1;
+            vui.video_format = 5;
+            vui.video_full_range_flag = 0;
+            vui.colour_description_present_flag = 0;
+        }
+        vui
+    }
+
+    pub fn set_video_full_range(&mut self, full_range: bool) {
+        self.video_signal_type_mut().video_full_range_flag = full_range as u8;
+    }
+
+    /// Sets colour_primaries, transfer_characteristics and matrix_coefficients
+    /// using the code points of Tables E-3 to E-5.
+    pub fn set_colour_description(&mut self, colour_primaries: u8, transfer_characteristics: u8,
+                                  matrix_coefficients: u8) {
+        let vui = self.video_signal_type_mut();
+        vui.colour_description_present_flag = 1;
+        vui.colour_primaries = colour_primaries;
+        vui.transfer_characteristics = transfer_characteristics;
+        vui.matrix_coefficients = matrix_coefficients;
+    }
+
+    /// Sets the bitstream restriction. If there was none, the other fields
+    /// get the values the spec infers when they are absent.
+    pub fn set_bitstream_restriction(&mut self, max_num_reorder_frames: u32, max_dec_frame_buffering: u32) {
+        let vui = self.vui_mut();
+        if vui.bitstream_restriction_flag == 0 {
+            vui.bitstream_restriction_flag = 1;
+            vui.motion_vectors_over_pic_boundaries_flag = 1;
+            vui.max_bytes_per_pic_denom = 2;
+            vui.max_bits_per_mb_denom = 1;
+            vui.log2_max_mv_length_horizontal = 16;
+            vui.log2_max_mv_length_vertical = 16;
+        }
+        vui.max_num_reorder_frames = max_num_reorder_frames;
+        vui.max_dec_frame_buffering = max_dec_frame_buffering;
+    }
+
+    /// Signals that no frames are reordered and that the decoder doesn't
+    /// need to buffer more than the reference frames, so players can output
+    /// each frame as soon as it's decoded.
+    pub fn set_low_latency(&mut self) {
+        let max_dec_frame_buffering = cmp::max(self.max_num_ref_frames, 1);
+        self.set_bitstream_restriction(0, max_dec_frame_buffering);
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// The profiles whose SPS carries chroma_format_idc, bit depths and scaling
+/// matrices.
+pub fn profile_idc_has_chroma_info(profile_idc: u8) -> bool {
+    let certain_profiles : [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+    certain_profiles.contains(&profile_idc)
+}
+
 impl fmt::Display for H264NalUnitSPS {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SPS: {{\n")?;
-        write!(f, "    profile_idc: {}\n", self.profile_idc)?;
-        write!(f, "    constraint_0_flag: {}\n", self.constraint_0_flag)?;
-        write!(f, "    constraint_1_flag: {}\n", self.constraint_1_flag)?;
-        write!(f, "    constraint_2_flag: {}\n", self.constraint_2_flag)?;
-        write!(f, "    constraint_3_flag: {}\n", self.constraint_3_flag)?;
-        write!(f, "    constraint_4_flag: {}\n", self.constraint_4_flag)?;
-        write!(f, "    constraint_5_flag: {}\n", self.constraint_5_flag)?;
-        write!(f, "    level_idc: {}\n", self.level_idc)?;
-        write!(f, "    seq_parameter_set_id: {}\n", self.seq_parameter_set_id)?;
-        write!(f, "    chroma_format_idc: {}\n", self.chroma_format_idc)?;
-        write!(f, "    separate_colour_plane_flag: {}\n", self.separate_colour_plane_flag)?;
-        write!(f, "    bit_depth_luma_minus8: {}\n", self.bit_depth_luma_minus8)?;
-        write!(f, "    bit_depth_chroma_minus8: {}\n", self.bit_depth_chroma_minus8)?;
-        write!(f, "    qpprime_y_zero_transform_bypass_flag: {}\n", self.qpprime_y_zero_transform_bypass_flag)?;
-        write!(f, "    seq_scaling_matrix_present_flag: {}\n", self.seq_scaling_matrix_present_flag)?;
-        write!(f, "    seq_scaling_list_present_flag: {:?}\n", self.seq_scaling_list_present_flag)?;
-        write!(f, "    scaling_list_4x4: {:?}\n", self.scaling_list_4x4)?;
-        write!(f, "    scaling_list_8x8: {:?}\n", self.scaling_list_8x8)?;
-        write!(f, "    log2_max_frame_num_minus4: {}\n", self.log2_max_frame_num_minus4)?;
-        write!(f, "    pic_order_cnt_type: {}\n", self.pic_order_cnt_type)?;
-        write!(f, "    log2_max_pic_order_cnt_lsb_minus4: {}\n", self.log2_max_pic_order_cnt_lsb_minus4)?;
-        write!(f, "    delta_pic_order_always_zero_flag: {}\n", self.delta_pic_order_always_zero_flag)?;
-        write!(f, "    offset_for_non_ref_pic: {}\n", self.offset_for_non_ref_pic)?;
-        write!(f, "    offset_for_top_to_bottom_field: {}\n", self.offset_for_top_to_bottom_field)?;
-        write!(f, "    num_ref_frames_in_pic_order_cnt_cycle: {}\n", self.num_ref_frames_in_pic_order_cnt_cycle)?;
-        write!(f, "    offset_for_ref_frame: {:?}\n", self.offset_for_ref_frame)?;
-        write!(f, "    max_num_ref_frames: {}\n", self.max_num_ref_frames)?;
-        write!(f, "    gaps_in_frame_num_value_allowed_flag: {}\n", self.gaps_in_frame_num_value_allowed_flag)?;
-        write!(f, "    pic_width_in_mbs_minus1: {}\n", self.pic_width_in_mbs_minus1)?;
-        write!(f, "    pic_height_in_map_units_minus1: {}\n", self.pic_height_in_map_units_minus1)?;
-        write!(f, "    frame_mbs_only_flag: {}\n", self.frame_mbs_only_flag)?;
-        write!(f, "    mb_adaptive_frame_field_flag: {}\n", self.mb_adaptive_frame_field_flag)?;
-        write!(f, "    direct_8x8_inference_flag: {}\n", self.direct_8x8_inference_flag)?;
-        write!(f, "    frame_cropping_flag: {}\n", self.frame_cropping_flag)?;
-        write!(f, "    frame_crop_left_offset: {}\n", self.frame_crop_left_offset)?;
-        write!(f, "    frame_crop_right_offset: {}\n", self.frame_crop_right_offset)?;
-        write!(f, "    frame_crop_top_offset: {}\n", self.frame_crop_top_offset)?;
-        write!(f, "    frame_crop_bottom_offset: {}\n", self.frame_crop_bottom_offset)?;
+        writeln!(f, "SPS: {{")?;
+        writeln!(f, "    profile_idc: {}", self.profile_idc)?;
+        writeln!(f, "    constraint_0_flag: {}", self.constraint_0_flag)?;
+        writeln!(f, "    constraint_1_flag: {}", self.constraint_1_flag)?;
+        writeln!(f, "    constraint_2_flag: {}", self.constraint_2_flag)?;
+        writeln!(f, "    constraint_3_flag: {}", self.constraint_3_flag)?;
+        writeln!(f, "    constraint_4_flag: {}", self.constraint_4_flag)?;
+        writeln!(f, "    constraint_5_flag: {}", self.constraint_5_flag)?;
+        writeln!(f, "    level_idc: {}", self.level_idc)?;
+        writeln!(f, "    seq_parameter_set_id: {}", self.seq_parameter_set_id)?;
+        writeln!(f, "    chroma_format_idc: {}", self.chroma_format_idc)?;
+        writeln!(f, "    separate_colour_plane_flag: {}", self.separate_colour_plane_flag)?;
+        writeln!(f, "    bit_depth_luma_minus8: {}", self.bit_depth_luma_minus8)?;
+        writeln!(f, "    bit_depth_chroma_minus8: {}", self.bit_depth_chroma_minus8)?;
+        writeln!(f, "    qpprime_y_zero_transform_bypass_flag: {}", self.qpprime_y_zero_transform_bypass_flag)?;
+        writeln!(f, "    seq_scaling_matrix_present_flag: {}", self.seq_scaling_matrix_present_flag)?;
+        writeln!(f, "    seq_scaling_list_present_flag: {:?}", self.seq_scaling_list_present_flag)?;
+        writeln!(f, "    scaling_list_4x4: {:?}", self.scaling_list_4x4)?;
+        writeln!(f, "    scaling_list_8x8: {:?}", self.scaling_list_8x8)?;
+        writeln!(f, "    use_default_scaling_matrix_flag: {:?}", self.use_default_scaling_matrix_flag)?;
+        writeln!(f, "    log2_max_frame_num_minus4: {}", self.log2_max_frame_num_minus4)?;
+        writeln!(f, "    pic_order_cnt_type: {}", self.pic_order_cnt_type)?;
+        writeln!(f, "    log2_max_pic_order_cnt_lsb_minus4: {}", self.log2_max_pic_order_cnt_lsb_minus4)?;
+        writeln!(f, "    delta_pic_order_always_zero_flag: {}", self.delta_pic_order_always_zero_flag)?;
+        writeln!(f, "    offset_for_non_ref_pic: {}", self.offset_for_non_ref_pic)?;
+        writeln!(f, "    offset_for_top_to_bottom_field: {}", self.offset_for_top_to_bottom_field)?;
+        writeln!(f, "    num_ref_frames_in_pic_order_cnt_cycle: {}", self.num_ref_frames_in_pic_order_cnt_cycle)?;
+        writeln!(f, "    offset_for_ref_frame: {:?}", self.offset_for_ref_frame)?;
+        writeln!(f, "    max_num_ref_frames: {}", self.max_num_ref_frames)?;
+        writeln!(f, "    gaps_in_frame_num_value_allowed_flag: {}", self.gaps_in_frame_num_value_allowed_flag)?;
+        writeln!(f, "    pic_width_in_mbs_minus1: {}", self.pic_width_in_mbs_minus1)?;
+        writeln!(f, "    pic_height_in_map_units_minus1: {}", self.pic_height_in_map_units_minus1)?;
+        writeln!(f, "    frame_mbs_only_flag: {}", self.frame_mbs_only_flag)?;
+        writeln!(f, "    mb_adaptive_frame_field_flag: {}", self.mb_adaptive_frame_field_flag)?;
+        writeln!(f, "    direct_8x8_inference_flag: {}", self.direct_8x8_inference_flag)?;
+        writeln!(f, "    frame_cropping_flag: {}", self.frame_cropping_flag)?;
+        writeln!(f, "    frame_crop_left_offset: {}", self.frame_crop_left_offset)?;
+        writeln!(f, "    frame_crop_right_offset: {}", self.frame_crop_right_offset)?;
+        writeln!(f, "    frame_crop_top_offset: {}", self.frame_crop_top_offset)?;
+        writeln!(f, "    frame_crop_bottom_offset: {}", self.frame_crop_bottom_offset)?;
         if self.vui_parameters_present_flag != 0 {
-            write!(f, "    vui_parameters_present_flag: {}\n", self.vui_parameters_present_flag)?;
-            match self.vui_parameters {
-                Some(ref v) => write!(f, "    vui_parameters: {}\n", v)?,
-                None => {}
-            };
+            writeln!(f, "    vui_parameters_present_flag: {}", self.vui_parameters_present_flag)?;
+            if let Some(ref v) = self.vui_parameters {
+                writeln!(f, "    vui_parameters: {}", v)?;
+            }
         }
-        write!(f, "}}\n")
+        writeln!(f, "}}")
     }
 }
 
@@ -365,14 +645,25 @@ pub struct H264NalUnitPPS {
     pub deblocking_filter_control_present_flag: bool,
     pub constrained_intra_pred_flag: u8,
     pub redundant_pic_cnt_present_flag: bool,
+    /// Whether the optional transform_8x8_mode_flag..second_chroma_qp_index_offset
+    /// tail is present.
+    pub more_rbsp_data: bool,
     pub transform_8x8_mode_flag: u8,
     pub pic_scaling_matrix_present_flag: u8,
     pub pic_scaling_list_present_flag: Vec<u8>,
     pub scaling_list_4x4: Vec<Vec<u8>>,
     pub scaling_list_8x8: Vec<Vec<u8>>,
+    pub use_default_scaling_matrix_flag: Vec<bool>,
+    pub pic_scaling_list_delta_scale: Vec<Vec<i32>>,
     pub second_chroma_qp_index_offset: i32
 }
 
+impl Default for H264NalUnitPPS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl H264NalUnitPPS {
     pub fn new() -> H264NalUnitPPS {
         H264NalUnitPPS {
@@ -399,11 +690,14 @@ impl H264NalUnitPPS {
             deblocking_filter_control_present_flag: false,
             constrained_intra_pred_flag: 0,
             redundant_pic_cnt_present_flag: false,
+            more_rbsp_data: false,
             transform_8x8_mode_flag: 0,
             pic_scaling_matrix_present_flag: 0,
             pic_scaling_list_present_flag: Vec::new(),
             scaling_list_4x4: vec![vec![0u8; 16]; 6],
             scaling_list_8x8: vec![vec![0u8; 64]; 6],
+            use_default_scaling_matrix_flag: vec![false; 12],
+            pic_scaling_list_delta_scale: vec![Vec::new(); 12],
             second_chroma_qp_index_offset: 0
         }
     }
@@ -412,65 +706,69 @@ impl H264NalUnitPPS {
 impl fmt::Display for H264NalUnitPPS {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "PPS {{")?;
-        write!(f, "    pic_parameter_set_id: {:?}\n", self.pic_parameter_set_id)?;
-        write!(f, "    seq_parameter_set_id: {:?}\n", self.seq_parameter_set_id)?;
-        write!(f, "    entropy_coding_mode_flag: {:?}\n", self.entropy_coding_mode_flag)?;
-        write!(f, "    bottom_field_pic_order_in_frame_present_flag: {:?}\n", self.bottom_field_pic_order_in_frame_present_flag)?;
-        write!(f, "    num_slice_groups_minus1: {:?}\n", self.num_slice_groups_minus1)?;
-        write!(f, "    slice_group_map_type: {:?}\n", self.slice_group_map_type)?;
-        write!(f, "    run_length_minus1: {:?}\n", self.run_length_minus1)?;
-        write!(f, "    top_left: {:?}\n", self.top_left)?;
-        write!(f, "    bottom_right: {:?}\n", self.bottom_right)?;
-        write!(f, "    slice_group_change_direction_flag: {:?}\n", self.slice_group_change_direction_flag)?;
-        write!(f, "    slice_group_change_rate_minus1: {:?}\n", self.slice_group_change_rate_minus1)?;
-        write!(f, "    pic_size_in_map_units_minus1: {:?}\n", self.pic_size_in_map_units_minus1)?;
-        write!(f, "    slice_group_id: {:?}\n", self.slice_group_id)?;
-        write!(f, "    num_ref_idx_l0_default_active_minus1: {:?}\n", self.num_ref_idx_l0_default_active_minus1)?;
-        write!(f, "    num_ref_idx_l1_default_active_minus1: {:?}\n", self.num_ref_idx_l1_default_active_minus1)?;
-        write!(f, "    weighted_pred_flag: {:?}\n", self.weighted_pred_flag)?;
-        write!(f, "    weighted_bipred_idc: {:?}\n", self.weighted_bipred_idc)?;
-        write!(f, "    pic_init_qp_minus26: {:?}\n", self.pic_init_qp_minus26)?;
-        write!(f, "    pic_init_qs_minus26: {:?}\n", self.pic_init_qs_minus26)?;
-        write!(f, "    chroma_qp_index_offset: {:?}\n", self.chroma_qp_index_offset)?;
-        write!(f, "    deblocking_filter_control_present_flag: {:?}\n", self.deblocking_filter_control_present_flag)?;
-        write!(f, "    constrained_intra_pred_flag: {:?}\n", self.constrained_intra_pred_flag)?;
-        write!(f, "    redundant_pic_cnt_present_flag: {:?}\n", self.redundant_pic_cnt_present_flag)?;
-        write!(f, "    transform_8x8_mode_flag: {:?}\n", self.transform_8x8_mode_flag)?;
-        write!(f, "    pic_scaling_matrix_present_flag: {:?}\n", self.pic_scaling_matrix_present_flag)?;
-        write!(f, "    pic_scaling_list_present_flag: {:?}\n", self.pic_scaling_list_present_flag)?;
-        write!(f, "    scaling_list_4x4: {:?}\n", self.scaling_list_4x4)?;
-        write!(f, "    scaling_list_8x8: {:?}\n", self.scaling_list_8x8)?;
-        write!(f, "    second_chroma_qp_index_offset: {:?}\n", self.second_chroma_qp_index_offset)?;
+        writeln!(f, "    pic_parameter_set_id: {:?}", self.pic_parameter_set_id)?;
+        writeln!(f, "    seq_parameter_set_id: {:?}", self.seq_parameter_set_id)?;
+        writeln!(f, "    entropy_coding_mode_flag: {:?}", self.entropy_coding_mode_flag)?;
+        writeln!(f, "    bottom_field_pic_order_in_frame_present_flag: {:?}", self.bottom_field_pic_order_in_frame_present_flag)?;
+        writeln!(f, "    num_slice_groups_minus1: {:?}", self.num_slice_groups_minus1)?;
+        writeln!(f, "    slice_group_map_type: {:?}", self.slice_group_map_type)?;
+        writeln!(f, "    run_length_minus1: {:?}", self.run_length_minus1)?;
+        writeln!(f, "    top_left: {:?}", self.top_left)?;
+        writeln!(f, "    bottom_right: {:?}", self.bottom_right)?;
+        writeln!(f, "    slice_group_change_direction_flag: {:?}", self.slice_group_change_direction_flag)?;
+        writeln!(f, "    slice_group_change_rate_minus1: {:?}", self.slice_group_change_rate_minus1)?;
+        writeln!(f, "    pic_size_in_map_units_minus1: {:?}", self.pic_size_in_map_units_minus1)?;
+        writeln!(f, "    slice_group_id: {:?}", self.slice_group_id)?;
+        writeln!(f, "    num_ref_idx_l0_default_active_minus1: {:?}", self.num_ref_idx_l0_default_active_minus1)?;
+        writeln!(f, "    num_ref_idx_l1_default_active_minus1: {:?}", self.num_ref_idx_l1_default_active_minus1)?;
+        writeln!(f, "    weighted_pred_flag: {:?}", self.weighted_pred_flag)?;
+        writeln!(f, "    weighted_bipred_idc: {:?}", self.weighted_bipred_idc)?;
+        writeln!(f, "    pic_init_qp_minus26: {:?}", self.pic_init_qp_minus26)?;
+        writeln!(f, "    pic_init_qs_minus26: {:?}", self.pic_init_qs_minus26)?;
+        writeln!(f, "    chroma_qp_index_offset: {:?}", self.chroma_qp_index_offset)?;
+        writeln!(f, "    deblocking_filter_control_present_flag: {:?}", self.deblocking_filter_control_present_flag)?;
+        writeln!(f, "    constrained_intra_pred_flag: {:?}", self.constrained_intra_pred_flag)?;
+        writeln!(f, "    redundant_pic_cnt_present_flag: {:?}", self.redundant_pic_cnt_present_flag)?;
+        writeln!(f, "    more_rbsp_data: {:?}", self.more_rbsp_data)?;
+        writeln!(f, "    transform_8x8_mode_flag: {:?}", self.transform_8x8_mode_flag)?;
+        writeln!(f, "    pic_scaling_matrix_present_flag: {:?}", self.pic_scaling_matrix_present_flag)?;
+        writeln!(f, "    pic_scaling_list_present_flag: {:?}", self.pic_scaling_list_present_flag)?;
+        writeln!(f, "    scaling_list_4x4: {:?}", self.scaling_list_4x4)?;
+        writeln!(f, "    scaling_list_8x8: {:?}", self.scaling_list_8x8)?;
+        writeln!(f, "    use_default_scaling_matrix_flag: {:?}", self.use_default_scaling_matrix_flag)?;
+        writeln!(f, "    second_chroma_qp_index_offset: {:?}", self.second_chroma_qp_index_offset)?;
         write!(f, "}}")
     }
 }
 
+/// Ceil(Log2(val)), 0 for 0 and 1.
 pub fn ceil_log2(val: u32) -> u32 {
-    let t = vec![0xFFFFFFFF00000000,
-                 0x00000000FFFF0000,
-                 0x000000000000FF00,
-                 0x00000000000000F0,
-                 0x000000000000000C,
-                 0x0000000000000002];
-    let mut x = val;
-    let mut y = if val & (val - 1) == 0 {
-        0
-    } else {
-        1
-    };
-    let mut j = 32;
-    for i in 0..6 {
-        let k = if x & t[i] == 0 {
-            0
-        } else {
-            j
-        };
-        y += k;
-        x >>= k;
-        j >>= 1;
+    if val <= 1 {
+        return 0;
     }
+    32 - (val - 1).leading_zeros()
+}
 
-    y
+/// Number of bits of each slice_group_id in the PPS.
+pub fn slice_group_id_bits(num_slice_groups_minus1: u32) -> u32 {
+    let mut nbits = 0;
+    while (1u32 << nbits) < num_slice_groups_minus1 + 1 {
+        nbits += 1;
+    }
+    nbits
+}
+
+/// Number of bits of slice_group_change_cycle in the slice header,
+/// Ceil(Log2(PicSizeInMapUnits / SliceGroupChangeRate + 1)).
+pub fn slice_group_change_cycle_bits(sps: &H264NalUnitSPS, pps: &H264NalUnitPPS) -> u32 {
+    let pic_size_in_map_units = (sps.pic_width_in_mbs_minus1 as u64 + 1) *
+        (sps.pic_height_in_map_units_minus1 as u64 + 1);
+    let slice_group_change_rate = pps.slice_group_change_rate_minus1 as u64 + 1;
+    let mut nbits = 0;
+    while slice_group_change_rate << nbits < pic_size_in_map_units + slice_group_change_rate {
+        nbits += 1;
+    }
+    nbits
 }
 
 const P_SLICE : u32 = 0;
@@ -478,10 +776,17 @@ const B_SLICE : u32 = 1;
 const I_SLICE : u32 = 2;
 const SP_SLICE : u32 = 3;
 const SI_SLICE : u32 = 4;
+// Types 5..9 say all slices of the picture have the same type, the
+// slice_type_is_* checks cover them with the modulo.
+#[allow(dead_code)]
 const S_P_SLICE : u32 = 5;
+#[allow(dead_code)]
 const S_B_SLICE : u32 = 6;
+#[allow(dead_code)]
 const S_I_SLICE : u32 = 7;
+#[allow(dead_code)]
 const S_SP_SLICE : u32 = 8;
+#[allow(dead_code)]
 const S_SI_SLICE : u32 = 9;
 
 pub fn slice_type_is_p_slice(slice_type: u32) -> bool {
@@ -500,6 +805,83 @@ pub fn slice_type_is_si_slice(slice_type: u32) -> bool {
     (slice_type % 5) == SI_SLICE
 }
 
+/// One entry of ref_pic_list_modification(). value holds
+/// abs_diff_pic_num_minus1, long_term_pic_num or abs_diff_view_idx_minus1
+/// depending on modification_of_pic_nums_idc.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264RefPicListModification {
+    pub modification_of_pic_nums_idc: u32,
+    pub value: u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264PredWeightTable {
+    pub luma_log2_weight_denom: u32,
+    pub chroma_log2_weight_denom: u32,
+    pub luma_weight_l0_flag: Vec<bool>,
+    pub luma_weight_l0: Vec<i32>,
+    pub luma_offset_l0: Vec<i32>,
+    pub chroma_weight_l0_flag: Vec<bool>,
+    pub chroma_weight_l0: Vec<[i32; 2]>,
+    pub chroma_offset_l0: Vec<[i32; 2]>,
+    pub luma_weight_l1_flag: Vec<bool>,
+    pub luma_weight_l1: Vec<i32>,
+    pub luma_offset_l1: Vec<i32>,
+    pub chroma_weight_l1_flag: Vec<bool>,
+    pub chroma_weight_l1: Vec<[i32; 2]>,
+    pub chroma_offset_l1: Vec<[i32; 2]>
+}
+
+impl Default for H264PredWeightTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264PredWeightTable {
+    pub fn new() -> H264PredWeightTable {
+        H264PredWeightTable {
+            luma_log2_weight_denom: 0,
+            chroma_log2_weight_denom: 0,
+            luma_weight_l0_flag: Vec::new(),
+            luma_weight_l0: Vec::new(),
+            luma_offset_l0: Vec::new(),
+            chroma_weight_l0_flag: Vec::new(),
+            chroma_weight_l0: Vec::new(),
+            chroma_offset_l0: Vec::new(),
+            luma_weight_l1_flag: Vec::new(),
+            luma_weight_l1: Vec::new(),
+            luma_offset_l1: Vec::new(),
+            chroma_weight_l1_flag: Vec::new(),
+            chroma_weight_l1: Vec::new(),
+            chroma_offset_l1: Vec::new()
+        }
+    }
+}
+
+/// One memory_management_control_operation of dec_ref_pic_marking(). Only
+/// the fields used by the operation are set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264MemoryManagementOperation {
+    pub memory_management_control_operation: u32,
+    pub difference_of_pic_nums_minus1: u32,
+    pub long_term_pic_num: u32,
+    pub long_term_frame_idx: u32,
+    pub max_long_term_frame_idx_plus1: u32
+}
+
+impl H264MemoryManagementOperation {
+    pub fn new(memory_management_control_operation: u32) -> H264MemoryManagementOperation {
+        H264MemoryManagementOperation {
+            memory_management_control_operation,
+            difference_of_pic_nums_minus1: 0,
+            long_term_pic_num: 0,
+            long_term_frame_idx: 0,
+            max_long_term_frame_idx_plus1: 0
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct H264NalUnitSlice {
     pub first_mb_in_slice: u32,
@@ -519,21 +901,19 @@ pub struct H264NalUnitSlice {
     pub num_ref_idx_l0_active_minus1: u32,
     pub num_ref_idx_l1_active_minus1: u32,
 
-    // TODO: When I have a B slice to parse
-    // ref_pic_list_mvc_modification
-    // ref_pic_list_modification
+    // ref_pic_list_modification / ref_pic_list_mvc_modification
+    pub ref_pic_list_modification_flag_l0: bool,
+    pub ref_pic_list_modification_l0: Vec<H264RefPicListModification>,
+    pub ref_pic_list_modification_flag_l1: bool,
+    pub ref_pic_list_modification_l1: Vec<H264RefPicListModification>,
 
-    // pred_weight_table
-    // TODO: When I have a P slice to parse
+    pub pred_weight_table: Option<H264PredWeightTable>,
 
     // dec_ref_pic_marking
     pub no_output_of_prior_pics_flag: bool,
     pub long_term_reference_flag: bool,
     pub adaptive_ref_pic_marking_mode_flag: bool,
-    pub difference_of_pic_nums_minus1: u32,
-    pub long_term_pic_num: u32,
-    pub long_term_frame_idx: u32,
-    pub max_long_term_frame_idx_plus1: u32,
+    pub memory_management_control_operations: Vec<H264MemoryManagementOperation>,
 
     pub cabac_init_idc: u32,
     pub slice_qp_delta: i32,
@@ -548,6 +928,12 @@ pub struct H264NalUnitSlice {
     pub slice_group_change_cycle: u32,
 }
 
+impl Default for H264NalUnitSlice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl H264NalUnitSlice {
     pub fn new() -> H264NalUnitSlice {
         H264NalUnitSlice {
@@ -568,17 +954,15 @@ impl H264NalUnitSlice {
             num_ref_idx_l0_active_minus1: 0,
             num_ref_idx_l1_active_minus1: 0,
 
-            // ref_pic_list_mvc_modification
-            // ref_pic_list_modification
-            // pred_weight_table
-            // dec_ref_pic_marking
+            ref_pic_list_modification_flag_l0: false,
+            ref_pic_list_modification_l0: Vec::new(),
+            ref_pic_list_modification_flag_l1: false,
+            ref_pic_list_modification_l1: Vec::new(),
+            pred_weight_table: None,
             no_output_of_prior_pics_flag: false,
             long_term_reference_flag: false,
             adaptive_ref_pic_marking_mode_flag: false,
-            difference_of_pic_nums_minus1: 0,
-            long_term_pic_num: 0,
-            long_term_frame_idx: 0,
-            max_long_term_frame_idx_plus1: 0,
+            memory_management_control_operations: Vec::new(),
 
             cabac_init_idc: 0,
             slice_qp_delta: 0,
@@ -594,39 +978,41 @@ impl H264NalUnitSlice {
 
 impl fmt::Display for H264NalUnitSlice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Slice {{\n")?;
-        write!(f, "    first_mb_in_slice: {:?}\n", self.first_mb_in_slice)?;
-        write!(f, "    slice_type: {:?}\n", self.slice_type)?;
-        write!(f, "    pic_parameter_set_id: {:?}\n", self.pic_parameter_set_id)?;
-        write!(f, "    colour_plane_id: {:?}\n", self.colour_plane_id)?;
-        write!(f, "    frame_num: {:?}\n", self.frame_num)?;
-        write!(f, "    field_pic_flag: {:?}\n", self.field_pic_flag)?;
-        write!(f, "    bottom_field_flag: {:?}\n", self.bottom_field_flag)?;
-        write!(f, "    idr_pic_id: {:?}\n", self.idr_pic_id)?;
-        write!(f, "    pic_order_cnt_lsb: {:?}\n", self.pic_order_cnt_lsb)?;
-        write!(f, "    delta_pic_order_cnt_bottom: {:?}\n", self.delta_pic_order_cnt_bottom)?;
-        write!(f, "    delta_pic_order_cnt: {:?}\n", self.delta_pic_order_cnt)?;
-        write!(f, "    redundant_pic_cnt: {:?}\n", self.redundant_pic_cnt)?;
-        write!(f, "    direct_spatial_mv_pred_flag: {:?}\n", self.direct_spatial_mv_pred_flag)?;
-        write!(f, "    num_ref_idx_active_override_flag: {:?}\n", self.num_ref_idx_active_override_flag)?;
-        write!(f, "    num_ref_idx_l0_active_minus1: {:?}\n", self.num_ref_idx_l0_active_minus1)?;
-        write!(f, "    num_ref_idx_l1_active_minus1: {:?}\n", self.num_ref_idx_l1_active_minus1)?;
-        write!(f, "    no_output_of_prior_pics_flag: {:?}\n", self.no_output_of_prior_pics_flag)?;
-        write!(f, "    long_term_reference_flag: {:?}\n", self.long_term_reference_flag)?;
-        write!(f, "    adaptive_ref_pic_marking_mode_flag: {:?}\n", self.adaptive_ref_pic_marking_mode_flag)?;
-        write!(f, "    difference_of_pic_nums_minus1: {:?}\n", self.difference_of_pic_nums_minus1)?;
-        write!(f, "    long_term_pic_num: {:?}\n", self.long_term_pic_num)?;
-        write!(f, "    long_term_frame_idx: {:?}\n", self.long_term_frame_idx)?;
-        write!(f, "    max_long_term_frame_idx_plus1: {:?}\n", self.max_long_term_frame_idx_plus1)?;
-        write!(f, "    cabac_init_idc: {:?}\n", self.cabac_init_idc)?;
-        write!(f, "    slice_qp_delta: {:?}\n", self.slice_qp_delta)?;
-        write!(f, "    sp_for_switch_flag: {:?}\n", self.sp_for_switch_flag)?;
-        write!(f, "    slice_qs_delta: {:?}\n", self.slice_qs_delta)?;
-        write!(f, "    disable_deblocking_filter_idc: {:?}\n", self.disable_deblocking_filter_idc)?;
-        write!(f, "    slice_alpha_c0_offset_div2: {:?}\n", self.slice_alpha_c0_offset_div2)?;
-        write!(f, "    slice_beta_offset_div2: {:?}\n", self.slice_beta_offset_div2)?;
-        write!(f, "    slice_group_change_cycle: {:?}\n", self.slice_group_change_cycle)?;
-        write!(f, "}}\n")
+        writeln!(f, "Slice {{")?;
+        writeln!(f, "    first_mb_in_slice: {:?}", self.first_mb_in_slice)?;
+        writeln!(f, "    slice_type: {:?}", self.slice_type)?;
+        writeln!(f, "    pic_parameter_set_id: {:?}", self.pic_parameter_set_id)?;
+        writeln!(f, "    colour_plane_id: {:?}", self.colour_plane_id)?;
+        writeln!(f, "    frame_num: {:?}", self.frame_num)?;
+        writeln!(f, "    field_pic_flag: {:?}", self.field_pic_flag)?;
+        writeln!(f, "    bottom_field_flag: {:?}", self.bottom_field_flag)?;
+        writeln!(f, "    idr_pic_id: {:?}", self.idr_pic_id)?;
+        writeln!(f, "    pic_order_cnt_lsb: {:?}", self.pic_order_cnt_lsb)?;
+        writeln!(f, "    delta_pic_order_cnt_bottom: {:?}", self.delta_pic_order_cnt_bottom)?;
+        writeln!(f, "    delta_pic_order_cnt: {:?}", self.delta_pic_order_cnt)?;
+        writeln!(f, "    redundant_pic_cnt: {:?}", self.redundant_pic_cnt)?;
+        writeln!(f, "    direct_spatial_mv_pred_flag: {:?}", self.direct_spatial_mv_pred_flag)?;
+        writeln!(f, "    num_ref_idx_active_override_flag: {:?}", self.num_ref_idx_active_override_flag)?;
+        writeln!(f, "    num_ref_idx_l0_active_minus1: {:?}", self.num_ref_idx_l0_active_minus1)?;
+        writeln!(f, "    num_ref_idx_l1_active_minus1: {:?}", self.num_ref_idx_l1_active_minus1)?;
+        writeln!(f, "    ref_pic_list_modification_flag_l0: {:?}", self.ref_pic_list_modification_flag_l0)?;
+        writeln!(f, "    ref_pic_list_modification_l0: {:?}", self.ref_pic_list_modification_l0)?;
+        writeln!(f, "    ref_pic_list_modification_flag_l1: {:?}", self.ref_pic_list_modification_flag_l1)?;
+        writeln!(f, "    ref_pic_list_modification_l1: {:?}", self.ref_pic_list_modification_l1)?;
+        writeln!(f, "    pred_weight_table: {:?}", self.pred_weight_table)?;
+        writeln!(f, "    no_output_of_prior_pics_flag: {:?}", self.no_output_of_prior_pics_flag)?;
+        writeln!(f, "    long_term_reference_flag: {:?}", self.long_term_reference_flag)?;
+        writeln!(f, "    adaptive_ref_pic_marking_mode_flag: {:?}", self.adaptive_ref_pic_marking_mode_flag)?;
+        writeln!(f, "    memory_management_control_operations: {:?}", self.memory_management_control_operations)?;
+        writeln!(f, "    cabac_init_idc: {:?}", self.cabac_init_idc)?;
+        writeln!(f, "    slice_qp_delta: {:?}", self.slice_qp_delta)?;
+        writeln!(f, "    sp_for_switch_flag: {:?}", self.sp_for_switch_flag)?;
+        writeln!(f, "    slice_qs_delta: {:?}", self.slice_qs_delta)?;
+        writeln!(f, "    disable_deblocking_filter_idc: {:?}", self.disable_deblocking_filter_idc)?;
+        writeln!(f, "    slice_alpha_c0_offset_div2: {:?}", self.slice_alpha_c0_offset_div2)?;
+        writeln!(f, "    slice_beta_offset_div2: {:?}", self.slice_beta_offset_div2)?;
+        writeln!(f, "    slice_group_change_cycle: {:?}", self.slice_group_change_cycle)?;
+        writeln!(f, "}}")
     }
 }
 
@@ -653,20 +1039,577 @@ impl H264NalUnit {
     {
         let nal_unit_type = match unit_type {
             5 => H264NalUnitType::IDR,
+            6 => H264NalUnitType::SEI,
             7 => H264NalUnitType::SPS,
             8 => H264NalUnitType::PPS,
             _ => H264NalUnitType::UNKNOWN
         };
         H264NalUnit {
             name: "Unit".to_string(),
-            sc_offset: sc_offset,
-            data_offset: data_offset,
-            size: size,
+            sc_offset,
+            data_offset,
+            size,
             idr_pic_flag: nal_unit_type == H264NalUnitType::IDR,
             nal_ref_idc: ref_idc,
             nal_unit_type_num: unit_type,
-            nal_unit_type: nal_unit_type,
+            nal_unit_type,
         }
     }
 }
 
+
+pub const SEI_MASTERING_DISPLAY_COLOUR_VOLUME : u32 = 137;
+pub const SEI_CONTENT_LIGHT_LEVEL_INFO : u32 = 144;
+pub const SEI_ALTERNATIVE_TRANSFER_CHARACTERISTICS : u32 = 147;
+pub const SEI_BUFFERING_PERIOD : u32 = 0;
+pub const SEI_PIC_TIMING : u32 = 1;
+
+/// buffering_period() from D.1.2. The delays are in units of a 90 kHz
+/// clock, one per SchedSelIdx, for the NAL and VCL HRD that the SPS has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264BufferingPeriod {
+    pub seq_parameter_set_id: u32,
+    pub nal_initial_cpb_removal_delay: Vec<u32>,
+    pub nal_initial_cpb_removal_delay_offset: Vec<u32>,
+    pub vcl_initial_cpb_removal_delay: Vec<u32>,
+    pub vcl_initial_cpb_removal_delay_offset: Vec<u32>
+}
+
+/// pic_timing() from D.1.3, without the clock timestamps. The delays are
+/// only there when the VUI has HRD parameters, and pic_struct when
+/// pic_struct_present_flag is set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct H264PicTiming {
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    pub pic_struct: Option<u8>
+}
+
+impl H264PicTiming {
+    /// How many clock ticks the picture is shown for according to
+    /// pic_struct (Table D-1), counting a field as one tick.
+    pub fn display_ticks(&self) -> Option<u64> {
+        self.pic_struct.map(|pic_struct| match pic_struct {
+            1 | 2 => 1,
+            3 | 4 => 2,
+            5 | 6 => 3,
+            7 => 4,
+            8 => 6,
+            _ => 2
+        })
+    }
+}
+
+/// mastering_display_colour_volume() from D.1.29. Primaries and the white
+/// point are in increments of 0.00002, luminances in 0.0001 cd/m^2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264MasteringDisplayColourVolume {
+    pub display_primaries_x: [u16; 3],
+    pub display_primaries_y: [u16; 3],
+    pub white_point_x: u16,
+    pub white_point_y: u16,
+    pub max_display_mastering_luminance: u32,
+    pub min_display_mastering_luminance: u32
+}
+
+impl Default for H264MasteringDisplayColourVolume {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264MasteringDisplayColourVolume {
+    pub fn new() -> H264MasteringDisplayColourVolume {
+        H264MasteringDisplayColourVolume {
+            display_primaries_x: [0; 3],
+            display_primaries_y: [0; 3],
+            white_point_x: 0,
+            white_point_y: 0,
+            max_display_mastering_luminance: 0,
+            min_display_mastering_luminance: 0
+        }
+    }
+
+    /// The SEI payload, which is also the payload of the mdcv box.
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(24);
+        for c in 0..3 {
+            payload.extend_from_slice(&self.display_primaries_x[c].to_be_bytes());
+            payload.extend_from_slice(&self.display_primaries_y[c].to_be_bytes());
+        }
+        payload.extend_from_slice(&self.white_point_x.to_be_bytes());
+        payload.extend_from_slice(&self.white_point_y.to_be_bytes());
+        payload.extend_from_slice(&self.max_display_mastering_luminance.to_be_bytes());
+        payload.extend_from_slice(&self.min_display_mastering_luminance.to_be_bytes());
+        payload
+    }
+}
+
+/// content_light_level_info() from D.1.31, both values in cd/m^2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264ContentLightLevelInfo {
+    pub max_content_light_level: u16,
+    pub max_pic_average_light_level: u16
+}
+
+impl Default for H264ContentLightLevelInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264ContentLightLevelInfo {
+    pub fn new() -> H264ContentLightLevelInfo {
+        H264ContentLightLevelInfo {
+            max_content_light_level: 0,
+            max_pic_average_light_level: 0
+        }
+    }
+
+    /// The SEI payload, which is also the payload of the clli box.
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(4);
+        payload.extend_from_slice(&self.max_content_light_level.to_be_bytes());
+        payload.extend_from_slice(&self.max_pic_average_light_level.to_be_bytes());
+        payload
+    }
+}
+
+/// alternative_transfer_characteristics() from D.1.32.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264AlternativeTransferCharacteristics {
+    pub preferred_transfer_characteristics: u8
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum H264SEIPayload {
+    MasteringDisplayColourVolume(H264MasteringDisplayColourVolume),
+    ContentLightLevelInfo(H264ContentLightLevelInfo),
+    AlternativeTransferCharacteristics(H264AlternativeTransferCharacteristics),
+    FramePackingArrangement(H264FramePackingArrangement),
+    StereoVideoInfo(H264StereoVideoInfo),
+    /// Payloads we don't parse yet are kept as their raw RBSP bytes.
+    Unknown(Vec<u8>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264SEIMessage {
+    pub payload_type: u32,
+    pub payload_size: u32,
+    pub payload: H264SEIPayload
+}
+
+impl fmt::Display for H264SEIMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "SEI {{")?;
+        writeln!(f, "    payload_type: {:?}", self.payload_type)?;
+        writeln!(f, "    payload_size: {:?}", self.payload_size)?;
+        writeln!(f, "    payload: {:?}", self.payload)?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Colour description of a stream, combining the VUI colour fields of the
+/// SPS with the HDR SEI messages. The accessors produce the payloads (without
+/// the box header) of the ISO-BMFF colr, mdcv and clli boxes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264ColourDescription {
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub video_full_range_flag: u8,
+    pub preferred_transfer_characteristics: Option<u8>,
+    pub mastering_display_colour_volume: Option<H264MasteringDisplayColourVolume>,
+    pub content_light_level_info: Option<H264ContentLightLevelInfo>
+}
+
+impl H264ColourDescription {
+    /// Starts with the "unspecified" code points (2) and fills in whatever
+    /// the VUI and the SEI messages signal.
+    pub fn new(sps: &H264NalUnitSPS, messages: &[H264SEIMessage]) -> H264ColourDescription {
+        let mut desc = H264ColourDescription {
+            colour_primaries: 2,
+            transfer_characteristics: 2,
+            matrix_coefficients: 2,
+            video_full_range_flag: 0,
+            preferred_transfer_characteristics: None,
+            mastering_display_colour_volume: None,
+            content_light_level_info: None
+        };
+        if let Some(ref vui) = sps.vui_parameters {
+            if vui.video_signal_type_present_flag == 1 {
+                desc.video_full_range_flag = vui.video_full_range_flag;
+                if vui.colour_description_present_flag == 1 {
+                    desc.colour_primaries = vui.colour_primaries;
+                    desc.transfer_characteristics = vui.transfer_characteristics;
+                    desc.matrix_coefficients = vui.matrix_coefficients;
+                }
+            }
+        }
+        for message in messages {
+            match message.payload {
+                H264SEIPayload::MasteringDisplayColourVolume(ref m) =>
+                    desc.mastering_display_colour_volume = Some(m.clone()),
+                H264SEIPayload::ContentLightLevelInfo(ref c) =>
+                    desc.content_light_level_info = Some(c.clone()),
+                H264SEIPayload::AlternativeTransferCharacteristics(ref a) =>
+                    desc.preferred_transfer_characteristics = Some(a.preferred_transfer_characteristics),
+                _ => {}
+            }
+        }
+        desc
+    }
+
+    /// The transfer characteristics a player should use: the SEI preferred
+    /// value when present (e.g. HLG), otherwise the VUI value.
+    pub fn effective_transfer_characteristics(&self) -> u8 {
+        self.preferred_transfer_characteristics.unwrap_or(self.transfer_characteristics)
+    }
+
+    /// colr box payload with colour_type 'nclx'.
+    pub fn colr_payload(&self) -> Vec<u8> {
+        let mut payload = b"nclx".to_vec();
+        payload.extend_from_slice(&(self.colour_primaries as u16).to_be_bytes());
+        payload.extend_from_slice(&(self.effective_transfer_characteristics() as u16).to_be_bytes());
+        payload.extend_from_slice(&(self.matrix_coefficients as u16).to_be_bytes());
+        payload.push(self.video_full_range_flag << 7);
+        payload
+    }
+
+    /// mdcv box payload, which has the same layout as the SEI message.
+    pub fn mdcv_payload(&self) -> Option<Vec<u8>> {
+        self.mastering_display_colour_volume.as_ref().map(|m| m.payload())
+    }
+
+    /// clli box payload.
+    pub fn clli_payload(&self) -> Option<Vec<u8>> {
+        self.content_light_level_info.as_ref().map(|c| c.payload())
+    }
+}
+
+pub const SEI_STEREO_VIDEO_INFO : u32 = 21;
+pub const SEI_FRAME_PACKING_ARRANGEMENT : u32 = 45;
+
+/// The frame packing layouts of Table D-8.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264FramePackingType {
+    Checkerboard,
+    ColumnInterleaved,
+    RowInterleaved,
+    SideBySide,
+    TopBottom,
+    FrameAlternation,
+    Mono2D,
+    TileFormat,
+    Reserved(u8)
+}
+
+impl H264FramePackingType {
+    pub fn from_u8(frame_packing_arrangement_type: u8) -> H264FramePackingType {
+        match frame_packing_arrangement_type {
+            0 => H264FramePackingType::Checkerboard,
+            1 => H264FramePackingType::ColumnInterleaved,
+            2 => H264FramePackingType::RowInterleaved,
+            3 => H264FramePackingType::SideBySide,
+            4 => H264FramePackingType::TopBottom,
+            5 => H264FramePackingType::FrameAlternation,
+            6 => H264FramePackingType::Mono2D,
+            7 => H264FramePackingType::TileFormat,
+            t => H264FramePackingType::Reserved(t)
+        }
+    }
+}
+
+/// frame_packing_arrangement() from D.1.26.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264FramePackingArrangement {
+    pub frame_packing_arrangement_id: u32,
+    pub frame_packing_arrangement_cancel_flag: bool,
+    pub frame_packing_arrangement_type: u8,
+    pub quincunx_sampling_flag: bool,
+    pub content_interpretation_type: u8,
+    pub spatial_flipping_flag: bool,
+    pub frame0_flipped_flag: bool,
+    pub field_views_flag: bool,
+    pub current_frame_is_frame0_flag: bool,
+    pub frame0_self_contained_flag: bool,
+    pub frame1_self_contained_flag: bool,
+    pub frame0_grid_position_x: u8,
+    pub frame0_grid_position_y: u8,
+    pub frame1_grid_position_x: u8,
+    pub frame1_grid_position_y: u8,
+    pub frame_packing_arrangement_reserved_byte: u8,
+    pub frame_packing_arrangement_repetition_period: u32,
+    pub frame_packing_arrangement_extension_flag: bool
+}
+
+impl Default for H264FramePackingArrangement {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264FramePackingArrangement {
+    pub fn new() -> H264FramePackingArrangement {
+        H264FramePackingArrangement {
+            frame_packing_arrangement_id: 0,
+            frame_packing_arrangement_cancel_flag: false,
+            frame_packing_arrangement_type: 0,
+            quincunx_sampling_flag: false,
+            content_interpretation_type: 0,
+            spatial_flipping_flag: false,
+            frame0_flipped_flag: false,
+            field_views_flag: false,
+            current_frame_is_frame0_flag: false,
+            frame0_self_contained_flag: false,
+            frame1_self_contained_flag: false,
+            frame0_grid_position_x: 0,
+            frame0_grid_position_y: 0,
+            frame1_grid_position_x: 0,
+            frame1_grid_position_y: 0,
+            frame_packing_arrangement_reserved_byte: 0,
+            frame_packing_arrangement_repetition_period: 0,
+            frame_packing_arrangement_extension_flag: false
+        }
+    }
+
+    pub fn packing_type(&self) -> H264FramePackingType {
+        H264FramePackingType::from_u8(self.frame_packing_arrangement_type)
+    }
+
+    /// True if the arrangement carries two views, i.e. it isn't cancelled
+    /// and isn't the 2D (type 6) arrangement.
+    pub fn is_stereo(&self) -> bool {
+        !self.frame_packing_arrangement_cancel_flag &&
+            self.packing_type() != H264FramePackingType::Mono2D
+    }
+}
+
+/// stereo_video_info() from D.1.23.
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264StereoVideoInfo {
+    pub field_views_flag: bool,
+    pub top_field_is_left_view_flag: bool,
+    pub current_frame_is_left_view_flag: bool,
+    pub next_frame_is_second_view_flag: bool,
+    pub left_view_self_contained_flag: bool,
+    pub right_view_self_contained_flag: bool
+}
+
+impl Default for H264StereoVideoInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264StereoVideoInfo {
+    pub fn new() -> H264StereoVideoInfo {
+        H264StereoVideoInfo {
+            field_views_flag: false,
+            top_field_is_left_view_flag: false,
+            current_frame_is_left_view_flag: false,
+            next_frame_is_second_view_flag: false,
+            left_view_self_contained_flag: false,
+            right_view_self_contained_flag: false
+        }
+    }
+}
+
+/// A primary coded picture together with the non-VCL NAL units that
+/// precede it.
+#[derive(Debug, Clone)]
+pub struct H264AccessUnit {
+    pub offset: usize,
+    pub size: usize,
+    pub nal_units: Vec<H264NalUnit>,
+    pub sei: Vec<H264SEIMessage>,
+    /// SEI NAL units that couldn't be parsed, by their index in nal_units.
+    /// The messages of these are missing from sei.
+    pub sei_errors: Vec<(usize, H264NalParseError)>,
+
+    /// The frame packing and stereo video info in effect for this access
+    /// unit, including ones that persist from earlier access units.
+    pub frame_packing_arrangement: Option<H264FramePackingArrangement>,
+    pub stereo_video_info: Option<H264StereoVideoInfo>
+}
+
+impl H264AccessUnit {
+    pub fn new(offset: usize) -> H264AccessUnit {
+        H264AccessUnit {
+            offset,
+            size: 0,
+            nal_units: Vec::new(),
+            sei: Vec::new(),
+            sei_errors: Vec::new(),
+            frame_packing_arrangement: None,
+            stereo_video_info: None
+        }
+    }
+
+    pub fn is_idr(&self) -> bool {
+        self.nal_units.iter().any(|n| n.idr_pic_flag)
+    }
+
+    /// True if either SEI signals that this access unit carries two views.
+    pub fn is_stereo_3d(&self) -> bool {
+        self.frame_packing_arrangement.as_ref().is_some_and(|f| f.is_stereo()) ||
+            self.stereo_video_info.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sps(chroma_format_idc: u32, width_in_mbs: u32, height_in_map_units: u32) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 244;
+        sps.chroma_format_idc = chroma_format_idc;
+        sps.pic_width_in_mbs_minus1 = width_in_mbs - 1;
+        sps.pic_height_in_map_units_minus1 = height_in_map_units - 1;
+        sps.frame_mbs_only_flag = true;
+        sps
+    }
+
+    #[test]
+    fn cropped_420() {
+        let mut sps = sps(1, 120, 68);
+        sps.frame_cropping_flag = 1;
+        sps.frame_crop_bottom_offset = 4;
+        assert_eq!(sps.chroma_subsampling(), (2, 2));
+        assert_eq!(sps.crop_units(), (2, 2));
+        assert_eq!((sps.width(), sps.height()), (1920, 1088));
+        assert_eq!((sps.cropped_width(), sps.cropped_height()), (1920, 1080));
+        assert_eq!(sps.frame_size_in_mbs(), 8160);
+        assert_eq!(sps.display_aspect_ratio(), (16, 9));
+    }
+
+    #[test]
+    fn cropped_422_and_444() {
+        let mut sps = sps(2, 45, 36);
+        sps.frame_cropping_flag = 1;
+        sps.frame_crop_left_offset = 4;
+        sps.frame_crop_right_offset = 4;
+        sps.frame_crop_bottom_offset = 3;
+        assert_eq!(sps.chroma_subsampling(), (2, 1));
+        assert_eq!(sps.crop_units(), (2, 1));
+        assert_eq!((sps.cropped_width(), sps.cropped_height()), (704, 573));
+
+        sps.chroma_format_idc = 3;
+        assert_eq!(sps.chroma_subsampling(), (1, 1));
+        assert_eq!((sps.cropped_width(), sps.cropped_height()), (712, 573));
+
+        // Colour planes coded separately are cropped like monochrome.
+        sps.separate_colour_plane_flag = true;
+        assert_eq!(sps.chroma_array_type(), 0);
+        assert_eq!(sps.chroma_subsampling(), (0, 0));
+        assert_eq!(sps.crop_units(), (1, 1));
+    }
+
+    #[test]
+    fn cropped_interlaced() {
+        let mut sps = sps(1, 120, 34);
+        sps.frame_mbs_only_flag = false;
+        sps.frame_cropping_flag = 1;
+        sps.frame_crop_bottom_offset = 2;
+        assert_eq!(sps.frame_height_in_mbs(), 68);
+        assert_eq!(sps.crop_units(), (2, 4));
+        assert_eq!((sps.cropped_width(), sps.cropped_height()), (1920, 1080));
+    }
+
+    #[test]
+    fn frame_rate_fraction_is_reduced() {
+        let mut sps = sps(1, 120, 68);
+        assert_eq!(sps.frame_rate_fraction(), None);
+        sps.set_timing_info(1001, 60000, true);
+        assert_eq!(sps.frame_rate_fraction(), Some((30000, 1001)));
+        sps.set_timing_info(1, 50, true);
+        assert_eq!(sps.frame_rate_fraction(), Some((25, 1)));
+        // 2 * num_units_in_tick doesn't fit in 32 bits.
+        sps.set_timing_info(0x80000001, 0x80000001, false);
+        assert_eq!(sps.frame_rate_fraction(), Some((1, 2)));
+        sps.set_timing_info(0xFFFFFFFF, 1, false);
+        assert_eq!(sps.frame_rate_fraction(), Some((1, 0x1FFFFFFFE)));
+    }
+
+    fn sar(sps: &mut H264NalUnitSPS, aspect_ratio_idc: u8, sar_width: u16, sar_height: u16) -> Option<(u32, u32)> {
+        let vui = sps.vui_mut();
+        vui.aspect_ratio_info_present_flag = 1;
+        vui.aspect_ratio_idc = aspect_ratio_idc;
+        vui.sar_width = sar_width;
+        vui.sar_height = sar_height;
+        sps.sample_aspect_ratio()
+    }
+
+    #[test]
+    fn sample_aspect_ratio_from_table_e1() {
+        let mut sps = sps(1, 44, 36);
+        assert_eq!(sps.sample_aspect_ratio(), None);
+        assert_eq!(sar(&mut sps, 0, 0, 0), None);
+        assert_eq!(sar(&mut sps, 1, 0, 0), Some((1, 1)));
+        assert_eq!(sar(&mut sps, 4, 0, 0), Some((16, 11)));
+        // 704x576 with 16:11 samples is 16:9
+        assert_eq!(sps.display_aspect_ratio(), (16, 9));
+        assert_eq!(sar(&mut sps, 13, 0, 0), Some((160, 99)));
+        assert_eq!(sar(&mut sps, 16, 0, 0), Some((2, 1)));
+        // Reserved values
+        assert_eq!(sar(&mut sps, 17, 0, 0), None);
+        assert_eq!(sar(&mut sps, 254, 4, 3), None);
+        assert_eq!(sps.display_aspect_ratio(), (11, 9));
+
+        // sar_width and sar_height only count with EXTENDED_SAR.
+        assert_eq!(sar(&mut sps, EXTENDED_SAR, 64, 45), Some((64, 45)));
+        assert_eq!(sps.display_aspect_ratio(), (704, 405));
+        assert_eq!(sar(&mut sps, EXTENDED_SAR, 0, 45), None);
+        assert_eq!(sar(&mut sps, EXTENDED_SAR, 65535, 1), Some((65535, 1)));
+
+        sps.vui_mut().aspect_ratio_info_present_flag = 0;
+        assert_eq!(sps.sample_aspect_ratio(), None);
+    }
+
+    #[test]
+    fn sizes_saturate() {
+        let mut huge = sps(1, 1, 1);
+        huge.pic_width_in_mbs_minus1 = u32::MAX;
+        huge.pic_height_in_map_units_minus1 = u32::MAX - 1;
+        huge.frame_mbs_only_flag = false;
+        assert_eq!(huge.frame_width_in_mbs(), u32::MAX);
+        assert_eq!(huge.frame_height_in_mbs(), u32::MAX);
+        assert_eq!(huge.frame_size_in_mbs(), u32::MAX);
+        assert_eq!((huge.width(), huge.height()), (u32::MAX, u32::MAX));
+        huge.frame_cropping_flag = 1;
+        huge.frame_crop_left_offset = u32::MAX;
+        huge.frame_crop_right_offset = 1;
+        huge.frame_crop_bottom_offset = u32::MAX;
+        assert_eq!((huge.cropped_width(), huge.cropped_height()), (0, 0));
+
+        // 65536 x 65536 macroblocks is just over u32
+        let mut wide = sps(1, 65536, 65536);
+        assert_eq!(wide.frame_size_in_mbs(), u32::MAX);
+        wide.pic_height_in_map_units_minus1 = 65534;
+        assert_eq!(wide.frame_size_in_mbs(), 65536 * 65535);
+    }
+
+    #[test]
+    fn bitstream_restriction_gets_the_inferred_values() {
+        let mut sps = sps(1, 120, 68);
+        sps.max_num_ref_frames = 3;
+        sps.set_low_latency();
+        {
+            let vui = sps.vui_parameters.as_ref().unwrap();
+            assert_eq!(sps.vui_parameters_present_flag, 1);
+            assert_eq!(vui.bitstream_restriction_flag, 1);
+            assert_eq!(vui.motion_vectors_over_pic_boundaries_flag, 1);
+            assert_eq!((vui.max_bytes_per_pic_denom, vui.max_bits_per_mb_denom), (2, 1));
+            assert_eq!((vui.log2_max_mv_length_horizontal, vui.log2_max_mv_length_vertical), (16, 16));
+            assert_eq!((vui.max_num_reorder_frames, vui.max_dec_frame_buffering), (0, 3));
+        }
+
+        // An existing restriction is kept apart from the two fields.
+        sps.vui_mut().log2_max_mv_length_vertical = 9;
+        sps.set_bitstream_restriction(2, 4);
+        let vui = sps.vui_parameters.as_ref().unwrap();
+        assert_eq!(vui.log2_max_mv_length_vertical, 9);
+        assert_eq!((vui.max_num_reorder_frames, vui.max_dec_frame_buffering), (2, 4));
+    }
+}
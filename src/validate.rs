@@ -0,0 +1,539 @@
+use parser::H264NalParser;
+use profile::{H264ProfileLevelError, PROFILE_BASELINE, PROFILE_MAIN, PROFILE_EXTENDED, PROFILE_HIGH,
+              PROFILE_HIGH_10, PROFILE_HIGH_422, PROFILE_HIGH_444, PROFILE_CAVLC_444_INTRA};
+pub use types::*;
+
+/// profile_idc values of the SVC and MVC profiles, which are known even
+/// though only the base layer is parsed.
+const PROFILE_IDCS_EXTENSIONS : [u8; 8] = [83, 86, 118, 128, 134, 135, 138, 139];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum H264ValidationSeverity {
+    /// Allowed, but worth knowing about, such as unspecified NAL unit types.
+    Info,
+    /// Something decoders are told to ignore, such as reserved values.
+    Warning,
+    /// The stream doesn't conform and decoders may fail on it.
+    Error
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct H264ValidationFinding {
+    pub severity: H264ValidationSeverity,
+    /// Clause of ITU-T H.264 that is violated, e.g. "7.4.2.1.1".
+    pub clause: &'static str,
+    pub message: String,
+    /// Byte offset of the NAL unit in the stream, at its start code or
+    /// length prefix.
+    pub offset: usize,
+    /// Index of the access unit in the stream.
+    pub access_unit: u64,
+    /// Index of the NAL unit in the stream, None for findings about the
+    /// stream as a whole.
+    pub nal_unit: Option<u64>
+}
+
+#[derive(Debug, Clone)]
+pub struct H264ValidationReport {
+    pub access_units: u64,
+    pub nal_units: u64,
+    /// In the order they were found, which is mostly stream order.
+    pub findings: Vec<H264ValidationFinding>
+}
+
+impl H264ValidationReport {
+    pub fn errors(&self) -> Vec<&H264ValidationFinding> {
+        self.findings.iter().filter(|f| f.severity == H264ValidationSeverity::Error).collect()
+    }
+
+    pub fn warnings(&self) -> Vec<&H264ValidationFinding> {
+        self.findings.iter().filter(|f| f.severity == H264ValidationSeverity::Warning).collect()
+    }
+
+    /// No errors, there may be warnings.
+    pub fn is_valid(&self) -> bool {
+        self.findings.iter().all(|f| f.severity != H264ValidationSeverity::Error)
+    }
+}
+
+/// Where in the stream the current NAL unit is.
+#[derive(Clone, Copy)]
+struct Position {
+    offset: usize,
+    access_unit: u64,
+    nal_unit: Option<u64>
+}
+
+struct Findings {
+    findings: Vec<H264ValidationFinding>
+}
+
+impl Findings {
+    fn add(&mut self, position: Position, severity: H264ValidationSeverity, clause: &'static str, message: String) {
+        self.findings.push(H264ValidationFinding {
+            severity,
+            clause,
+            message,
+            offset: position.offset,
+            access_unit: position.access_unit,
+            nal_unit: position.nal_unit
+        });
+    }
+
+    fn error(&mut self, position: Position, clause: &'static str, message: String) {
+        self.add(position, H264ValidationSeverity::Error, clause, message);
+    }
+
+    fn warning(&mut self, position: Position, clause: &'static str, message: String) {
+        self.add(position, H264ValidationSeverity::Warning, clause, message);
+    }
+
+    /// An error if value is above max.
+    fn check_max(&mut self, position: Position, clause: &'static str, name: &str, value: u32, max: u32) {
+        if value > max {
+            self.error(position, clause, format!("{} is {}, the most allowed is {}", name, value, max));
+        }
+    }
+
+    /// An error if value is outside min..=max.
+    fn check_range(&mut self, position: Position, clause: &'static str, name: &str, value: i32, min: i32, max: i32) {
+        if value < min || value > max {
+            self.error(position, clause, format!("{} is {}, outside of {}..{}", name, value, min, max));
+        }
+    }
+}
+
+/// Walks a stream and reports what doesn't conform to the syntax and
+/// semantics of clause 7 and the profiles of Annex A. Stops at the first
+/// access unit that can't be split off, which is reported as an error.
+pub fn validate(data: &[u8], format: H264NalFormat, nal_length_size: usize) -> H264ValidationReport {
+    // The parser refuses NAL units with forbidden_zero_bit set, clear it
+    // so the rest of them can still be checked.
+    let (data, forbidden_bits) = clear_forbidden_zero_bits(data, format, nal_length_size);
+    let data = &data[..];
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut findings = Findings { findings: Vec::new() };
+    let mut sps_list : Vec<(Position, H264NalUnitSPS)> = Vec::new();
+    let mut pps_list : Vec<H264NalUnitPPS> = Vec::new();
+    // PrevRefFrameNum of 7.4.3, None until the first picture.
+    let mut prev_ref_frame_num : Option<u32> = None;
+    // idr_pic_id if the previous access unit was an IDR access unit.
+    let mut prev_idr_pic_id : Option<u32> = None;
+    let mut access_unit = 0;
+    let mut nal_index = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let au = match parser.parse_access_unit(offset) {
+            Ok(au) => au,
+            Err(e) => {
+                let position = Position { offset, access_unit, nal_unit: None };
+                findings.error(position, "7.3.1", format!("can't split off an access unit: {:?}", e));
+                break;
+            }
+        };
+        if au.size == 0 {
+            break;
+        }
+        offset += au.size;
+        let last_access_unit = offset >= data.len();
+
+        let mut seen_vcl = false;
+        let mut idr_slices = 0;
+        let mut non_idr_slices = 0;
+        let mut first_slice = None;
+        for (i, unit) in au.nal_units.iter().enumerate() {
+            let position = Position { offset: unit.sc_offset, access_unit, nal_unit: Some(nal_index) };
+            nal_index += 1;
+            if forbidden_bits.contains(&unit.data_offset) {
+                findings.error(position, "7.4.1", "forbidden_zero_bit is set".to_string());
+            }
+            check_nal_header(&mut findings, position, parser.nal_data(unit).first().cloned().unwrap_or(0));
+            let is_vcl = unit.nal_unit_type_num >= 1 && unit.nal_unit_type_num <= 5;
+
+            // 7.4.1.2.3
+            match unit.nal_unit_type_num {
+                9 if i != 0 => findings.error(position, "7.4.1.2.3",
+                                              "access unit delimiter isn't the first NAL unit of the access unit".to_string()),
+                6 if seen_vcl => findings.error(position, "7.4.1.2.3",
+                                                "SEI NAL unit after the first VCL NAL unit of the primary coded picture".to_string()),
+                12 if !seen_vcl => findings.error(position, "7.4.1.2.3",
+                                                  "filler data before the first VCL NAL unit of the primary coded picture".to_string()),
+                10 if au.nal_units[i + 1..].iter().any(|n| n.nal_unit_type_num != 11) =>
+                    findings.error(position, "7.4.1.2.3", "end of sequence isn't at the end of the access unit".to_string()),
+                11 if i + 1 != au.nal_units.len() || !last_access_unit =>
+                    findings.error(position, "7.4.1.2.3", "end of stream isn't the last NAL unit of the stream".to_string()),
+                _ => {}
+            }
+            seen_vcl |= is_vcl;
+
+            match unit.nal_unit_type_num {
+                7 => match parser.parse_sps(unit.data_offset) {
+                    Ok(sps) => {
+                        check_sps(&mut findings, position, &sps);
+                        sps_list.retain(|(_, s)| s.seq_parameter_set_id != sps.seq_parameter_set_id);
+                        sps_list.push((position, sps));
+                    },
+                    Err(e) => findings.error(position, "7.3.2.1.1", format!("can't parse the SPS: {:?}", e))
+                },
+                8 => match parser.parse_pps(unit.data_offset) {
+                    Ok(pps) => {
+                        let sps = parser.find_sps(pps.seq_parameter_set_id);
+                        if sps.is_none() {
+                            findings.error(position, "7.4.2.2", format!("PPS {} refers to SPS {}, which hasn't been received",
+                                                                        pps.pic_parameter_set_id, pps.seq_parameter_set_id));
+                        }
+                        check_pps(&mut findings, position, &pps, sps);
+                        pps_list.retain(|p| p.pic_parameter_set_id != pps.pic_parameter_set_id);
+                        pps_list.push(pps);
+                    },
+                    Err(e) => findings.error(position, "7.3.2.2", format!("can't parse the PPS: {:?}", e))
+                },
+                6 => {
+                    if let Err(e) = parser.parse_sei(unit) {
+                        findings.warning(position, "7.3.2.3", format!("can't parse the SEI messages: {:?}", e));
+                    }
+                },
+                1..=5 => {
+                    if unit.idr_pic_flag { idr_slices += 1; } else { non_idr_slices += 1; }
+                    let (_, slice_type, pps_id) = match parser.peek_slice_header(unit) {
+                        Some(header) => header,
+                        None => {
+                            findings.error(position, "7.3.3", "slice header is cut short".to_string());
+                            continue;
+                        }
+                    };
+                    if slice_type > 9 {
+                        findings.error(position, "7.4.3", format!("slice_type {} is reserved", slice_type));
+                        continue;
+                    }
+                    if unit.idr_pic_flag && !slice_type_is_i_slice(slice_type) && !slice_type_is_si_slice(slice_type) {
+                        findings.error(position, "7.4.3", "IDR picture with a slice that isn't I or SI".to_string());
+                    }
+                    if parser.find_pps(pps_id).is_none() {
+                        let message = if pps_list.is_empty() {
+                            "slice before any SPS and PPS".to_string()
+                        } else {
+                            format!("slice refers to PPS {}, which hasn't been received", pps_id)
+                        };
+                        findings.error(position, "7.4.1.2.1", message);
+                        continue;
+                    }
+                    match parser.parse_slice(unit.data_offset, unit) {
+                        Ok(slice) => {
+                            if first_slice.is_none() {
+                                first_slice = Some((position, unit.clone(), slice));
+                            }
+                        },
+                        Err(e) => findings.error(position, "7.3.3", format!("can't parse the slice header: {:?}", e))
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let position = Position {
+            offset: au.nal_units.first().map_or(au.offset, |n| n.sc_offset),
+            access_unit,
+            nal_unit: None
+        };
+        if idr_slices > 0 && non_idr_slices > 0 {
+            findings.error(position, "7.4.1", "picture with both IDR and non-IDR slices".to_string());
+        }
+        if !seen_vcl && !au.nal_units.iter().all(|n| n.nal_unit_type_num == 10 || n.nal_unit_type_num == 11) {
+            findings.warning(position, "7.4.1.2.3", "access unit without a primary coded picture".to_string());
+        }
+        if let Some((position, unit, slice)) = first_slice {
+            check_picture(&mut findings, position, &parser, &unit, &slice,
+                          &mut prev_ref_frame_num, &mut prev_idr_pic_id);
+        }
+        access_unit += 1;
+    }
+
+    // A.2 and the A.3 limits of the SPS, with every PPS that refers to
+    // each SPS.
+    for &(position, ref sps) in &sps_list {
+        match sps.check_profile_level(&sps.profile_level(), &pps_list) {
+            Ok(()) => {},
+            Err(H264ProfileLevelError::UnknownLevel(level_idc)) =>
+                findings.error(position, "A.3", format!("level_idc {} isn't a known level", level_idc)),
+            Err(H264ProfileLevelError::Violation(message)) =>
+                findings.error(position, "A.2", format!("profile_idc {}: {}", sps.profile_idc, message)),
+            Err(H264ProfileLevelError::LevelLimit(limit)) =>
+                findings.error(position, "A.3", format!("level_idc {}: exceeds {:?}", sps.level_idc, limit)),
+            Err(H264ProfileLevelError::Parse(e)) =>
+                findings.error(position, "A.2", format!("can't check the profile: {:?}", e))
+        }
+    }
+
+    H264ValidationReport {
+        access_units: access_unit,
+        nal_units: nal_index,
+        findings: findings.findings
+    }
+}
+
+/// A copy of the stream with forbidden_zero_bit cleared in every NAL unit
+/// header, and the offsets of the headers which had it set.
+fn clear_forbidden_zero_bits(data: &[u8], format: H264NalFormat, nal_length_size: usize) -> (Vec<u8>, Vec<usize>) {
+    let mut data = data.to_vec();
+    let mut headers = Vec::new();
+    let bytestream = match format {
+        H264NalFormat::BYTESTREAM => true,
+        H264NalFormat::AVC => false,
+        H264NalFormat::UNKNOWN => data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1])
+    };
+    if bytestream {
+        let mut i = 0;
+        while i + 3 < data.len() {
+            if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+                headers.push(i + 3);
+                i += 3;
+            } else {
+                i += 1;
+            }
+        }
+    } else {
+        let mut i = 0;
+        while i + nal_length_size < data.len() {
+            let length = data[i..i + nal_length_size].iter().fold(0, |l, &b| (l << 8) | b as usize);
+            if length == 0 {
+                break;
+            }
+            headers.push(i + nal_length_size);
+            i += nal_length_size + length;
+        }
+    }
+    let mut forbidden = Vec::new();
+    for header in headers {
+        if data[header] & 0x80 != 0 {
+            data[header] &= 0x7F;
+            forbidden.push(header);
+        }
+    }
+    (data, forbidden)
+}
+
+/// 7.4.1
+fn check_nal_header(findings: &mut Findings, position: Position, header: u8) {
+    let nal_ref_idc = (header >> 5) & 0x3;
+    let nal_unit_type = header & 0x1F;
+    match nal_unit_type {
+        5 if nal_ref_idc == 0 => findings.error(position, "7.4.1", "IDR slice with nal_ref_idc 0".to_string()),
+        6 | 9 | 10 | 11 | 12 if nal_ref_idc != 0 =>
+            findings.error(position, "7.4.1", format!("nal_ref_idc {} for nal_unit_type {}, it has to be 0", nal_ref_idc, nal_unit_type)),
+        0 | 24..=31 => findings.add(position, H264ValidationSeverity::Info, "7.4.1",
+                                     format!("unspecified nal_unit_type {}", nal_unit_type)),
+        16..=18 | 22 | 23 => findings.warning(position, "7.4.1", format!("reserved nal_unit_type {}", nal_unit_type)),
+        _ => {}
+    }
+}
+
+/// 7.4.2.1.1 and the reserved values of E.2.1.
+fn check_sps(findings: &mut Findings, position: Position, sps: &H264NalUnitSPS) {
+    let clause = "7.4.2.1.1";
+    let known_profile = [PROFILE_BASELINE, PROFILE_MAIN, PROFILE_EXTENDED, PROFILE_HIGH, PROFILE_HIGH_10,
+                         PROFILE_HIGH_422, PROFILE_HIGH_444, PROFILE_CAVLC_444_INTRA].contains(&sps.profile_idc)
+        || PROFILE_IDCS_EXTENSIONS.contains(&sps.profile_idc);
+    if !known_profile {
+        findings.warning(position, "A.2", format!("unknown profile_idc {}", sps.profile_idc));
+    }
+    if sps.reserved_zero_2bits != 0 {
+        findings.warning(position, clause, "reserved_zero_2bits isn't 0".to_string());
+    }
+    findings.check_max(position, clause, "seq_parameter_set_id", sps.seq_parameter_set_id, 31);
+    findings.check_max(position, clause, "chroma_format_idc", sps.chroma_format_idc, 3);
+    findings.check_max(position, clause, "bit_depth_luma_minus8", sps.bit_depth_luma_minus8, 6);
+    findings.check_max(position, clause, "bit_depth_chroma_minus8", sps.bit_depth_chroma_minus8, 6);
+    findings.check_max(position, clause, "log2_max_frame_num_minus4", sps.log2_max_frame_num_minus4, 12);
+    findings.check_max(position, clause, "pic_order_cnt_type", sps.pic_order_cnt_type, 2);
+    if sps.pic_order_cnt_type == 0 {
+        findings.check_max(position, clause, "log2_max_pic_order_cnt_lsb_minus4", sps.log2_max_pic_order_cnt_lsb_minus4, 12);
+    }
+    if sps.pic_order_cnt_type == 1 {
+        findings.check_max(position, clause, "num_ref_frames_in_pic_order_cnt_cycle", sps.num_ref_frames_in_pic_order_cnt_cycle, 255);
+    }
+    findings.check_max(position, clause, "max_num_ref_frames", sps.max_num_ref_frames, 16);
+
+    if let Some(ref vui) = sps.vui_parameters {
+        if vui.aspect_ratio_info_present_flag != 0 && vui.aspect_ratio_idc > 16 && vui.aspect_ratio_idc != 255 {
+            findings.warning(position, "E.2.1", format!("aspect_ratio_idc {} is reserved", vui.aspect_ratio_idc));
+        }
+        if vui.video_signal_type_present_flag != 0 && vui.video_format > 5 {
+            findings.warning(position, "E.2.1", format!("video_format {} is reserved", vui.video_format));
+        }
+        if vui.timing_info_present_flag != 0 && (vui.num_units_in_tick == 0 || vui.time_scale == 0) {
+            findings.error(position, "E.2.1", "num_units_in_tick and time_scale have to be more than 0".to_string());
+        }
+    }
+}
+
+/// 7.4.2.2
+fn check_pps(findings: &mut Findings, position: Position, pps: &H264NalUnitPPS, sps: Option<&H264NalUnitSPS>) {
+    let clause = "7.4.2.2";
+    findings.check_max(position, clause, "pic_parameter_set_id", pps.pic_parameter_set_id, 255);
+    findings.check_max(position, clause, "seq_parameter_set_id", pps.seq_parameter_set_id, 31);
+    findings.check_max(position, clause, "num_slice_groups_minus1", pps.num_slice_groups_minus1, 7);
+    if pps.num_slice_groups_minus1 > 0 {
+        findings.check_max(position, clause, "slice_group_map_type", pps.slice_group_map_type, 6);
+    }
+    findings.check_max(position, clause, "num_ref_idx_l0_default_active_minus1", pps.num_ref_idx_l0_default_active_minus1, 31);
+    findings.check_max(position, clause, "num_ref_idx_l1_default_active_minus1", pps.num_ref_idx_l1_default_active_minus1, 31);
+    if pps.weighted_bipred_idc > 2 {
+        findings.error(position, clause, format!("weighted_bipred_idc {} is reserved", pps.weighted_bipred_idc));
+    }
+    let qp_bd_offset = sps.map_or(0, |s| 6 * s.bit_depth_luma_minus8 as i32);
+    findings.check_range(position, clause, "pic_init_qp_minus26", pps.pic_init_qp_minus26, -(26 + qp_bd_offset), 25);
+    findings.check_range(position, clause, "pic_init_qs_minus26", pps.pic_init_qs_minus26, -26, 25);
+    findings.check_range(position, clause, "chroma_qp_index_offset", pps.chroma_qp_index_offset, -12, 12);
+    findings.check_range(position, clause, "second_chroma_qp_index_offset", pps.second_chroma_qp_index_offset, -12, 12);
+}
+
+/// frame_num and idr_pic_id of 7.4.3, from the first slice of a primary
+/// coded picture.
+fn check_picture(findings: &mut Findings, position: Position, parser: &H264NalParser, unit: &H264NalUnit,
+                 slice: &H264NalUnitSlice, prev_ref_frame_num: &mut Option<u32>, prev_idr_pic_id: &mut Option<u32>) {
+    let sps = match parser.find_pps(slice.pic_parameter_set_id)
+        .and_then(|pps| parser.find_sps(pps.seq_parameter_set_id)) {
+        Some(sps) => sps,
+        None => return
+    };
+    let max_frame_num = 1u32 << (sps.log2_max_frame_num_minus4 + 4);
+    if unit.idr_pic_flag {
+        if slice.frame_num != 0 {
+            findings.error(position, "7.4.3", format!("IDR picture with frame_num {}", slice.frame_num));
+        }
+        if *prev_idr_pic_id == Some(slice.idr_pic_id) {
+            findings.error(position, "7.4.3",
+                           format!("consecutive IDR access units with the same idr_pic_id {}", slice.idr_pic_id));
+        }
+        *prev_idr_pic_id = Some(slice.idr_pic_id);
+    } else {
+        *prev_idr_pic_id = None;
+        if let Some(prev) = *prev_ref_frame_num {
+            let expected = (prev + 1) % max_frame_num;
+            if slice.frame_num != prev && slice.frame_num != expected && sps.gaps_in_frame_num_value_allowed_flag == 0 {
+                findings.error(position, "7.4.3", format!("frame_num gap from {} to {} without gaps_in_frame_num_value_allowed_flag",
+                                                          prev, slice.frame_num));
+            }
+        }
+    }
+    let mmco5 = slice.memory_management_control_operations.iter()
+        .any(|op| op.memory_management_control_operation == 5);
+    if mmco5 {
+        *prev_ref_frame_num = Some(0);
+    } else if unit.nal_ref_idc != 0 || prev_ref_frame_num.is_none() {
+        *prev_ref_frame_num = Some(slice.frame_num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use writer::H264NalWriter;
+
+    fn baseline_sps() -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 66;
+        sps.level_idc = 10;
+        sps.frame_mbs_only_flag = true;
+        sps
+    }
+
+    fn slice_nal(slice: &H264NalUnitSlice, nal_ref_idc: u8, nal_unit_type: u8,
+                 sps: &H264NalUnitSPS, pps: &H264NalUnitPPS) -> Vec<u8> {
+        let unit = H264NalUnit::new(0, 4, 0, nal_ref_idc, nal_unit_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, sps, pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        writer.to_nal(nal_ref_idc, nal_unit_type)
+    }
+
+    fn bytestream(nal_units: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nal_units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    fn has_error(report: &H264ValidationReport, clause: &str, message: &str) -> bool {
+        report.errors().iter().any(|f| f.clause == clause && f.message.contains(message))
+    }
+
+    #[test]
+    fn truncated_sps_is_an_error() {
+        let mut sps = baseline_sps().to_bytes().unwrap();
+        sps.truncate(4);
+        let report = validate(&bytestream(&[sps]), H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.2.1.1", "NotEnoughBytes"));
+    }
+
+    #[test]
+    fn out_of_range_sps_is_an_error() {
+        // frame_num would be read with 33 bits
+        let mut sps = baseline_sps();
+        sps.log2_max_frame_num_minus4 = 29;
+        let report = validate(&bytestream(&[sps.to_bytes().unwrap()]), H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.2.1.1", "OutOfRange"));
+
+        // MaxPicOrderCntLsb wouldn't fit in the POC computation
+        let mut sps = baseline_sps();
+        sps.log2_max_pic_order_cnt_lsb_minus4 = 13;
+        let report = validate(&bytestream(&[sps.to_bytes().unwrap()]), H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.2.1.1", "OutOfRange"));
+    }
+
+    #[test]
+    fn ue_with_32_leading_zeros_is_an_error() {
+        // seq_parameter_set_id has 32 leading zeros, across an emulation
+        // prevention byte.
+        let data = [0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x1e, 0x00, 0x00, 0x03, 0x00, 0x00, 0x80,
+                    0xff, 0xff, 0xff, 0xff, 0x80];
+        let report = validate(&data, H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.2.1.1", "NotEnoughBytes"));
+    }
+
+    #[test]
+    fn truncated_slice_header_is_an_error() {
+        let sps = baseline_sps();
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 7;
+        let mut idr = slice_nal(&slice, 3, 5, &sps, &pps);
+        idr.truncate(3);
+        let report = validate(&bytestream(&[sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), idr]),
+                              H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.3", "NotEnoughBytes"));
+
+        // The header isn't read on into the NAL units after it.
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 7;
+        slice.idr_pic_id = 1;
+        let next_idr = slice_nal(&slice, 3, 5, &sps, &pps);
+        let report = validate(&bytestream(&[sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(),
+                                            vec![0x65, 0x88, 0x84], vec![0x09, 0xF0], next_idr]),
+                              H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.3", "NotEnoughBytes"), "{:?}", report.errors());
+    }
+
+    #[test]
+    fn out_of_range_slice_header_is_an_error() {
+        let sps = baseline_sps();
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 7;
+        let idr = slice_nal(&slice, 3, 5, &sps, &pps);
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 5;
+        slice.frame_num = 1;
+        slice.num_ref_idx_active_override_flag = true;
+        slice.num_ref_idx_l0_active_minus1 = 40;
+        let p = slice_nal(&slice, 2, 1, &sps, &pps);
+        let report = validate(&bytestream(&[sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), idr, p]),
+                              H264NalFormat::BYTESTREAM, 4);
+        assert!(has_error(&report, "7.3.3", "OutOfRange"));
+    }
+}
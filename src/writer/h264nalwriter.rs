@@ -0,0 +1,214 @@
+/// Inserts emulation prevention bytes into an RBSP so that it can be
+/// carried in a NAL unit. A 0x03 goes in after every two zero bytes that
+/// are followed by a byte <= 0x03, which is exactly where the
+/// H264NalReader drops them again.
+pub fn rbsp_to_nal(rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 0x03 {
+            nal.push(0x03);
+            zeros = 0;
+        }
+        zeros = if byte == 0x00 { zeros + 1 } else { 0 };
+        nal.push(byte);
+    }
+    // A NAL unit may not end in a zero byte
+    if zeros > 0 {
+        nal.push(0x03);
+    }
+    nal
+}
+
+/// A value the writer was asked to write but can't.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum H264NalWriteError {
+    /// More bits than the type of the value has.
+    TooManyBits(u32),
+    /// The value doesn't fit in the number of bits.
+    ValueTooLarge(u32),
+    /// An Exp-Golomb value longer than 9.1 allows.
+    OutOfRange
+}
+
+pub struct H264NalWriter {
+    data: Vec<u8>,
+    cache: u8,
+    bits_in_cache: u32,
+}
+
+impl Default for H264NalWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264NalWriter {
+    pub fn new() -> H264NalWriter {
+        H264NalWriter {
+            data: Vec::new(),
+            cache: 0,
+            bits_in_cache: 0,
+        }
+    }
+
+    /// Writes 1 bit.
+    pub fn write_flag(&mut self, flag: bool) {
+        self.put_bits(1, if flag { 1 } else { 0 });
+    }
+
+    /// Writes val in nbits bits.
+    pub fn write_u8(&mut self, nbits: u32, val: u8) -> Result<(), H264NalWriteError> {
+        if nbits > 8 {
+            return Err(H264NalWriteError::TooManyBits(nbits));
+        }
+        self.write_u32(nbits, val as u32)
+    }
+
+    /// Writes val in nbits bits.
+    pub fn write_u16(&mut self, nbits: u32, val: u16) -> Result<(), H264NalWriteError> {
+        if nbits > 16 {
+            return Err(H264NalWriteError::TooManyBits(nbits));
+        }
+        self.write_u32(nbits, val as u32)
+    }
+
+    /// Writes val in nbits bits, most significant bit first.
+    pub fn write_u32(&mut self, nbits: u32, val: u32) -> Result<(), H264NalWriteError> {
+        if nbits > 32 {
+            return Err(H264NalWriteError::TooManyBits(nbits));
+        }
+        if nbits < 32 && val >> nbits != 0 {
+            return Err(H264NalWriteError::ValueTooLarge(val));
+        }
+        self.put_bits(nbits, val);
+        Ok(())
+    }
+
+    /// Writes val as an unsigned Exp-Golomb code. u32::MAX would need 32
+    /// leading zero bits, more than 9.1 allows.
+    pub fn write_ue(&mut self, val: u32) -> Result<(), H264NalWriteError> {
+        if val == u32::MAX {
+            return Err(H264NalWriteError::OutOfRange);
+        }
+        let code_num = val + 1;
+        let leading_zeros = 31 - code_num.leading_zeros();
+        // leading zeros and the 1 bit, then the rest of code_num
+        self.put_bits(leading_zeros, 0);
+        self.put_bits(1, 1);
+        self.put_bits(leading_zeros, code_num & ((1 << leading_zeros) - 1));
+        Ok(())
+    }
+
+    /// Writes val as a signed Exp-Golomb code. i32::MIN has no code.
+    pub fn write_se(&mut self, val: i32) -> Result<(), H264NalWriteError> {
+        if val == i32::MIN {
+            return Err(H264NalWriteError::OutOfRange);
+        }
+        let code_num = if val > 0 {
+            2 * val as i64 - 1
+        } else {
+            -2 * val as i64
+        };
+        self.write_ue(code_num as u32)
+    }
+
+    /// Writes the low nbits of val, nbits is at most 32.
+    fn put_bits(&mut self, nbits: u32, val: u32) {
+        for i in (0..nbits).rev() {
+            self.cache = (self.cache << 1) | ((val >> i) & 0x01) as u8;
+            self.bits_in_cache += 1;
+            if self.bits_in_cache == 8 {
+                self.data.push(self.cache);
+                self.cache = 0;
+                self.bits_in_cache = 0;
+            }
+        }
+    }
+
+    pub fn is_byte_aligned(&self) -> bool {
+        self.bits_in_cache == 0
+    }
+
+    /// Number of bits written so far.
+    pub fn bits_written(&self) -> usize {
+        self.data.len() * 8 + self.bits_in_cache as usize
+    }
+
+    /// Pads with zero bits up to the next byte boundary.
+    pub fn byte_align(&mut self) {
+        while !self.is_byte_aligned() {
+            self.put_bits(1, 0);
+        }
+    }
+
+    /// rbsp_stop_one_bit followed by rbsp_alignment_zero_bits.
+    pub fn write_rbsp_trailing_bits(&mut self) {
+        self.put_bits(1, 1);
+        self.byte_align();
+    }
+
+    /// The RBSP written so far. Any partially written byte is zero padded.
+    pub fn rbsp(&self) -> Vec<u8> {
+        let mut rbsp = self.data.clone();
+        if self.bits_in_cache > 0 {
+            rbsp.push(self.cache << (8 - self.bits_in_cache));
+        }
+        rbsp
+    }
+
+    /// The NAL unit with a header for nal_ref_idc and nal_unit_type and the
+    /// RBSP with emulation prevention bytes. The caller is expected to have
+    /// written the rbsp_trailing_bits.
+    pub fn to_nal(&self, nal_ref_idc: u8, nal_unit_type: u8) -> Vec<u8> {
+        let mut nal = vec![((nal_ref_idc & 0x03) << 5) | (nal_unit_type & 0x1F)];
+        nal.extend(rbsp_to_nal(&self.rbsp()));
+        nal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_that_do_not_fit_are_errors() {
+        let mut writer = H264NalWriter::new();
+        assert_eq!(writer.write_u8(9, 0), Err(H264NalWriteError::TooManyBits(9)));
+        assert_eq!(writer.write_u16(17, 0), Err(H264NalWriteError::TooManyBits(17)));
+        assert_eq!(writer.write_u32(33, 0), Err(H264NalWriteError::TooManyBits(33)));
+        assert_eq!(writer.write_u8(2, 4), Err(H264NalWriteError::ValueTooLarge(4)));
+        assert_eq!(writer.write_u32(31, 0x80000000), Err(H264NalWriteError::ValueTooLarge(0x80000000)));
+        assert_eq!(writer.write_ue(u32::MAX), Err(H264NalWriteError::OutOfRange));
+        assert_eq!(writer.write_se(i32::MIN), Err(H264NalWriteError::OutOfRange));
+        // nothing was written by the failed calls
+        assert_eq!(writer.bits_written(), 0);
+
+        assert_eq!(writer.write_u32(32, 0xFFFFFFFF), Ok(()));
+        assert_eq!(writer.rbsp(), vec![0xFF; 4]);
+    }
+
+    #[test]
+    fn exp_golomb_limits() {
+        // 31 leading zeros, a 1 and 31 bits of ones for code_num 2^32 - 1
+        let mut writer = H264NalWriter::new();
+        writer.write_ue(u32::MAX - 1).unwrap();
+        assert_eq!(writer.bits_written(), 63);
+        assert_eq!(writer.rbsp(), vec![0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFE]);
+
+        // se(i32::MAX) is code_num 2^32 - 3, se(i32::MIN + 1) is 2^32 - 2
+        let mut writer = H264NalWriter::new();
+        writer.write_se(i32::MAX).unwrap();
+        assert_eq!(writer.rbsp(), vec![0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFC]);
+        let mut writer = H264NalWriter::new();
+        writer.write_se(i32::MIN + 1).unwrap();
+        assert_eq!(writer.rbsp(), vec![0x00, 0x00, 0x00, 0x01, 0xFF, 0xFF, 0xFF, 0xFE]);
+
+        let mut writer = H264NalWriter::new();
+        for val in 0..4 {
+            writer.write_ue(val).unwrap();
+        }
+        // 1 010 011 00100
+        assert_eq!(writer.rbsp(), vec![0xA6, 0x40]);
+    }
+}
@@ -0,0 +1,1566 @@
+use std::cmp;
+use parser::{H264NalParser, H264NalParseError};
+pub use types::*;
+mod h264nalwriter;
+pub use self::h264nalwriter::{H264NalWriter, H264NalWriteError, rbsp_to_nal};
+
+pub const SEI_USER_DATA_REGISTERED_ITU_T_T35 : u32 = 4;
+pub const SEI_USER_DATA_UNREGISTERED : u32 = 5;
+
+const START_CODE : [u8; 4] = [0, 0, 0, 1];
+
+/// Builds an SEI NAL unit out of one or more SEI messages.
+pub struct H264SEIBuilder {
+    rbsp: Vec<u8>
+}
+
+impl Default for H264SEIBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl H264SEIBuilder {
+    pub fn new() -> H264SEIBuilder {
+        H264SEIBuilder {
+            rbsp: Vec::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rbsp.is_empty()
+    }
+
+    /// Appends an sei_message() with an already serialized payload.
+    pub fn add_message(&mut self, payload_type: u32, payload: &[u8]) -> &mut H264SEIBuilder {
+        write_sei_value(&mut self.rbsp, payload_type);
+        write_sei_value(&mut self.rbsp, payload.len() as u32);
+        self.rbsp.extend_from_slice(payload);
+        self
+    }
+
+    /// user_data_unregistered(), identified by a 16 byte UUID.
+    pub fn add_user_data_unregistered(&mut self, uuid: &[u8; 16], data: &[u8]) -> &mut H264SEIBuilder {
+        let mut payload = uuid.to_vec();
+        payload.extend_from_slice(data);
+        self.add_message(SEI_USER_DATA_UNREGISTERED, &payload)
+    }
+
+    /// user_data_registered_itu_t_t35(). The data starts right after the
+    /// itu_t_t35_country_code byte.
+    pub fn add_user_data_registered_itu_t_t35(&mut self, country_code: u8, data: &[u8]) -> &mut H264SEIBuilder {
+        let mut payload = vec![country_code];
+        payload.extend_from_slice(data);
+        self.add_message(SEI_USER_DATA_REGISTERED_ITU_T_T35, &payload)
+    }
+
+    pub fn add_mastering_display_colour_volume(&mut self, mdcv: &H264MasteringDisplayColourVolume)
+                                               -> &mut H264SEIBuilder {
+        self.add_message(SEI_MASTERING_DISPLAY_COLOUR_VOLUME, &mdcv.payload())
+    }
+
+    pub fn add_content_light_level_info(&mut self, clli: &H264ContentLightLevelInfo) -> &mut H264SEIBuilder {
+        self.add_message(SEI_CONTENT_LIGHT_LEVEL_INFO, &clli.payload())
+    }
+
+    /// CEA-708 caption data wrapped in the ATSC A/53 GA94 structure. Each
+    /// entry of cc_data is a (cc_valid/cc_type, cc_data_1, cc_data_2) triple.
+    pub fn add_cea708_captions(&mut self, cc_data: &[[u8; 3]]) -> &mut H264SEIBuilder {
+        let cc_count = cmp::min(cc_data.len(), 31);
+        let mut data = vec![0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+        // reserved, process_cc_data_flag, zero_bit, cc_count
+        data.push(0x40 | (cc_count as u8 & 0x1F));
+        data.push(0xFF); // em_data
+        for cc in &cc_data[..cc_count] {
+            data.push(0xF8 | cc[0]);
+            data.push(cc[1]);
+            data.push(cc[2]);
+        }
+        data.push(0xFF); // marker_bits
+        self.add_user_data_registered_itu_t_t35(0xB5, &data)
+    }
+
+    /// The SEI NAL unit without start code or length prefix: the NAL header,
+    /// the messages, rbsp_trailing_bits and emulation prevention bytes.
+    pub fn build(&self) -> Vec<u8> {
+        let mut rbsp = self.rbsp.clone();
+        rbsp.push(0x80);
+        let mut nal = vec![0x06];
+        nal.extend(rbsp_to_nal(&rbsp));
+        nal
+    }
+}
+
+fn write_sei_value(rbsp: &mut Vec<u8>, mut value: u32) {
+    while value >= 0xFF {
+        rbsp.push(0xFF);
+        value -= 0xFF;
+    }
+    rbsp.push(value as u8);
+}
+
+/// Appends a NAL unit to a stream in the given format, with a 4 byte start
+/// code for byte streams or a nal_length_size byte length for AVC.
+pub fn write_nalunit(out: &mut Vec<u8>, format: H264NalFormat, nal_length_size: usize, nal: &[u8]) {
+    match format {
+        H264NalFormat::AVC => {
+            for i in (0..nal_length_size).rev() {
+                out.push((nal.len() >> (8 * i)) as u8);
+            }
+        },
+        _ => out.extend_from_slice(&START_CODE)
+    }
+    out.extend_from_slice(nal);
+}
+
+/// Rewrites a stream, inserting an SEI NAL unit into every access unit for
+/// which make_sei returns one. make_sei is called with the index of the
+/// access unit. The SEI goes after any AUD, SPS, PPS and existing SEI NAL
+/// units and before the first VCL NAL unit of the access unit.
+pub fn insert_sei<F>(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                     mut make_sei: F) -> Result<Vec<u8>, H264NalParseError>
+    where F: FnMut(usize) -> Option<Vec<u8>>
+{
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut out = Vec::with_capacity(data.len());
+    let mut offset = 0;
+    let mut index = 0;
+    while offset < data.len() {
+        let au = parser.parse_access_unit(offset)?;
+        let format = parser.format;
+        let sei = make_sei(index);
+        let insert_at = au.nal_units.iter()
+            .position(|n| (n.nal_unit_type_num >= 1 && n.nal_unit_type_num <= 5) || n.nal_unit_type_num == 14)
+            .unwrap_or(au.nal_units.len());
+        for (i, unit) in au.nal_units.iter().enumerate() {
+            if i == insert_at {
+                if let Some(ref nal) = sei {
+                    write_nalunit(&mut out, format, nal_length_size, nal);
+                }
+            }
+            out.extend_from_slice(&data[unit.sc_offset..unit.sc_offset + unit.size]);
+        }
+        if insert_at == au.nal_units.len() {
+            if let Some(ref nal) = sei {
+                write_nalunit(&mut out, format, nal_length_size, nal);
+            }
+        }
+        offset += au.size;
+        index += 1;
+    }
+    Ok(out)
+}
+
+/// Rewrites every SPS of a stream after passing it to edit, copying all
+/// other NAL units as they are. Only edit fields which slices don't depend
+/// on, such as the VUI, or the slices will no longer parse.
+pub fn rewrite_sps<F>(data: &[u8], format: H264NalFormat, nal_length_size: usize,
+                      mut edit: F) -> Result<Vec<u8>, H264NalParseError>
+    where F: FnMut(&mut H264NalUnitSPS)
+{
+    let mut parser = H264NalParser::from_bytes(data.to_vec());
+    parser.format = format;
+    parser.nal_length_size = nal_length_size;
+    let mut out = Vec::with_capacity(data.len() + 64);
+    let mut offset = 0;
+    while offset < data.len() {
+        let unit = parser.parse_nalunit(offset)?;
+        if unit.nal_unit_type == H264NalUnitType::SPS {
+            let mut sps = parser.parse_sps(unit.data_offset)?;
+            edit(&mut sps);
+            let mut writer = H264NalWriter::new();
+            sps.write(&mut writer)?;
+            let nal = writer.to_nal(unit.nal_ref_idc, unit.nal_unit_type_num);
+            if parser.format == H264NalFormat::AVC {
+                write_nalunit(&mut out, H264NalFormat::AVC, nal_length_size, &nal);
+            } else {
+                // keep the original start code
+                out.extend_from_slice(&data[unit.sc_offset..unit.data_offset]);
+                out.extend_from_slice(&nal);
+            }
+        } else {
+            out.extend_from_slice(&data[unit.sc_offset..unit.sc_offset + unit.size]);
+        }
+        offset += unit.size;
+    }
+    Ok(out)
+}
+
+impl H264NalUnitSPS {
+    /// Writes seq_parameter_set_rbsp(), including the rbsp_trailing_bits.
+    pub fn write(&self, writer: &mut H264NalWriter) -> Result<(), H264NalWriteError> {
+        writer.write_u8(8, self.profile_idc)?;
+        writer.write_u8(1, self.constraint_0_flag)?;
+        writer.write_u8(1, self.constraint_1_flag)?;
+        writer.write_u8(1, self.constraint_2_flag)?;
+        writer.write_u8(1, self.constraint_3_flag)?;
+        writer.write_u8(1, self.constraint_4_flag)?;
+        writer.write_u8(1, self.constraint_5_flag)?;
+        writer.write_u8(2, self.reserved_zero_2bits)?;
+        writer.write_u8(8, self.level_idc)?;
+        writer.write_ue(self.seq_parameter_set_id)?;
+
+        if profile_idc_has_chroma_info(self.profile_idc) {
+            writer.write_ue(self.chroma_format_idc)?;
+            if self.chroma_format_idc == 3 {
+                writer.write_flag(self.separate_colour_plane_flag);
+            }
+            writer.write_ue(self.bit_depth_luma_minus8)?;
+            writer.write_ue(self.bit_depth_chroma_minus8)?;
+            writer.write_u8(1, self.qpprime_y_zero_transform_bypass_flag)?;
+            writer.write_u8(1, self.seq_scaling_matrix_present_flag)?;
+            if self.seq_scaling_matrix_present_flag == 1 {
+                let scaling_lists = if self.chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..scaling_lists {
+                    let present = self.seq_scaling_list_present_flag.get(i).cloned().unwrap_or(0);
+                    writer.write_u8(1, present)?;
+                    if present == 1 {
+                        let list = if i < 6 { &self.scaling_list_4x4[i] } else { &self.scaling_list_8x8[i - 6] };
+                        write_scaling_list(writer, list, self.use_default_scaling_matrix_flag[i],
+                                           &self.seq_scaling_list_delta_scale[i])?;
+                    }
+                }
+            }
+        }
+
+        writer.write_ue(self.log2_max_frame_num_minus4)?;
+        writer.write_ue(self.pic_order_cnt_type)?;
+        if self.pic_order_cnt_type == 0 {
+            writer.write_ue(self.log2_max_pic_order_cnt_lsb_minus4)?;
+        } else if self.pic_order_cnt_type == 1 {
+            writer.write_flag(self.delta_pic_order_always_zero_flag);
+            writer.write_se(self.offset_for_non_ref_pic)?;
+            writer.write_se(self.offset_for_top_to_bottom_field)?;
+            writer.write_ue(self.num_ref_frames_in_pic_order_cnt_cycle)?;
+            for i in 0..self.num_ref_frames_in_pic_order_cnt_cycle as usize {
+                writer.write_se(self.offset_for_ref_frame.get(i).cloned().unwrap_or(0))?;
+            }
+        }
+        writer.write_ue(self.max_num_ref_frames)?;
+        writer.write_u8(1, self.gaps_in_frame_num_value_allowed_flag)?;
+        writer.write_ue(self.pic_width_in_mbs_minus1)?;
+        writer.write_ue(self.pic_height_in_map_units_minus1)?;
+        writer.write_flag(self.frame_mbs_only_flag);
+        if !self.frame_mbs_only_flag {
+            writer.write_u8(1, self.mb_adaptive_frame_field_flag)?;
+        }
+        writer.write_u8(1, self.direct_8x8_inference_flag)?;
+        writer.write_u8(1, self.frame_cropping_flag)?;
+        if self.frame_cropping_flag == 1 {
+            writer.write_ue(self.frame_crop_left_offset)?;
+            writer.write_ue(self.frame_crop_right_offset)?;
+            writer.write_ue(self.frame_crop_top_offset)?;
+            writer.write_ue(self.frame_crop_bottom_offset)?;
+        }
+        match self.vui_parameters {
+            Some(ref vui) if self.vui_parameters_present_flag == 1 => {
+                writer.write_flag(true);
+                vui.write(writer)?;
+            },
+            _ => writer.write_flag(false)
+        }
+        writer.write_rbsp_trailing_bits();
+        Ok(())
+    }
+
+    /// The SPS as a NAL unit with nal_ref_idc 3, without start code.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, H264NalWriteError> {
+        let mut writer = H264NalWriter::new();
+        self.write(&mut writer)?;
+        Ok(writer.to_nal(3, 7))
+    }
+}
+
+impl H264VUIParameters {
+    /// Writes vui_parameters().
+    pub fn write(&self, writer: &mut H264NalWriter) -> Result<(), H264NalWriteError> {
+        writer.write_u8(1, self.aspect_ratio_info_present_flag)?;
+        if self.aspect_ratio_info_present_flag == 1 {
+            writer.write_u8(8, self.aspect_ratio_idc)?;
+            if self.aspect_ratio_idc == EXTENDED_SAR {
+                writer.write_u16(16, self.sar_width)?;
+                writer.write_u16(16, self.sar_height)?;
+            }
+        }
+        writer.write_u8(1, self.overscan_info_present_flag)?;
+        if self.overscan_info_present_flag == 1 {
+            writer.write_u8(1, self.overscan_appropriate_flag)?;
+        }
+        writer.write_u8(1, self.video_signal_type_present_flag)?;
+        if self.video_signal_type_present_flag == 1 {
+            writer.write_u8(3, self.video_format)?;
+            writer.write_u8(1, self.video_full_range_flag)?;
+            writer.write_u8(1, self.colour_description_present_flag)?;
+            if self.colour_description_present_flag == 1 {
+                writer.write_u8(8, self.colour_primaries)?;
+                writer.write_u8(8, self.transfer_characteristics)?;
+                writer.write_u8(8, self.matrix_coefficients)?;
+            }
+        }
+        writer.write_u8(1, self.chroma_loc_info_present_flag)?;
+        if self.chroma_loc_info_present_flag == 1 {
+            writer.write_ue(self.chroma_sample_loc_type_top_field)?;
+            writer.write_ue(self.chroma_sample_loc_type_bottom_field)?;
+        }
+        writer.write_u8(1, self.timing_info_present_flag)?;
+        if self.timing_info_present_flag == 1 {
+            writer.write_u32(32, self.num_units_in_tick)?;
+            writer.write_u32(32, self.time_scale)?;
+            writer.write_u8(1, self.fixed_frame_rate_flag)?;
+        }
+        let nal_hrd = write_hrd_parameters(writer, self.nal_hrd_parameters_present_flag, &self.nal_hrd_parameters)?;
+        let vcl_hrd = write_hrd_parameters(writer, self.vcl_hrd_parameters_present_flag, &self.vcl_hrd_parameters)?;
+        if nal_hrd || vcl_hrd {
+            writer.write_u8(1, self.low_delay_hrd_flag)?;
+        }
+        writer.write_u8(1, self.pic_struct_present_flag)?;
+        writer.write_u8(1, self.bitstream_restriction_flag)?;
+        if self.bitstream_restriction_flag == 1 {
+            writer.write_u8(1, self.motion_vectors_over_pic_boundaries_flag)?;
+            writer.write_ue(self.max_bytes_per_pic_denom)?;
+            writer.write_ue(self.max_bits_per_mb_denom)?;
+            writer.write_ue(self.log2_max_mv_length_horizontal)?;
+            writer.write_ue(self.log2_max_mv_length_vertical)?;
+            writer.write_ue(self.max_num_reorder_frames)?;
+            writer.write_ue(self.max_dec_frame_buffering)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the present flag and, when set, the HRD parameters. Returns
+/// whether they were written.
+fn write_hrd_parameters(writer: &mut H264NalWriter, present_flag: u8,
+                        params: &Option<H264HDRParameters>) -> Result<bool, H264NalWriteError> {
+    match *params {
+        Some(ref hrd) if present_flag == 1 => {
+            writer.write_flag(true);
+            hrd.write(writer)?;
+            Ok(true)
+        },
+        _ => {
+            writer.write_flag(false);
+            Ok(false)
+        }
+    }
+}
+
+impl H264HDRParameters {
+    /// Writes hrd_parameters().
+    pub fn write(&self, writer: &mut H264NalWriter) -> Result<(), H264NalWriteError> {
+        writer.write_ue(self.cpb_cnt_minus1)?;
+        writer.write_u8(4, self.bit_rate_scale)?;
+        writer.write_u8(4, self.cpb_size_scale)?;
+        for i in 0..self.cpb_cnt_minus1 as usize + 1 {
+            writer.write_ue(self.bit_rate_value_minus1.get(i).cloned().unwrap_or(0))?;
+            writer.write_ue(self.cpb_size_value_minus1.get(i).cloned().unwrap_or(0))?;
+            writer.write_u8(1, self.cbr_flag.get(i).cloned().unwrap_or(0))?;
+        }
+        writer.write_u8(5, self.initial_cpb_removal_delay_length_minus1)?;
+        writer.write_u8(5, self.cpb_removal_delay_length_minus1)?;
+        writer.write_u8(5, self.dpb_output_delay_length_minus1)?;
+        writer.write_u8(5, self.time_offset_length)?;
+        Ok(())
+    }
+}
+
+impl H264NalUnitPPS {
+    /// Writes pic_parameter_set_rbsp(), including the rbsp_trailing_bits.
+    /// chroma_format_idc of the referenced SPS decides how many scaling
+    /// lists there are.
+    pub fn write(&self, writer: &mut H264NalWriter, chroma_format_idc: u32) -> Result<(), H264NalWriteError> {
+        writer.write_ue(self.pic_parameter_set_id)?;
+        writer.write_ue(self.seq_parameter_set_id)?;
+        writer.write_flag(self.entropy_coding_mode_flag);
+        writer.write_flag(self.bottom_field_pic_order_in_frame_present_flag);
+        writer.write_ue(self.num_slice_groups_minus1)?;
+        if self.num_slice_groups_minus1 > 0 {
+            writer.write_ue(self.slice_group_map_type)?;
+            if self.slice_group_map_type == 0 {
+                for i in 0..self.num_slice_groups_minus1 as usize + 1 {
+                    writer.write_ue(self.run_length_minus1[i])?;
+                }
+            } else if self.slice_group_map_type == 2 {
+                for i in 0..self.num_slice_groups_minus1 as usize {
+                    writer.write_ue(self.top_left[i])?;
+                    writer.write_ue(self.bottom_right[i])?;
+                }
+            } else if self.slice_group_map_type == 3 ||
+                        self.slice_group_map_type == 4 ||
+                        self.slice_group_map_type == 5 {
+                writer.write_u8(1, self.slice_group_change_direction_flag)?;
+                writer.write_ue(self.slice_group_change_rate_minus1)?;
+            } else if self.slice_group_map_type == 6 {
+                writer.write_ue(self.pic_size_in_map_units_minus1)?;
+                let nbits = slice_group_id_bits(self.num_slice_groups_minus1);
+                for i in 0..self.pic_size_in_map_units_minus1 as usize + 1 {
+                    writer.write_u32(nbits, self.slice_group_id[i])?;
+                }
+            }
+        }
+        writer.write_ue(self.num_ref_idx_l0_default_active_minus1)?;
+        writer.write_ue(self.num_ref_idx_l1_default_active_minus1)?;
+        writer.write_flag(self.weighted_pred_flag);
+        writer.write_u8(2, self.weighted_bipred_idc)?;
+        writer.write_se(self.pic_init_qp_minus26)?;
+        writer.write_se(self.pic_init_qs_minus26)?;
+        writer.write_se(self.chroma_qp_index_offset)?;
+        writer.write_flag(self.deblocking_filter_control_present_flag);
+        writer.write_u8(1, self.constrained_intra_pred_flag)?;
+        writer.write_flag(self.redundant_pic_cnt_present_flag);
+        if self.more_rbsp_data {
+            writer.write_u8(1, self.transform_8x8_mode_flag)?;
+            writer.write_u8(1, self.pic_scaling_matrix_present_flag)?;
+            if self.pic_scaling_matrix_present_flag == 1 {
+                let scaling_lists = 6 + (if chroma_format_idc != 3 { 2 } else { 6 }) *
+                    self.transform_8x8_mode_flag as usize;
+                for i in 0..scaling_lists {
+                    let present = self.pic_scaling_list_present_flag.get(i).cloned().unwrap_or(0);
+                    writer.write_u8(1, present)?;
+                    if present == 1 {
+                        let list = if i < 6 { &self.scaling_list_4x4[i] } else { &self.scaling_list_8x8[i - 6] };
+                        write_scaling_list(writer, list, self.use_default_scaling_matrix_flag[i],
+                                           &self.pic_scaling_list_delta_scale[i])?;
+                    }
+                }
+            }
+            writer.write_se(self.second_chroma_qp_index_offset)?;
+        }
+        writer.write_rbsp_trailing_bits();
+        Ok(())
+    }
+
+    /// The PPS as a NAL unit with nal_ref_idc 3, without start code.
+    pub fn to_bytes(&self, chroma_format_idc: u32) -> Result<Vec<u8>, H264NalWriteError> {
+        let mut writer = H264NalWriter::new();
+        self.write(&mut writer, chroma_format_idc)?;
+        Ok(writer.to_nal(3, 8))
+    }
+}
+
+impl H264NalUnitSlice {
+    /// Writes slice_header() for the NAL unit the slice was parsed from. The
+    /// header is not byte aligned, slice_data() follows it directly.
+    pub fn write(&self, writer: &mut H264NalWriter, nalu: &H264NalUnit,
+                 sps: &H264NalUnitSPS, pps: &H264NalUnitPPS) -> Result<(), H264NalWriteError> {
+        writer.write_ue(self.first_mb_in_slice)?;
+        writer.write_ue(self.slice_type)?;
+        writer.write_ue(self.pic_parameter_set_id)?;
+        if sps.separate_colour_plane_flag {
+            writer.write_u8(2, self.colour_plane_id)?;
+        }
+        writer.write_u32(sps.log2_max_frame_num_minus4 + 4, self.frame_num)?;
+        if !sps.frame_mbs_only_flag {
+            writer.write_flag(self.field_pic_flag);
+            if self.field_pic_flag {
+                writer.write_flag(self.bottom_field_flag);
+            }
+        }
+        if nalu.idr_pic_flag {
+            writer.write_ue(self.idr_pic_id)?;
+        }
+        if sps.pic_order_cnt_type == 0 {
+            writer.write_u16(sps.log2_max_pic_order_cnt_lsb_minus4 + 4, self.pic_order_cnt_lsb)?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !self.field_pic_flag {
+                writer.write_se(self.delta_pic_order_cnt_bottom)?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            writer.write_se(self.delta_pic_order_cnt[0])?;
+            if pps.bottom_field_pic_order_in_frame_present_flag && self.field_pic_flag {
+                writer.write_se(self.delta_pic_order_cnt[1])?;
+            }
+        }
+        if pps.redundant_pic_cnt_present_flag {
+            writer.write_ue(self.redundant_pic_cnt)?;
+        }
+        if slice_type_is_b_slice(self.slice_type) {
+            writer.write_flag(self.direct_spatial_mv_pred_flag);
+        }
+        if slice_type_is_p_slice(self.slice_type) || slice_type_is_b_slice(self.slice_type)
+            || slice_type_is_sp_slice(self.slice_type) {
+            writer.write_flag(self.num_ref_idx_active_override_flag);
+            if self.num_ref_idx_active_override_flag {
+                writer.write_ue(self.num_ref_idx_l0_active_minus1)?;
+                if slice_type_is_b_slice(self.slice_type) {
+                    writer.write_ue(self.num_ref_idx_l1_active_minus1)?;
+                }
+            }
+        }
+
+        if !slice_type_is_i_slice(self.slice_type) && !slice_type_is_si_slice(self.slice_type) {
+            writer.write_flag(self.ref_pic_list_modification_flag_l0);
+            if self.ref_pic_list_modification_flag_l0 {
+                write_ref_pic_list_modification(writer, &self.ref_pic_list_modification_l0)?;
+            }
+        }
+        if slice_type_is_b_slice(self.slice_type) {
+            writer.write_flag(self.ref_pic_list_modification_flag_l1);
+            if self.ref_pic_list_modification_flag_l1 {
+                write_ref_pic_list_modification(writer, &self.ref_pic_list_modification_l1)?;
+            }
+        }
+
+        if pps.weighted_pred_flag && (slice_type_is_p_slice(self.slice_type) || slice_type_is_sp_slice(self.slice_type)) ||
+            (pps.weighted_bipred_idc == 1 && slice_type_is_b_slice(self.slice_type)) {
+            let empty = H264PredWeightTable::new();
+            let table = self.pred_weight_table.as_ref().unwrap_or(&empty);
+            write_pred_weight_table(writer, table, self, sps)?;
+        }
+        if nalu.nal_ref_idc != 0 {
+            if nalu.idr_pic_flag {
+                writer.write_flag(self.no_output_of_prior_pics_flag);
+                writer.write_flag(self.long_term_reference_flag);
+            } else {
+                writer.write_flag(self.adaptive_ref_pic_marking_mode_flag);
+                if self.adaptive_ref_pic_marking_mode_flag {
+                    for op in &self.memory_management_control_operations {
+                        let mem_op = op.memory_management_control_operation;
+                        writer.write_ue(mem_op)?;
+                        if mem_op == 1 || mem_op == 3 {
+                            writer.write_ue(op.difference_of_pic_nums_minus1)?;
+                        }
+                        if mem_op == 2 {
+                            writer.write_ue(op.long_term_pic_num)?;
+                        }
+                        if mem_op == 3 || mem_op == 6 {
+                            writer.write_ue(op.long_term_frame_idx)?;
+                        }
+                        if mem_op == 4 {
+                            writer.write_ue(op.max_long_term_frame_idx_plus1)?;
+                        }
+                    }
+                    writer.write_ue(0)?;
+                }
+            }
+        }
+
+        if pps.entropy_coding_mode_flag && !slice_type_is_i_slice(self.slice_type) && !slice_type_is_si_slice(self.slice_type) {
+            writer.write_ue(self.cabac_init_idc)?;
+        }
+        writer.write_se(self.slice_qp_delta)?;
+        if slice_type_is_sp_slice(self.slice_type) || slice_type_is_si_slice(self.slice_type) {
+            if slice_type_is_sp_slice(self.slice_type) {
+                writer.write_flag(self.sp_for_switch_flag);
+            }
+            writer.write_se(self.slice_qs_delta)?;
+        }
+        if pps.deblocking_filter_control_present_flag {
+            writer.write_ue(self.disable_deblocking_filter_idc)?;
+            if self.disable_deblocking_filter_idc != 1 {
+                writer.write_se(self.slice_alpha_c0_offset_div2)?;
+                writer.write_se(self.slice_beta_offset_div2)?;
+            }
+        }
+        if pps.num_slice_groups_minus1 > 0 && pps.slice_group_map_type >= 3 &&
+            pps.slice_group_map_type <= 5 {
+            writer.write_u32(slice_group_change_cycle_bits(sps, pps), self.slice_group_change_cycle)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_ref_pic_list_modification(writer: &mut H264NalWriter, modifications: &[H264RefPicListModification])
+                                   -> Result<(), H264NalWriteError> {
+    for m in modifications {
+        writer.write_ue(m.modification_of_pic_nums_idc)?;
+        writer.write_ue(m.value)?;
+    }
+    writer.write_ue(3)
+}
+
+fn write_pred_weight_table(writer: &mut H264NalWriter, table: &H264PredWeightTable,
+                           slice: &H264NalUnitSlice, sps: &H264NalUnitSPS) -> Result<(), H264NalWriteError> {
+    let chroma = sps.chroma_array_type() != 0;
+    writer.write_ue(table.luma_log2_weight_denom)?;
+    if chroma {
+        writer.write_ue(table.chroma_log2_weight_denom)?;
+    }
+    let lists = if slice_type_is_b_slice(slice.slice_type) { 2 } else { 1 };
+    for list in 0..lists {
+        let (count, luma_flag, luma_weight, luma_offset, chroma_flag, chroma_weight, chroma_offset) = if list == 0 {
+            (slice.num_ref_idx_l0_active_minus1 + 1, &table.luma_weight_l0_flag, &table.luma_weight_l0,
+             &table.luma_offset_l0, &table.chroma_weight_l0_flag, &table.chroma_weight_l0, &table.chroma_offset_l0)
+        } else {
+            (slice.num_ref_idx_l1_active_minus1 + 1, &table.luma_weight_l1_flag, &table.luma_weight_l1,
+             &table.luma_offset_l1, &table.chroma_weight_l1_flag, &table.chroma_weight_l1, &table.chroma_offset_l1)
+        };
+        for i in 0..count as usize {
+            let flag = luma_flag.get(i).cloned().unwrap_or(false);
+            writer.write_flag(flag);
+            if flag {
+                writer.write_se(luma_weight[i])?;
+                writer.write_se(luma_offset[i])?;
+            }
+            if chroma {
+                let flag = chroma_flag.get(i).cloned().unwrap_or(false);
+                writer.write_flag(flag);
+                if flag {
+                    for j in 0..2 {
+                        writer.write_se(chroma_weight[i][j])?;
+                        writer.write_se(chroma_offset[i][j])?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes scaling_list(). The coded delta_scale values are reused when they
+/// still describe the list so that unmodified parameter sets are written
+/// back bit for bit, otherwise the list is coded from scratch.
+fn write_scaling_list(writer: &mut H264NalWriter, list: &[u8], use_default: bool,
+                      delta_scale: &[i32]) -> Result<(), H264NalWriteError> {
+    if decode_scaling_list(delta_scale, list.len()) == Some((list.to_vec(), use_default)) {
+        for &delta in delta_scale {
+            writer.write_se(delta)?;
+        }
+        return Ok(());
+    }
+    if use_default {
+        // nextScale becomes 0 at j == 0
+        return writer.write_se(-8);
+    }
+    // Trailing values equal to the one before them can be left out by
+    // setting nextScale to 0.
+    let mut run = list.len();
+    while run > 1 && list[run - 1] == list[run - 2] {
+        run -= 1;
+    }
+    let mut last_scale = 8i32;
+    for &value in &list[..run] {
+        writer.write_se(wrap_delta_scale(value as i32 - last_scale))?;
+        last_scale = value as i32;
+    }
+    if run < list.len() {
+        writer.write_se(wrap_delta_scale(-last_scale))?;
+    }
+    Ok(())
+}
+
+/// delta_scale is coded in the range -128..127 and applied modulo 256.
+fn wrap_delta_scale(delta: i32) -> i32 {
+    (delta + 128).rem_euclid(256) - 128
+}
+
+/// The scaling list and useDefaultScalingMatrixFlag that delta_scale
+/// describes, or None if it is not a complete coding of a list.
+fn decode_scaling_list(delta_scale: &[i32], size: usize) -> Option<(Vec<u8>, bool)> {
+    let mut list = vec![0u8; size];
+    let mut deltas = delta_scale.iter();
+    let mut use_default = false;
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for (j, entry) in list.iter_mut().enumerate() {
+        if next_scale != 0 {
+            let delta = *deltas.next()?;
+            next_scale = (last_scale + delta + 256) % 256;
+            use_default = j == 0 && next_scale == 0;
+        }
+        *entry = (if next_scale == 0 { last_scale } else { next_scale }) as u8;
+        last_scale = *entry as i32;
+    }
+    if deltas.next().is_some() {
+        return None;
+    }
+    Some((list, use_default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Puts the NAL units in a byte stream and parses their headers.
+    fn parse_nals(nals: &[&[u8]]) -> (H264NalParser, Vec<H264NalUnit>) {
+        let mut data = Vec::new();
+        for nal in nals {
+            write_nalunit(&mut data, H264NalFormat::BYTESTREAM, 4, nal);
+        }
+        let mut parser = H264NalParser::from_bytes(data.clone());
+        parser.format = H264NalFormat::BYTESTREAM;
+        let mut units = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let unit = parser.parse_nalunit(offset).unwrap();
+            offset += unit.size;
+            units.push(unit);
+        }
+        (parser, units)
+    }
+
+    fn hrd(bit_rates: &[u32], cpb_sizes: &[u32], cbr: &[u8]) -> H264HDRParameters {
+        let mut hrd = H264HDRParameters::new();
+        hrd.cpb_cnt_minus1 = bit_rates.len() as u32 - 1;
+        hrd.bit_rate_scale = 2;
+        hrd.cpb_size_scale = 5;
+        hrd.bit_rate_value_minus1 = bit_rates.to_vec();
+        hrd.cpb_size_value_minus1 = cpb_sizes.to_vec();
+        hrd.cbr_flag = cbr.to_vec();
+        hrd.initial_cpb_removal_delay_length_minus1 = 23;
+        hrd.cpb_removal_delay_length_minus1 = 17;
+        hrd.dpb_output_delay_length_minus1 = 9;
+        hrd.time_offset_length = 24;
+        hrd
+    }
+
+    fn high_profile_sps() -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = 100;
+        sps.level_idc = 40;
+        sps.chroma_format_idc = 1;
+        sps.log2_max_frame_num_minus4 = 2;
+        sps.log2_max_pic_order_cnt_lsb_minus4 = 4;
+        sps.max_num_ref_frames = 4;
+        sps.pic_width_in_mbs_minus1 = 119;
+        sps.pic_height_in_map_units_minus1 = 67;
+        sps.frame_mbs_only_flag = true;
+        sps.direct_8x8_inference_flag = 1;
+        sps.frame_cropping_flag = 1;
+        sps.frame_crop_bottom_offset = 4;
+        sps
+    }
+
+    #[test]
+    fn sps_scaling_lists_round_trip() {
+        let mut sps = high_profile_sps();
+        sps.seq_scaling_matrix_present_flag = 1;
+        sps.seq_scaling_list_present_flag = vec![1, 1, 0, 1, 0, 0, 1, 1];
+        // Not the shortest coding of the list, it has to be kept as is.
+        sps.seq_scaling_list_delta_scale[0] = vec![2, 3, 0, -13];
+        sps.scaling_list_4x4[0] = vec![10, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13, 13];
+        sps.seq_scaling_list_delta_scale[1] = vec![-8];
+        sps.use_default_scaling_matrix_flag[1] = true;
+        // Lists without delta_scale are coded from scratch.
+        sps.scaling_list_4x4[3] = (0..16).map(|i| 16 + i as u8).collect();
+        sps.scaling_list_8x8[0] = vec![4; 64];
+        sps.scaling_list_8x8[0][0] = 200;
+        sps.use_default_scaling_matrix_flag[7] = true;
+
+        let bytes = sps.to_bytes().unwrap();
+        let (mut parser, units) = parse_nals(&[&bytes]);
+        let parsed = parser.parse_sps(units[0].data_offset).unwrap();
+        assert_eq!(parsed.seq_scaling_list_present_flag, sps.seq_scaling_list_present_flag);
+        assert_eq!(parsed.seq_scaling_list_delta_scale[0], vec![2, 3, 0, -13]);
+        assert_eq!(parsed.scaling_list_4x4[0], sps.scaling_list_4x4[0]);
+        assert!(parsed.use_default_scaling_matrix_flag[1]);
+        assert_eq!(parsed.scaling_list_4x4[3], sps.scaling_list_4x4[3]);
+        assert_eq!(parsed.scaling_list_8x8[0], sps.scaling_list_8x8[0]);
+        assert!(parsed.use_default_scaling_matrix_flag[7]);
+        assert_eq!(parsed.frame_crop_bottom_offset, 4);
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn sps_vui_with_nal_and_vcl_hrd_round_trips() {
+        let mut vui = H264VUIParameters::new();
+        vui.aspect_ratio_info_present_flag = 1;
+        vui.aspect_ratio_idc = EXTENDED_SAR;
+        vui.sar_width = 4;
+        vui.sar_height = 3;
+        vui.video_signal_type_present_flag = 1;
+        vui.video_format = 5;
+        vui.colour_description_present_flag = 1;
+        vui.colour_primaries = 9;
+        vui.transfer_characteristics = 16;
+        vui.matrix_coefficients = 9;
+        vui.timing_info_present_flag = 1;
+        vui.num_units_in_tick = 1001;
+        vui.time_scale = 60000;
+        vui.fixed_frame_rate_flag = 1;
+        vui.nal_hrd_parameters_present_flag = 1;
+        vui.nal_hrd_parameters = Some(hrd(&[7811, 15624], &[9374, 18749], &[0, 1]));
+        vui.vcl_hrd_parameters_present_flag = 1;
+        vui.vcl_hrd_parameters = Some(hrd(&[6509], &[7812], &[0]));
+        vui.pic_struct_present_flag = 1;
+        vui.bitstream_restriction_flag = 1;
+        vui.motion_vectors_over_pic_boundaries_flag = 1;
+        vui.log2_max_mv_length_horizontal = 15;
+        vui.log2_max_mv_length_vertical = 15;
+        vui.max_num_reorder_frames = 2;
+        vui.max_dec_frame_buffering = 4;
+        let mut sps = high_profile_sps();
+        sps.vui_parameters_present_flag = 1;
+        sps.vui_parameters = Some(vui);
+
+        let bytes = sps.to_bytes().unwrap();
+        let (mut parser, units) = parse_nals(&[&bytes]);
+        let parsed = parser.parse_sps(units[0].data_offset).unwrap();
+        let vui = parsed.vui_parameters.as_ref().unwrap();
+        assert_eq!((vui.sar_width, vui.sar_height), (4, 3));
+        assert_eq!((vui.num_units_in_tick, vui.time_scale), (1001, 60000));
+        let nal_hrd = vui.nal_hrd_parameters.as_ref().unwrap();
+        assert_eq!(nal_hrd.cpb_cnt_minus1, 1);
+        assert_eq!(nal_hrd.bit_rate_value_minus1, vec![7811, 15624]);
+        assert_eq!(nal_hrd.cpb_size_value_minus1, vec![9374, 18749]);
+        assert_eq!(nal_hrd.cbr_flag, vec![0, 1]);
+        assert_eq!(nal_hrd.time_offset_length, 24);
+        let vcl_hrd = vui.vcl_hrd_parameters.as_ref().unwrap();
+        assert_eq!(vcl_hrd.bit_rate_value_minus1, vec![6509]);
+        assert_eq!(vui.pic_struct_present_flag, 1);
+        assert_eq!((vui.max_num_reorder_frames, vui.max_dec_frame_buffering), (2, 4));
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn pps_tail_with_transform_8x8_round_trips() {
+        let sps = high_profile_sps();
+        let mut pps = H264NalUnitPPS::new();
+        pps.entropy_coding_mode_flag = true;
+        pps.weighted_pred_flag = true;
+        pps.weighted_bipred_idc = 2;
+        pps.pic_init_qp_minus26 = -3;
+        pps.chroma_qp_index_offset = 1;
+        pps.deblocking_filter_control_present_flag = true;
+        pps.more_rbsp_data = true;
+        pps.transform_8x8_mode_flag = 1;
+        pps.pic_scaling_matrix_present_flag = 1;
+        pps.pic_scaling_list_present_flag = vec![0, 0, 0, 0, 0, 1, 1, 1];
+        pps.use_default_scaling_matrix_flag[5] = true;
+        pps.scaling_list_8x8[0] = (0..64).map(|i| 6 + i as u8 / 2).collect();
+        pps.use_default_scaling_matrix_flag[7] = true;
+        pps.second_chroma_qp_index_offset = -2;
+
+        let bytes = pps.to_bytes(sps.chroma_format_idc).unwrap();
+        let (mut parser, units) = parse_nals(&[&sps.to_bytes().unwrap(), &bytes]);
+        parser.parse_sps(units[0].data_offset).unwrap();
+        let parsed = parser.parse_pps(units[1].data_offset).unwrap();
+        assert!(parsed.more_rbsp_data);
+        assert_eq!(parsed.transform_8x8_mode_flag, 1);
+        assert_eq!(parsed.pic_scaling_list_present_flag, pps.pic_scaling_list_present_flag);
+        assert!(parsed.use_default_scaling_matrix_flag[5]);
+        assert_eq!(parsed.scaling_list_8x8[0], pps.scaling_list_8x8[0]);
+        assert!(parsed.use_default_scaling_matrix_flag[7]);
+        assert_eq!(parsed.second_chroma_qp_index_offset, -2);
+        assert_eq!(parsed.to_bytes(sps.chroma_format_idc).unwrap(), bytes);
+
+        // Without the tail the second offset is the first one.
+        let mut short = pps.clone();
+        short.more_rbsp_data = false;
+        let bytes = short.to_bytes(sps.chroma_format_idc).unwrap();
+        let (mut parser, units) = parse_nals(&[&bytes]);
+        let parsed = parser.parse_pps(units[0].data_offset).unwrap();
+        assert!(!parsed.more_rbsp_data);
+        assert_eq!(parsed.second_chroma_qp_index_offset, 1);
+        assert_eq!(parsed.to_bytes(sps.chroma_format_idc).unwrap(), bytes);
+    }
+
+    fn weight_table(count: usize) -> H264PredWeightTable {
+        let mut table = H264PredWeightTable::new();
+        table.luma_log2_weight_denom = 6;
+        table.chroma_log2_weight_denom = 4;
+        for i in 0..count {
+            table.luma_weight_l0_flag.push(i % 2 == 0);
+            table.luma_weight_l0.push(if i % 2 == 0 { 70 - i as i32 } else { 0 });
+            table.luma_offset_l0.push(if i % 2 == 0 { -3 } else { 0 });
+            table.chroma_weight_l0_flag.push(i == 1);
+            table.chroma_weight_l0.push(if i == 1 { [15, 17] } else { [0, 0] });
+            table.chroma_offset_l0.push(if i == 1 { [1, -1] } else { [0, 0] });
+        }
+        table
+    }
+
+    /// Writes a slice header, parses it back and checks that writing the
+    /// parsed header gives the same bytes.
+    fn round_trip_slice(slice: &H264NalUnitSlice, nal_ref_idc: u8, nal_unit_type: u8,
+                        sps: &H264NalUnitSPS, pps: &H264NalUnitPPS) -> H264NalUnitSlice {
+        let unit = H264NalUnit::new(0, 4, 0, nal_ref_idc, nal_unit_type);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, sps, pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let nal = writer.to_nal(nal_ref_idc, nal_unit_type);
+        let (mut parser, units) = parse_nals(&[&sps.to_bytes().unwrap(), &pps.to_bytes(sps.chroma_format_idc).unwrap(), &nal]);
+        parser.parse_sps(units[0].data_offset).unwrap();
+        parser.parse_pps(units[1].data_offset).unwrap();
+        let parsed = parser.parse_slice(units[2].data_offset, &units[2]).unwrap();
+        let mut writer = H264NalWriter::new();
+        parsed.write(&mut writer, &units[2], sps, pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        assert_eq!(writer.to_nal(nal_ref_idc, nal_unit_type), nal);
+        parsed
+    }
+
+    fn weighted_pps() -> H264NalUnitPPS {
+        let mut pps = H264NalUnitPPS::new();
+        pps.weighted_pred_flag = true;
+        pps.weighted_bipred_idc = 1;
+        pps.deblocking_filter_control_present_flag = true;
+        pps
+    }
+
+    #[test]
+    fn p_slice_header_round_trips() {
+        let sps = high_profile_sps();
+        let pps = weighted_pps();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 5;
+        slice.frame_num = 9;
+        slice.pic_order_cnt_lsb = 18;
+        slice.num_ref_idx_active_override_flag = true;
+        slice.num_ref_idx_l0_active_minus1 = 2;
+        slice.ref_pic_list_modification_flag_l0 = true;
+        slice.ref_pic_list_modification_l0 = vec![
+            H264RefPicListModification { modification_of_pic_nums_idc: 0, value: 3 },
+            H264RefPicListModification { modification_of_pic_nums_idc: 2, value: 1 }];
+        slice.pred_weight_table = Some(weight_table(3));
+        slice.adaptive_ref_pic_marking_mode_flag = true;
+        let mut unmark = H264MemoryManagementOperation::new(1);
+        unmark.difference_of_pic_nums_minus1 = 4;
+        let mut long_term = H264MemoryManagementOperation::new(3);
+        long_term.long_term_frame_idx = 1;
+        let mut max_idx = H264MemoryManagementOperation::new(4);
+        max_idx.max_long_term_frame_idx_plus1 = 3;
+        let mut current = H264MemoryManagementOperation::new(6);
+        current.long_term_frame_idx = 2;
+        slice.memory_management_control_operations = vec![unmark, long_term, max_idx, current];
+        slice.slice_qp_delta = -4;
+        slice.disable_deblocking_filter_idc = 2;
+        slice.slice_alpha_c0_offset_div2 = -1;
+        slice.slice_beta_offset_div2 = 1;
+
+        let parsed = round_trip_slice(&slice, 2, 1, &sps, &pps);
+        assert_eq!(parsed.num_ref_idx_l0_active_minus1, 2);
+        assert_eq!(parsed.ref_pic_list_modification_l0, slice.ref_pic_list_modification_l0);
+        assert_eq!(parsed.pred_weight_table, slice.pred_weight_table);
+        assert_eq!(parsed.memory_management_control_operations, slice.memory_management_control_operations);
+        assert_eq!(parsed.slice_qp_delta, -4);
+        assert_eq!((parsed.slice_alpha_c0_offset_div2, parsed.slice_beta_offset_div2), (-1, 1));
+    }
+
+    #[test]
+    fn b_slice_header_round_trips() {
+        let sps = high_profile_sps();
+        let pps = weighted_pps();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 1;
+        slice.frame_num = 3;
+        slice.pic_order_cnt_lsb = 6;
+        slice.direct_spatial_mv_pred_flag = true;
+        slice.num_ref_idx_active_override_flag = true;
+        slice.num_ref_idx_l0_active_minus1 = 1;
+        slice.num_ref_idx_l1_active_minus1 = 0;
+        slice.ref_pic_list_modification_flag_l1 = true;
+        slice.ref_pic_list_modification_l1 = vec![
+            H264RefPicListModification { modification_of_pic_nums_idc: 1, value: 0 }];
+        let mut table = weight_table(2);
+        table.luma_weight_l1_flag = vec![true];
+        table.luma_weight_l1 = vec![64];
+        table.luma_offset_l1 = vec![2];
+        table.chroma_weight_l1_flag = vec![false];
+        table.chroma_weight_l1 = vec![[0, 0]];
+        table.chroma_offset_l1 = vec![[0, 0]];
+        slice.pred_weight_table = Some(table);
+        slice.disable_deblocking_filter_idc = 1;
+
+        let parsed = round_trip_slice(&slice, 0, 1, &sps, &pps);
+        assert!(parsed.direct_spatial_mv_pred_flag);
+        assert!(!parsed.ref_pic_list_modification_flag_l0);
+        assert_eq!(parsed.ref_pic_list_modification_l1, slice.ref_pic_list_modification_l1);
+        assert_eq!(parsed.pred_weight_table, slice.pred_weight_table);
+        assert!(parsed.memory_management_control_operations.is_empty());
+    }
+
+    fn hdr_messages() -> (H264MasteringDisplayColourVolume, H264ContentLightLevelInfo) {
+        let mut mdcv = H264MasteringDisplayColourVolume::new();
+        mdcv.display_primaries_x = [34000, 13250, 7500];
+        mdcv.display_primaries_y = [16000, 34500, 3000];
+        mdcv.white_point_x = 15635;
+        mdcv.white_point_y = 16450;
+        mdcv.max_display_mastering_luminance = 10000000;
+        mdcv.min_display_mastering_luminance = 50;
+        let mut clli = H264ContentLightLevelInfo::new();
+        clli.max_content_light_level = 1000;
+        clli.max_pic_average_light_level = 400;
+        (mdcv, clli)
+    }
+
+    #[test]
+    fn hdr_sei_round_trips() {
+        let (mdcv, clli) = hdr_messages();
+        let mut builder = H264SEIBuilder::new();
+        builder.add_mastering_display_colour_volume(&mdcv).add_content_light_level_info(&clli);
+        let nal = builder.build();
+        let (parser, units) = parse_nals(&[&nal]);
+        let messages = parser.parse_sei(&units[0]).unwrap();
+        assert_eq!(messages.iter().map(|m| m.payload.clone()).collect::<Vec<_>>(),
+                   vec![H264SEIPayload::MasteringDisplayColourVolume(mdcv),
+                        H264SEIPayload::ContentLightLevelInfo(clli)]);
+    }
+
+    /// Splits a stream back into the NAL unit types of its access units.
+    fn access_unit_types(data: &[u8], format: H264NalFormat, nal_length_size: usize) -> Vec<Vec<u8>> {
+        let mut parser = H264NalParser::from_bytes(data.to_vec());
+        parser.format = format;
+        parser.nal_length_size = nal_length_size;
+        let mut types = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let au = parser.parse_access_unit(offset).unwrap();
+            types.push(au.nal_units.iter().map(|n| n.nal_unit_type_num).collect());
+            offset += au.size;
+        }
+        types
+    }
+
+    #[test]
+    fn sei_is_inserted_before_the_first_slice() {
+        let (_, clli) = hdr_messages();
+        let mut builder = H264SEIBuilder::new();
+        builder.add_content_light_level_info(&clli);
+        let sei = builder.build();
+        let nals : [&[u8]; 6] = [&[0x09, 0xF0], &[0x67, 0x42, 0x00, 0x0A, 0xF8], &[0x68, 0xCE, 0x38, 0x80],
+                                 &[0x65, 0x88, 0x80], &[0x65, 0x4C, 0x80], &[0x41, 0x9A, 0x80]];
+        for &(format, nal_length_size) in &[(H264NalFormat::BYTESTREAM, 4), (H264NalFormat::AVC, 2)] {
+            let mut data = Vec::new();
+            for nal in &nals {
+                write_nalunit(&mut data, format, nal_length_size, nal);
+            }
+            let out = insert_sei(&data, format, nal_length_size, |i| if i == 0 { Some(sei.clone()) } else { None })
+                .unwrap();
+            assert_eq!(out.len(), data.len() + sei.len() + if format == H264NalFormat::AVC { 2 } else { 4 });
+            assert_eq!(access_unit_types(&out, format, nal_length_size), vec![vec![9, 7, 8, 6, 5, 5], vec![1]]);
+            let mut parser = H264NalParser::from_bytes(out);
+            parser.format = format;
+            parser.nal_length_size = nal_length_size;
+            let au = parser.parse_access_unit(0).unwrap();
+            assert_eq!(au.sei.len(), 1);
+            assert_eq!(au.sei[0].payload, H264SEIPayload::ContentLightLevelInfo(clli.clone()));
+        }
+    }
+
+    #[test]
+    fn cea708_captions_match_ga94() {
+        let mut builder = H264SEIBuilder::new();
+        builder.add_cea708_captions(&[[0x04, 0x94, 0x2C], [0x04, 0x80, 0x80]]);
+        // The caption SEI as x264 and ffmpeg write it.
+        assert_eq!(builder.build(), vec![
+            0x06, 0x04, 0x11,
+            0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0x42, 0xFF,
+            0xFC, 0x94, 0x2C, 0xFC, 0x80, 0x80, 0xFF,
+            0x80]);
+    }
+
+    #[test]
+    fn rewrite_sps_leaves_the_slices_alone() {
+        let sps = high_profile_sps();
+        let pps = H264NalUnitPPS::new();
+        let mut slice = H264NalUnitSlice::new();
+        slice.slice_type = 7;
+        let unit = H264NalUnit::new(0, 4, 0, 3, 5);
+        let mut writer = H264NalWriter::new();
+        slice.write(&mut writer, &unit, &sps, &pps).unwrap();
+        writer.write_rbsp_trailing_bits();
+        let nals = vec![sps.to_bytes().unwrap(), pps.to_bytes(1).unwrap(), writer.to_nal(3, 5)];
+
+        for &(format, nal_length_size) in &[(H264NalFormat::BYTESTREAM, 4), (H264NalFormat::AVC, 4), (H264NalFormat::AVC, 2)] {
+            let mut data = Vec::new();
+            for nal in &nals {
+                write_nalunit(&mut data, format, nal_length_size, nal);
+            }
+            let out = rewrite_sps(&data, format, nal_length_size, |s| {
+                s.set_timing_info(1001, 60000, true);
+                s.set_video_full_range(true);
+                s.set_colour_description(1, 1, 1);
+                s.set_low_latency();
+            }).unwrap();
+
+            let mut parser = H264NalParser::from_bytes(out.clone());
+            parser.format = format;
+            parser.nal_length_size = nal_length_size;
+            let sps_unit = parser.parse_nalunit(0).unwrap();
+            let parsed = parser.parse_sps(sps_unit.data_offset).unwrap();
+            assert_eq!(parsed.frame_rate_fraction(), Some((30000, 1001)));
+            let vui = parsed.vui_parameters.as_ref().unwrap();
+            assert_eq!((vui.video_full_range_flag, vui.colour_primaries), (1, 1));
+            assert_eq!((vui.max_num_reorder_frames, vui.max_dec_frame_buffering), (0, 4));
+            assert_eq!(vui.log2_max_mv_length_vertical, 16);
+            // Everything after the SPS is copied as it was.
+            let old_sps_size = nals[0].len() + if format == H264NalFormat::AVC { nal_length_size } else { 4 };
+            assert_eq!(&out[sps_unit.size..], &data[old_sps_size..]);
+        }
+    }
+
+    /// xorshift64, enough to vary the generated headers without a
+    /// dependency. Seeded so that failures can be reproduced.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// A value in 0..=max.
+        fn below(&mut self, max: u32) -> u32 {
+            (self.next() % (max as u64 + 1)) as u32
+        }
+
+        fn flag(&mut self) -> bool {
+            self.next() & 1 == 1
+        }
+
+        fn bit(&mut self) -> u8 {
+            self.flag() as u8
+        }
+
+        /// Mostly small values, sometimes the largest an ue(v) can code.
+        fn ue(&mut self) -> u32 {
+            match self.below(7) {
+                0 => u32::MAX - 1 - self.below(3),
+                1 => self.next() as u32 >> 1,
+                _ => self.below(40)
+            }
+        }
+
+        /// Mostly small values, sometimes the extremes of se(v).
+        fn se(&mut self) -> i32 {
+            match self.below(7) {
+                0 => i32::MAX - self.below(3) as i32,
+                1 => i32::MIN + 1 + self.below(3) as i32,
+                _ => self.below(80) as i32 - 40
+            }
+        }
+
+        fn scaling_list(&mut self, len: usize) -> Vec<u8> {
+            let mut list : Vec<u8> = (0..len).map(|_| 1 + self.below(254) as u8).collect();
+            // A run at the end is coded with nextScale 0.
+            if self.flag() {
+                let run = self.below(len as u32 - 1) as usize;
+                let last = list[len - run - 1];
+                for value in &mut list[len - run..] {
+                    *value = last;
+                }
+            }
+            list
+        }
+    }
+
+    fn random_hrd(rng: &mut Rng) -> H264HDRParameters {
+        let mut hrd = H264HDRParameters::new();
+        hrd.cpb_cnt_minus1 = rng.below(31);
+        hrd.bit_rate_scale = rng.below(15) as u8;
+        hrd.cpb_size_scale = rng.below(15) as u8;
+        for _ in 0..hrd.cpb_cnt_minus1 + 1 {
+            hrd.bit_rate_value_minus1.push(rng.ue());
+            hrd.cpb_size_value_minus1.push(rng.ue());
+            hrd.cbr_flag.push(rng.bit());
+        }
+        hrd.initial_cpb_removal_delay_length_minus1 = rng.below(31) as u8;
+        hrd.cpb_removal_delay_length_minus1 = rng.below(31) as u8;
+        hrd.dpb_output_delay_length_minus1 = rng.below(31) as u8;
+        hrd.time_offset_length = rng.below(31) as u8;
+        hrd
+    }
+
+    fn random_vui(rng: &mut Rng) -> H264VUIParameters {
+        let mut vui = H264VUIParameters::new();
+        vui.aspect_ratio_info_present_flag = rng.bit();
+        if vui.aspect_ratio_info_present_flag == 1 {
+            vui.aspect_ratio_idc = if rng.flag() { EXTENDED_SAR } else { rng.below(16) as u8 };
+            if vui.aspect_ratio_idc == EXTENDED_SAR {
+                vui.sar_width = rng.next() as u16;
+                vui.sar_height = rng.next() as u16;
+            }
+        }
+        vui.overscan_info_present_flag = rng.bit();
+        if vui.overscan_info_present_flag == 1 {
+            vui.overscan_appropriate_flag = rng.bit();
+        }
+        vui.video_signal_type_present_flag = rng.bit();
+        if vui.video_signal_type_present_flag == 1 {
+            vui.video_format = rng.below(7) as u8;
+            vui.video_full_range_flag = rng.bit();
+            vui.colour_description_present_flag = rng.bit();
+            if vui.colour_description_present_flag == 1 {
+                vui.colour_primaries = rng.next() as u8;
+                vui.transfer_characteristics = rng.next() as u8;
+                vui.matrix_coefficients = rng.next() as u8;
+            }
+        }
+        vui.chroma_loc_info_present_flag = rng.bit();
+        if vui.chroma_loc_info_present_flag == 1 {
+            vui.chroma_sample_loc_type_top_field = rng.below(5);
+            vui.chroma_sample_loc_type_bottom_field = rng.below(5);
+        }
+        vui.timing_info_present_flag = rng.bit();
+        if vui.timing_info_present_flag == 1 {
+            vui.num_units_in_tick = rng.next() as u32;
+            vui.time_scale = rng.next() as u32;
+            vui.fixed_frame_rate_flag = rng.bit();
+        }
+        vui.nal_hrd_parameters_present_flag = rng.bit();
+        if vui.nal_hrd_parameters_present_flag == 1 {
+            vui.nal_hrd_parameters = Some(random_hrd(rng));
+        }
+        vui.vcl_hrd_parameters_present_flag = rng.bit();
+        if vui.vcl_hrd_parameters_present_flag == 1 {
+            vui.vcl_hrd_parameters = Some(random_hrd(rng));
+        }
+        if vui.nal_hrd_parameters_present_flag == 1 || vui.vcl_hrd_parameters_present_flag == 1 {
+            vui.low_delay_hrd_flag = rng.bit();
+        }
+        vui.pic_struct_present_flag = rng.bit();
+        vui.bitstream_restriction_flag = rng.bit();
+        if vui.bitstream_restriction_flag == 1 {
+            vui.motion_vectors_over_pic_boundaries_flag = rng.bit();
+            vui.max_bytes_per_pic_denom = rng.below(16);
+            vui.max_bits_per_mb_denom = rng.below(16);
+            vui.log2_max_mv_length_horizontal = rng.below(16);
+            vui.log2_max_mv_length_vertical = rng.below(16);
+            vui.max_num_reorder_frames = rng.below(16);
+            vui.max_dec_frame_buffering = rng.below(16);
+        }
+        vui
+    }
+
+    fn random_sps(rng: &mut Rng, pic_order_cnt_type: u32) -> H264NalUnitSPS {
+        let mut sps = H264NalUnitSPS::new();
+        sps.profile_idc = [66, 77, 88, 100, 110, 122, 244][rng.below(6) as usize];
+        sps.constraint_0_flag = rng.bit();
+        sps.constraint_1_flag = rng.bit();
+        sps.constraint_3_flag = rng.bit();
+        sps.level_idc = [10, 11, 13, 21, 30, 31, 40, 42, 51, 62][rng.below(9) as usize];
+        sps.seq_parameter_set_id = rng.below(31);
+        sps.chroma_format_idc = 1;
+        if profile_idc_has_chroma_info(sps.profile_idc) {
+            sps.chroma_format_idc = rng.below(3);
+            if sps.chroma_format_idc == 3 {
+                sps.separate_colour_plane_flag = rng.flag();
+            }
+            sps.bit_depth_luma_minus8 = rng.below(6);
+            sps.bit_depth_chroma_minus8 = rng.below(6);
+            sps.qpprime_y_zero_transform_bypass_flag = rng.bit();
+            sps.seq_scaling_matrix_present_flag = rng.bit();
+            if sps.seq_scaling_matrix_present_flag == 1 {
+                let scaling_lists = if sps.chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..scaling_lists {
+                    sps.seq_scaling_list_present_flag.push(rng.bit());
+                    if sps.seq_scaling_list_present_flag[i] == 1 {
+                        if rng.below(3) == 0 {
+                            sps.use_default_scaling_matrix_flag[i] = true;
+                        } else if i < 6 {
+                            sps.scaling_list_4x4[i] = rng.scaling_list(16);
+                        } else {
+                            sps.scaling_list_8x8[i - 6] = rng.scaling_list(64);
+                        }
+                    }
+                }
+            }
+        }
+        sps.log2_max_frame_num_minus4 = rng.below(12);
+        sps.pic_order_cnt_type = pic_order_cnt_type;
+        if pic_order_cnt_type == 0 {
+            sps.log2_max_pic_order_cnt_lsb_minus4 = rng.below(12);
+        } else if pic_order_cnt_type == 1 {
+            sps.delta_pic_order_always_zero_flag = rng.flag();
+            sps.offset_for_non_ref_pic = rng.se();
+            sps.offset_for_top_to_bottom_field = rng.se();
+            sps.num_ref_frames_in_pic_order_cnt_cycle = if rng.flag() { rng.below(255) } else { rng.below(4) };
+            sps.offset_for_ref_frame = (0..sps.num_ref_frames_in_pic_order_cnt_cycle).map(|_| rng.se()).collect();
+        }
+        sps.max_num_ref_frames = rng.below(16);
+        sps.gaps_in_frame_num_value_allowed_flag = rng.bit();
+        sps.pic_width_in_mbs_minus1 = rng.below(511);
+        sps.pic_height_in_map_units_minus1 = rng.below(511);
+        sps.frame_mbs_only_flag = rng.flag();
+        if !sps.frame_mbs_only_flag {
+            sps.mb_adaptive_frame_field_flag = rng.bit();
+        }
+        sps.direct_8x8_inference_flag = rng.bit();
+        sps.frame_cropping_flag = rng.bit();
+        if sps.frame_cropping_flag == 1 {
+            sps.frame_crop_left_offset = rng.below(8);
+            sps.frame_crop_right_offset = rng.below(8);
+            sps.frame_crop_top_offset = rng.below(8);
+            sps.frame_crop_bottom_offset = rng.below(8);
+        }
+        sps.vui_parameters_present_flag = rng.bit();
+        if sps.vui_parameters_present_flag == 1 {
+            sps.vui_parameters = Some(random_vui(rng));
+        }
+        sps
+    }
+
+    fn random_pps(rng: &mut Rng, sps: &H264NalUnitSPS) -> H264NalUnitPPS {
+        let mut pps = H264NalUnitPPS::new();
+        pps.pic_parameter_set_id = rng.below(255);
+        pps.seq_parameter_set_id = sps.seq_parameter_set_id;
+        pps.entropy_coding_mode_flag = rng.flag();
+        pps.bottom_field_pic_order_in_frame_present_flag = rng.flag();
+        if rng.below(3) == 0 {
+            pps.num_slice_groups_minus1 = 1 + rng.below(6);
+            pps.slice_group_map_type = rng.below(6);
+            match pps.slice_group_map_type {
+                0 => pps.run_length_minus1 = (0..pps.num_slice_groups_minus1 + 1).map(|_| rng.ue()).collect(),
+                2 => {
+                    pps.top_left = (0..pps.num_slice_groups_minus1).map(|_| rng.ue()).collect();
+                    pps.bottom_right = (0..pps.num_slice_groups_minus1).map(|_| rng.ue()).collect();
+                },
+                3..=5 => {
+                    pps.slice_group_change_direction_flag = rng.bit();
+                    pps.slice_group_change_rate_minus1 = rng.below(100);
+                },
+                6 => {
+                    pps.pic_size_in_map_units_minus1 = rng.below(50);
+                    let groups = pps.num_slice_groups_minus1;
+                    pps.slice_group_id = (0..pps.pic_size_in_map_units_minus1 + 1).map(|_| rng.below(groups)).collect();
+                },
+                _ => {}
+            }
+        }
+        pps.num_ref_idx_l0_default_active_minus1 = rng.below(31);
+        pps.num_ref_idx_l1_default_active_minus1 = rng.below(31);
+        pps.weighted_pred_flag = rng.flag();
+        pps.weighted_bipred_idc = rng.below(2) as u8;
+        pps.pic_init_qp_minus26 = rng.below(51) as i32 - 26;
+        pps.pic_init_qs_minus26 = rng.below(51) as i32 - 26;
+        pps.chroma_qp_index_offset = rng.below(24) as i32 - 12;
+        pps.deblocking_filter_control_present_flag = rng.flag();
+        pps.constrained_intra_pred_flag = rng.bit();
+        pps.redundant_pic_cnt_present_flag = rng.flag();
+        pps.second_chroma_qp_index_offset = pps.chroma_qp_index_offset;
+        pps.more_rbsp_data = rng.flag();
+        if pps.more_rbsp_data {
+            pps.transform_8x8_mode_flag = rng.bit();
+            pps.pic_scaling_matrix_present_flag = rng.bit();
+            if pps.pic_scaling_matrix_present_flag == 1 {
+                let scaling_lists = 6 + (if sps.chroma_format_idc != 3 { 2 } else { 6 }) *
+                    pps.transform_8x8_mode_flag as usize;
+                for i in 0..scaling_lists {
+                    pps.pic_scaling_list_present_flag.push(rng.bit());
+                    if pps.pic_scaling_list_present_flag[i] == 1 {
+                        if rng.below(3) == 0 {
+                            pps.use_default_scaling_matrix_flag[i] = true;
+                        } else if i < 6 {
+                            pps.scaling_list_4x4[i] = rng.scaling_list(16);
+                        } else {
+                            pps.scaling_list_8x8[i - 6] = rng.scaling_list(64);
+                        }
+                    }
+                }
+            }
+            pps.second_chroma_qp_index_offset = rng.below(24) as i32 - 12;
+        }
+        pps
+    }
+
+    fn random_weights(rng: &mut Rng, table: &mut H264PredWeightTable, list: usize, count: u32,
+                      chroma_present: bool) {
+        for _ in 0..count {
+            let luma = rng.flag();
+            let luma_weight = if luma { rng.below(255) as i32 - 128 } else { 0 };
+            let luma_offset = if luma { rng.below(255) as i32 - 128 } else { 0 };
+            let chroma = chroma_present && rng.flag();
+            let mut chroma_weight = [0; 2];
+            let mut chroma_offset = [0; 2];
+            if chroma {
+                for j in 0..2 {
+                    chroma_weight[j] = rng.below(255) as i32 - 128;
+                    chroma_offset[j] = rng.below(255) as i32 - 128;
+                }
+            }
+            if list == 0 {
+                table.luma_weight_l0_flag.push(luma);
+                table.luma_weight_l0.push(luma_weight);
+                table.luma_offset_l0.push(luma_offset);
+                table.chroma_weight_l0_flag.push(chroma);
+                table.chroma_weight_l0.push(chroma_weight);
+                table.chroma_offset_l0.push(chroma_offset);
+            } else {
+                table.luma_weight_l1_flag.push(luma);
+                table.luma_weight_l1.push(luma_weight);
+                table.luma_offset_l1.push(luma_offset);
+                table.chroma_weight_l1_flag.push(chroma);
+                table.chroma_weight_l1.push(chroma_weight);
+                table.chroma_offset_l1.push(chroma_offset);
+            }
+        }
+    }
+
+    fn random_slice(rng: &mut Rng, sps: &H264NalUnitSPS, pps: &H264NalUnitPPS,
+                    nal_ref_idc: u8, idr: bool) -> H264NalUnitSlice {
+        let mut slice = H264NalUnitSlice::new();
+        slice.first_mb_in_slice = rng.below(100);
+        slice.slice_type = if idr { [2, 4, 7, 9][rng.below(3) as usize] } else { rng.below(9) };
+        slice.pic_parameter_set_id = pps.pic_parameter_set_id;
+        if sps.separate_colour_plane_flag {
+            slice.colour_plane_id = rng.below(2) as u8;
+        }
+        slice.frame_num = if idr { 0 } else { rng.below((1 << (sps.log2_max_frame_num_minus4 + 4)) - 1) };
+        if !sps.frame_mbs_only_flag {
+            slice.field_pic_flag = rng.flag();
+            if slice.field_pic_flag {
+                slice.bottom_field_flag = rng.flag();
+            }
+        }
+        if idr {
+            slice.idr_pic_id = rng.below(65535);
+        }
+        if sps.pic_order_cnt_type == 0 {
+            slice.pic_order_cnt_lsb = rng.below((1 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4)) - 1) as u16;
+            if pps.bottom_field_pic_order_in_frame_present_flag && !slice.field_pic_flag {
+                slice.delta_pic_order_cnt_bottom = rng.se();
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            slice.delta_pic_order_cnt[0] = rng.se();
+            if pps.bottom_field_pic_order_in_frame_present_flag && slice.field_pic_flag {
+                slice.delta_pic_order_cnt[1] = rng.se();
+            }
+        }
+        if pps.redundant_pic_cnt_present_flag {
+            slice.redundant_pic_cnt = rng.below(127);
+        }
+        let b = slice_type_is_b_slice(slice.slice_type);
+        let p = slice_type_is_p_slice(slice.slice_type) || slice_type_is_sp_slice(slice.slice_type);
+        if b {
+            slice.direct_spatial_mv_pred_flag = rng.flag();
+        }
+        slice.num_ref_idx_l0_active_minus1 = pps.num_ref_idx_l0_default_active_minus1;
+        slice.num_ref_idx_l1_active_minus1 = pps.num_ref_idx_l1_default_active_minus1;
+        if p || b {
+            slice.num_ref_idx_active_override_flag = rng.flag();
+            if slice.num_ref_idx_active_override_flag {
+                slice.num_ref_idx_l0_active_minus1 = rng.below(31);
+                if b {
+                    slice.num_ref_idx_l1_active_minus1 = rng.below(31);
+                }
+            }
+        }
+        let modifications = |rng: &mut Rng| (0..rng.below(4)).map(|_| H264RefPicListModification {
+            modification_of_pic_nums_idc: rng.below(2),
+            value: rng.ue()
+        }).collect::<Vec<_>>();
+        if p || b {
+            slice.ref_pic_list_modification_flag_l0 = rng.flag();
+            if slice.ref_pic_list_modification_flag_l0 {
+                slice.ref_pic_list_modification_l0 = modifications(rng);
+            }
+        }
+        if b {
+            slice.ref_pic_list_modification_flag_l1 = rng.flag();
+            if slice.ref_pic_list_modification_flag_l1 {
+                slice.ref_pic_list_modification_l1 = modifications(rng);
+            }
+        }
+        if (pps.weighted_pred_flag && p) || (pps.weighted_bipred_idc == 1 && b) {
+            let mut table = H264PredWeightTable::new();
+            table.luma_log2_weight_denom = rng.below(7);
+            let chroma = sps.chroma_array_type() != 0;
+            if chroma {
+                table.chroma_log2_weight_denom = rng.below(7);
+            }
+            random_weights(rng, &mut table, 0, slice.num_ref_idx_l0_active_minus1 + 1, chroma);
+            if b {
+                random_weights(rng, &mut table, 1, slice.num_ref_idx_l1_active_minus1 + 1, chroma);
+            }
+            slice.pred_weight_table = Some(table);
+        }
+        if nal_ref_idc != 0 {
+            if idr {
+                slice.no_output_of_prior_pics_flag = rng.flag();
+                slice.long_term_reference_flag = rng.flag();
+            } else {
+                slice.adaptive_ref_pic_marking_mode_flag = rng.flag();
+                if slice.adaptive_ref_pic_marking_mode_flag {
+                    for _ in 0..rng.below(4) {
+                        let mut op = H264MemoryManagementOperation::new(1 + rng.below(5));
+                        op.difference_of_pic_nums_minus1 = rng.below(30);
+                        op.long_term_pic_num = rng.below(30);
+                        op.long_term_frame_idx = rng.below(15);
+                        op.max_long_term_frame_idx_plus1 = rng.below(16);
+                        slice.memory_management_control_operations.push(op);
+                    }
+                }
+            }
+        }
+        if pps.entropy_coding_mode_flag && (p || b) {
+            slice.cabac_init_idc = rng.below(2);
+        }
+        slice.slice_qp_delta = rng.below(51) as i32 - 26;
+        if slice_type_is_sp_slice(slice.slice_type) || slice_type_is_si_slice(slice.slice_type) {
+            slice.sp_for_switch_flag = slice_type_is_sp_slice(slice.slice_type) && rng.flag();
+            slice.slice_qs_delta = rng.below(51) as i32 - 26;
+        }
+        if pps.deblocking_filter_control_present_flag {
+            slice.disable_deblocking_filter_idc = rng.below(2);
+            if slice.disable_deblocking_filter_idc != 1 {
+                slice.slice_alpha_c0_offset_div2 = rng.below(12) as i32 - 6;
+                slice.slice_beta_offset_div2 = rng.below(12) as i32 - 6;
+            }
+        }
+        if pps.num_slice_groups_minus1 > 0 && pps.slice_group_map_type >= 3 && pps.slice_group_map_type <= 5 {
+            let nbits = slice_group_change_cycle_bits(sps, pps);
+            slice.slice_group_change_cycle = (rng.next() & ((1 << nbits) - 1)) as u32;
+        }
+        slice
+    }
+
+    #[test]
+    fn generated_headers_round_trip() {
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        for i in 0..600 {
+            let sps = random_sps(&mut rng, i % 3);
+            let sps_bytes = sps.to_bytes().unwrap();
+            let pps = random_pps(&mut rng, &sps);
+            let pps_bytes = pps.to_bytes(sps.chroma_format_idc).unwrap();
+            let (mut parser, units) = parse_nals(&[&sps_bytes, &pps_bytes]);
+            let parsed_sps = parser.parse_sps(units[0].data_offset).unwrap();
+            let parsed_pps = parser.parse_pps(units[1].data_offset).unwrap();
+            assert_eq!(parsed_sps.to_bytes().unwrap(), sps_bytes, "SPS {}", i);
+            assert_eq!(parsed_pps.to_bytes(sps.chroma_format_idc).unwrap(), pps_bytes, "PPS {}", i);
+
+            assert_eq!(parsed_sps.pic_order_cnt_type, sps.pic_order_cnt_type);
+            assert_eq!(parsed_sps.log2_max_pic_order_cnt_lsb_minus4, sps.log2_max_pic_order_cnt_lsb_minus4);
+            assert_eq!(parsed_sps.offset_for_non_ref_pic, sps.offset_for_non_ref_pic);
+            assert_eq!(parsed_sps.offset_for_ref_frame, sps.offset_for_ref_frame);
+            assert_eq!(parsed_sps.seq_scaling_list_present_flag, sps.seq_scaling_list_present_flag);
+            for j in 0..sps.seq_scaling_list_present_flag.len() {
+                if sps.seq_scaling_list_present_flag[j] == 1 && !sps.use_default_scaling_matrix_flag[j] {
+                    let (list, parsed) = if j < 6 {
+                        (&sps.scaling_list_4x4[j], &parsed_sps.scaling_list_4x4[j])
+                    } else {
+                        (&sps.scaling_list_8x8[j - 6], &parsed_sps.scaling_list_8x8[j - 6])
+                    };
+                    assert_eq!(parsed, list, "SPS {} scaling list {}", i, j);
+                }
+            }
+            assert_eq!(parsed_sps.use_default_scaling_matrix_flag, sps.use_default_scaling_matrix_flag);
+            match (&parsed_sps.vui_parameters, &sps.vui_parameters) {
+                (Some(parsed), Some(vui)) => {
+                    assert_eq!(parsed.nal_hrd_parameters.as_ref().map(|h| &h.bit_rate_value_minus1),
+                               vui.nal_hrd_parameters.as_ref().map(|h| &h.bit_rate_value_minus1));
+                    assert_eq!(parsed.vcl_hrd_parameters.as_ref().map(|h| &h.cpb_size_value_minus1),
+                               vui.vcl_hrd_parameters.as_ref().map(|h| &h.cpb_size_value_minus1));
+                    assert_eq!((parsed.num_units_in_tick, parsed.time_scale), (vui.num_units_in_tick, vui.time_scale));
+                },
+                (None, None) => {},
+                _ => panic!("SPS {} VUI presence changed", i)
+            }
+            assert_eq!(parsed_pps.pic_scaling_list_present_flag, pps.pic_scaling_list_present_flag);
+            assert_eq!(parsed_pps.slice_group_id, pps.slice_group_id);
+            assert_eq!(parsed_pps.second_chroma_qp_index_offset, pps.second_chroma_qp_index_offset);
+
+            for _ in 0..4 {
+                let nal_ref_idc = rng.below(3) as u8;
+                let idr = nal_ref_idc != 0 && rng.below(3) == 0;
+                let slice = random_slice(&mut rng, &sps, &pps, nal_ref_idc, idr);
+                let parsed = round_trip_slice(&slice, nal_ref_idc, if idr { 5 } else { 1 }, &sps, &pps);
+                assert_eq!(parsed.slice_type, slice.slice_type);
+                assert_eq!(parsed.frame_num, slice.frame_num);
+                assert_eq!(parsed.pic_order_cnt_lsb, slice.pic_order_cnt_lsb);
+                assert_eq!(parsed.delta_pic_order_cnt, slice.delta_pic_order_cnt);
+                assert_eq!(parsed.num_ref_idx_l0_active_minus1, slice.num_ref_idx_l0_active_minus1);
+                assert_eq!(parsed.ref_pic_list_modification_l1, slice.ref_pic_list_modification_l1);
+                assert_eq!(parsed.pred_weight_table, slice.pred_weight_table);
+                assert_eq!(parsed.memory_management_control_operations.len(),
+                           slice.memory_management_control_operations.len());
+                assert_eq!(parsed.slice_group_change_cycle, slice.slice_group_change_cycle);
+            }
+        }
+    }
+}